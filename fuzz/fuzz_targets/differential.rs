@@ -0,0 +1,276 @@
+//! Generate random well-formed toy functions, and check that they survive a
+//! round trip through the parser, and that the JIT computes the same results
+//! as the interpreters.
+
+#![no_main]
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use cranelift_jit_demo::format::DisplayFunction;
+use cranelift_jit_demo::frontend::{parser, Expr};
+use cranelift_jit_demo::interp::Interpreter;
+use cranelift_jit_demo::jit::JIT;
+use libfuzzer_sys::fuzz_target;
+use std::mem;
+
+/// A function the generated functions may call.
+const HELPER_CODE: &str = r#"
+    fn helper(a, b) -> (r) {
+        r = a * 3 - b
+    }
+"#;
+
+const PARAMS: [&str; 3] = ["a", "b", "c"];
+const LOCALS: [&str; 3] = ["x", "y", "z"];
+const RETURN: &str = "r";
+
+/// The loop counters. These are only ever assigned by the loops themselves,
+/// so that all loops terminate.
+const COUNTERS: [&str; 3] = ["i", "j", "k"];
+
+/// How deeply expressions may nest.
+const MAX_DEPTH: u32 = 4;
+
+/// A generated toy function, together with the arguments to call it with.
+#[derive(Debug)]
+struct Function {
+    params: Vec<String>,
+    stmts: Vec<Expr>,
+    args: Vec<isize>,
+}
+
+impl<'a> Arbitrary<'a> for Function {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let num_params = u.int_in_range(0..=PARAMS.len())?;
+        let mut gen = Generator {
+            u,
+            num_params,
+            counters: 0,
+        };
+
+        // Assign all the locals up front, so that they're declared.
+        let mut stmts = Vec::new();
+        for local in &LOCALS {
+            let value = gen.expr(1)?;
+            stmts.push(Expr::Assign(local.to_string(), Box::new(value)));
+        }
+        gen.stmts(&mut stmts, 0)?;
+
+        let mut args = Vec::new();
+        for _ in 0..num_params {
+            args.push(gen.u.arbitrary()?);
+        }
+
+        Ok(Function {
+            params: PARAMS[..num_params].iter().map(|p| p.to_string()).collect(),
+            stmts,
+            args,
+        })
+    }
+}
+
+/// Generates well-formed toy code: all variables are declared, loops
+/// terminate, and division is only by non-zero constants.
+struct Generator<'a, 'b> {
+    u: &'b mut Unstructured<'a>,
+    num_params: usize,
+
+    /// The number of loop counters in use by the enclosing loops.
+    counters: usize,
+}
+
+impl<'a, 'b> Generator<'a, 'b> {
+    fn stmts(&mut self, stmts: &mut Vec<Expr>, depth: u32) -> Result<()> {
+        for _ in 0..self.u.int_in_range(0..=4)? {
+            if depth < MAX_DEPTH && self.counters < COUNTERS.len() && self.u.ratio(1, 5)? {
+                self.while_loop(stmts, depth + 1)?;
+            } else if self.u.arbitrary()? {
+                let name = self.assignable()?;
+                let value = self.expr(depth + 1)?;
+                stmts.push(Expr::Assign(name, Box::new(value)));
+            } else {
+                stmts.push(self.expr(depth + 1)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate a loop which counts a fresh counter up to a small bound.
+    fn while_loop(&mut self, stmts: &mut Vec<Expr>, depth: u32) -> Result<()> {
+        let counter = COUNTERS[self.counters].to_string();
+        let bound = self.u.int_in_range(0..=5)?.to_string();
+        stmts.push(Expr::Assign(
+            counter.clone(),
+            Box::new(Expr::Literal("0".to_string())),
+        ));
+
+        self.counters += 1;
+        let mut loop_body = Vec::new();
+        self.stmts(&mut loop_body, depth)?;
+        self.counters -= 1;
+
+        loop_body.push(Expr::Assign(
+            counter.clone(),
+            Box::new(Expr::Add(
+                Box::new(Expr::Identifier(counter.clone())),
+                Box::new(Expr::Literal("1".to_string())),
+            )),
+        ));
+        stmts.push(Expr::WhileLoop(
+            Box::new(Expr::Lt(
+                Box::new(Expr::Identifier(counter)),
+                Box::new(Expr::Literal(bound)),
+            )),
+            loop_body,
+        ));
+        Ok(())
+    }
+
+    fn expr(&mut self, depth: u32) -> Result<Expr> {
+        if depth >= MAX_DEPTH {
+            return self.leaf();
+        }
+        let binary = |f: fn(Box<Expr>, Box<Expr>) -> Expr, gen: &mut Self| -> Result<Expr> {
+            let lhs = gen.expr(depth + 1)?;
+            let rhs = gen.expr(depth + 1)?;
+            Ok(f(Box::new(lhs), Box::new(rhs)))
+        };
+        Ok(match self.u.int_in_range(0..=15)? {
+            0 => binary(Expr::Eq, self)?,
+            1 => binary(Expr::Ne, self)?,
+            2 => binary(Expr::Lt, self)?,
+            3 => binary(Expr::Le, self)?,
+            4 => binary(Expr::Gt, self)?,
+            5 => binary(Expr::Ge, self)?,
+            6 => binary(Expr::Add, self)?,
+            7 => binary(Expr::Sub, self)?,
+            8 => binary(Expr::Mul, self)?,
+            9 => {
+                let lhs = self.expr(depth + 1)?;
+                let rhs = self.u.int_in_range(1..=100)?.to_string();
+                Expr::Div(Box::new(lhs), Box::new(Expr::Literal(rhs)))
+            }
+            10 => {
+                let condition = self.expr(depth + 1)?;
+                let mut then_body = Vec::new();
+                self.stmts(&mut then_body, depth + 1)?;
+                let mut else_body = Vec::new();
+                self.stmts(&mut else_body, depth + 1)?;
+                Expr::IfElse(Box::new(condition), then_body, else_body)
+            }
+            11 => {
+                let a = self.expr(depth + 1)?;
+                let b = self.expr(depth + 1)?;
+                Expr::Call("helper".to_string(), vec![a, b])
+            }
+            12 => {
+                let name = self.assignable()?;
+                let value = self.expr(depth + 1)?;
+                Expr::Assign(name, Box::new(value))
+            }
+            _ => self.leaf()?,
+        })
+    }
+
+    fn leaf(&mut self) -> Result<Expr> {
+        if self.u.arbitrary()? {
+            // Literals are 32-bit, and there are no negative literals.
+            let value: u32 = self.u.arbitrary()?;
+            let value = if self.u.arbitrary()? {
+                value % 16
+            } else {
+                value >> 1
+            };
+            Ok(Expr::Literal(value.to_string()))
+        } else {
+            let mut names: Vec<&str> = PARAMS[..self.num_params].to_vec();
+            names.extend(&LOCALS);
+            names.push(RETURN);
+            names.extend(&COUNTERS[..self.counters]);
+            Ok(Expr::Identifier(self.u.choose(&names)?.to_string()))
+        }
+    }
+
+    /// Choose a variable which may be assigned to.
+    fn assignable(&mut self) -> Result<String> {
+        let mut names: Vec<&str> = PARAMS[..self.num_params].to_vec();
+        names.extend(&LOCALS);
+        names.push(RETURN);
+        Ok(self.u.choose(&names)?.to_string())
+    }
+}
+
+/// Call a compiled function with up to three arguments.
+unsafe fn call_native(code: *const u8, args: &[isize]) -> isize {
+    type I = isize;
+    match *args {
+        [] => mem::transmute::<*const u8, extern "C" fn() -> I>(code)(),
+        [a] => mem::transmute::<*const u8, extern "C" fn(I) -> I>(code)(a),
+        [a, b] => mem::transmute::<*const u8, extern "C" fn(I, I) -> I>(code)(a, b),
+        [a, b, c] => mem::transmute::<*const u8, extern "C" fn(I, I, I) -> I>(code)(a, b, c),
+        _ => unreachable!(),
+    }
+}
+
+fuzz_target!(|function: Function| {
+    let source = DisplayFunction {
+        name: "f",
+        params: &function.params,
+        returns: &[RETURN.to_string()],
+        stmts: &function.stmts,
+    }
+    .to_string();
+
+    // The source must parse back into the same AST.
+    let parsed = parser::function(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+    assert_eq!(
+        parsed,
+        (
+            "f".to_string(),
+            function.params.clone(),
+            vec![RETURN.to_string()],
+            function.stmts.clone()
+        ),
+        "{}",
+        source
+    );
+
+    // The reference result, from the AST interpreter.
+    let mut interp = Interpreter::default();
+    interp.define(HELPER_CODE).unwrap();
+    interp.define(&source).unwrap();
+    let expected = interp.call("f", &function.args).unwrap();
+
+    // Without the helper, the calls to it have to be reported as errors,
+    // rather than left for finalizing the module to fail on.
+    if source.contains("helper(") {
+        let mut jit = JIT::default();
+        assert_eq!(
+            jit.compile(&source).map(|_| ()),
+            Err("function `helper` not defined".to_string()),
+            "{}",
+            source
+        );
+    }
+
+    let mut jit = JIT::default();
+    jit.enable_clif_interpreter();
+    jit.compile(HELPER_CODE).unwrap();
+    let code = jit
+        .compile(&source)
+        .unwrap_or_else(|e| panic!("{}\n{}", e, source));
+
+    let clif_result = jit
+        .clif_interpreter()
+        .unwrap()
+        .call("f", &function.args)
+        .unwrap();
+    assert_eq!(
+        vec![expected as i64],
+        clif_result,
+        "Cranelift IR interpreter\n{}",
+        source
+    );
+
+    let native_result = unsafe { call_native(code, &function.args) };
+    assert_eq!(expected, native_result, "native code\n{}", source);
+});
@@ -0,0 +1,892 @@
+use cranelift_jit_demo::frontend::*;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// All values in the toy language are pointer-sized integers, so this is the
+/// type of every variable, parameter and return value.
+pub const INT: &str = "isize";
+
+const KEYWORDS: [&str; 9] = [
+    "fn",
+    "if",
+    "else",
+    "while",
+    "struct",
+    "extern",
+    "global",
+    "const",
+    "thread_local",
+];
+
+/// The functions built into the language, which the parser handles itself.
+const BUILTINS: [&str; 14] = [
+    "array", "len", "f64", "load8", "load16", "load32", "load64", "sload8", "sload16", "sload32",
+    "store8", "store16", "store32", "store64",
+];
+
+/// A zero-based line and column in a document. Outside of comments toy code
+/// is ASCII, so columns count bytes, characters and UTF-16 code units alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pos {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A token of toy source code: an identifier, a literal or an operator.
+pub struct Token {
+    pub text: String,
+    pub start: Pos,
+}
+
+impl Token {
+    pub fn end(&self) -> Pos {
+        Pos {
+            line: self.start.line,
+            column: self.start.column + self.text.len() as u32,
+        }
+    }
+
+    fn is_identifier(&self) -> bool {
+        self.text
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && !KEYWORDS.contains(&&self.text[..])
+    }
+}
+
+/// The names the host makes available to toy code. The server can't know
+/// what a program registers at runtime, so these come from the client's
+/// configuration.
+#[derive(Default)]
+pub struct Host {
+    pub functions: Vec<String>,
+    pub data: Vec<String>,
+}
+
+/// A toy function, located by its tokens.
+pub struct Function {
+    pub name: String,
+    pub name_token: usize,
+    pub params: Vec<usize>,
+    pub returns: Vec<usize>,
+
+    /// The tokens of the whole function, from `fn` up to the next function.
+    pub tokens: Range<usize>,
+
+    /// The tokens which declare the function's variables: its parameters,
+    /// its return variables and the first assignment to each other variable.
+    pub variables: Vec<usize>,
+}
+
+/// A C function declared with `extern fn`, located by its name.
+pub struct Extern {
+    pub name: String,
+    pub name_token: usize,
+    pub params: usize,
+    pub variadic: bool,
+
+    /// The declaration, with the types of untyped parameters and return
+    /// values spelled out.
+    pub signature: String,
+}
+
+/// A global variable declared with `global`, or a constant declared with
+/// `const`, located by its name.
+pub struct Global {
+    pub name: String,
+    pub name_token: usize,
+    pub constant: bool,
+    pub thread_local: bool,
+}
+
+/// What a token refers to.
+pub enum Symbol<'a> {
+    Function(&'a str),
+    Variable(&'a Function, &'a str),
+    Global(&'a str),
+    Data(&'a str),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Diagnostic {
+    pub start: Pos,
+    pub end: Pos,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// An analyzed source file.
+pub struct Document {
+    pub tokens: Vec<Token>,
+    pub functions: Vec<Function>,
+    pub externs: Vec<Extern>,
+    pub globals: Vec<Global>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    pub fn new(text: &str, host: &Host) -> Self {
+        let tokens = tokenize(text);
+        let globals = find_globals(&tokens);
+        let functions = find_functions(&tokens, &globals);
+        let externs = find_externs(&tokens);
+        let mut doc = Self {
+            tokens,
+            functions,
+            externs,
+            globals,
+            diagnostics: Vec::new(),
+        };
+
+        // Name resolution only makes sense for code which parses, and the
+        // parser finds the first syntax error.
+        match parser::items(text) {
+            Ok(_) => doc.check_names(host),
+            Err(e) => {
+                let start = Pos {
+                    line: e.location.line as u32 - 1,
+                    column: e.location.column as u32 - 1,
+                };
+                doc.diagnostics.push(Diagnostic {
+                    start,
+                    end: Pos {
+                        line: start.line,
+                        column: start.column + 1,
+                    },
+                    severity: Severity::Error,
+                    message: format!("expected {}", e.expected),
+                });
+            }
+        }
+        doc
+    }
+
+    /// Return the function whose code contains the token `index`.
+    pub fn function_at(&self, index: usize) -> Option<&Function> {
+        self.functions
+            .iter()
+            .find(|function| function.tokens.contains(&index))
+    }
+
+    /// Return the function whose code spans the line `line`.
+    pub fn function_on_line(&self, line: u32) -> Option<&Function> {
+        self.functions.iter().rev().find(|function| {
+            self.tokens
+                .get(function.tokens.start)
+                .is_some_and(|token| token.start.line <= line)
+        })
+    }
+
+    /// Return the index of the token at `pos`, including a token which ends
+    /// right at `pos`, as a cursor at the end of a word is on that word.
+    pub fn token_at(&self, pos: Pos) -> Option<usize> {
+        self.tokens
+            .iter()
+            .position(|token| token.start <= pos && pos <= token.end())
+    }
+
+    /// Return what the token `index` refers to, if it's a name.
+    pub fn symbol(&self, index: usize) -> Option<Symbol<'_>> {
+        let token = &self.tokens[index];
+        if !token.is_identifier() {
+            return None;
+        }
+        if index > 0 && self.tokens[index - 1].text == "&" {
+            if self.function(&token.text).is_some() {
+                return Some(Symbol::Function(&token.text));
+            }
+            return Some(Symbol::Data(&token.text));
+        }
+        // Struct fields and types are checked when the functions using them
+        // are compiled.
+        if index > 0 && [".", ":"].contains(&&self.tokens[index - 1].text[..]) {
+            return None;
+        }
+        let function = self.function_at(index);
+        if self.text(index + 1) == Some("(") {
+            // Calling a variable calls the function it refers to.
+            if let Some(function) = function {
+                if self.defines_variable(function, &token.text) {
+                    return Some(Symbol::Variable(function, &token.text));
+                }
+            }
+            if BUILTINS.contains(&&token.text[..])
+                || RUNTIME_FUNCTIONS
+                    .iter()
+                    .any(|(name, _)| *name == token.text)
+            {
+                return None;
+            }
+            return Some(Symbol::Function(&token.text));
+        }
+        // Functions read and assign globals unless they have variables of
+        // the same names.
+        if let Some(global) = self.global(&token.text) {
+            if global.name_token == index
+                || !function.is_some_and(|function| self.defines_variable(function, &token.text))
+            {
+                return Some(Symbol::Global(&token.text));
+            }
+        }
+        Some(Symbol::Variable(function?, &token.text))
+    }
+
+    fn defines_variable(&self, function: &Function, name: &str) -> bool {
+        function
+            .variables
+            .iter()
+            .any(|&index| self.tokens[index].text == name)
+    }
+
+    /// Return the token which defines `symbol`, if it's defined in this
+    /// document.
+    pub fn definition(&self, symbol: &Symbol) -> Option<usize> {
+        match *symbol {
+            Symbol::Function(name) => self
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| function.name_token)
+                .or_else(|| self.extern_function(name).map(|e| e.name_token)),
+            Symbol::Variable(function, name) => function
+                .variables
+                .iter()
+                .copied()
+                .find(|&index| self.tokens[index].text == name),
+            // `&name` is the address of the global `name`, if there's one.
+            Symbol::Global(name) | Symbol::Data(name) => {
+                self.global(name).map(|global| global.name_token)
+            }
+        }
+    }
+
+    /// Return the toy function named `name`.
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    /// Return the global variable named `name`.
+    pub fn global(&self, name: &str) -> Option<&Global> {
+        self.globals.iter().find(|global| global.name == name)
+    }
+
+    /// Return the C function named `name`.
+    pub fn extern_function(&self, name: &str) -> Option<&Extern> {
+        self.externs.iter().find(|e| e.name == name)
+    }
+
+    /// Return the signature of a toy function, with the types spelled out.
+    pub fn signature(&self, function: &Function) -> String {
+        let params: Vec<String> = function
+            .params
+            .iter()
+            .map(|&index| format!("{}: {}", self.tokens[index].text, INT))
+            .collect();
+        let returns: Vec<String> = function
+            .returns
+            .iter()
+            .map(|&index| format!("{}: {}", self.tokens[index].text, INT))
+            .collect();
+        format!(
+            "fn {}({}) -> ({})",
+            function.name,
+            params.join(", "),
+            returns.join(", ")
+        )
+    }
+
+    /// Return the names of the data objects referenced with `&`.
+    pub fn data_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        for index in 0..self.tokens.len() {
+            if let Some(Symbol::Data(name)) = self.symbol(index) {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    fn text(&self, index: usize) -> Option<&str> {
+        self.tokens.get(index).map(|token| &token.text[..])
+    }
+
+    /// Check that every name refers to something, and that calls to toy
+    /// functions pass the right number of arguments.
+    fn check_names(&mut self, host: &Host) {
+        let mut diagnostics = Vec::new();
+
+        let mut seen = HashSet::new();
+        for function in &self.functions {
+            if !seen.insert(&function.name) {
+                diagnostics.push(self.error(
+                    function.name_token,
+                    format!("function `{}` is defined more than once", function.name),
+                ));
+            }
+        }
+        let mut seen = HashSet::new();
+        for global in &self.globals {
+            if !seen.insert(&global.name) {
+                diagnostics.push(self.error(
+                    global.name_token,
+                    format!("global `{}` is declared more than once", global.name),
+                ));
+            }
+        }
+
+        for index in 0..self.tokens.len() {
+            let diagnostic = match self.symbol(index) {
+                Some(Symbol::Variable(function, name)) => {
+                    if self.definition(&Symbol::Variable(function, name)).is_some() {
+                        continue;
+                    }
+                    self.error(index, format!("variable `{}` not defined", name))
+                }
+                Some(Symbol::Function(name)) => {
+                    if let Some(function) = self.function(name) {
+                        // `&name` is a reference to the function, not a call.
+                        if self.text(index + 1) != Some("(") {
+                            continue;
+                        }
+                        let args = self.count_args(index + 1);
+                        if function.name_token == index || args == function.params.len() {
+                            continue;
+                        }
+                        self.error(
+                            index,
+                            format!(
+                                "function `{}` takes {} arguments but {} were supplied",
+                                name,
+                                function.params.len(),
+                                args
+                            ),
+                        )
+                    } else if let Some(function) = self.extern_function(name) {
+                        let args = self.count_args(index + 1);
+                        if function.name_token == index
+                            || args == function.params
+                            || function.variadic && args > function.params
+                        {
+                            continue;
+                        }
+                        let at_least = if function.variadic { "at least " } else { "" };
+                        self.error(
+                            index,
+                            format!(
+                                "function `{}` takes {}{} arguments but {} were supplied",
+                                name, at_least, function.params, args
+                            ),
+                        )
+                    } else if host.functions.iter().any(|f| f == name) {
+                        continue;
+                    } else {
+                        self.warning(
+                            index,
+                            format!("function `{}` is not defined here or by the host", name),
+                        )
+                    }
+                }
+                Some(Symbol::Global(name)) => match self.global(name) {
+                    Some(global)
+                        if global.constant
+                            && global.name_token != index
+                            && self.text(index + 1) == Some("=") =>
+                    {
+                        self.error(index, format!("cannot assign to constant `{}`", name))
+                    }
+                    _ => continue,
+                },
+                Some(Symbol::Data(name)) => {
+                    if host.data.iter().chain(&host.functions).any(|d| d == name)
+                        || self.global(name).is_some()
+                    {
+                        continue;
+                    }
+                    self.warning(
+                        index,
+                        format!("data object `{}` is not defined by the host", name),
+                    )
+                }
+                None => continue,
+            };
+            diagnostics.push(diagnostic);
+        }
+        self.diagnostics = diagnostics;
+    }
+
+    /// Count the arguments of the call whose `(` is the token `open`.
+    fn count_args(&self, open: usize) -> usize {
+        if self.text(open + 1) == Some(")") {
+            return 0;
+        }
+        let mut depth = 0;
+        let mut args = 1;
+        for token in &self.tokens[open..] {
+            match &token.text[..] {
+                "(" => depth += 1,
+                ")" if depth == 1 => break,
+                ")" => depth -= 1,
+                "," if depth == 1 => args += 1,
+                _ => {}
+            }
+        }
+        args
+    }
+
+    fn error(&self, index: usize, message: String) -> Diagnostic {
+        self.diagnostic(index, Severity::Error, message)
+    }
+
+    fn warning(&self, index: usize, message: String) -> Diagnostic {
+        self.diagnostic(index, Severity::Warning, message)
+    }
+
+    fn diagnostic(&self, index: usize, severity: Severity, message: String) -> Diagnostic {
+        let token = &self.tokens[index];
+        Diagnostic {
+            start: token.start,
+            end: token.end(),
+            severity,
+            message,
+        }
+    }
+}
+
+/// Split toy source code into tokens, skipping comments.
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (line, code) in text.lines().enumerate() {
+        let code = match comment_start(code) {
+            Some(index) => &code[..index],
+            None => code,
+        };
+        let bytes = code.as_bytes();
+        let mut column = 0;
+        while column < bytes.len() {
+            let c = bytes[column];
+            let len = if c.is_ascii_whitespace() {
+                column += 1;
+                continue;
+            } else if c.is_ascii_alphanumeric() || c == b'_' {
+                bytes[column..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+                    .count()
+            } else if c == b'"' {
+                // A string literal, up to and including its closing quote,
+                // if any.
+                let mut len = 1;
+                while column + len < bytes.len() && bytes[column + len] != b'"' {
+                    len += if bytes[column + len] == b'\\' { 2 } else { 1 };
+                }
+                (len + 1).min(bytes.len() - column)
+            } else if bytes[column..].len() >= 2
+                && ["==", "!=", "<=", ">=", "->"].contains(&&code[column..column + 2])
+            {
+                2
+            } else {
+                // Anything else, including non-ASCII characters which the
+                // parser will reject anyway, is a token of its own.
+                code[column..].chars().next().unwrap().len_utf8()
+            };
+            tokens.push(Token {
+                text: code[column..column + len].to_owned(),
+                start: Pos {
+                    line: line as u32,
+                    column: column as u32,
+                },
+            });
+            column += len;
+        }
+    }
+    tokens
+}
+
+/// Find the C functions declared in a token stream, by the `extern` at the
+/// beginning of a line their declarations start with.
+fn find_externs(tokens: &[Token]) -> Vec<Extern> {
+    let text = |index: usize| tokens.get(index).map(|token| &token.text[..]);
+    let mut externs = Vec::new();
+    for start in 0..tokens.len() {
+        if text(start) != Some("extern")
+            || start > 0 && tokens[start - 1].start.line == tokens[start].start.line
+        {
+            continue;
+        }
+        // An ABI, like `"system"`, may follow `extern`.
+        let abi = text(start + 1).filter(|abi| abi.starts_with('"'));
+        let name_token = start + 2 + usize::from(abi.is_some());
+        if text(name_token - 1) != Some("fn")
+            || !tokens.get(name_token).is_some_and(Token::is_identifier)
+            || text(name_token + 1) != Some("(")
+        {
+            continue;
+        }
+
+        // The parameters, each a name, optionally followed by `: type`.
+        let mut params = Vec::new();
+        let mut variadic = false;
+        let mut index = name_token + 2;
+        while index < tokens.len() && text(index) != Some(")") && text(index) != Some(";") {
+            if text(index) == Some(".") {
+                variadic = true;
+            } else if tokens[index].is_identifier() {
+                match (text(index + 1), text(index + 2)) {
+                    (Some(":"), Some(ty)) => {
+                        params.push(format!("{}: {}", tokens[index].text, ty));
+                        index += 2;
+                    }
+                    _ => params.push(format!("{}: {}", tokens[index].text, INT)),
+                }
+            }
+            index += 1;
+        }
+        let returns = match (text(index + 1), text(index + 2)) {
+            (Some("->"), Some(ty)) => ty,
+            _ => INT,
+        };
+
+        let name = tokens[name_token].text.clone();
+        let count = params.len();
+        if variadic {
+            params.push("...".to_owned());
+        }
+        let abi = abi.map_or(String::new(), |abi| format!("{} ", abi));
+        let signature = format!(
+            "extern {}fn {}({}) -> {}",
+            abi,
+            name,
+            params.join(", "),
+            returns
+        );
+        externs.push(Extern {
+            name,
+            name_token,
+            params: count,
+            variadic,
+            signature,
+        });
+    }
+    externs
+}
+
+/// Find the global variables and the constants declared in a token stream,
+/// by the `global`, `thread_local global` or `const` at the beginning of a
+/// line their declarations start with.
+fn find_globals(tokens: &[Token]) -> Vec<Global> {
+    let text = |index: usize| tokens.get(index).map(|token| &token.text[..]);
+    let mut globals = Vec::new();
+    for start in 0..tokens.len() {
+        if !["global", "const", "thread_local"].contains(&&tokens[start].text[..])
+            || start > 0 && tokens[start - 1].start.line == tokens[start].start.line
+        {
+            continue;
+        }
+        let thread_local = tokens[start].text == "thread_local";
+        let name_token = start + 1 + usize::from(thread_local);
+        if thread_local && text(start + 1) != Some("global") {
+            continue;
+        }
+        if tokens.get(name_token).is_some_and(Token::is_identifier) {
+            globals.push(Global {
+                name: tokens[name_token].text.clone(),
+                name_token,
+                constant: tokens[start].text == "const",
+                thread_local,
+            });
+        }
+    }
+    globals
+}
+
+/// Find the functions in a token stream. Each function starts with `fn` at
+/// the beginning of a line and extends up to the next function or
+/// declaration, which keeps the rest of the file usable while the function
+/// being edited doesn't parse.
+fn find_functions(tokens: &[Token], globals: &[Global]) -> Vec<Function> {
+    let starts: Vec<usize> = (0..tokens.len())
+        .filter(|&index| {
+            ["fn", "struct", "extern", "global", "const", "thread_local"]
+                .contains(&&tokens[index].text[..])
+                && (index == 0 || tokens[index - 1].start.line != tokens[index].start.line)
+        })
+        .collect();
+
+    let mut functions = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        if tokens[start].text != "fn" {
+            continue;
+        }
+        let end = starts.get(i + 1).copied().unwrap_or(tokens.len());
+        let name_token = start + 1;
+        if name_token >= end || !tokens[name_token].is_identifier() {
+            continue;
+        }
+        let text = |index: usize| tokens.get(index).map(|token| &token.text[..]);
+
+        // The header: `fn name(params) -> (returns) {`.
+        let mut index = name_token + 1;
+        let mut params = Vec::new();
+        if text(index) == Some("(") {
+            index += 1;
+            while index < end && text(index) != Some(")") && text(index) != Some("{") {
+                if tokens[index].is_identifier() {
+                    params.push(index);
+                }
+                index += 1;
+            }
+        }
+        let mut returns = Vec::new();
+        if text(index) == Some(")") && text(index + 1) == Some("->") && text(index + 2) == Some("(")
+        {
+            index += 3;
+            while index < end && text(index) != Some(")") && text(index) != Some("{") {
+                if tokens[index].is_identifier() {
+                    returns.push(index);
+                }
+                index += 1;
+            }
+        }
+
+        // Whether the identifier at `index` is one of those a call's return
+        // values are assigned to, in `a, b = f(args)`.
+        let destructured = |index: usize| {
+            let mut end = index;
+            while text(end + 1) == Some(",")
+                && tokens.get(end + 2).is_some_and(Token::is_identifier)
+            {
+                end += 2;
+            }
+            end > index && text(end + 1) == Some("=")
+        };
+
+        let mut variables = params.clone();
+        variables.extend(&returns);
+        let mut closure_params = false;
+        for index in index..end {
+            if text(index) == Some("|") {
+                closure_params = !closure_params;
+            }
+            // Variables are declared by assigning them, by declaring them as
+            // struct pointers, or as the parameters of closures, which are
+            // taken to be variables of the enclosing function. Assigning a
+            // global doesn't declare a variable.
+            let global = globals.iter().any(|g| g.name == tokens[index].text);
+            if tokens[index].is_identifier()
+                && (closure_params
+                    || text(index + 1) == Some("=") && !global
+                    || text(index + 1) == Some(":")
+                    || destructured(index) && !global)
+                && text(index - 1) != Some(".")
+                && !variables
+                    .iter()
+                    .any(|&v| tokens[v].text == tokens[index].text)
+            {
+                variables.push(index);
+            }
+        }
+
+        functions.push(Function {
+            name: tokens[name_token].text.clone(),
+            name_token,
+            params,
+            returns,
+            tokens: start..end,
+            variables,
+        });
+    }
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+extern fn puts(s);
+global counter = 0
+const LIMIT = 10
+
+fn add(a, b) -> (c) {
+    c = a + b
+}
+
+fn main(x) -> (r) {
+    y = add(x, LIMIT)
+    counter = counter + 1
+    r = y + puts(&message)
+}
+";
+
+    fn host() -> Host {
+        Host {
+            functions: Vec::new(),
+            data: vec!["message".to_owned()],
+        }
+    }
+
+    /// Return the index of the `nth` token with the text `text`.
+    fn token(doc: &Document, text: &str, nth: usize) -> usize {
+        doc.tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, token)| token.text == text)
+            .nth(nth)
+            .unwrap()
+            .0
+    }
+
+    fn messages(doc: &Document) -> Vec<&str> {
+        doc.diagnostics
+            .iter()
+            .map(|diagnostic| &diagnostic.message[..])
+            .collect()
+    }
+
+    #[test]
+    fn no_diagnostics() {
+        let doc = Document::new(SOURCE, &host());
+        assert!(doc.diagnostics.is_empty(), "{:?}", messages(&doc));
+    }
+
+    #[test]
+    fn diagnostics() {
+        let source = "\
+extern fn puts(s);
+const LIMIT = 10
+
+fn f(x) -> (r) {
+    r = g(x) + puts(x, x) + f(x, x) + nope
+    LIMIT = 2
+    r = &unknown
+}
+";
+        let doc = Document::new(source, &Host::default());
+        assert_eq!(
+            messages(&doc),
+            [
+                "function `g` is not defined here or by the host",
+                "function `puts` takes 1 arguments but 2 were supplied",
+                "function `f` takes 1 arguments but 2 were supplied",
+                "variable `nope` not defined",
+                "cannot assign to constant `LIMIT`",
+                "data object `unknown` is not defined by the host",
+            ]
+        );
+        let nope = &doc.diagnostics[3];
+        assert_eq!(
+            nope.start,
+            Pos {
+                line: 4,
+                column: 38
+            }
+        );
+        assert_eq!(
+            nope.end,
+            Pos {
+                line: 4,
+                column: 42
+            }
+        );
+        assert!(nope.severity == Severity::Error);
+        assert!(doc.diagnostics[0].severity == Severity::Warning);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let doc = Document::new("fn f() -> (r) {\n    r = (1\n}\n", &Host::default());
+        assert_eq!(doc.diagnostics.len(), 1);
+        assert_eq!(
+            doc.diagnostics[0].start,
+            Pos {
+                line: 1,
+                column: 10
+            }
+        );
+        assert!(doc.diagnostics[0].message.starts_with("expected "));
+    }
+
+    #[test]
+    fn definitions() {
+        let doc = Document::new(SOURCE, &host());
+        let definition = |index| doc.definition(&doc.symbol(index).unwrap());
+
+        // Calls go to the function, and uses of variables to their first
+        // assignment or the parameter.
+        assert_eq!(
+            definition(token(&doc, "add", 1)),
+            Some(token(&doc, "add", 0))
+        );
+        assert_eq!(
+            definition(token(&doc, "puts", 1)),
+            Some(token(&doc, "puts", 0))
+        );
+        assert_eq!(definition(token(&doc, "y", 1)), Some(token(&doc, "y", 0)));
+        assert_eq!(definition(token(&doc, "x", 1)), Some(token(&doc, "x", 0)));
+        assert_eq!(definition(token(&doc, "r", 1)), Some(token(&doc, "r", 0)));
+        assert_eq!(
+            definition(token(&doc, "counter", 2)),
+            Some(token(&doc, "counter", 0))
+        );
+        assert_eq!(
+            definition(token(&doc, "LIMIT", 1)),
+            Some(token(&doc, "LIMIT", 0))
+        );
+        // Host data has no definition in the document.
+        assert_eq!(definition(token(&doc, "message", 0)), None);
+    }
+
+    #[test]
+    fn symbols() {
+        let doc = Document::new(SOURCE, &host());
+        match doc.symbol(token(&doc, "a", 1)) {
+            Some(Symbol::Variable(function, "a")) => assert_eq!(function.name, "add"),
+            _ => panic!("`a` isn't a variable of `add`"),
+        }
+        assert!(matches!(
+            doc.symbol(token(&doc, "counter", 1)),
+            Some(Symbol::Global("counter"))
+        ));
+        assert!(matches!(
+            doc.symbol(token(&doc, "message", 0)),
+            Some(Symbol::Data("message"))
+        ));
+        assert!(doc.symbol(token(&doc, "+", 0)).is_none());
+        assert_eq!(doc.data_names(), ["message"]);
+        assert!(doc.global("LIMIT").unwrap().constant);
+    }
+
+    #[test]
+    fn hover_and_completion_helpers() {
+        let doc = Document::new(SOURCE, &host());
+        let add = doc.function("add").unwrap();
+        assert_eq!(
+            doc.signature(add),
+            "fn add(a: isize, b: isize) -> (c: isize)"
+        );
+        assert_eq!(
+            doc.extern_function("puts").unwrap().signature,
+            "extern fn puts(s: isize) -> isize"
+        );
+
+        // The cursor is on a word when it's right after it too.
+        let y = token(&doc, "y", 0);
+        assert_eq!(doc.token_at(Pos { line: 9, column: 5 }), Some(y));
+        assert_eq!(doc.function_on_line(9).unwrap().name, "main");
+        assert_eq!(doc.function_on_line(5).unwrap().name, "add");
+        let variables: Vec<&str> = doc
+            .function("main")
+            .unwrap()
+            .variables
+            .iter()
+            .map(|&index| &doc.tokens[index].text[..])
+            .collect();
+        assert_eq!(variables, ["x", "r", "y"]);
+    }
+}
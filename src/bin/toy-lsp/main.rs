@@ -0,0 +1,548 @@
+//! A language server for the toy language, speaking the Language Server
+//! Protocol over stdio.
+//!
+//! It reports syntax errors and undefined names as diagnostics, and supports
+//! go-to-definition, hover, completion and document symbols. As the host
+//! functions and data objects a program provides are only known at runtime,
+//! clients can list them in the initialization options:
+//!
+//! ```json
+//! { "hostFunctions": ["puts"], "dataObjects": ["hello_string"] }
+//! ```
+
+mod analysis;
+
+use analysis::{Document, Host, Pos, Severity, Symbol, INT};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::Notification as _;
+use lsp_types::request::Request as _;
+use lsp_types::*;
+use serde_json::Value;
+use std::collections::HashMap;
+
+fn main() -> Result<(), String> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncKind::FULL.into()),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["&".to_owned()]),
+            ..CompletionOptions::default()
+        }),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    let params = connection
+        .initialize(serde_json::to_value(capabilities).unwrap())
+        .map_err(|e| e.to_string())?;
+    let params: InitializeParams = serde_json::from_value(params).map_err(|e| e.to_string())?;
+
+    let mut server = Server {
+        connection: &connection,
+        host: host_from_options(params.initialization_options),
+        documents: HashMap::new(),
+    };
+    server.run()?;
+
+    // The IO threads only finish once the connection is closed.
+    drop(connection);
+    io_threads.join().map_err(|e| e.to_string())
+}
+
+/// Read the names the host provides from the initialization options.
+fn host_from_options(options: Option<Value>) -> Host {
+    let names = |key: &str| -> Vec<String> {
+        options
+            .as_ref()
+            .and_then(|options| options.get(key))
+            .and_then(Value::as_array)
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    Host {
+        functions: names("hostFunctions"),
+        data: names("dataObjects"),
+    }
+}
+
+struct Server<'a> {
+    connection: &'a Connection,
+    host: Host,
+
+    /// The open documents, analyzed.
+    documents: HashMap<Url, Document>,
+}
+
+impl Server<'_> {
+    fn run(&mut self) -> Result<(), String> {
+        for message in &self.connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if self
+                        .connection
+                        .handle_shutdown(&request)
+                        .map_err(|e| e.to_string())?
+                    {
+                        return Ok(());
+                    }
+                    let response = self.handle_request(request);
+                    self.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => self.handle_notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: Message) -> Result<(), String> {
+        self.connection
+            .sender
+            .send(message)
+            .map_err(|e| e.to_string())
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        let result = match &request.method[..] {
+            request::GotoDefinition::METHOD => serde_json::from_value(request.params)
+                .map(|params| serde_json::to_value(self.definition(params)).unwrap()),
+            request::HoverRequest::METHOD => serde_json::from_value(request.params)
+                .map(|params| serde_json::to_value(self.hover(params)).unwrap()),
+            request::Completion::METHOD => serde_json::from_value(request.params)
+                .map(|params| serde_json::to_value(self.completion(params)).unwrap()),
+            request::DocumentSymbolRequest::METHOD => serde_json::from_value(request.params)
+                .map(|params| serde_json::to_value(self.document_symbols(params)).unwrap()),
+            _ => {
+                return Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request `{}`", request.method),
+                )
+            }
+        };
+        match result {
+            Ok(result) => Response::new_ok(request.id, result),
+            Err(e) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, e.to_string()),
+        }
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<(), String> {
+        match &notification.method[..] {
+            notification::DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    serde_json::from_value(notification.params).map_err(|e| e.to_string())?;
+                self.update(params.text_document.uri, &params.text_document.text)
+            }
+            notification::DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    serde_json::from_value(notification.params).map_err(|e| e.to_string())?;
+                // With full synchronization, the last change is the whole
+                // new text.
+                match params.content_changes.last() {
+                    Some(change) => self.update(params.text_document.uri, &change.text),
+                    None => Ok(()),
+                }
+            }
+            notification::DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(notification.params).map_err(|e| e.to_string())?;
+                self.documents.remove(&params.text_document.uri);
+                self.publish_diagnostics(params.text_document.uri, Vec::new())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reanalyze a document after it changed, and publish its diagnostics.
+    fn update(&mut self, uri: Url, text: &str) -> Result<(), String> {
+        let doc = Document::new(text, &self.host);
+        let diagnostics = doc
+            .diagnostics
+            .iter()
+            .map(|diagnostic| Diagnostic {
+                range: range(diagnostic.start, diagnostic.end),
+                severity: Some(match diagnostic.severity {
+                    Severity::Error => DiagnosticSeverity::ERROR,
+                    Severity::Warning => DiagnosticSeverity::WARNING,
+                }),
+                source: Some("toy".to_owned()),
+                message: diagnostic.message.clone(),
+                ..Diagnostic::default()
+            })
+            .collect();
+        self.documents.insert(uri.clone(), doc);
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    fn publish_diagnostics(&self, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<(), String> {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        self.send(Message::Notification(Notification::new(
+            notification::PublishDiagnostics::METHOD.to_owned(),
+            params,
+        )))
+    }
+
+    /// Return the document and the index of the token at a position.
+    fn token_at(&self, position: &TextDocumentPositionParams) -> Option<(&Document, usize)> {
+        let doc = self.documents.get(&position.text_document.uri)?;
+        let index = doc.token_at(pos(position.position))?;
+        Some((doc, index))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let (doc, index) = self.token_at(&position)?;
+        let definition = doc.definition(&doc.symbol(index)?)?;
+        let token = &doc.tokens[definition];
+        Some(GotoDefinitionResponse::Scalar(Location {
+            uri: position.text_document.uri,
+            range: range(token.start, token.end()),
+        }))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let (doc, index) = self.token_at(&params.text_document_position_params)?;
+        let (code, description) = match doc.symbol(index)? {
+            Symbol::Function(name) => match (doc.function(name), doc.extern_function(name)) {
+                (Some(function), _) => (doc.signature(function), "toy function".to_owned()),
+                (None, Some(function)) => (function.signature.clone(), "C function".to_owned()),
+                (None, None) if self.host.functions.iter().any(|f| f == name) => (
+                    format!("fn {}(..) -> {}", name, INT),
+                    "host function".to_owned(),
+                ),
+                (None, None) => return None,
+            },
+            Symbol::Variable(function, name) => {
+                let definition = doc.definition(&Symbol::Variable(function, name))?;
+                let kind = if function.params.contains(&definition) {
+                    "parameter"
+                } else if function.returns.contains(&definition) {
+                    "return variable"
+                } else {
+                    "variable"
+                };
+                (
+                    format!("{}: {}", name, INT),
+                    format!("{} of `{}`", kind, function.name),
+                )
+            }
+            Symbol::Global(name) if doc.global(name)?.constant => {
+                (format!("const {}: {}", name, INT), "constant".to_owned())
+            }
+            Symbol::Global(name) if doc.global(name)?.thread_local => (
+                format!("thread_local global {}: {}", name, INT),
+                "thread-local global variable".to_owned(),
+            ),
+            Symbol::Global(name) => (
+                format!("global {}: {}", name, INT),
+                "global variable".to_owned(),
+            ),
+            Symbol::Data(name) => (
+                format!("&{}: {}", name, INT),
+                "address of a data object".to_owned(),
+            ),
+        };
+        let token = &doc.tokens[index];
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```toy\n{}\n```\n{}", code, description),
+            }),
+            range: Some(range(token.start, token.end())),
+        })
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let position = params.text_document_position;
+        let doc = self.documents.get(&position.text_document.uri)?;
+        let cursor = pos(position.position);
+
+        // Data objects and functions are completed after `&`, either right
+        // after it or with part of the name typed already.
+        let after_ampersand = match doc.token_at(cursor) {
+            Some(index) if index > 0 && doc.symbol(index).is_some() => {
+                doc.tokens[index - 1].text == "&"
+            }
+            _ => doc
+                .tokens
+                .iter()
+                .rev()
+                .find(|token| token.end() <= cursor)
+                .is_some_and(|token| token.text == "&"),
+        };
+
+        let mut items = Vec::new();
+        let mut item = |label: &str, kind, detail: String| {
+            items.push(CompletionItem {
+                label: label.to_owned(),
+                kind: Some(kind),
+                detail: Some(detail),
+                ..CompletionItem::default()
+            })
+        };
+        if after_ampersand {
+            let mut names = doc.data_names();
+            for name in doc.globals.iter().map(|g| &g.name).chain(&self.host.data) {
+                if !names.contains(&&name[..]) {
+                    names.push(name);
+                }
+            }
+            for name in names {
+                item(
+                    name,
+                    CompletionItemKind::CONSTANT,
+                    format!("&{}: {}", name, INT),
+                );
+            }
+            for function in &doc.functions {
+                item(
+                    &function.name,
+                    CompletionItemKind::FUNCTION,
+                    doc.signature(function),
+                );
+            }
+        } else {
+            for function in &doc.functions {
+                item(
+                    &function.name,
+                    CompletionItemKind::FUNCTION,
+                    doc.signature(function),
+                );
+            }
+            for name in &self.host.functions {
+                item(
+                    name,
+                    CompletionItemKind::FUNCTION,
+                    format!("fn {}(..) -> {}", name, INT),
+                );
+            }
+            for global in &doc.globals {
+                let (kind, keyword) = if global.constant {
+                    (CompletionItemKind::CONSTANT, "const")
+                } else {
+                    (CompletionItemKind::VARIABLE, "global")
+                };
+                item(
+                    &global.name,
+                    kind,
+                    format!("{} {}: {}", keyword, global.name, INT),
+                );
+            }
+            if let Some(function) = doc.function_on_line(cursor.line) {
+                for &index in &function.variables {
+                    let name = &doc.tokens[index].text;
+                    item(
+                        name,
+                        CompletionItemKind::VARIABLE,
+                        format!("{}: {}", name, INT),
+                    );
+                }
+            }
+        }
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let doc = self.documents.get(&params.text_document.uri)?;
+        let symbols = doc
+            .functions
+            .iter()
+            .map(|function| {
+                let first = &doc.tokens[function.tokens.start];
+                let last = &doc.tokens[function.tokens.end - 1];
+                let name = &doc.tokens[function.name_token];
+                let children = function
+                    .variables
+                    .iter()
+                    .map(|&index| {
+                        let token = &doc.tokens[index];
+                        symbol(
+                            &token.text,
+                            INT.to_owned(),
+                            SymbolKind::VARIABLE,
+                            range(token.start, token.end()),
+                            None,
+                        )
+                    })
+                    .collect();
+                let mut function_symbol = symbol(
+                    &function.name,
+                    doc.signature(function),
+                    SymbolKind::FUNCTION,
+                    range(first.start, last.end()),
+                    Some(children),
+                );
+                function_symbol.selection_range = range(name.start, name.end());
+                function_symbol
+            })
+            .collect();
+        Some(DocumentSymbolResponse::Nested(symbols))
+    }
+}
+
+#[allow(deprecated)] // `DocumentSymbol::deprecated` has to be initialized.
+fn symbol(
+    name: &str,
+    detail: String,
+    kind: SymbolKind,
+    range: Range,
+    children: Option<Vec<DocumentSymbol>>,
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name: name.to_owned(),
+        detail: Some(detail),
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range: range,
+        children,
+    }
+}
+
+fn pos(position: Position) -> Pos {
+    Pos {
+        line: position.line,
+        column: position.character,
+    }
+}
+
+fn range(start: Pos, end: Pos) -> Range {
+    Range {
+        start: Position::new(start.line, start.column),
+        end: Position::new(end.line, end.column),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+global counter = 0
+
+fn add(a, b) -> (c) {
+    c = a + b
+}
+
+fn main(x) -> (r) {
+    r = add(x, counter) + puts(&message)
+}
+";
+
+    fn position(uri: &Url, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri.clone() },
+            position: Position::new(line, character),
+        }
+    }
+
+    /// Open `SOURCE` in a server, and run `f` with it.
+    fn with_server(f: impl FnOnce(&Server, &Url)) {
+        let (connection, client) = Connection::memory();
+        let mut server = Server {
+            connection: &connection,
+            host: host_from_options(Some(serde_json::json!({
+                "hostFunctions": ["puts"],
+                "dataObjects": ["message"],
+            }))),
+            documents: HashMap::new(),
+        };
+        let uri = Url::parse("file:///test.toy").unwrap();
+        server.update(uri.clone(), SOURCE).unwrap();
+
+        // The diagnostics are published when the document changes.
+        match client.receiver.try_recv() {
+            Ok(Message::Notification(notification)) => {
+                let params: PublishDiagnosticsParams =
+                    serde_json::from_value(notification.params).unwrap();
+                assert!(params.diagnostics.is_empty(), "{:?}", params.diagnostics);
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+        f(&server, &uri);
+    }
+
+    #[test]
+    fn definition() {
+        with_server(|server, uri| {
+            let params = GotoDefinitionParams {
+                text_document_position_params: position(uri, 7, 9),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            };
+            match server.definition(params) {
+                Some(GotoDefinitionResponse::Scalar(location)) => {
+                    assert_eq!(location.range.start, Position::new(2, 3));
+                    assert_eq!(location.range.end, Position::new(2, 6));
+                }
+                response => panic!("unexpected response {:?}", response),
+            }
+        });
+    }
+
+    #[test]
+    fn hover() {
+        with_server(|server, uri| {
+            let hover = |line, character| {
+                let params = HoverParams {
+                    text_document_position_params: position(uri, line, character),
+                    work_done_progress_params: Default::default(),
+                };
+                match server.hover(params).map(|hover| hover.contents) {
+                    Some(HoverContents::Markup(markup)) => markup.value,
+                    contents => panic!("unexpected contents {:?}", contents),
+                }
+            };
+            assert_eq!(
+                hover(7, 9),
+                "```toy\nfn add(a: isize, b: isize) -> (c: isize)\n```\ntoy function"
+            );
+            assert_eq!(hover(7, 13), "```toy\nx: isize\n```\nparameter of `main`");
+            assert_eq!(
+                hover(7, 20),
+                "```toy\nglobal counter: isize\n```\nglobal variable"
+            );
+            assert_eq!(
+                hover(7, 28),
+                "```toy\nfn puts(..) -> isize\n```\nhost function"
+            );
+        });
+    }
+
+    #[test]
+    fn completion() {
+        with_server(|server, uri| {
+            let labels = |line, character| {
+                let params = CompletionParams {
+                    text_document_position: position(uri, line, character),
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                    context: None,
+                };
+                match server.completion(params) {
+                    Some(CompletionResponse::Array(items)) => {
+                        items.into_iter().map(|item| item.label).collect::<Vec<_>>()
+                    }
+                    response => panic!("unexpected response {:?}", response),
+                }
+            };
+            // The functions, the host functions, the globals, and the
+            // variables of the function the cursor is in.
+            assert_eq!(labels(7, 4), ["add", "main", "puts", "counter", "x", "r"]);
+            // After `&`, the data objects and the functions.
+            assert_eq!(labels(7, 37), ["message", "counter", "add", "main"]);
+        });
+    }
+}
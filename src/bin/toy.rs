@@ -1,7 +1,13 @@
 use core::mem;
-use cranelift_jit_demo::jit;
+use cranelift_jit_demo::{format, jit};
+use std::fs;
 
 fn main() -> Result<(), String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|arg| &arg[..]) == Some("fmt") {
+        return run_fmt(&args[1..]);
+    }
+
     // Create the JIT instance, which manages all generated functions and data.
     let mut jit = jit::JIT::default();
     println!("the answer is: {}", run_foo(&mut jit)?);
@@ -34,6 +40,18 @@ fn run_hello(jit: &mut jit::JIT) -> Result<isize, String> {
     unsafe { run_code(jit, HELLO_CODE, ()) }
 }
 
+/// Reformats the given toy source files in place.
+fn run_fmt(paths: &[String]) -> Result<(), String> {
+    for path in paths {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let formatted = format::format_source(&source).map_err(|e| format!("{}: {}", path, e))?;
+        if formatted != source {
+            fs::write(path, formatted).map_err(|e| format!("{}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
 /// Executes the given code using the cranelift JIT compiler.
 ///
 /// Feeds the given input into the JIT compiled function and returns the resulting output.
@@ -48,7 +66,7 @@ unsafe fn run_code<I, O>(jit: &mut jit::JIT, code: &str, input: I) -> Result<O,
     // Cast the raw pointer to a typed function pointer. This is unsafe, because
     // this is the critical point where you have to trust that the generated code
     // is safe to be called.
-    let code_fn = mem::transmute::<_, fn(I) -> O>(code_ptr);
+    let code_fn = mem::transmute::<*const u8, fn(I) -> O>(code_ptr);
     // And now we can call it!
     Ok(code_fn(input))
 }
@@ -0,0 +1,505 @@
+//! An interpreter for the subset of Cranelift IR the toy language is
+//! lowered to.
+//!
+//! Cranelift's own interpreter, the `cranelift-interpreter` crate, isn't a
+//! dependency of this demo, so this is a small interpreter of its own. It
+//! only knows the instructions `FunctionTranslator` emits, and returns an
+//! error naming the instruction for anything else, rather than guessing at
+//! its semantics. An IR construct the toy frontend starts emitting has to be
+//! added here too before functions using it can be interpreted.
+
+use crate::interp::HostFunction;
+use crate::runtime::catch_trap;
+use crate::tiered::{call_native, MAX_NATIVE_ARGS};
+use cranelift::codegen::ir::{
+    self, ExternalName, FuncRef, GlobalValue, GlobalValueData, InstructionData, Opcode, StackSlot,
+};
+use cranelift::prelude::*;
+use cranelift_module::{DataId, FuncId, ModuleDeclarations};
+use std::collections::HashMap;
+use std::ptr;
+
+/// A function resolving the names of the native functions the IR calls.
+type SymbolLookup = Box<dyn Fn(&str) -> Option<*const u8>>;
+
+/// An interpreter for the Cranelift IR of toy functions.
+///
+/// This runs the IR the JIT's `FunctionTranslator` produces, before any of
+/// Cranelift's optimizations or its backend get to see it. Comparing its
+/// results against the natively compiled code tells a miscompile in
+/// Cranelift apart from a bug in the lowering of the toy language. See
+/// `JIT::enable_clif_interpreter`.
+///
+/// It supports the instructions the toy language is lowered to, and reports
+/// anything else as an error, without running the function any further.
+/// Memory is the host's: loads and stores go to the same arrays, data
+/// objects and C memory as compiled code, and calls to the runtime and to C
+/// functions run natively. Toy functions are interpreted even when they're
+/// called through references, which hold the addresses of their compiled
+/// code. The garbage collector can't see the values the interpreter holds,
+/// so it mustn't run while interpreted code does.
+#[derive(Default)]
+pub struct ClifInterpreter {
+    /// The IR of the toy functions compiled so far, by name.
+    functions: HashMap<String, ClifFunction>,
+
+    /// The names of the toy functions, by the addresses of their compiled
+    /// code, which references to them hold.
+    names: HashMap<isize, String>,
+
+    /// The addresses of the compiled code of the toy functions, by name.
+    addresses: HashMap<String, isize>,
+
+    /// Functions provided by the host, which are called when a function
+    /// called from the IR isn't a toy function.
+    host_functions: HashMap<String, HostFunction>,
+
+    /// The addresses of the data objects.
+    data: HashMap<DataId, isize>,
+
+    /// Resolves the names of the native functions called, like the JIT's
+    /// module does, if set.
+    symbol_lookup: Option<SymbolLookup>,
+}
+
+/// The IR of a toy function, with the names of everything it references
+/// resolved.
+struct ClifFunction {
+    func: ir::Function,
+    callees: HashMap<FuncRef, String>,
+    symbols: HashMap<GlobalValue, (DataId, String)>,
+}
+
+impl ClifInterpreter {
+    /// Add the IR of the function `name`. The external names in `func` are
+    /// resolved using `declarations`, which must be the declarations of the
+    /// module the function was built for.
+    pub(crate) fn define(
+        &mut self,
+        name: &str,
+        func: ir::Function,
+        declarations: &ModuleDeclarations,
+    ) {
+        let user_name = |name: &ExternalName| match *name {
+            ExternalName::User(user_ref) => Some(func.params.user_named_funcs()[user_ref].clone()),
+            _ => None,
+        };
+
+        // Like the module does when it resolves relocations, tell functions
+        // apart from data objects by the namespace of their names.
+        let mut callees = HashMap::new();
+        for (func_ref, ext_func) in func.dfg.ext_funcs.iter() {
+            if let Some(user_name) = user_name(&ext_func.name) {
+                let decl = declarations.get_function_decl(FuncId::from_u32(user_name.index));
+                callees.insert(func_ref, decl.name.clone());
+            }
+        }
+        let mut symbols = HashMap::new();
+        for (global_value, data) in func.global_values.iter() {
+            if let GlobalValueData::Symbol { name, .. } = data {
+                if let Some(user_name) = user_name(name) {
+                    let id = DataId::from_u32(user_name.index);
+                    let decl = declarations.get_data_decl(id);
+                    symbols.insert(global_value, (id, decl.name.clone()));
+                }
+            }
+        }
+
+        self.functions.insert(
+            name.to_owned(),
+            ClifFunction {
+                func,
+                callees,
+                symbols,
+            },
+        );
+    }
+
+    /// Record the address of the compiled code of the toy function `name`.
+    pub(crate) fn define_function_address(&mut self, name: &str, address: *const u8) {
+        self.names.insert(address as isize, name.to_owned());
+        self.addresses.insert(name.to_owned(), address as isize);
+    }
+
+    /// Record the address of the data object `id`.
+    pub(crate) fn define_data(&mut self, id: DataId, address: *const u8) {
+        self.data.insert(id, address as isize);
+    }
+
+    /// Return the data objects the functions refer to whose addresses
+    /// aren't known yet.
+    pub(crate) fn undefined_data(&self) -> Vec<DataId> {
+        let mut undefined = Vec::new();
+        for function in self.functions.values() {
+            for &(id, _) in function.symbols.values() {
+                if !self.data.contains_key(&id) && !undefined.contains(&id) {
+                    undefined.push(id);
+                }
+            }
+        }
+        undefined
+    }
+
+    /// Resolve the names of the native functions called with `lookup`.
+    pub(crate) fn set_symbol_lookup<F>(&mut self, lookup: F)
+    where
+        F: Fn(&str) -> Option<*const u8> + 'static,
+    {
+        self.symbol_lookup = Some(Box::new(lookup));
+    }
+
+    /// Make a host function available to interpreted code under `name`.
+    pub fn register_host_function<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[isize]) -> isize + 'static,
+    {
+        self.host_functions
+            .insert(name.to_owned(), Box::new(function));
+    }
+
+    /// Call the function named `name`, which may be either a toy function or
+    /// a host function, with the given arguments, and return all the values
+    /// it returns. Host functions return one.
+    pub fn call(&self, name: &str, args: &[isize]) -> Result<Vec<i64>, String> {
+        if let Some(function) = self.functions.get(name) {
+            let params = &function.func.signature.params;
+            if params.len() != args.len() {
+                return Err(format!(
+                    "function `{}` takes {} arguments but {} were supplied",
+                    name,
+                    params.len(),
+                    args.len()
+                ));
+            }
+            let args: Vec<i64> = args.iter().map(|&arg| arg as i64).collect();
+            self.run(function, &args)
+        } else if let Some(function) = self.host_functions.get(name) {
+            Ok(vec![function(args) as i64])
+        } else if let Some(address) = self.lookup(name) {
+            if args.len() > MAX_NATIVE_ARGS {
+                return Err(format!(
+                    "native functions can take at most {} arguments",
+                    MAX_NATIVE_ARGS
+                ));
+            }
+            // Traps the runtime raises unwind out of the native code, like
+            // they do out of compiled toy code.
+            let result = catch_trap(|| unsafe { call_native(address as usize, args) })
+                .map_err(|trap| format!("trap: {}", trap))?;
+            Ok(vec![result as i64])
+        } else {
+            Err(format!("function `{}` not defined", name))
+        }
+    }
+
+    /// Return the address of the native function `name`, if it's known.
+    fn lookup(&self, name: &str) -> Option<*const u8> {
+        self.symbol_lookup.as_ref().and_then(|lookup| lookup(name))
+    }
+
+    /// Run the IR of a function, starting at its entry block.
+    fn run(&self, function: &ClifFunction, args: &[i64]) -> Result<Vec<i64>, String> {
+        let func = &function.func;
+        let dfg = &func.dfg;
+
+        // The values computed so far. All values are kept sign-extended to
+        // 64 bits, regardless of their type, and floating-point values as
+        // their bits.
+        let mut values: HashMap<Value, i64> = HashMap::new();
+        let value = |values: &HashMap<Value, i64>, v: Value| values[&dfg.resolve_aliases(v)];
+
+        // The memory of the stack slots of this call, allocated when first
+        // used. Native code may get their addresses, so they stay put.
+        let mut stack_slots: HashMap<StackSlot, Box<[u64]>> = HashMap::new();
+        let mut stack_slot = |slot: StackSlot| {
+            let size = func.sized_stack_slots[slot].size as usize;
+            stack_slots
+                .entry(slot)
+                .or_insert_with(|| vec![0; size.div_ceil(8)].into_boxed_slice())
+                .as_mut_ptr() as i64
+        };
+
+        let mut block = func
+            .layout
+            .entry_block()
+            .ok_or("function has no entry block")?;
+        for (&param, &arg) in dfg.block_params(block).iter().zip(args) {
+            values.insert(param, arg);
+        }
+
+        loop {
+            let mut next_block = None;
+            for inst in func.layout.block_insts(block) {
+                let args: Vec<i64> = dfg
+                    .inst_args(inst)
+                    .iter()
+                    .map(|&v| value(&values, v))
+                    .collect();
+                let arg_ty = |i: usize| dfg.value_type(dfg.inst_args(inst)[i]);
+                let results = dfg.inst_results(inst);
+                let ty = results
+                    .first()
+                    .map_or(types::INVALID, |&v| dfg.value_type(v));
+
+                let data = dfg.insts[inst];
+                let call = match data {
+                    InstructionData::Call { func_ref, .. } => {
+                        let name = &function.callees[&func_ref];
+                        // Native calls only pass integers.
+                        let signature = &dfg.signatures[dfg.ext_funcs[func_ref].signature];
+                        let native = !self.functions.contains_key(name)
+                            && !self.host_functions.contains_key(name);
+                        if native
+                            && signature
+                                .params
+                                .iter()
+                                .chain(&signature.returns)
+                                .any(|param| param.value_type.is_float())
+                        {
+                            return Err(format!(
+                                "function `{}` takes or returns floating-point values, which the \
+                                 Cranelift IR interpreter can't pass to native code",
+                                name
+                            ));
+                        }
+                        Some((name.clone(), &args[..]))
+                    }
+                    InstructionData::CallIndirect { .. } => {
+                        match self.names.get(&(args[0] as isize)) {
+                            Some(name) => Some((name.clone(), &args[1..])),
+                            None => {
+                                return Err(format!(
+                                "indirect call to {:#x}, which isn't an interpreted toy function",
+                                args[0]
+                            ))
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                if let Some((name, args)) = call {
+                    // Calls are the only instructions with several results,
+                    // or none at all.
+                    let args: Vec<isize> = args.iter().map(|&arg| arg as isize).collect();
+                    let values_returned = self.call(&name, &args)?;
+                    if values_returned.len() < results.len() {
+                        return Err(format!(
+                            "function `{}` returns {} values but {} were expected",
+                            name,
+                            values_returned.len(),
+                            results.len()
+                        ));
+                    }
+                    for (&result, value) in results.iter().zip(values_returned) {
+                        values.insert(result, extend(dfg.value_type(result), value));
+                    }
+                    continue;
+                }
+                let result = match (data, data.opcode()) {
+                    (InstructionData::UnaryImm { imm, .. }, Opcode::Iconst) => {
+                        extend(ty, imm.bits())
+                    }
+                    (_, Opcode::Iadd) => extend(ty, args[0].wrapping_add(args[1])),
+                    (_, Opcode::Isub) => extend(ty, args[0].wrapping_sub(args[1])),
+                    (_, Opcode::Imul) => extend(ty, args[0].wrapping_mul(args[1])),
+                    (InstructionData::BinaryImm64 { imm, .. }, Opcode::IaddImm) => {
+                        extend(ty, args[0].wrapping_add(imm.bits()))
+                    }
+                    (InstructionData::BinaryImm64 { imm, .. }, Opcode::ImulImm) => {
+                        extend(ty, args[0].wrapping_mul(imm.bits()))
+                    }
+                    (_, Opcode::Udiv) => {
+                        let (lhs, rhs) = (unsigned(ty, args[0]), unsigned(ty, args[1]));
+                        if rhs == 0 {
+                            return Err("trap: integer division by zero".to_owned());
+                        }
+                        extend(ty, (lhs / rhs) as i64)
+                    }
+                    (_, Opcode::Uextend) => extend(ty, unsigned(arg_ty(0), args[0]) as i64),
+                    // Values are kept sign-extended already.
+                    (_, Opcode::Sextend) => args[0],
+                    (_, Opcode::Ireduce) => extend(ty, args[0]),
+                    // References and integers, and floating-point values and
+                    // their bits, are kept alike.
+                    (_, Opcode::Bitcast) => args[0],
+                    (_, Opcode::FcvtFromSint) => match ty {
+                        types::F32 => i64::from((args[0] as f32).to_bits()),
+                        _ => (args[0] as f64).to_bits() as i64,
+                    },
+                    // Like `fcvt_to_sint_sat`, `as` saturates, and turns NaN
+                    // into 0.
+                    (_, Opcode::FcvtToSintSat) => {
+                        let float = match arg_ty(0) {
+                            types::F32 => f64::from(f32::from_bits(args[0] as u32)),
+                            _ => f64::from_bits(args[0] as u64),
+                        };
+                        extend(ty, saturate(ty, float))
+                    }
+                    (InstructionData::IntCompare { cond, .. }, Opcode::Icmp) => {
+                        i64::from(icmp(cond, arg_ty(0), args[0], args[1]))
+                    }
+                    (InstructionData::IntCompareImm { cond, imm, .. }, Opcode::IcmpImm) => {
+                        let rhs = extend(arg_ty(0), imm.bits());
+                        i64::from(icmp(cond, arg_ty(0), args[0], rhs))
+                    }
+                    (InstructionData::UnaryGlobalValue { global_value, .. }, _) => {
+                        let (id, name) = &function.symbols[&global_value];
+                        match self.data.get(id) {
+                            Some(&address) => address as i64,
+                            None => return Err(format!("data object `{}` not defined", name)),
+                        }
+                    }
+                    (InstructionData::FuncAddr { func_ref, .. }, _) => {
+                        let name = &function.callees[&func_ref];
+                        match self.addresses.get(name) {
+                            Some(&address) => address as i64,
+                            None => match self.lookup(name) {
+                                Some(address) => address as i64,
+                                None => return Err(format!("function `{}` has no address", name)),
+                            },
+                        }
+                    }
+                    (InstructionData::Load { offset, .. }, Opcode::Load) => {
+                        let address = args[0].wrapping_add(i64::from(i32::from(offset)));
+                        unsafe { load(ty, address) }
+                    }
+                    (InstructionData::Store { offset, .. }, Opcode::Store) => {
+                        let address = args[1].wrapping_add(i64::from(i32::from(offset)));
+                        unsafe { store(arg_ty(0), address, args[0]) };
+                        continue;
+                    }
+                    (
+                        InstructionData::StackLoad {
+                            stack_slot: slot,
+                            offset,
+                            ..
+                        },
+                        Opcode::StackAddr,
+                    ) => stack_slot(slot) + i64::from(i32::from(offset)),
+                    (
+                        InstructionData::StackStore {
+                            stack_slot: slot,
+                            offset,
+                            ..
+                        },
+                        _,
+                    ) => {
+                        let address = stack_slot(slot) + i64::from(i32::from(offset));
+                        unsafe { store(arg_ty(0), address, args[0]) };
+                        continue;
+                    }
+                    (InstructionData::Trap { code, .. }, _) => {
+                        return Err(format!("trap: {}", code));
+                    }
+                    (InstructionData::Jump { destination, .. }, _) => {
+                        next_block = Some(destination);
+                        break;
+                    }
+                    (InstructionData::Brif { blocks, .. }, _) => {
+                        next_block = Some(if args[0] != 0 { blocks[0] } else { blocks[1] });
+                        break;
+                    }
+                    (_, Opcode::Return) => return Ok(args),
+                    (_, opcode) => {
+                        return Err(format!(
+                            "instruction `{}` isn't supported by the Cranelift IR interpreter",
+                            opcode
+                        ));
+                    }
+                };
+                values.insert(results[0], result);
+            }
+
+            // Pass the branch arguments to the parameters of the block we're
+            // branching to. All the arguments are read before any parameter is
+            // written, as a block may branch to itself.
+            let destination = next_block.ok_or("block has no terminator")?;
+            block = destination.block(&dfg.value_lists);
+            let args: Vec<i64> = destination
+                .args_slice(&dfg.value_lists)
+                .iter()
+                .map(|&v| value(&values, v))
+                .collect();
+            for (&param, arg) in dfg.block_params(block).iter().zip(args) {
+                values.insert(param, arg);
+            }
+        }
+    }
+}
+
+/// Sign-extend the low bits of `value` which make up an integer of type
+/// `ty`, the way values are kept by the interpreter. Types without a width,
+/// like that of an instruction without results, leave it as it is.
+fn extend(ty: Type, value: i64) -> i64 {
+    match shift(ty) {
+        Some(shift) => (value << shift) >> shift,
+        None => value,
+    }
+}
+
+/// Zero-extend the low bits of `value` which make up an integer of type `ty`.
+fn unsigned(ty: Type, value: i64) -> u64 {
+    match shift(ty) {
+        Some(shift) => ((value as u64) << shift) >> shift,
+        None => value as u64,
+    }
+}
+
+/// Return how far the bits of a value of type `ty` have to be shifted to be
+/// the high bits of 64, if it has any.
+fn shift(ty: Type) -> Option<u32> {
+    match ty.bits() {
+        0 => None,
+        bits => Some(64 - bits.min(64)),
+    }
+}
+
+/// Convert `value` to the integer type `ty`, saturating.
+fn saturate(ty: Type, value: f64) -> i64 {
+    match ty {
+        types::I8 => i64::from(value as i8),
+        types::I16 => i64::from(value as i16),
+        types::I32 => i64::from(value as i32),
+        _ => value as i64,
+    }
+}
+
+/// Read a value of type `ty` from `address`, which may be misaligned.
+unsafe fn load(ty: Type, address: i64) -> i64 {
+    let pointer = address as *const u8;
+    match ty {
+        types::I8 => i64::from(ptr::read_unaligned(pointer as *const i8)),
+        types::I16 => i64::from(ptr::read_unaligned(pointer as *const i16)),
+        types::I32 => i64::from(ptr::read_unaligned(pointer as *const i32)),
+        types::F32 => i64::from(ptr::read_unaligned(pointer as *const u32)),
+        _ => ptr::read_unaligned(pointer as *const i64),
+    }
+}
+
+/// Write the low bits of `value` which make up a value of type `ty` to
+/// `address`, which may be misaligned.
+unsafe fn store(ty: Type, address: i64, value: i64) {
+    let pointer = address as *mut u8;
+    match ty.bytes() {
+        1 => ptr::write_unaligned(pointer as *mut i8, value as i8),
+        2 => ptr::write_unaligned(pointer as *mut i16, value as i16),
+        4 => ptr::write_unaligned(pointer as *mut i32, value as i32),
+        _ => ptr::write_unaligned(pointer as *mut i64, value),
+    }
+}
+
+fn icmp(cond: IntCC, ty: Type, lhs: i64, rhs: i64) -> bool {
+    let (ulhs, urhs) = (unsigned(ty, lhs), unsigned(ty, rhs));
+    match cond {
+        IntCC::Equal => lhs == rhs,
+        IntCC::NotEqual => lhs != rhs,
+        IntCC::SignedLessThan => lhs < rhs,
+        IntCC::SignedLessThanOrEqual => lhs <= rhs,
+        IntCC::SignedGreaterThan => lhs > rhs,
+        IntCC::SignedGreaterThanOrEqual => lhs >= rhs,
+        IntCC::UnsignedLessThan => ulhs < urhs,
+        IntCC::UnsignedLessThanOrEqual => ulhs <= urhs,
+        IntCC::UnsignedGreaterThan => ulhs > urhs,
+        IntCC::UnsignedGreaterThanOrEqual => ulhs >= urhs,
+    }
+}
@@ -0,0 +1,226 @@
+use crate::frontend::*;
+use std::collections::HashMap;
+use std::mem;
+
+/// A toy function, as returned by `parser::function`: its name, parameters,
+/// return variables and body.
+pub(crate) type Function = (String, Vec<String>, Vec<String>, Vec<Expr>);
+
+/// The parameter the code of a closure gets the closure itself in, and its
+/// return variable. Neither is an identifier, so they can't clash with the
+/// variables of the closure's body.
+const ENV: &str = "$env";
+const RESULT: &str = "$result";
+
+/// Lift the closures out of a function, into functions of their own, and
+/// return the function followed by those.
+///
+/// A closure is an array on the heap: its first element is the address of
+/// the function it was lifted into, and the others are the values of the
+/// variables it captures, copied when it's created. The function takes the
+/// closure as an extra first parameter, and starts by copying the captured
+/// values back into variables of the same names. Nested closures are lifted
+/// out of the function their enclosing closure was lifted into, so they can
+/// capture its parameters too. The global variables, which `is_global` tells
+/// apart, aren't captured, but accessed by the closures themselves.
+pub(crate) fn lift(function: Function, is_global: &dyn Fn(&str) -> bool) -> Vec<Function> {
+    let mut functions = Vec::new();
+    let mut worklist = vec![function];
+    while let Some((name, params, returns, mut stmts)) = worklist.pop() {
+        let mut variables = params.clone();
+        variables.extend(returns.iter().cloned());
+        let mut annotations = HashMap::new();
+        for stmt in &mut stmts {
+            declare_variables(&mut variables, &mut annotations, is_global, stmt);
+        }
+
+        let mut lifter = Lifter {
+            name: &name,
+            variables: &variables,
+            annotations: &annotations,
+            lifted: Vec::new(),
+        };
+        for stmt in &mut stmts {
+            lifter.lift_in_expr(stmt);
+        }
+        let lifted = lifter.lifted;
+
+        functions.push((name, params, returns, stmts));
+        // Keep the functions in the order the closures appear in.
+        worklist.extend(lifted.into_iter().rev());
+    }
+    functions
+}
+
+/// Return the variables of `function`: its parameters, its return variables
+/// and those its body assigns, except for the globals `is_global` tells apart.
+pub(crate) fn variables(function: &mut Function, is_global: &dyn Fn(&str) -> bool) -> Vec<String> {
+    let (_, params, returns, stmts) = function;
+    let mut variables = params.clone();
+    variables.extend(returns.iter().cloned());
+    let mut annotations = HashMap::new();
+    for stmt in stmts {
+        declare_variables(&mut variables, &mut annotations, is_global, stmt);
+    }
+    variables
+}
+
+struct Lifter<'a> {
+    /// The name of the function the closures are lifted out of, which the
+    /// names of the functions they're lifted into start with.
+    name: &'a str,
+
+    /// The variables of the function, which closures may capture, and the
+    /// structs those declared as struct pointers point to.
+    variables: &'a [String],
+    annotations: &'a HashMap<String, String>,
+
+    lifted: Vec<Function>,
+}
+
+impl Lifter<'_> {
+    fn lift_in_expr(&mut self, expr: &mut Expr) {
+        if let Expr::Closure(params, body) = expr {
+            let name = format!("{}::{{closure#{}}}", self.name, self.lifted.len());
+            let mut used = Vec::new();
+            find_names(&mut used, body);
+            let captures: Vec<String> = used
+                .into_iter()
+                .filter(|name| self.variables.contains(name) && !params.contains(name))
+                .collect();
+
+            let mut stmts = Vec::new();
+            for (i, capture) in captures.iter().enumerate() {
+                if let Some(struct_name) = self.annotations.get(capture) {
+                    stmts.push(Expr::Annotate(capture.clone(), struct_name.clone()));
+                }
+                let index = Expr::Literal((i + 1).to_string());
+                let value = Expr::Index(ENV.to_owned(), Box::new(index));
+                stmts.push(Expr::Assign(capture.clone(), Box::new(value)));
+            }
+            let body = mem::replace(&mut **body, Expr::Literal("0".to_owned()));
+            stmts.push(Expr::Assign(RESULT.to_owned(), Box::new(body)));
+
+            let mut lifted_params = vec![ENV.to_owned()];
+            lifted_params.append(params);
+            let returns = vec![RESULT.to_owned()];
+            self.lifted
+                .push((name.clone(), lifted_params, returns, stmts));
+            *expr = Expr::MakeClosure(name, captures);
+            return;
+        }
+        for child in children(expr) {
+            self.lift_in_expr(child);
+        }
+    }
+}
+
+/// Find the variables a function declares by assigning them, or declaring
+/// them as struct pointers, outside of closures. Assigning a global variable
+/// doesn't declare one.
+fn declare_variables(
+    variables: &mut Vec<String>,
+    annotations: &mut HashMap<String, String>,
+    is_global: &dyn Fn(&str) -> bool,
+    expr: &mut Expr,
+) {
+    match expr {
+        Expr::Closure(..) => return,
+        Expr::Assign(name, _) if !variables.contains(name) && !is_global(name) => {
+            variables.push(name.clone())
+        }
+        Expr::Destructure(names, ..) => {
+            for name in names {
+                if !variables.contains(name) && !is_global(name) {
+                    variables.push(name.clone());
+                }
+            }
+        }
+        Expr::Annotate(name, struct_name) => {
+            if !variables.contains(name) {
+                variables.push(name.clone());
+            }
+            annotations.insert(name.clone(), struct_name.clone());
+        }
+        _ => (),
+    }
+    for child in children(expr) {
+        declare_variables(variables, annotations, is_global, child);
+    }
+}
+
+/// Find the names an expression refers to which may be variables, in the
+/// order they first appear in, including those in nested closures.
+fn find_names(names: &mut Vec<String>, expr: &mut Expr) {
+    match expr {
+        Expr::Identifier(name)
+        | Expr::Assign(name, _)
+        | Expr::Index(name, _)
+        | Expr::AssignIndex(name, ..)
+        | Expr::Annotate(name, _)
+        | Expr::Field(name, _)
+        | Expr::AssignField(name, ..)
+        | Expr::Call(name, _)
+            if !names.contains(name) =>
+        {
+            names.push(name.clone())
+        }
+        Expr::Destructure(assigned, name, _) => {
+            for name in assigned.iter().chain([&*name]) {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        _ => (),
+    }
+    for child in children(expr) {
+        find_names(names, child);
+    }
+}
+
+/// Return the expressions directly nested in `expr`, including the body of
+/// a closure.
+fn children(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::Field(..)
+        | Expr::MakeClosure(..) => Vec::new(),
+        Expr::Assign(_, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Float(expr)
+        | Expr::Index(_, expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::AssignField(_, _, expr)
+        | Expr::Closure(_, expr) => vec![&mut **expr],
+        Expr::AssignIndex(_, lhs, rhs)
+        | Expr::Store(_, lhs, rhs)
+        | Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => vec![&mut **lhs, &mut **rhs],
+        Expr::IfElse(condition, then_body, else_body) => {
+            let mut children = vec![&mut **condition];
+            children.extend(then_body.iter_mut().chain(else_body));
+            children
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            let mut children = vec![&mut **condition];
+            children.extend(loop_body);
+            children
+        }
+        Expr::Call(_, args) | Expr::Destructure(_, _, args) => args.iter_mut().collect(),
+    }
+}
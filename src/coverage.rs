@@ -0,0 +1,254 @@
+use cranelift::codegen::ir::GlobalValue;
+use cranelift_jit::JITModule;
+use cranelift_module::DataId;
+use std::fmt::Write;
+use std::slice;
+
+/// Coverage counters for toy functions.
+///
+/// Once enabled with `JIT::enable_coverage`, the JIT gives each function it
+/// compiles a data object with a counter per basic block: the entry block,
+/// the then and else blocks of each `if`, and the body of each `while` and
+/// the blocks following them. Loop headers don't need a counter of their
+/// own, as they run exactly as often as the body and the exit together. The
+/// report maps the counters back to the source lines of the statements in
+/// each block, and to the branches taken by each `if` and `while`.
+///
+/// The counters are plain loads and stores, so counts from functions running
+/// on several threads at once may be lost.
+#[derive(Default)]
+pub(crate) struct Coverage {
+    functions: Vec<FunctionCoverage>,
+}
+
+/// The counters of a function, and what they count.
+pub(crate) struct FunctionCoverage {
+    name: String,
+    file_name: String,
+    line: u32,
+
+    /// The data object with the counters, and how the function's IR refers
+    /// to it.
+    pub(crate) data: DataId,
+    pub(crate) global_value: GlobalValue,
+    counters: u32,
+
+    /// The source lines of the statements, with the counter of the block
+    /// each is in.
+    lines: Vec<(u32, u32)>,
+
+    /// The source lines of the `if`s and `while`s, with the counters of the
+    /// blocks they branch to.
+    branches: Vec<(u32, [u32; 2])>,
+}
+
+impl Coverage {
+    pub(crate) fn add_function(&mut self, function: FunctionCoverage) {
+        self.functions.push(function);
+    }
+
+    /// Read the counters of the functions, which must all be finalized.
+    pub(crate) fn report(&self, module: &JITModule) -> CoverageReport {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                let (address, _) = module.get_finalized_data(function.data);
+                // TODO: Can we move the unsafe into cranelift?
+                let counters = unsafe {
+                    slice::from_raw_parts(address as *const u64, function.counters as usize)
+                };
+                let mut branches: Vec<BranchCoverage> = function
+                    .branches
+                    .iter()
+                    .map(|&(line, [taken, not_taken])| BranchCoverage {
+                        line,
+                        taken: counters[taken as usize],
+                        not_taken: counters[not_taken as usize],
+                    })
+                    .collect();
+                // A `while` is recorded after the branches in its body.
+                branches.sort_by_key(|branch| branch.line);
+                FunctionCoverageReport {
+                    name: function.name.clone(),
+                    file_name: function.file_name.clone(),
+                    line: function.line,
+                    calls: counters[0],
+                    lines: function
+                        .lines
+                        .iter()
+                        .map(|&(line, counter)| LineCoverage {
+                            line,
+                            hits: counters[counter as usize],
+                        })
+                        .collect(),
+                    branches,
+                }
+            })
+            .collect();
+        CoverageReport { functions }
+    }
+}
+
+impl FunctionCoverage {
+    /// Start recording the counters of a function, which are kept in the
+    /// data object `data`. The entry block gets the first counter, so the
+    /// function's header line counts its calls.
+    pub(crate) fn new(
+        name: &str,
+        file_name: &str,
+        line: u32,
+        data: DataId,
+        global_value: GlobalValue,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            file_name: file_name.to_owned(),
+            line,
+            data,
+            global_value,
+            counters: 1,
+            lines: vec![(line, 0)],
+            branches: Vec::new(),
+        }
+    }
+
+    /// Return the index of a new counter.
+    pub(crate) fn add_counter(&mut self) -> u32 {
+        self.counters += 1;
+        self.counters - 1
+    }
+
+    /// Record the line of a statement, unless it's recorded already, as for
+    /// the code of a closure, which is all on one line.
+    pub(crate) fn add_line(&mut self, line: u32, counter: u32) {
+        if !self.lines.iter().any(|&(l, _)| l == line) {
+            self.lines.push((line, counter));
+        }
+    }
+
+    /// Record a branch on the source line `line`, which goes to the block
+    /// with the counter `taken` if the condition holds, and to the one with
+    /// `not_taken` otherwise.
+    pub(crate) fn add_branch(&mut self, line: u32, taken: u32, not_taken: u32) {
+        self.branches.push((line, [taken, not_taken]));
+    }
+
+    /// Return the size of the data object with the counters.
+    pub(crate) fn size(&self) -> usize {
+        self.counters as usize * 8
+    }
+}
+
+/// The counters of the functions compiled with coverage enabled, as returned
+/// by `JIT::coverage_report`.
+#[derive(Clone, Debug)]
+pub struct CoverageReport {
+    pub functions: Vec<FunctionCoverageReport>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FunctionCoverageReport {
+    pub name: String,
+    pub file_name: String,
+
+    /// The source line of the function's header.
+    pub line: u32,
+    pub calls: u64,
+
+    /// The lines of the function's header and statements, in source order.
+    pub lines: Vec<LineCoverage>,
+
+    /// The `if`s and `while`s of the function, in source order.
+    pub branches: Vec<BranchCoverage>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LineCoverage {
+    pub line: u32,
+    pub hits: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BranchCoverage {
+    pub line: u32,
+
+    /// How often the condition held, running the then block or the loop
+    /// body, and how often it didn't.
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl CoverageReport {
+    /// Return the report in the lcov tracefile format, which `genhtml` and
+    /// most coverage services read.
+    pub fn to_lcov(&self) -> String {
+        let mut file_names: Vec<&str> = Vec::new();
+        for function in &self.functions {
+            if !file_names.contains(&&function.file_name[..]) {
+                file_names.push(&function.file_name);
+            }
+        }
+
+        let mut lcov = String::new();
+        for file_name in file_names {
+            let functions: Vec<&FunctionCoverageReport> = self
+                .functions
+                .iter()
+                .filter(|function| function.file_name == file_name)
+                .collect();
+            writeln!(lcov, "TN:").unwrap();
+            writeln!(lcov, "SF:{}", file_name).unwrap();
+
+            for function in &functions {
+                writeln!(lcov, "FN:{},{}", function.line, function.name).unwrap();
+            }
+            for function in &functions {
+                writeln!(lcov, "FNDA:{},{}", function.calls, function.name).unwrap();
+            }
+            writeln!(lcov, "FNF:{}", functions.len()).unwrap();
+            let hit = functions.iter().filter(|f| f.calls > 0).count();
+            writeln!(lcov, "FNH:{}", hit).unwrap();
+
+            let branches: Vec<&BranchCoverage> =
+                functions.iter().flat_map(|f| &f.branches).collect();
+            for (block, branch) in branches.iter().enumerate() {
+                for (index, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                    // lcov tells branches whose condition never ran apart from
+                    // ones which were never taken.
+                    if branch.taken + branch.not_taken == 0 {
+                        writeln!(lcov, "BRDA:{},{},{},-", branch.line, block, index).unwrap();
+                    } else {
+                        writeln!(lcov, "BRDA:{},{},{},{}", branch.line, block, index, count)
+                            .unwrap();
+                    }
+                }
+            }
+            writeln!(lcov, "BRF:{}", branches.len() * 2).unwrap();
+            let hit = branches
+                .iter()
+                .map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize)
+                .sum::<usize>();
+            writeln!(lcov, "BRH:{}", hit).unwrap();
+
+            // A line with a closure on it is in both the function and the
+            // closure, and counts as run as often as the more often run.
+            let mut lines: Vec<LineCoverage> = Vec::new();
+            for line in functions.iter().flat_map(|f| &f.lines) {
+                match lines.iter_mut().find(|l| l.line == line.line) {
+                    Some(l) => l.hits = l.hits.max(line.hits),
+                    None => lines.push(line.clone()),
+                }
+            }
+            lines.sort_by_key(|line| line.line);
+            for line in &lines {
+                writeln!(lcov, "DA:{},{}", line.line, line.hits).unwrap();
+            }
+            writeln!(lcov, "LF:{}", lines.len()).unwrap();
+            let hit = lines.iter().filter(|line| line.hits > 0).count();
+            writeln!(lcov, "LH:{}", hit).unwrap();
+            writeln!(lcov, "end_of_record").unwrap();
+        }
+        lcov
+    }
+}
@@ -0,0 +1,606 @@
+use crate::jit::CompiledFunction;
+use cranelift::codegen::gimli::write::{
+    Address, AttributeValue, DebugFrame, DwarfUnit, EndianVec, Expression, FileId, FrameTable,
+    LineProgram, LineString, Location, LocationList, Range, RangeList, Sections,
+};
+use cranelift::codegen::gimli::{self, constants, Encoding, Format, LineEncoding, RunTimeEndian};
+use cranelift::codegen::ir::{self, LabelValueLoc, ValueLabel};
+use cranelift::codegen::isa::unwind::{systemv, UnwindInfo};
+use cranelift::codegen::isa::TargetIsa;
+use cranelift::codegen::ValueLabelsRanges;
+use cranelift::prelude::*;
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, Module};
+use object::elf;
+use object::write::elf::{FileHeader, SectionHeader, Sym, Writer};
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::Mutex;
+
+/// Debug info for JIT'd toy functions.
+///
+/// Once enabled with `JIT::enable_debug_info`, the JIT describes the
+/// functions it compiles in DWARF: which lines of toy source each
+/// instruction comes from, where the variables live, and how to unwind the
+/// stack. Each batch of functions is wrapped up in an in-memory ELF image
+/// and registered with the GDB JIT interface, so that debuggers which
+/// support it (GDB, and LLDB with `plugin.jit-loader.gdb.enable`) can set
+/// breakpoints on toy functions and lines and print toy variables.
+#[derive(Default)]
+pub(crate) struct DebugInfo {
+    /// The functions defined, but not yet finalized.
+    pending: Vec<FunctionInfo>,
+
+    /// The images registered with the debugger. Dropping them unregisters
+    /// them.
+    registrations: Vec<Registration>,
+}
+
+/// What the debug info says about one function.
+pub(crate) struct FunctionInfo {
+    id: FuncId,
+    name: String,
+    file_name: String,
+
+    /// The line of the function's header.
+    line: u32,
+
+    code_size: u32,
+
+    /// The offsets in the code at which the line changes, with the new line.
+    lines: Vec<(u32, u32)>,
+
+    variables: Vec<VariableInfo>,
+    unwind_info: Option<systemv::UnwindInfo>,
+}
+
+struct VariableInfo {
+    name: String,
+    is_param: bool,
+
+    /// The ranges of code offsets the variable is live in, with the DWARF
+    /// expression for where it is.
+    locations: Vec<(u32, u32, Expression)>,
+}
+
+impl DebugInfo {
+    /// Record the debug info of a function which was just compiled.
+    pub(crate) fn add_function(&mut self, function: &CompiledFunction, isa: &dyn TargetIsa) {
+        let code = function.code;
+        let mut variables: Vec<(&String, &Variable)> = function.variables.iter().collect();
+        variables.sort_by_key(|(_, var)| var.index());
+        let variables = variables
+            .into_iter()
+            .map(|(name, var)| VariableInfo {
+                name: name.clone(),
+                is_param: function.params.contains(name),
+                locations: locations(&code.value_labels_ranges, *var, isa),
+            })
+            .collect();
+
+        let unwind_info = match code.create_unwind_info(isa) {
+            Ok(Some(UnwindInfo::SystemV(unwind_info))) => Some(unwind_info),
+            _ => None,
+        };
+
+        self.pending.push(FunctionInfo {
+            id: function.id,
+            name: function.name.to_owned(),
+            file_name: function.file_name.to_owned(),
+            line: function.line,
+            code_size: code.buffer.data().len() as u32,
+            lines: function.lines(),
+            variables,
+            unwind_info,
+        });
+    }
+
+    /// Register the functions defined since the last call with the debugger,
+    /// now that the module has been finalized and their addresses are known.
+    pub(crate) fn register(&mut self, module: &JITModule) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let functions: Vec<(FunctionInfo, u64)> = self
+            .pending
+            .drain(..)
+            .map(|function| {
+                let address = module.get_finalized_function(function.id) as u64;
+                (function, address)
+            })
+            .collect();
+
+        let isa = module.isa();
+        let mut sections = write_dwarf(&functions, isa).map_err(|e| e.to_string())?;
+        if let Some(debug_frame) = write_debug_frame(&functions, isa).map_err(|e| e.to_string())? {
+            sections.push((".debug_frame", debug_frame));
+        }
+        let image = write_elf(&functions, &sections, isa)?;
+        self.registrations.push(Registration::new(image));
+        Ok(())
+    }
+}
+
+/// Translate the ranges in which a variable lives in registers or on the
+/// stack into DWARF expressions.
+fn locations(
+    ranges: &ValueLabelsRanges,
+    var: Variable,
+    isa: &dyn TargetIsa,
+) -> Vec<(u32, u32, Expression)> {
+    let ranges = match ranges.get(&ValueLabel::new(var.index())) {
+        Some(ranges) => ranges,
+        None => return Vec::new(),
+    };
+    let mut locations = Vec::new();
+    for range in ranges {
+        if range.start == range.end {
+            continue;
+        }
+        let mut expression = Expression::new();
+        match range.loc {
+            LabelValueLoc::Reg(reg) => match isa.map_regalloc_reg_to_dwarf(reg) {
+                Ok(reg) => expression.op_reg(gimli::Register(reg)),
+                Err(_) => continue,
+            },
+            LabelValueLoc::SPOffset(offset) => match stack_pointer(isa) {
+                Some(sp) => expression.op_breg(sp, offset),
+                None => continue,
+            },
+        }
+        locations.push((range.start, range.end, expression));
+    }
+    locations
+}
+
+/// The DWARF register number of the stack pointer.
+fn stack_pointer(isa: &dyn TargetIsa) -> Option<gimli::Register> {
+    match isa.name() {
+        "x64" => Some(gimli::X86_64::RSP),
+        "aarch64" => Some(gimli::AArch64::SP),
+        "riscv64" => Some(gimli::Register(2)),
+        "s390x" => Some(gimli::Register(15)),
+        _ => None,
+    }
+}
+
+pub(crate) fn endianness(isa: &dyn TargetIsa) -> RunTimeEndian {
+    match isa.endianness() {
+        ir::Endianness::Little => RunTimeEndian::Little,
+        ir::Endianness::Big => RunTimeEndian::Big,
+    }
+}
+
+/// Write the DWARF sections describing the functions: a single compilation
+/// unit with a subprogram per function, and the line program.
+fn write_dwarf(
+    functions: &[(FunctionInfo, u64)],
+    isa: &dyn TargetIsa,
+) -> gimli::write::Result<Vec<(&'static str, Vec<u8>)>> {
+    let encoding = Encoding {
+        format: Format::Dwarf32,
+        version: 4,
+        address_size: isa.pointer_bytes(),
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let comp_dir = LineString::String(b".".to_vec());
+    let comp_file = LineString::String(functions[0].0.file_name.as_bytes().to_vec());
+    dwarf.unit.line_program =
+        LineProgram::new(encoding, LineEncoding::default(), comp_dir, comp_file, None);
+
+    let ranges = functions
+        .iter()
+        .map(|(function, address)| Range::StartLength {
+            begin: Address::Constant(*address),
+            length: u64::from(function.code_size),
+        })
+        .collect();
+    let ranges = dwarf.unit.ranges.add(RangeList(ranges));
+
+    let root = dwarf.unit.root();
+    let entry = dwarf.unit.get_mut(root);
+    entry.set(
+        constants::DW_AT_producer,
+        AttributeValue::String(b"cranelift-jit-demo".to_vec()),
+    );
+    // There's no language code for the toy language, and its expressions
+    // look enough like C for the debugger to evaluate them that way.
+    entry.set(
+        constants::DW_AT_language,
+        AttributeValue::Language(constants::DW_LANG_C),
+    );
+    entry.set(
+        constants::DW_AT_name,
+        AttributeValue::String(functions[0].0.file_name.as_bytes().to_vec()),
+    );
+    entry.set(
+        constants::DW_AT_comp_dir,
+        AttributeValue::String(b".".to_vec()),
+    );
+    entry.set(
+        constants::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+    entry.set(
+        constants::DW_AT_ranges,
+        AttributeValue::RangeListRef(ranges),
+    );
+
+    // All values in the toy language are pointer-sized integers.
+    let int = dwarf.unit.add(root, constants::DW_TAG_base_type);
+    let entry = dwarf.unit.get_mut(int);
+    entry.set(
+        constants::DW_AT_name,
+        AttributeValue::String(b"isize".to_vec()),
+    );
+    entry.set(
+        constants::DW_AT_encoding,
+        AttributeValue::Encoding(constants::DW_ATE_signed),
+    );
+    entry.set(
+        constants::DW_AT_byte_size,
+        AttributeValue::Data1(isa.pointer_bytes()),
+    );
+
+    let mut files: HashMap<&str, FileId> = HashMap::new();
+    for (function, address) in functions {
+        let line_program = &mut dwarf.unit.line_program;
+        let directory = line_program.default_directory();
+        let file = *files.entry(&function.file_name).or_insert_with(|| {
+            let file_name = LineString::String(function.file_name.as_bytes().to_vec());
+            line_program.add_file(file_name, directory, None)
+        });
+
+        line_program.begin_sequence(Some(Address::Constant(*address)));
+        for &(offset, line) in &function.lines {
+            let row = line_program.row();
+            row.address_offset = u64::from(offset);
+            row.file = file;
+            row.line = u64::from(line);
+            line_program.generate_row();
+        }
+        line_program.end_sequence(u64::from(function.code_size));
+
+        let subprogram = dwarf.unit.add(root, constants::DW_TAG_subprogram);
+        let mut frame_base = Expression::new();
+        frame_base.op(constants::DW_OP_call_frame_cfa);
+        let entry = dwarf.unit.get_mut(subprogram);
+        entry.set(
+            constants::DW_AT_name,
+            AttributeValue::String(function.name.as_bytes().to_vec()),
+        );
+        entry.set(constants::DW_AT_external, AttributeValue::Flag(true));
+        entry.set(
+            constants::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(*address)),
+        );
+        entry.set(
+            constants::DW_AT_high_pc,
+            AttributeValue::Udata(u64::from(function.code_size)),
+        );
+        entry.set(
+            constants::DW_AT_frame_base,
+            AttributeValue::Exprloc(frame_base),
+        );
+        entry.set(
+            constants::DW_AT_decl_file,
+            AttributeValue::FileIndex(Some(file)),
+        );
+        entry.set(
+            constants::DW_AT_decl_line,
+            AttributeValue::Udata(u64::from(function.line)),
+        );
+        entry.set(constants::DW_AT_type, AttributeValue::UnitRef(int));
+
+        for variable in &function.variables {
+            let tag = if variable.is_param {
+                constants::DW_TAG_formal_parameter
+            } else {
+                constants::DW_TAG_variable
+            };
+            let locations = variable
+                .locations
+                .iter()
+                .map(|(start, end, expression)| Location::StartLength {
+                    begin: Address::Constant(address + u64::from(*start)),
+                    length: u64::from(end - start),
+                    data: expression.clone(),
+                })
+                .collect();
+            let locations = dwarf.unit.locations.add(LocationList(locations));
+
+            let child = dwarf.unit.add(subprogram, tag);
+            let entry = dwarf.unit.get_mut(child);
+            entry.set(
+                constants::DW_AT_name,
+                AttributeValue::String(variable.name.as_bytes().to_vec()),
+            );
+            entry.set(constants::DW_AT_type, AttributeValue::UnitRef(int));
+            entry.set(
+                constants::DW_AT_location,
+                AttributeValue::LocationListRef(locations),
+            );
+        }
+    }
+
+    let mut sections = Sections::new(EndianVec::new(endianness(isa)));
+    dwarf.write(&mut sections)?;
+    let mut result = Vec::new();
+    sections.for_each(|id, section| -> gimli::write::Result<()> {
+        if !section.slice().is_empty() {
+            result.push((id.name(), section.slice().to_vec()));
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+/// Write the call frame information the debugger needs to unwind through
+/// the functions, if the target supports it.
+fn write_debug_frame(
+    functions: &[(FunctionInfo, u64)],
+    isa: &dyn TargetIsa,
+) -> gimli::write::Result<Option<Vec<u8>>> {
+    let cie = match isa.create_systemv_cie() {
+        Some(cie) => cie,
+        None => return Ok(None),
+    };
+    let mut frame_table = FrameTable::default();
+    let cie = frame_table.add_cie(cie);
+    for (function, address) in functions {
+        if let Some(unwind_info) = &function.unwind_info {
+            frame_table.add_fde(cie, unwind_info.to_fde(Address::Constant(*address)));
+        }
+    }
+    let mut debug_frame = DebugFrame::from(EndianVec::new(endianness(isa)));
+    frame_table.write_debug_frame(&mut debug_frame)?;
+    Ok(Some(debug_frame.0.into_vec()))
+}
+
+/// The ELF machine type of the target.
+pub(crate) fn elf_machine(isa: &dyn TargetIsa) -> Result<u16, String> {
+    match isa.name() {
+        "x64" => Ok(elf::EM_X86_64),
+        "aarch64" => Ok(elf::EM_AARCH64),
+        "riscv64" => Ok(elf::EM_RISCV),
+        "s390x" => Ok(elf::EM_S390),
+        name => Err(format!("no ELF machine type for `{}`", name)),
+    }
+}
+
+/// Wrap the debug info up in an ELF relocatable object, the way the GDB JIT
+/// interface expects it.
+///
+/// The object has a `.text` section without contents, whose address is the
+/// address the code was actually loaded at, a symbol for each function and
+/// the debug sections. All addresses in the debug info are absolute, so
+/// nothing needs to be relocated.
+fn write_elf(
+    functions: &[(FunctionInfo, u64)],
+    sections: &[(&str, Vec<u8>)],
+    isa: &dyn TargetIsa,
+) -> Result<Vec<u8>, String> {
+    let machine = elf_machine(isa)?;
+    let endian = match isa.endianness() {
+        ir::Endianness::Little => object::Endianness::Little,
+        ir::Endianness::Big => object::Endianness::Big,
+    };
+    let text_start = functions.iter().map(|(_, address)| *address).min().unwrap();
+    let text_end = functions
+        .iter()
+        .map(|(function, address)| address + u64::from(function.code_size))
+        .max()
+        .unwrap();
+
+    let mut buffer = Vec::new();
+    let mut writer = Writer::new(endian, isa.pointer_bytes() == 8, &mut buffer);
+
+    // Reserve everything first, in the order it's going to be written.
+    writer.reserve_file_header();
+    writer.reserve_null_section_index();
+    let text_name = writer.add_section_name(b".text");
+    let text_index = writer.reserve_section_index();
+    let section_names: Vec<_> = sections
+        .iter()
+        .map(|(name, _)| {
+            let name = writer.add_section_name(name.as_bytes());
+            writer.reserve_section_index();
+            name
+        })
+        .collect();
+    writer.reserve_null_symbol_index();
+    let symbol_names: Vec<_> = functions
+        .iter()
+        .map(|(function, _)| {
+            let name = writer.add_string(function.name.as_bytes());
+            writer.reserve_symbol_index(Some(text_index));
+            name
+        })
+        .collect();
+    writer.reserve_symtab_section_index();
+    writer.reserve_strtab_section_index();
+    writer.reserve_shstrtab_section_index();
+    let section_offsets: Vec<usize> = sections
+        .iter()
+        .map(|(_, data)| writer.reserve(data.len(), 1))
+        .collect();
+    writer.reserve_symtab();
+    writer.reserve_strtab();
+    writer.reserve_shstrtab();
+    writer.reserve_section_headers();
+
+    writer
+        .write_file_header(&FileHeader {
+            os_abi: elf::ELFOSABI_NONE,
+            abi_version: 0,
+            e_type: elf::ET_REL,
+            e_machine: machine,
+            e_entry: 0,
+            e_flags: 0,
+        })
+        .map_err(|e| e.to_string())?;
+    for (_, data) in sections {
+        writer.write(data);
+    }
+    writer.write_null_symbol();
+    for ((function, address), name) in functions.iter().zip(symbol_names) {
+        writer.write_symbol(&Sym {
+            name: Some(name),
+            section: Some(text_index),
+            st_info: (elf::STB_GLOBAL << 4) | elf::STT_FUNC,
+            st_other: elf::STV_DEFAULT,
+            st_shndx: 0,
+            st_value: *address,
+            st_size: u64::from(function.code_size),
+        });
+    }
+    writer.write_strtab();
+    writer.write_shstrtab();
+
+    writer.write_null_section_header();
+    writer.write_section_header(&SectionHeader {
+        name: Some(text_name),
+        sh_type: elf::SHT_NOBITS,
+        sh_flags: u64::from(elf::SHF_ALLOC | elf::SHF_EXECINSTR),
+        sh_addr: text_start,
+        sh_offset: 0,
+        sh_size: text_end - text_start,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 16,
+        sh_entsize: 0,
+    });
+    for ((_, data), (offset, section_name)) in sections
+        .iter()
+        .zip(section_offsets.into_iter().zip(section_names))
+    {
+        writer.write_section_header(&SectionHeader {
+            name: Some(section_name),
+            sh_type: elf::SHT_PROGBITS,
+            sh_flags: 0,
+            sh_addr: 0,
+            sh_offset: offset as u64,
+            sh_size: data.len() as u64,
+            sh_link: 0,
+            sh_info: 0,
+            sh_addralign: 1,
+            sh_entsize: 0,
+        });
+    }
+    // Only the null symbol is local.
+    writer.write_symtab_section_header(1);
+    writer.write_strtab_section_header();
+    writer.write_shstrtab_section_header();
+
+    Ok(buffer)
+}
+
+// The GDB JIT interface. Debuggers put a breakpoint on
+// `__jit_debug_register_code`, and read the list of object files from
+// `__jit_debug_descriptor` whenever it's hit. See
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/JIT-Interface.html
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+#[no_mangle]
+#[inline(never)]
+extern "C" fn __jit_debug_register_code() {
+    // The debugger's breakpoint needs an instruction to go on, so make sure
+    // the function isn't optimized away.
+    unsafe { std::arch::asm!("", options(nostack, preserves_flags)) }
+}
+
+/// Serializes the changes to the list of entries, which JITs on different
+/// threads may make at the same time.
+static JIT_DEBUG_LOCK: Mutex<()> = Mutex::new(());
+
+/// An ELF image registered with the debugger, until dropped.
+struct Registration {
+    entry: Box<JitCodeEntry>,
+
+    // The entry points into the image.
+    _image: Vec<u8>,
+}
+
+impl Registration {
+    fn new(image: Vec<u8>) -> Self {
+        let mut entry = Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: image.as_ptr(),
+            symfile_size: image.len() as u64,
+        });
+        let _lock = JIT_DEBUG_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry: *mut JitCodeEntry = &mut *entry;
+            (*entry).next_entry = (*descriptor).first_entry;
+            if let Some(first) = (*descriptor).first_entry.as_mut() {
+                first.prev_entry = entry;
+            }
+            (*descriptor).first_entry = entry;
+            notify_debugger(descriptor, entry, JIT_REGISTER_FN);
+        }
+        Self {
+            entry,
+            _image: image,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = JIT_DEBUG_LOCK.lock().unwrap();
+        unsafe {
+            let descriptor = ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry: *mut JitCodeEntry = &mut *self.entry;
+            match (*entry).prev_entry.as_mut() {
+                Some(prev) => prev.next_entry = (*entry).next_entry,
+                None => (*descriptor).first_entry = (*entry).next_entry,
+            }
+            if let Some(next) = (*entry).next_entry.as_mut() {
+                next.prev_entry = (*entry).prev_entry;
+            }
+            notify_debugger(descriptor, entry, JIT_UNREGISTER_FN);
+        }
+    }
+}
+
+/// Tell the debugger about a change to the list of entries.
+///
+/// # Safety
+///
+/// The caller must hold `JIT_DEBUG_LOCK`.
+unsafe fn notify_debugger(descriptor: *mut JitDescriptor, entry: *mut JitCodeEntry, action: u32) {
+    (*descriptor).relevant_entry = entry;
+    (*descriptor).action_flag = action;
+    __jit_debug_register_code();
+    (*descriptor).action_flag = JIT_NOACTION;
+}
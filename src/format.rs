@@ -0,0 +1,396 @@
+use crate::frontend::*;
+use std::fmt::{self, Display, Write};
+
+/// The indentation of each level of nesting in canonical toy source.
+const INDENT: &str = "    ";
+
+/// A function, as returned by `parser::function`, which displays as
+/// canonical toy source.
+pub struct DisplayFunction<'a> {
+    pub name: &'a str,
+    pub params: &'a [String],
+    pub returns: &'a [String],
+    pub stmts: &'a [Expr],
+}
+
+impl Display for DisplayFunction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "fn {}({}) -> ({}) {{",
+            self.name,
+            self.params.join(", "),
+            self.returns.join(", ")
+        )?;
+        fmt_body(f, self.stmts)?;
+        writeln!(f, "}}")
+    }
+}
+
+/// Declarations display as canonical toy source, with one field per line.
+impl Display for Declaration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Declaration::Struct(name, fields) => {
+                writeln!(f, "struct {} {{", name)?;
+                for (field, ty) in fields {
+                    writeln!(f, "{}{}: {},", INDENT, field, ty.name())?;
+                }
+                writeln!(f, "}}")
+            }
+            Declaration::Extern(function) => {
+                let mut params: Vec<String> = function
+                    .params
+                    .iter()
+                    .map(|(name, ty)| match ty {
+                        Some(ty) => format!("{}: {}", name, ty.name()),
+                        None => name.clone(),
+                    })
+                    .collect();
+                if function.variadic {
+                    params.push("...".to_owned());
+                }
+                write!(f, "extern ")?;
+                if let Some(abi) = &function.abi {
+                    write!(f, "\"{}\" ", abi)?;
+                }
+                write!(f, "fn {}({})", function.name, params.join(", "))?;
+                if let Some(ty) = function.returns {
+                    write!(f, " -> {}", ty.name())?;
+                }
+                writeln!(f, ";")
+            }
+            Declaration::Global(name, value, thread_local) => {
+                if *thread_local {
+                    write!(f, "thread_local ")?;
+                }
+                writeln!(f, "global {} = {}", name, value)
+            }
+            Declaration::Const(name, value) => writeln!(f, "const {} = {}", name, value),
+            Declaration::ConstTable(name, values) => {
+                let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                writeln!(f, "const {} = [{}]", name, values.join(", "))
+            }
+        }
+    }
+}
+
+impl Display for TableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TableEntry::Value(value) => write!(f, "{}", value),
+            TableEntry::Address(name) => write!(f, "&{}", name),
+            TableEntry::Str(s) => write!(f, "{}", escape_string(s)),
+        }
+    }
+}
+
+/// Constant expressions display like expressions, with as few parentheses as
+/// the precedence of the operators allows.
+impl Display for ConstExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let binary = |f: &mut fmt::Formatter, op, lhs: &ConstExpr, rhs: &ConstExpr| {
+            // Like in `fmt_binary`, the operators are right-associative.
+            let level = const_precedence(self);
+            fmt_const_operand(f, lhs, const_precedence(lhs) <= level)?;
+            write!(f, " {} ", op)?;
+            fmt_const_operand(f, rhs, const_precedence(rhs) < level)
+        };
+        match self {
+            ConstExpr::Literal(n) => write!(f, "{}", n),
+            ConstExpr::Name(name) => write!(f, "{}", name),
+            ConstExpr::Neg(e) => {
+                write!(f, "-")?;
+                fmt_const_operand(f, e, const_precedence(e) < const_precedence(self))
+            }
+            ConstExpr::Add(lhs, rhs) => binary(f, "+", lhs, rhs),
+            ConstExpr::Sub(lhs, rhs) => binary(f, "-", lhs, rhs),
+            ConstExpr::Mul(lhs, rhs) => binary(f, "*", lhs, rhs),
+            ConstExpr::Div(lhs, rhs) => binary(f, "/", lhs, rhs),
+            ConstExpr::Shl(lhs, rhs) => binary(f, "<<", lhs, rhs),
+            ConstExpr::Shr(lhs, rhs) => binary(f, ">>", lhs, rhs),
+        }
+    }
+}
+
+/// Expressions display as canonical toy source, with as few parentheses as
+/// the precedence of the operators allows. Nested statements are indented
+/// relative to the line the expression starts on.
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Literal(literal) => write!(f, "{}", literal),
+            Expr::Str(string) => write!(f, "{}", escape_string(string)),
+            Expr::Identifier(name) => write!(f, "{}", name),
+            Expr::Assign(name, expr) => write!(f, "{} = {}", name, expr),
+            Expr::Destructure(names, name, args) => {
+                let call = Expr::Call(name.clone(), args.clone());
+                write!(f, "{} = {}", names.join(", "), call)
+            }
+            Expr::Eq(lhs, rhs) => fmt_binary(f, self, "==", lhs, rhs),
+            Expr::Ne(lhs, rhs) => fmt_binary(f, self, "!=", lhs, rhs),
+            Expr::Lt(lhs, rhs) => fmt_binary(f, self, "<", lhs, rhs),
+            Expr::Le(lhs, rhs) => fmt_binary(f, self, "<=", lhs, rhs),
+            Expr::Gt(lhs, rhs) => fmt_binary(f, self, ">", lhs, rhs),
+            Expr::Ge(lhs, rhs) => fmt_binary(f, self, ">=", lhs, rhs),
+            Expr::Add(lhs, rhs) => fmt_binary(f, self, "+", lhs, rhs),
+            Expr::Sub(lhs, rhs) => fmt_binary(f, self, "-", lhs, rhs),
+            Expr::Mul(lhs, rhs) => fmt_binary(f, self, "*", lhs, rhs),
+            Expr::Div(lhs, rhs) => fmt_binary(f, self, "/", lhs, rhs),
+            Expr::IfElse(condition, then_body, else_body) => {
+                writeln!(f, "if {} {{", condition)?;
+                fmt_body(f, then_body)?;
+                writeln!(f, "}} else {{")?;
+                fmt_body(f, else_body)?;
+                write!(f, "}}")
+            }
+            Expr::WhileLoop(condition, loop_body) => {
+                writeln!(f, "while {} {{", condition)?;
+                fmt_body(f, loop_body)?;
+                write!(f, "}}")
+            }
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::GlobalDataAddr(name) => write!(f, "&{}", name),
+            Expr::Array(len) => write!(f, "array({})", len),
+            Expr::Len(array) => write!(f, "len({})", array),
+            Expr::Index(name, index) => write!(f, "{}[{}]", name, index),
+            Expr::AssignIndex(name, index, expr) => write!(f, "{}[{}] = {}", name, index, expr),
+            Expr::Load(width, pointer) => write!(f, "load{}({})", width.bits(), pointer),
+            Expr::SignedLoad(width, pointer) => write!(f, "sload{}({})", width.bits(), pointer),
+            Expr::Store(width, pointer, expr) => {
+                write!(f, "store{}({}, {})", width.bits(), pointer, expr)
+            }
+            Expr::Annotate(name, struct_name) => write!(f, "{}: {}", name, struct_name),
+            Expr::Field(name, field) => write!(f, "{}.{}", name, field),
+            Expr::AssignField(name, field, expr) => write!(f, "{}.{} = {}", name, field, expr),
+            Expr::Closure(params, body) => write!(f, "|{}| {}", params.join(", "), body),
+            Expr::MakeClosure(name, captures) => write!(f, "&{}[{}]", name, captures.join(", ")),
+            Expr::Float(expr) => write!(f, "f64({})", expr),
+        }
+    }
+}
+
+/// The precedence of an expression, following the levels of `binary_op` in
+/// the grammar. Higher binds tighter. Anything which isn't part of
+/// `binary_op` has to be parenthesized to be used as an operand.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::Assign(..)
+        | Expr::Destructure(..)
+        | Expr::AssignIndex(..)
+        | Expr::AssignField(..)
+        | Expr::Annotate(..)
+        | Expr::IfElse(..)
+        | Expr::WhileLoop(..)
+        | Expr::Closure(..) => 0,
+        Expr::Eq(..) | Expr::Ne(..) | Expr::Lt(..) | Expr::Le(..) | Expr::Gt(..) | Expr::Ge(..) => {
+            1
+        }
+        Expr::Add(..) | Expr::Sub(..) => 2,
+        Expr::Mul(..) | Expr::Div(..) => 3,
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::Call(..)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Array(_)
+        | Expr::Len(_)
+        | Expr::Index(..)
+        | Expr::Load(..)
+        | Expr::SignedLoad(..)
+        | Expr::Store(..)
+        | Expr::Field(..)
+        | Expr::MakeClosure(..)
+        | Expr::Float(_) => 4,
+    }
+}
+
+/// The precedence of a constant expression, following the levels of
+/// `const_expr` in the grammar. Higher binds tighter.
+fn const_precedence(expr: &ConstExpr) -> u8 {
+    match expr {
+        ConstExpr::Shl(..) | ConstExpr::Shr(..) => 1,
+        ConstExpr::Add(..) | ConstExpr::Sub(..) => 2,
+        ConstExpr::Mul(..) | ConstExpr::Div(..) => 3,
+        ConstExpr::Neg(_) => 4,
+        ConstExpr::Literal(_) | ConstExpr::Name(_) => 5,
+    }
+}
+
+fn fmt_binary(
+    f: &mut fmt::Formatter,
+    expr: &Expr,
+    op: &str,
+    lhs: &Expr,
+    rhs: &Expr,
+) -> fmt::Result {
+    // The grammar makes all the binary operators right-associative, so the
+    // left operand needs parentheses at the same precedence too.
+    let level = precedence(expr);
+    fmt_operand(f, lhs, precedence(lhs) <= level)?;
+    write!(f, " {} ", op)?;
+    fmt_operand(f, rhs, precedence(rhs) < level)
+}
+
+fn fmt_operand(f: &mut fmt::Formatter, expr: &Expr, parenthesize: bool) -> fmt::Result {
+    if parenthesize {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+fn fmt_const_operand(f: &mut fmt::Formatter, expr: &ConstExpr, parenthesize: bool) -> fmt::Result {
+    if parenthesize {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+/// Write the statements of a body, one per line, indented one level deeper
+/// than the surrounding code.
+fn fmt_body(f: &mut fmt::Formatter, stmts: &[Expr]) -> fmt::Result {
+    let mut indented = Indented {
+        inner: f,
+        at_line_start: true,
+    };
+    for stmt in stmts {
+        writeln!(indented, "{}", stmt)?;
+    }
+    Ok(())
+}
+
+/// A writer which indents every line written through it by one level.
+struct Indented<'a, 'b> {
+    inner: &'a mut fmt::Formatter<'b>,
+    at_line_start: bool,
+}
+
+impl Write for Indented<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if self.at_line_start && line != "\n" {
+                self.inner.write_str(INDENT)?;
+            }
+            self.inner.write_str(line)?;
+            self.at_line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+/// Reformat a source file in the toy language into canonical form,
+/// preserving its comments and blank lines.
+///
+/// The grammar only allows line breaks in fixed places, so each line of code
+/// in the input corresponds to exactly one line of the canonical output.
+/// Comments are carried over to the output line of the code line they
+/// appear on or in front of. Runs of blank lines are collapsed into one, and
+/// functions and struct declarations are always separated by one from what
+/// precedes them. The other declarations, which take a single line each, keep
+/// the blank lines of the input.
+pub fn format_source(input: &str) -> Result<String, String> {
+    let items = parser::items(input).map_err(|e| e.to_string())?;
+
+    let mut code = String::new();
+    for item in &items {
+        match item {
+            Item::Declaration(declaration) => write!(code, "{}", declaration).unwrap(),
+            Item::Function(name, params, returns, stmts) => {
+                let function = DisplayFunction {
+                    name,
+                    params,
+                    returns,
+                    stmts,
+                };
+                write!(code, "{}", function).unwrap();
+            }
+        }
+    }
+    let mut code_lines = code.lines();
+
+    // The comments on lines of their own which are waiting for the line of
+    // code they precede, with empty strings standing for blank lines.
+    let mut pending: Vec<&str> = Vec::new();
+    let mut blank = false;
+    let mut output = String::new();
+    for line in input.lines() {
+        let (line_code, comment) = split_comment(line);
+        if line_code.trim().is_empty() {
+            match comment {
+                Some(comment) => {
+                    if blank && !(output.is_empty() && pending.is_empty()) {
+                        pending.push("");
+                    }
+                    blank = false;
+                    pending.push(comment);
+                }
+                None => blank = true,
+            }
+            continue;
+        }
+
+        let code_line = code_lines
+            .next()
+            .ok_or("formatting changed the number of lines of code")?;
+        if blank && !(output.is_empty() && pending.is_empty()) {
+            pending.push("");
+        }
+        blank = false;
+        let item_start = code_line.starts_with("fn ") || code_line.starts_with("struct ");
+        if item_start && !output.is_empty() && pending.first() != Some(&"") {
+            pending.insert(0, "");
+        }
+
+        // Comments are indented like the code they precede, or like the body
+        // they're at the end of.
+        let mut indent = code_line[..code_line.len() - code_line.trim_start().len()].to_owned();
+        if code_line.trim_start().starts_with('}') {
+            indent.push_str(INDENT);
+        }
+        for comment in pending.drain(..) {
+            if !comment.is_empty() {
+                output.push_str(&indent);
+                output.push_str(comment);
+            }
+            output.push('\n');
+        }
+        output.push_str(code_line);
+        if let Some(comment) = comment {
+            output.push(' ');
+            output.push_str(comment);
+        }
+        output.push('\n');
+    }
+    if code_lines.next().is_some() {
+        return Err("formatting changed the number of lines of code".to_owned());
+    }
+
+    // Comments after the last line of code stay at the top level.
+    if blank && !pending.is_empty() {
+        pending.insert(0, "");
+    }
+    for comment in pending {
+        output.push_str(comment);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Split a line into its code and its trailing comment, if any.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match comment_start(line) {
+        Some(index) => (&line[..index], Some(line[index..].trim_end())),
+        None => (line, None),
+    }
+}
@@ -1,8 +1,20 @@
+use std::collections::HashMap;
+
 /// The AST node for expressions.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Expr {
     Literal(String),
+
+    /// A string literal, with its escapes already replaced. It evaluates to
+    /// the address of a NUL-terminated copy in read-only data.
+    Str(String),
+
     Identifier(String),
     Assign(String, Box<Expr>),
+
+    /// `a, b = f(args)`, assigning the results of a call to a function with
+    /// several return values.
+    Destructure(Vec<String>, String, Vec<Expr>),
     Eq(Box<Expr>, Box<Expr>),
     Ne(Box<Expr>, Box<Expr>),
     Lt(Box<Expr>, Box<Expr>),
@@ -17,44 +29,537 @@ pub enum Expr {
     WhileLoop(Box<Expr>, Vec<Expr>),
     Call(String, Vec<Expr>),
     GlobalDataAddr(String),
+
+    /// `array(len)`, which allocates an array of `len` zeros.
+    Array(Box<Expr>),
+
+    /// `len(array)`.
+    Len(Box<Expr>),
+
+    /// `name[index]`, reading an element of the array in a variable.
+    Index(String, Box<Expr>),
+
+    /// `name[index] = value`.
+    AssignIndex(String, Box<Expr>, Box<Expr>),
+
+    /// `load8(pointer)` and so on, which read an integer of the given width
+    /// from memory, zero-extending it.
+    Load(Width, Box<Expr>),
+
+    /// `sload8(pointer)` and so on, which sign-extend the integer instead.
+    SignedLoad(Width, Box<Expr>),
+
+    /// `store8(pointer, value)` and so on, which write the low bits of
+    /// `value` to memory, and return `value`.
+    Store(Width, Box<Expr>, Box<Expr>),
+
+    /// `name: Struct`, which declares that the variable holds a pointer to a
+    /// `Struct`, for the whole function.
+    Annotate(String, String),
+
+    /// `name.field`, reading a field of the struct the variable points to.
+    Field(String, String),
+
+    /// `name.field = value`.
+    AssignField(String, String, Box<Expr>),
+
+    /// `|params| body`, an anonymous function which captures the values of
+    /// the variables of the enclosing function it uses.
+    Closure(Vec<String>, Box<Expr>),
+
+    /// `f64(value)`, passing `value` to a variadic C function converted to a
+    /// double. It's only allowed as one of the variadic arguments of a call.
+    Float(Box<Expr>),
+
+    /// The creation of a closure once it's lifted into the function `name`,
+    /// capturing the variables `captures`. This isn't parsed: compilation
+    /// replaces `Closure`s with it.
+    MakeClosure(String, Vec<String>),
+}
+
+/// The AST node for the declarations which can precede functions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Declaration {
+    /// `struct Name { field: type ... }`, which is laid out like the C struct
+    /// with the same fields.
+    Struct(String, Vec<(String, FieldType)>),
+
+    /// `extern fn name(params) -> type;`, which declares a C function toy
+    /// code calls.
+    Extern(ExternFunction),
+
+    /// `global name = value`, which declares a global variable with an
+    /// initial value. Functions read and assign it by name, unless they
+    /// have a parameter or a return variable of the same name. Preceded by
+    /// `thread_local`, which sets the flag, each thread has its own.
+    Global(String, i64, bool),
+
+    /// `const NAME = value`, which declares a constant integer. Functions
+    /// read it by name, unless they have a variable of the same name, and
+    /// can't assign it.
+    Const(String, ConstExpr),
+
+    /// `const NAME = [values]`, which declares a table of constant integers
+    /// and pointers in read-only data, laid out like an array, which the
+    /// name evaluates to the address of.
+    ConstTable(String, Vec<TableEntry>),
+}
+
+/// An element of a constant table.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TableEntry {
+    /// A constant integer.
+    Value(ConstExpr),
+
+    /// `&name`, the address of a function or a data object defined before
+    /// the table, which is written when the table is finalized.
+    Address(String),
+
+    /// A string literal, which evaluates to the address of a NUL-terminated
+    /// copy in read-only data, like in code.
+    Str(String),
+}
+
+/// The value of a constant, which is evaluated at compile time, so it can
+/// only refer to other constants.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConstExpr {
+    Literal(i64),
+    Name(String),
+    Neg(Box<ConstExpr>),
+    Add(Box<ConstExpr>, Box<ConstExpr>),
+    Sub(Box<ConstExpr>, Box<ConstExpr>),
+    Mul(Box<ConstExpr>, Box<ConstExpr>),
+    Div(Box<ConstExpr>, Box<ConstExpr>),
+    Shl(Box<ConstExpr>, Box<ConstExpr>),
+
+    /// `a >> b`, an arithmetic shift.
+    Shr(Box<ConstExpr>, Box<ConstExpr>),
+}
+
+impl ConstExpr {
+    /// Evaluate the expression, given the values of the constants declared
+    /// so far.
+    pub fn eval(&self, consts: &HashMap<String, i64>) -> Result<i64, String> {
+        let overflow = || "overflow in constant expression".to_owned();
+        let shift = |b: i64| {
+            if (0..64).contains(&b) {
+                Ok(b as u32)
+            } else {
+                Err(format!("shift by {} in constant expression", b))
+            }
+        };
+        match self {
+            ConstExpr::Literal(n) => Ok(*n),
+            ConstExpr::Name(name) => match consts.get(name) {
+                Some(&value) => Ok(value),
+                None => Err(format!("constant `{}` not defined", name)),
+            },
+            ConstExpr::Neg(e) => e.eval(consts)?.checked_neg().ok_or_else(overflow),
+            ConstExpr::Add(a, b) => a
+                .eval(consts)?
+                .checked_add(b.eval(consts)?)
+                .ok_or_else(overflow),
+            ConstExpr::Sub(a, b) => a
+                .eval(consts)?
+                .checked_sub(b.eval(consts)?)
+                .ok_or_else(overflow),
+            ConstExpr::Mul(a, b) => a
+                .eval(consts)?
+                .checked_mul(b.eval(consts)?)
+                .ok_or_else(overflow),
+            ConstExpr::Div(a, b) => {
+                let (a, b) = (a.eval(consts)?, b.eval(consts)?);
+                if b == 0 {
+                    return Err("division by zero in constant expression".to_owned());
+                }
+                a.checked_div(b).ok_or_else(overflow)
+            }
+            ConstExpr::Shl(a, b) => Ok(a.eval(consts)? << shift(b.eval(consts)?)?),
+            ConstExpr::Shr(a, b) => Ok(a.eval(consts)? >> shift(b.eval(consts)?)?),
+        }
+    }
+}
+
+/// A C function declared with `extern fn`, with the types it's called with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternFunction {
+    pub name: String,
+
+    /// The ABI named after `extern`, `"C"` or `"system"`, if any. Without
+    /// one, it's `"C"`.
+    pub abi: Option<String>,
+
+    /// The parameters, with their types. Those without one take a value as
+    /// is, like `ptr`.
+    pub params: Vec<(String, Option<FieldType>)>,
+
+    /// Whether the function is variadic, which the parameters are followed
+    /// by `...` for.
+    pub variadic: bool,
+
+    /// The return type, if declared. Without one, the function returns a
+    /// value as is, like `ptr`.
+    pub returns: Option<FieldType>,
+}
+
+/// A top-level item of a source file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Declaration(Declaration),
+    Function(String, Vec<String>, Vec<String>, Vec<Expr>),
+}
+
+/// The type of a struct field, or of a parameter or the return value of a C
+/// function. Values are always pointer-sized integers, so the other types are
+/// converted when a field is read or written, or a C function called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    Ptr,
+}
+
+impl FieldType {
+    pub fn name(self) -> &'static str {
+        match self {
+            FieldType::I8 => "i8",
+            FieldType::I16 => "i16",
+            FieldType::I32 => "i32",
+            FieldType::I64 => "i64",
+            FieldType::U8 => "u8",
+            FieldType::U16 => "u16",
+            FieldType::U32 => "u32",
+            FieldType::U64 => "u64",
+            FieldType::F32 => "f32",
+            FieldType::F64 => "f64",
+            FieldType::Ptr => "ptr",
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(
+            self,
+            FieldType::I8 | FieldType::I16 | FieldType::I32 | FieldType::I64
+        )
+    }
+}
+
+/// The width of an integer in memory, as accessed by the memory intrinsics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    W8,
+    W16,
+    W32,
+    W64,
+}
+
+impl Width {
+    pub fn bits(self) -> u32 {
+        match self {
+            Width::W8 => 8,
+            Width::W16 => 16,
+            Width::W32 => 32,
+            Width::W64 => 64,
+        }
+    }
+}
+
+/// The functions of the string runtime, which toy code calls like any other
+/// function, with their numbers of parameters. The parser checks calls to
+/// them, and they take precedence over host functions with the same names.
+pub const RUNTIME_FUNCTIONS: [(&str, usize); 4] = [
+    ("str_len", 1),
+    ("str_concat", 2),
+    ("str_cmp", 2),
+    ("str_from_int", 1),
+];
+
+/// Return the byte offset of the `//` starting the comment on `line`, if
+/// any, skipping over string literals.
+pub fn comment_start(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut in_string = false;
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' if in_string => index += 1,
+            b'"' => in_string = !in_string,
+            b'/' if !in_string && bytes.get(index + 1) == Some(&b'/') => return Some(index),
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+/// Return `s` as the toy string literal which evaluates to it.
+pub fn escape_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            ' '..='~' => escaped.push(c),
+            _ => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Recursively descend through the AST, finding the names of the variables
+/// it assigns, including those holding the arrays it assigns elements of.
+pub fn assigned_names<'a>(names: &mut Vec<&'a str>, expr: &'a Expr) {
+    match expr {
+        Expr::Assign(name, expr) => {
+            names.push(name);
+            assigned_names(names, expr);
+        }
+        Expr::AssignIndex(name, index, expr) => {
+            names.push(name);
+            assigned_names(names, index);
+            assigned_names(names, expr);
+        }
+        Expr::Destructure(targets, _, args) => {
+            names.extend(targets.iter().map(|name| &name[..]));
+            for arg in args {
+                assigned_names(names, arg);
+            }
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::Field(..)
+        | Expr::MakeClosure(..) => {}
+        Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Float(expr)
+        | Expr::Index(_, expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::AssignField(_, _, expr)
+        | Expr::Closure(_, expr) => assigned_names(names, expr),
+        Expr::Store(_, lhs, rhs)
+        | Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            assigned_names(names, lhs);
+            assigned_names(names, rhs);
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            assigned_names(names, condition);
+            for expr in then_body.iter().chain(else_body) {
+                assigned_names(names, expr);
+            }
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            assigned_names(names, condition);
+            for expr in loop_body {
+                assigned_names(names, expr);
+            }
+        }
+        Expr::Call(_, args) => {
+            for arg in args {
+                assigned_names(names, arg);
+            }
+        }
+    }
+}
+
+/// Return the one-based numbers of the lines of `input` which contain code,
+/// rather than only whitespace and comments.
+///
+/// The grammar only allows line breaks in fixed places: after the header of
+/// a function, after each statement, and around the bodies of if-else and
+/// while constructs. So the lines of code of a function are its header, then
+/// each statement, with the lines taken up by the `} else {` and `}` of those
+/// constructs in between, in order.
+pub fn code_lines(input: &str) -> Vec<u32> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let code = match comment_start(line) {
+                Some(index) => &line[..index],
+                None => line,
+            };
+            !code.trim().is_empty()
+        })
+        .map(|(index, _)| index as u32 + 1)
+        .collect()
 }
 
 peg::parser!(pub grammar parser() for str {
-    pub rule function() -> (String, Vec<String>, String, Vec<Expr>)
-        = [' ' | '\t' | '\n']* "fn" _ name:identifier() _
+    pub rule function() -> (String, Vec<String>, Vec<String>, Vec<Expr>)
+        = blank_lines() _ "fn" _ name:identifier() _
         "(" params:((_ i:identifier() _ {i}) ** ",") ")" _
         "->" _
-        "(" returns:(_ i:identifier() _ {i}) ")" _
-        "{" _ "\n"
+        "(" returns:((_ i:identifier() _ {i}) ++ ",") ")" _
+        "{" newline()
         stmts:statements()
-        _ "}" _ "\n" _
+        _ "}" newline() _
         { (name, params, returns, stmts) }
 
+    /// A whole source file, which may contain any number of declarations
+    /// and functions.
+    pub rule items() -> Vec<Item>
+        = i:(item()*) blank_lines() _ { i }
+
+    rule item() -> Item
+        = d:declaration() { Item::Declaration(d) }
+        / f:function() { Item::Function(f.0, f.1, f.2, f.3) }
+
+    /// Any number of declarations, without functions.
+    pub rule declarations() -> Vec<Declaration>
+        = d:(declaration()*) blank_lines() _ { d }
+
+    pub rule declaration() -> Declaration
+        = blank_lines() _ "struct" _ name:identifier() _ "{" newline()
+        fields:(_ f:identifier() _ ":" _ t:field_type() _ ","? newline() { (f, t) })*
+        _ "}" newline() _
+        { Declaration::Struct(name, fields) }
+        / blank_lines() _ thread_local:("thread_local" _)? "global" _ name:identifier() _
+        "=" _ value:global_value() _ newline() _
+        { Declaration::Global(name, value, thread_local.is_some()) }
+        / blank_lines() _ "const" _ name:identifier() _ "=" _
+        "[" values:((_ e:table_entry() _ {e}) ** ",") "]" _ newline() _
+        { Declaration::ConstTable(name, values) }
+        / blank_lines() _ "const" _ name:identifier() _ "=" _ value:const_expr() _
+        newline() _
+        { Declaration::Const(name, value) }
+        / blank_lines() _ "extern" _ abi:(a:abi() _ {a})? "fn" _ name:identifier() _
+        "(" params:((_ p:extern_param() _ {p}) ** ",") variadic:("," _ "..." _)? ")" _
+        returns:("->" _ t:field_type() _ {t})? ";" newline() _ {?
+            // Variadic functions are called through a shim which only
+            // returns integers.
+            if variadic.is_some() && matches!(returns, Some(FieldType::F32 | FieldType::F64)) {
+                Err("an integer return type for a variadic function")
+            } else {
+                Ok(Declaration::Extern(ExternFunction {
+                    name,
+                    abi,
+                    params,
+                    variadic: variadic.is_some(),
+                    returns,
+                }))
+            }
+        }
+
+    rule global_value() -> i64
+        = n:$("-"? ['0'..='9']+) {? n.parse().or(Err("64-bit integer literal")) }
+
+    rule const_expr() -> ConstExpr = precedence!{
+        a:@ _ "<<" _ b:(@) { ConstExpr::Shl(Box::new(a), Box::new(b)) }
+        a:@ _ ">>" _ b:(@) { ConstExpr::Shr(Box::new(a), Box::new(b)) }
+        --
+        a:@ _ "+" _ b:(@) { ConstExpr::Add(Box::new(a), Box::new(b)) }
+        a:@ _ "-" _ b:(@) { ConstExpr::Sub(Box::new(a), Box::new(b)) }
+        --
+        a:@ _ "*" _ b:(@) { ConstExpr::Mul(Box::new(a), Box::new(b)) }
+        a:@ _ "/" _ b:(@) { ConstExpr::Div(Box::new(a), Box::new(b)) }
+        --
+        "-" _ e:@ { ConstExpr::Neg(Box::new(e)) }
+        --
+        n:const_literal() { ConstExpr::Literal(n) }
+        i:identifier() { ConstExpr::Name(i) }
+        "(" _ e:const_expr() _ ")" { e }
+    }
+
+    rule table_entry() -> TableEntry
+        = "&" i:identifier() { TableEntry::Address(i) }
+        / "\"" s:string_char()* "\"" { TableEntry::Str(s.into_iter().collect()) }
+        / e:const_expr() { TableEntry::Value(e) }
+
+    rule const_literal() -> i64
+        = n:$(['0'..='9']+) {? n.parse().or(Err("64-bit integer literal")) }
+
+    rule abi() -> String
+        = "\"" a:$("C" / "system") "\"" { a.to_owned() }
+        / expected!("\"C\" or \"system\"")
+
+    rule extern_param() -> (String, Option<FieldType>)
+        = i:identifier() _ t:(":" _ t:field_type() {t})? { (i, t) }
+
+    rule field_type() -> FieldType
+        = t:$(identifier()) {?
+            match t {
+                "i8" => Ok(FieldType::I8),
+                "i16" => Ok(FieldType::I16),
+                "i32" => Ok(FieldType::I32),
+                "i64" => Ok(FieldType::I64),
+                "u8" => Ok(FieldType::U8),
+                "u16" => Ok(FieldType::U16),
+                "u32" => Ok(FieldType::U32),
+                "u64" => Ok(FieldType::U64),
+                "f32" => Ok(FieldType::F32),
+                "f64" => Ok(FieldType::F64),
+                "ptr" => Ok(FieldType::Ptr),
+                _ => Err("field type"),
+            }
+        }
+
     rule statements() -> Vec<Expr>
         = s:(statement()*) { s }
 
     rule statement() -> Expr
-        = _ e:expression() _ "\n" { e }
+        = _ e:expression() newline() { e }
 
     rule expression() -> Expr
         = if_else()
         / while_loop()
+        / closure()
         / assignment()
         / binary_op()
 
+    rule closure() -> Expr
+        = "|" params:((_ i:identifier() _ {i}) ** ",") "|" _ e:expression()
+        { Expr::Closure(params, Box::new(e)) }
+
     rule if_else() -> Expr
-        = "if" _ e:expression() _ "{" _ "\n"
-        then_body:statements() _ "}" _ "else" _ "{" _ "\n"
+        = "if" _ e:expression() _ "{" newline()
+        then_body:statements() _ "}" _ "else" _ "{" newline()
         else_body:statements() _ "}"
         { Expr::IfElse(Box::new(e), then_body, else_body) }
 
     rule while_loop() -> Expr
-        = "while" _ e:expression() _ "{" _ "\n"
+        = "while" _ e:expression() _ "{" newline()
         loop_body:statements() _ "}"
         { Expr::WhileLoop(Box::new(e), loop_body) }
 
     rule assignment() -> Expr
-        = i:identifier() _ "=" _ e:expression() {Expr::Assign(i, Box::new(e))}
+        = names:(identifier() **<2,> (_ "," _)) _ "=" _
+        f:identifier() _ "(" args:((_ e:expression() _ {e}) ** ",") ")" {
+            Expr::Destructure(names, f, args)
+        }
+        / i:identifier() _ "=" _ e:expression() {Expr::Assign(i, Box::new(e))}
+        / i:identifier() _ ":" _ s:identifier() { Expr::Annotate(i, s) }
+        / i:identifier() "." f:identifier() _ "=" _ e:expression() {
+            Expr::AssignField(i, f, Box::new(e))
+        }
+        / i:identifier() _ "[" _ index:expression() _ "]" _ "=" _ e:expression() {
+            Expr::AssignIndex(i, Box::new(index), Box::new(e))
+        }
 
     rule binary_op() -> Expr = precedence!{
         a:@ _ "==" _ b:(@) { Expr::Eq(Box::new(a), Box::new(b)) }
@@ -70,18 +575,80 @@ peg::parser!(pub grammar parser() for str {
         a:@ _ "*" _ b:(@) { Expr::Mul(Box::new(a), Box::new(b)) }
         a:@ _ "/" _ b:(@) { Expr::Div(Box::new(a), Box::new(b)) }
         --
-        i:identifier() _ "(" args:((_ e:expression() _ {e}) ** ",") ")" { Expr::Call(i, args) }
+        "array" _ "(" _ e:expression() _ ")" { Expr::Array(Box::new(e)) }
+        "len" _ "(" _ e:expression() _ ")" { Expr::Len(Box::new(e)) }
+        "load" w:width() _ "(" _ e:expression() _ ")" { Expr::Load(w, Box::new(e)) }
+        "sload" w:narrow_width() _ "(" _ e:expression() _ ")" { Expr::SignedLoad(w, Box::new(e)) }
+        "store" w:width() _ "(" _ p:expression() _ "," _ e:expression() _ ")" {
+            Expr::Store(w, Box::new(p), Box::new(e))
+        }
+        "f64" _ "(" _ e:expression() _ ")" { Expr::Float(Box::new(e)) }
+        c:call() { c }
+        i:identifier() _ "[" _ e:expression() _ "]" { Expr::Index(i, Box::new(e)) }
+        i:identifier() "." f:identifier() { Expr::Field(i, f) }
         i:identifier() { Expr::Identifier(i) }
         l:literal() { l }
+        "(" _ e:expression() _ ")" { e }
     }
 
+    rule width() -> Width
+        = w:narrow_width() { w }
+        / "64" { Width::W64 }
+
+    /// The widths which are narrower than the 64-bit values, and so can be
+    /// sign-extended.
+    rule narrow_width() -> Width
+        = "8" { Width::W8 }
+        / "16" { Width::W16 }
+        / "32" { Width::W32 }
+
+    rule call() -> Expr
+        = i:identifier() _ "(" args:((_ e:expression() _ {e}) ** ",") ")" {?
+            match RUNTIME_FUNCTIONS.iter().find(|(name, _)| *name == i) {
+                Some((_, 1)) if args.len() != 1 => Err("1 argument"),
+                Some((_, 2)) if args.len() != 2 => Err("2 arguments"),
+                _ => Ok(Expr::Call(i, args)),
+            }
+        }
+
     rule identifier() -> String
         = quiet!{ n:$(['a'..='z' | 'A'..='Z' | '_']['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) { n.to_owned() } }
         / expected!("identifier")
 
     rule literal() -> Expr
-        = n:$(['0'..='9']+) { Expr::Literal(n.to_owned()) }
+        = n:$(['0'..='9']+) {?
+            // Literals are translated into 32-bit immediates.
+            match n.parse::<i32>() {
+                Ok(_) => Ok(Expr::Literal(n.to_owned())),
+                Err(_) => Err("32-bit integer literal"),
+            }
+        }
         / "&" i:identifier() { Expr::GlobalDataAddr(i) }
+        / "\"" s:string_char()* "\"" { Expr::Str(s.into_iter().collect()) }
+
+    /// A character of a string literal. Strings are NUL-terminated, and toy
+    /// source outside of comments is ASCII, so other characters have to be
+    /// escaped, as `\x01` to `\x7f`.
+    rule string_char() -> char
+        = c:$([' '..='!' | '#'..='[' | ']'..='~']) { c.chars().next().unwrap() }
+        / "\\n" { '\n' }
+        / "\\t" { '\t' }
+        / "\\r" { '\r' }
+        / "\\\\" { '\\' }
+        / "\\\"" { '"' }
+        / "\\x" h:$(['0'..='9' | 'a'..='f' | 'A'..='F']*<2>) {?
+            match u8::from_str_radix(h, 16) {
+                Ok(byte @ 1..=0x7f) => Ok(byte as char),
+                _ => Err("escape from \\x01 to \\x7f"),
+            }
+        }
+
+    /// The end of a line, followed by any number of blank lines.
+    rule newline() = quiet!{_ "\n" blank_lines()}
+
+    rule blank_lines() = quiet!{(_ "\n")*}
+
+    rule comment() = "//" (!"\n" [_])*
 
-    rule _() =  quiet!{[' ' | '\t']*}
+    rule _() =  quiet!{([' ' | '\t'] / comment())*}
 });
@@ -0,0 +1,181 @@
+use crate::jit::CompiledFunction;
+use cranelift::codegen::binemit::StackMap;
+use cranelift_jit::JITModule;
+use cranelift_module::FuncId;
+use std::collections::HashMap;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+
+/// The least number of bytes allocated between two collections, so that
+/// small heaps aren't collected all the time.
+const MIN_THRESHOLD: usize = 1 << 20;
+
+/// The stack maps of the JIT'd toy functions compiled with garbage
+/// collection enabled.
+///
+/// With garbage collection, the toy functions keep their values as
+/// references, which Cranelift tracks across calls: for each call at which
+/// some are live, it emits a stack map telling which words of the frame hold
+/// them.
+#[derive(Default)]
+pub(crate) struct StackMaps {
+    /// The stack maps of the functions compiled since the last time they
+    /// were registered, by the offset of the return address of the calls.
+    pending: Vec<(FuncId, Vec<(u32, StackMap)>)>,
+}
+
+impl StackMaps {
+    pub(crate) fn add_function(&mut self, function: &CompiledFunction) {
+        let stack_maps = function
+            .code
+            .buffer
+            .stack_maps()
+            .iter()
+            .map(|map| (map.offset_end, map.stack_map.clone()))
+            .collect();
+        self.pending.push((function.id, stack_maps));
+    }
+
+    /// Hand the stack maps of the functions compiled since the last time to
+    /// the collector, now that they're finalized.
+    pub(crate) fn register(&mut self, module: &JITModule, collector: &mut Collector) {
+        for (id, stack_maps) in self.pending.drain(..) {
+            let address = module.get_finalized_function(id) as usize;
+            for (offset, stack_map) in stack_maps {
+                collector
+                    .stack_maps
+                    .insert(address + offset as usize, stack_map);
+            }
+        }
+    }
+}
+
+/// The state of the collector of a heap.
+///
+/// Collections happen when enough was allocated since the last one, and
+/// allocations only happen in calls to the runtime, so the call sites are
+/// the only points at which toy code can be stopped for a collection. Loop
+/// back-edges don't need to be: a loop without calls can't allocate.
+///
+/// The roots are the references in the frames of the toy functions on the
+/// stack of the thread collecting, and the values the host registered with
+/// `add_root`. So toy code sharing a heap mustn't run on several threads at
+/// once, and the host has to root what it keeps of the values toy code
+/// returns across calls into toy code.
+#[derive(Default)]
+pub(crate) struct Collector {
+    /// The stack maps of the calls in the toy functions, by the address the
+    /// calls return to.
+    stack_maps: HashMap<usize, StackMap>,
+
+    /// The values the host rooted, with how many times.
+    roots: HashMap<isize, usize>,
+
+    /// The bytes allocated since the last collection, and how many may be
+    /// before the next one.
+    pub(crate) allocated: usize,
+    pub(crate) threshold: usize,
+
+    pub(crate) collections: usize,
+}
+
+impl Collector {
+    pub(crate) fn add_root(&mut self, value: isize) {
+        *self.roots.entry(value).or_insert(0) += 1;
+    }
+
+    pub(crate) fn remove_root(&mut self, value: isize) {
+        if let Some(count) = self.roots.get_mut(&value) {
+            *count -= 1;
+            if *count == 0 {
+                self.roots.remove(&value);
+            }
+        }
+    }
+
+    /// Return whether a collection is due, having allocated `bytes` more.
+    pub(crate) fn allocate(&mut self, bytes: usize) -> bool {
+        self.allocated += bytes;
+        self.allocated > self.threshold.max(MIN_THRESHOLD)
+    }
+
+    /// Return the roots: the values the host rooted, and those the toy
+    /// functions on the stack hold in their frames, which may or may not be
+    /// objects on the heap.
+    pub(crate) fn roots(&self) -> Vec<isize> {
+        let mut roots: Vec<isize> = self.roots.keys().copied().collect();
+        let mut walk = StackWalk {
+            stack_maps: &self.stack_maps,
+            roots: &mut roots,
+        };
+        unsafe { _Unwind_Backtrace(visit_frame, &mut walk as *mut StackWalk as *mut c_void) };
+        roots
+    }
+}
+
+struct StackWalk<'a> {
+    stack_maps: &'a HashMap<usize, StackMap>,
+    roots: &'a mut Vec<isize>,
+}
+
+/// Add the references in a frame to the roots, if it's the frame of a toy
+/// function stopped at a call with a stack map.
+extern "C" fn visit_frame(context: *mut UnwindContext, walk: *mut c_void) -> c_int {
+    let walk = unsafe { &mut *(walk as *mut StackWalk) };
+    let return_address = unsafe { _Unwind_GetIP(context) };
+    if let Some(stack_map) = walk.stack_maps.get(&return_address) {
+        // The stack map covers the words from the stack pointer at the call
+        // up. The canonical frame address the unwinder gives is that of the
+        // frame called, which is where the stack pointer is once it returns:
+        // the same as at the call.
+        let word = mem::size_of::<usize>();
+        let stack_pointer = unsafe { _Unwind_GetCFA(context) };
+        for i in 0..stack_map.mapped_words() as usize {
+            if stack_map.get_bit(i) {
+                let slot = (stack_pointer + i * word) as *const isize;
+                walk.roots.push(unsafe { *slot });
+            }
+        }
+    }
+    URC_NO_REASON
+}
+
+/// Whether the stack of toy functions can be walked on this platform, which
+/// takes the DWARF unwinder the unwind info is registered with.
+pub(crate) fn supported() -> bool {
+    cfg!(unix)
+}
+
+#[repr(C)]
+struct UnwindContext {
+    _private: [u8; 0],
+}
+
+const URC_NO_REASON: c_int = 0;
+
+#[cfg(unix)]
+extern "C" {
+    fn _Unwind_Backtrace(
+        trace: extern "C" fn(*mut UnwindContext, *mut c_void) -> c_int,
+        data: *mut c_void,
+    ) -> c_int;
+    fn _Unwind_GetIP(context: *mut UnwindContext) -> usize;
+    fn _Unwind_GetCFA(context: *mut UnwindContext) -> usize;
+}
+
+// Elsewhere, garbage collection isn't supported.
+#[cfg(not(unix))]
+unsafe fn _Unwind_Backtrace(
+    _trace: extern "C" fn(*mut UnwindContext, *mut c_void) -> c_int,
+    _data: *mut c_void,
+) -> c_int {
+    0
+}
+#[cfg(not(unix))]
+unsafe fn _Unwind_GetIP(_context: *mut UnwindContext) -> usize {
+    0
+}
+#[cfg(not(unix))]
+unsafe fn _Unwind_GetCFA(_context: *mut UnwindContext) -> usize {
+    0
+}
@@ -0,0 +1,995 @@
+use crate::closure;
+use crate::frontend::*;
+use crate::jit::DataPointer;
+use crate::layout::{self, StructLayout, Structs};
+use crate::runtime::{self, Heap, ToyGlobal, Trap};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// A function provided by the host, callable from interpreted toy code.
+pub type HostFunction = Box<dyn Fn(&[isize]) -> isize>;
+
+/// A toy function's replacement. See `Interpreter::replace_function`.
+type Replacement = Rc<dyn Fn(&[isize]) -> isize>;
+
+/// A hook which is told whenever the counts of an interpreted function
+/// change. See `Interpreter::set_profile_hook`.
+pub type ProfileHook = Box<dyn Fn(&Interpreter, &str, Counts)>;
+
+/// How often an interpreted function has been executed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counts {
+    /// The number of times the function has been called.
+    pub calls: u64,
+
+    /// The number of loop iterations the function has executed, that is,
+    /// the number of times it has taken the back-edge of a loop.
+    pub back_edges: u64,
+}
+
+/// A parsed toy function, ready to be evaluated.
+struct Function {
+    params: Vec<String>,
+    returns: Vec<String>,
+    stmts: Vec<Expr>,
+
+    /// The structs the variables declared as struct pointers point to.
+    types: HashMap<String, StructLayout>,
+
+    /// Whether this is the code of a closure, which takes the closure as an
+    /// extra first parameter.
+    closure: bool,
+
+    counts: Cell<Counts>,
+}
+
+/// The basic interpreter class.
+///
+/// This evaluates the toy-language AST directly, without generating any
+/// machine code. It mirrors the semantics of the JIT's `FunctionTranslator`,
+/// so it can be used where Cranelift isn't available, to avoid compile times
+/// for code that only runs once, or as a reference to test the JIT against.
+#[derive(Default)]
+pub struct Interpreter {
+    /// The toy functions defined so far, by name. They're boxed, as `&name`
+    /// evaluates to their address, which calls through it look up.
+    functions: HashMap<String, Box<Function>>,
+
+    /// Functions provided by the host. These are consulted when a call
+    /// doesn't name a toy function, like the JIT falls back to looking up
+    /// symbols in the host process.
+    host_functions: HashMap<String, HostFunction>,
+
+    /// The data objects, which `&name` evaluates to the address of.
+    data: HashMap<String, Box<[u8]>>,
+
+    /// The names of the global variables, whose values are kept in the data
+    /// objects of the same names.
+    globals: HashSet<String>,
+
+    /// The values of the constants declared so far.
+    consts: HashMap<String, i64>,
+
+    /// The names of the constant tables, which are kept in the data objects
+    /// of the same names.
+    tables: HashSet<String>,
+
+    /// The heap arrays and strings are allocated on. They have the same
+    /// layout as the JIT's, so they can be passed to compiled code and back.
+    heap: Heap,
+
+    /// The structs declared so far, laid out for the host.
+    structs: Structs,
+
+    /// The C functions declared so far, which are called as host functions.
+    externs: HashMap<String, ExternFunction>,
+
+    /// The string literals evaluated so far, NUL-terminated, by their
+    /// contents.
+    strings: RefCell<HashMap<String, Box<[u8]>>>,
+
+    /// Toy functions which have been replaced, typically with compiled code,
+    /// and aren't interpreted anymore.
+    replacements: RefCell<HashMap<String, Replacement>>,
+
+    /// The hook told about function calls and loop iterations, if any.
+    profile_hook: Option<ProfileHook>,
+}
+
+impl Interpreter {
+    /// Parse a string in the toy language and make the function available
+    /// to be called. Returns the name of the function.
+    pub fn define(&mut self, input: &str) -> Result<String, String> {
+        let function = parser::function(input).map_err(|e| e.to_string())?;
+        let name = function.0.clone();
+        if self.functions.contains_key(&name) {
+            return Err(format!("duplicate definition of function `{}`", name));
+        }
+
+        // Like the JIT, lift the closures out into functions of their own.
+        let mut functions = Vec::new();
+        let globals = &self.globals;
+        let is_global = |name: &str| globals.contains(name);
+        for (i, (name, params, returns, stmts)) in
+            closure::lift(function, &is_global).into_iter().enumerate()
+        {
+            let types = self
+                .structs
+                .variable_types(&stmts)?
+                .into_iter()
+                .map(|(variable, layout)| (variable, layout.clone()))
+                .collect();
+            let mut assigned = Vec::new();
+            for expr in &stmts {
+                assigned_names(&mut assigned, expr);
+            }
+            for name in assigned {
+                let shadowed = params
+                    .iter()
+                    .chain(&returns)
+                    .any(|variable| variable == name);
+                if !shadowed && (self.consts.contains_key(name) || self.tables.contains(name)) {
+                    return Err(format!("cannot assign to constant `{}`", name));
+                }
+            }
+            let function = Function {
+                params,
+                returns,
+                stmts,
+                types,
+                closure: i > 0,
+                counts: Cell::new(Counts::default()),
+            };
+            functions.push((name, Box::new(function)));
+        }
+        self.functions.extend(functions);
+        Ok(name)
+    }
+
+    /// Parse declarations, like `JIT::declare`.
+    pub fn declare(&mut self, input: &str) -> Result<(), String> {
+        let declarations = parser::declarations(input).map_err(|e| e.to_string())?;
+        for declaration in declarations {
+            match declaration {
+                Declaration::Struct(name, fields) => {
+                    self.structs
+                        .declare(&name, &fields, layout::host_config())?;
+                }
+                Declaration::Extern(function) => {
+                    if self.externs.contains_key(&function.name) {
+                        return Err(format!(
+                            "duplicate declaration of function `{}`",
+                            function.name
+                        ));
+                    }
+                    self.externs.insert(function.name.clone(), function);
+                }
+                // The interpreter only ever runs on the thread which created
+                // it, so thread-local globals are like the others.
+                Declaration::Global(name, value, _) => {
+                    if self.data.contains_key(&name) {
+                        return Err(format!("duplicate declaration of global `{}`", name));
+                    }
+                    let contents = (value as isize).to_ne_bytes().to_vec();
+                    let address = self.create_data(&name, contents)?.as_ptr();
+                    self.heap.add_global(address as *const isize);
+                    self.globals.insert(name);
+                }
+                Declaration::Const(name, value) => {
+                    if self.consts.contains_key(&name) || self.tables.contains(&name) {
+                        return Err(format!("duplicate declaration of constant `{}`", name));
+                    }
+                    let value = value.eval(&self.consts)?;
+                    self.consts.insert(name, value);
+                }
+                Declaration::ConstTable(name, values) => {
+                    if self.consts.contains_key(&name) || self.tables.contains(&name) {
+                        return Err(format!("duplicate declaration of constant `{}`", name));
+                    }
+                    let mut contents = (values.len() as isize).to_ne_bytes().to_vec();
+                    for value in values {
+                        let value = match value {
+                            TableEntry::Value(value) => value.eval(&self.consts)? as isize,
+                            TableEntry::Address(name) => {
+                                let pointer = if self.functions.contains_key(&name) {
+                                    DataPointer::Function(name)
+                                } else {
+                                    DataPointer::Data(name)
+                                };
+                                self.pointer_address(&pointer)?
+                            }
+                            TableEntry::Str(string) => self.intern_string(&string),
+                        };
+                        contents.extend_from_slice(&value.to_ne_bytes());
+                    }
+                    let table = self.create_data(&name, contents)?.as_ptr();
+                    self.heap.add_table(table as *const isize);
+                    self.tables.insert(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the layout of the struct `name`, like `JIT::struct_layout`.
+    pub fn struct_layout(&self, name: &str) -> Option<&StructLayout> {
+        self.structs.get(name)
+    }
+
+    /// Make a host function available to interpreted code under `name`.
+    pub fn register_host_function<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[isize]) -> isize + 'static,
+    {
+        self.host_functions
+            .insert(name.to_owned(), Box::new(function));
+    }
+
+    /// Replace the toy function `name` with `function`, which is called
+    /// instead of interpreting the function from now on. This takes effect
+    /// from the next call on; calls which are already executing continue to
+    /// be interpreted.
+    pub fn replace_function<F>(&self, name: &str, function: F)
+    where
+        F: Fn(&[isize]) -> isize + 'static,
+    {
+        self.replacements
+            .borrow_mut()
+            .insert(name.to_owned(), Rc::new(function));
+    }
+
+    /// Set the hook which is called on every call to a toy function and on
+    /// every iteration of a loop within one, with the updated counts of the
+    /// function.
+    pub fn set_profile_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&Interpreter, &str, Counts) + 'static,
+    {
+        self.profile_hook = Some(Box::new(hook));
+    }
+
+    /// Return how often the toy function `name` has been interpreted.
+    pub fn counts(&self, name: &str) -> Option<Counts> {
+        self.functions
+            .get(name)
+            .map(|function| function.counts.get())
+    }
+
+    /// Return a handle to the global variable `name`, like `JIT::global`.
+    pub fn global(&self, name: &str) -> Option<ToyGlobal<'_>> {
+        let address = self.global_address(name)?;
+        Some(unsafe { ToyGlobal::new(address) })
+    }
+
+    /// Return whether `name` is a global variable.
+    pub(crate) fn is_global(&self, name: &str) -> bool {
+        self.globals.contains(name)
+    }
+
+    /// Return the address of the global variable `name`, if it's declared.
+    fn global_address(&self, name: &str) -> Option<*mut isize> {
+        if !self.globals.contains(name) {
+            return None;
+        }
+        Some(self.data[name].as_ptr() as *mut isize)
+    }
+
+    /// Create a data object, like `JIT::create_data`.
+    pub fn create_data(&mut self, name: &str, contents: Vec<u8>) -> Result<&[u8], String> {
+        if self.data.contains_key(name) {
+            return Err(format!("duplicate definition of data object `{}`", name));
+        }
+        let data = self
+            .data
+            .entry(name.to_owned())
+            .or_insert(contents.into_boxed_slice());
+        Ok(data)
+    }
+
+    /// Create a data object with pointers to functions and other data
+    /// objects, like `JIT::create_data_with_pointers`. Those to functions are
+    /// only references they can be called through.
+    pub fn create_data_with_pointers(
+        &mut self,
+        name: &str,
+        mut contents: Vec<u8>,
+        pointers: &[(usize, DataPointer)],
+    ) -> Result<&[u8], String> {
+        let size = std::mem::size_of::<isize>();
+        for (offset, pointer) in pointers {
+            if offset
+                .checked_add(size)
+                .is_none_or(|end| end > contents.len())
+            {
+                return Err(format!(
+                    "pointer at offset {} is out of the bounds of data object `{}`",
+                    offset, name
+                ));
+            }
+            let address = self.pointer_address(pointer)?;
+            contents[*offset..offset + size].copy_from_slice(&address.to_ne_bytes());
+        }
+        self.create_data(name, contents)
+    }
+
+    /// Return the address `pointer` resolves to in a data object.
+    fn pointer_address(&self, pointer: &DataPointer) -> Result<isize, String> {
+        match pointer {
+            DataPointer::Function(name) => match self.functions.get(name) {
+                Some(function) => Ok(function_address(function)),
+                None => Err(format!("function `{}` not defined", name)),
+            },
+            DataPointer::Data(name) => match self.data.get(name) {
+                Some(data) => Ok(data.as_ptr() as isize),
+                None => Err(format!("data object `{}` not defined", name)),
+            },
+        }
+    }
+
+    /// Return the address of a NUL-terminated copy of `string`, which is the
+    /// same for each string with these contents, like in compiled code.
+    fn intern_string(&self, string: &str) -> isize {
+        let mut strings = self.strings.borrow_mut();
+        let contents = strings.entry(string.to_owned()).or_insert_with(|| {
+            let mut contents = string.as_bytes().to_vec();
+            contents.push(0);
+            contents.into_boxed_slice()
+        });
+        contents.as_ptr() as isize
+    }
+
+    /// Call the function named `name`, which may be either a toy function or
+    /// a host function, with the given arguments.
+    pub fn call(&self, name: &str, args: &[isize]) -> Result<isize, String> {
+        let results = self.call_results(name, args)?;
+        check_results(name, results.len(), 1)?;
+        Ok(results[0])
+    }
+
+    /// Call the function named `name` like `call`, returning all its return
+    /// values.
+    pub fn call_results(&self, name: &str, args: &[isize]) -> Result<Vec<isize>, String> {
+        if let Some(function) = self.functions.get(name) {
+            if function.params.len() != args.len() {
+                return Err(format!(
+                    "function `{}` takes {} arguments but {} were supplied",
+                    name,
+                    function.params.len(),
+                    args.len()
+                ));
+            }
+            // Clone the replacement out of the table, so that it may replace
+            // other functions itself.
+            let replacement = self.replacements.borrow().get(name).cloned();
+            match replacement {
+                Some(replacement) => Ok(vec![replacement(args)]),
+                None => self.call_toy_function(name, function, args),
+            }
+        } else if let Some(function) = self.host_functions.get(name) {
+            Ok(vec![function(args)])
+        } else {
+            Err(format!("function `{}` not defined", name))
+        }
+    }
+
+    fn call_toy_function(
+        &self,
+        name: &str,
+        function: &Function,
+        args: &[isize],
+    ) -> Result<Vec<isize>, String> {
+        let mut counts = function.counts.get();
+        counts.calls += 1;
+        self.update_counts(name, function, counts);
+
+        // The toy language allows variables to be declared implicitly. Like
+        // the JIT, walk the AST up front to find them all, and give them
+        // their initial values.
+        let mut variables = HashMap::new();
+        for (name, &value) in function.params.iter().zip(args) {
+            variables.insert(name.clone(), value);
+        }
+        for name in &function.returns {
+            variables.insert(name.clone(), 0);
+        }
+        for expr in &function.stmts {
+            declare_variables_in_stmt(&self.globals, &mut variables, expr);
+        }
+
+        let mut eval = FunctionEvaluator {
+            name,
+            function,
+            variables,
+            interp: self,
+        };
+        for expr in &function.stmts {
+            eval.eval_expr(expr)?;
+        }
+
+        // The return values are whatever the return variables hold when the
+        // function exits.
+        Ok(function
+            .returns
+            .iter()
+            .map(|name| eval.variables[name])
+            .collect())
+    }
+
+    fn update_counts(&self, name: &str, function: &Function, counts: Counts) {
+        function.counts.set(counts);
+        if let Some(hook) = &self.profile_hook {
+            hook(self, name, counts);
+        }
+    }
+}
+
+/// A collection of state used for evaluating toy-language AST nodes. This
+/// is the interpreter's counterpart of the JIT's `FunctionTranslator`.
+struct FunctionEvaluator<'a> {
+    name: &'a str,
+    function: &'a Function,
+    variables: HashMap<String, isize>,
+    interp: &'a Interpreter,
+}
+
+impl<'a> FunctionEvaluator<'a> {
+    /// Evaluate an expression, producing the same value the code generated
+    /// by the JIT would.
+    fn eval_expr(&mut self, expr: &Expr) -> Result<isize, String> {
+        match expr {
+            Expr::Literal(literal) => {
+                // The JIT only supports 32-bit immediates.
+                let imm: i32 = literal
+                    .parse()
+                    .map_err(|_| format!("invalid literal `{}`", literal))?;
+                Ok(imm as isize)
+            }
+
+            Expr::Add(lhs, rhs) => {
+                let lhs = self.eval_expr(lhs)?;
+                let rhs = self.eval_expr(rhs)?;
+                Ok(lhs.wrapping_add(rhs))
+            }
+
+            Expr::Sub(lhs, rhs) => {
+                let lhs = self.eval_expr(lhs)?;
+                let rhs = self.eval_expr(rhs)?;
+                Ok(lhs.wrapping_sub(rhs))
+            }
+
+            Expr::Mul(lhs, rhs) => {
+                let lhs = self.eval_expr(lhs)?;
+                let rhs = self.eval_expr(rhs)?;
+                Ok(lhs.wrapping_mul(rhs))
+            }
+
+            Expr::Div(lhs, rhs) => {
+                // The JIT uses `udiv`, so division is unsigned.
+                let lhs = self.eval_expr(lhs)? as usize;
+                let rhs = self.eval_expr(rhs)? as usize;
+                if rhs == 0 {
+                    return Err(Trap::DivisionByZero.to_string());
+                }
+                Ok((lhs / rhs) as isize)
+            }
+
+            Expr::Eq(lhs, rhs) => self.eval_icmp(|a, b| a == b, lhs, rhs),
+            Expr::Ne(lhs, rhs) => self.eval_icmp(|a, b| a != b, lhs, rhs),
+            Expr::Lt(lhs, rhs) => self.eval_icmp(|a, b| a < b, lhs, rhs),
+            Expr::Le(lhs, rhs) => self.eval_icmp(|a, b| a <= b, lhs, rhs),
+            Expr::Gt(lhs, rhs) => self.eval_icmp(|a, b| a > b, lhs, rhs),
+            Expr::Ge(lhs, rhs) => self.eval_icmp(|a, b| a >= b, lhs, rhs),
+            Expr::Call(name, args) => self.eval_call(name, args),
+            Expr::GlobalDataAddr(name) => self.eval_global_data_addr(name),
+            Expr::MakeClosure(name, captures) => self.eval_make_closure(name, captures),
+            Expr::Closure(..) => unreachable!("closures are lifted out before evaluation"),
+            Expr::Float(_) => Err("`f64(..)` can only be passed as a variadic argument".to_owned()),
+            Expr::Str(string) => Ok(self.eval_string(string)),
+            Expr::Identifier(name) => self.variable(name),
+            Expr::Assign(name, expr) => self.eval_assign(name, expr),
+            Expr::Destructure(names, name, args) => self.eval_destructure(names, name, args),
+            Expr::IfElse(condition, then_body, else_body) => {
+                self.eval_if_else(condition, then_body, else_body)
+            }
+            Expr::WhileLoop(condition, loop_body) => self.eval_while_loop(condition, loop_body),
+            Expr::Array(len) => {
+                let len = self.eval_expr(len)?;
+                self.interp.heap.allocate(len).map_err(|e| e.to_string())
+            }
+            Expr::Len(array) => {
+                let array = self.eval_expr(array)?;
+                self.interp.heap.array_len(array).map_err(|e| e.to_string())
+            }
+            Expr::Index(name, index) => {
+                let index = self.eval_expr(index)?;
+                let element = self.eval_element(name, index)?;
+                Ok(unsafe { element.read_unaligned() })
+            }
+            Expr::AssignIndex(name, index, expr) => {
+                let index = self.eval_expr(index)?;
+                let new_value = self.eval_expr(expr)?;
+                let element = self.eval_element(name, index)?;
+                unsafe { element.write_unaligned(new_value) };
+                Ok(new_value)
+            }
+            Expr::Load(width, pointer) => {
+                let pointer = self.eval_expr(pointer)?;
+                Ok(unsafe { load(*width, false, pointer) })
+            }
+            Expr::SignedLoad(width, pointer) => {
+                let pointer = self.eval_expr(pointer)?;
+                Ok(unsafe { load(*width, true, pointer) })
+            }
+            Expr::Store(width, pointer, expr) => {
+                let pointer = self.eval_expr(pointer)?;
+                let new_value = self.eval_expr(expr)?;
+                unsafe { store(*width, pointer, new_value) };
+                Ok(new_value)
+            }
+            Expr::Annotate(..) => Ok(0),
+            Expr::Field(name, field) => {
+                let (ty, pointer) = self.eval_field(name, field);
+                Ok(unsafe { read_field(ty, pointer) })
+            }
+            Expr::AssignField(name, field, expr) => {
+                let new_value = self.eval_expr(expr)?;
+                let (ty, pointer) = self.eval_field(name, field);
+                unsafe { write_field(ty, pointer, new_value) };
+                Ok(new_value)
+            }
+        }
+    }
+
+    /// Return the type and the address of the field `field` of the struct
+    /// the variable `name` points to.
+    fn eval_field(&self, name: &str, field: &str) -> (FieldType, isize) {
+        let layout = &self.function.types[name];
+        let field = layout.field(field).expect("field not checked");
+        (field.ty, self.variables[name] + field.offset as isize)
+    }
+
+    /// Return the address of an element of the array in the variable
+    /// `name`, checking its bounds like the JIT does.
+    fn eval_element(&mut self, name: &str, index: isize) -> Result<*mut isize, String> {
+        let array = self.variable(name)?;
+        self.interp
+            .heap
+            .array_element(array, index)
+            .map_err(|e| e.to_string())
+    }
+
+    fn eval_assign(&mut self, name: &str, expr: &Expr) -> Result<isize, String> {
+        let new_value = self.eval_expr(expr)?;
+        self.assign(name, new_value)?;
+        Ok(new_value)
+    }
+
+    /// Return the value of the variable `name`, or of the constant or the
+    /// global variable if there's no such variable.
+    fn variable(&self, name: &str) -> Result<isize, String> {
+        if let Some(&value) = self.variables.get(name) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.interp.consts.get(name) {
+            return Ok(value as isize);
+        }
+        if self.interp.tables.contains(name) {
+            return Ok(self.interp.data[name].as_ptr() as isize);
+        }
+        match self.interp.global_address(name) {
+            Some(address) => Ok(unsafe { address.read_unaligned() }),
+            None => Err(format!("variable `{}` not defined", name)),
+        }
+    }
+
+    /// Assign `new_value` to the variable `name`, or to the global variable
+    /// if there's no such variable.
+    fn assign(&mut self, name: &str, new_value: isize) -> Result<(), String> {
+        if let Some(variable) = self.variables.get_mut(name) {
+            *variable = new_value;
+            return Ok(());
+        }
+        match self.interp.global_address(name) {
+            Some(address) => unsafe { address.write_unaligned(new_value) },
+            None => return Err(format!("variable `{}` not defined", name)),
+        }
+        Ok(())
+    }
+
+    fn eval_destructure(
+        &mut self,
+        names: &[String],
+        name: &str,
+        args: &[Expr],
+    ) -> Result<isize, String> {
+        let results = self.eval_call_results(name, args, names.len())?;
+        for (name, &value) in names.iter().zip(&results) {
+            self.assign(name, value)?;
+        }
+        Ok(results[0])
+    }
+
+    fn eval_icmp(
+        &mut self,
+        cmp: fn(isize, isize) -> bool,
+        lhs: &Expr,
+        rhs: &Expr,
+    ) -> Result<isize, String> {
+        let lhs = self.eval_expr(lhs)?;
+        let rhs = self.eval_expr(rhs)?;
+        Ok(cmp(lhs, rhs) as isize)
+    }
+
+    fn eval_if_else(
+        &mut self,
+        condition: &Expr,
+        then_body: &[Expr],
+        else_body: &[Expr],
+    ) -> Result<isize, String> {
+        let condition_value = self.eval_expr(condition)?;
+        let body = if condition_value != 0 {
+            then_body
+        } else {
+            else_body
+        };
+
+        // If-else constructs in the toy language have a return value, which
+        // is the value of the last statement of the arm taken, or 0 if it's
+        // empty.
+        let mut value = 0;
+        for expr in body {
+            value = self.eval_expr(expr)?;
+        }
+        Ok(value)
+    }
+
+    fn eval_while_loop(&mut self, condition: &Expr, loop_body: &[Expr]) -> Result<isize, String> {
+        while self.eval_expr(condition)? != 0 {
+            for expr in loop_body {
+                self.eval_expr(expr)?;
+            }
+
+            // We're taking the back-edge to the loop header.
+            let mut counts = self.function.counts.get();
+            counts.back_edges += 1;
+            self.interp.update_counts(self.name, self.function, counts);
+        }
+
+        // Just return 0, like the JIT does.
+        Ok(0)
+    }
+
+    fn eval_call(&mut self, name: &str, args: &[Expr]) -> Result<isize, String> {
+        Ok(self.eval_call_results(name, args, 1)?[0])
+    }
+
+    /// Call the function `name` for `results` return values, which is an
+    /// error if it returns a different number of them, like in compiled
+    /// code. Functions of the runtime and of the host return one.
+    fn eval_call_results(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        results: usize,
+    ) -> Result<Vec<isize>, String> {
+        // The variadic arguments of a C function can be passed as doubles,
+        // but host functions only take integers, so they get the values
+        // unconverted.
+        let interp = self.interp;
+        let runtime = RUNTIME_FUNCTIONS.iter().any(|(n, _)| *n == name);
+        let function = match self.variables.contains_key(name) || runtime {
+            false => interp.externs.get(name),
+            true => None,
+        };
+        let mut fixed = args.len();
+        if let Some(function) = function {
+            check_extern_args(function, args.len())?;
+            if function.variadic {
+                fixed = function.params.len();
+            }
+        }
+        let mut arg_values = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            match arg {
+                Expr::Float(arg) if i >= fixed => arg_values.push(self.eval_expr(arg)?),
+                arg => arg_values.push(self.eval_expr(arg)?),
+            }
+        }
+        if let Some(&callee) = self.variables.get(name) {
+            return self.eval_indirect_call(callee, &arg_values, results);
+        }
+        let returns = self
+            .interp
+            .functions
+            .get(name)
+            .map_or(1, |function| function.returns.len());
+        check_results(name, returns, results)?;
+        if let Some(function) = function {
+            for (arg, &(_, ty)) in arg_values.iter_mut().zip(&function.params) {
+                if let Some(ty) = ty {
+                    *arg = narrow(ty, *arg);
+                }
+            }
+            let result = interp.call(name, &arg_values)?;
+            return Ok(vec![function
+                .returns
+                .map_or(result, |ty| narrow(ty, result))]);
+        }
+        if RUNTIME_FUNCTIONS.iter().any(|(n, _)| *n == name) {
+            let value = unsafe { runtime::call(&self.interp.heap, name, &arg_values) }
+                .map_err(|e| e.to_string())?;
+            return Ok(vec![value]);
+        }
+        self.interp.call_results(name, &arg_values)
+    }
+
+    /// Return the address of a NUL-terminated copy of a string literal,
+    /// which is the same each time it's evaluated, like in compiled code.
+    fn eval_string(&mut self, string: &str) -> isize {
+        self.interp.intern_string(string)
+    }
+
+    /// Call the toy function or the closure `callee` is a reference to,
+    /// checking it like compiled code does.
+    fn eval_indirect_call(
+        &mut self,
+        callee: isize,
+        args: &[isize],
+        results: usize,
+    ) -> Result<Vec<isize>, String> {
+        let find = |address: isize, closure: bool| {
+            self.interp.functions.iter().find(|(_, function)| {
+                function_address(function) == address && function.closure == closure
+            })
+        };
+        let mut args = args.to_vec();
+        let (name, function) = match find(callee, false) {
+            Some(function) => function,
+            None => {
+                // The code of a closure takes the closure first.
+                let code = self.interp.heap.first_element(callee).unwrap_or(0);
+                args.insert(0, callee);
+                find(code, true).ok_or_else(|| Trap::NotAFunction(callee).to_string())?
+            }
+        };
+        if function.params.len() != args.len() {
+            let trap = Trap::SignatureMismatch {
+                params: function.params.len() - usize::from(function.closure),
+                args: args.len() - usize::from(function.closure),
+            };
+            return Err(trap.to_string());
+        }
+        if function.returns.len() != results {
+            let trap = Trap::ResultsMismatch {
+                returns: function.returns.len(),
+                results,
+            };
+            return Err(trap.to_string());
+        }
+        self.interp.call_results(name, &args)
+    }
+
+    /// Create a closure, which has the same layout as in compiled code,
+    /// except that its first element is the address of the interpreter's
+    /// copy of the function it was lifted into.
+    fn eval_make_closure(&mut self, name: &str, captures: &[String]) -> Result<isize, String> {
+        let closure = self
+            .interp
+            .heap
+            .allocate(captures.len() as isize + 1)
+            .map_err(|e| e.to_string())?;
+        let mut values = vec![function_address(&self.interp.functions[name])];
+        for capture in captures {
+            values.push(self.variables[capture]);
+        }
+        for (i, value) in values.into_iter().enumerate() {
+            let element = self.interp.heap.array_element(closure, i as isize).unwrap();
+            unsafe { *element = value };
+        }
+        Ok(closure)
+    }
+
+    /// Return the address of the function or data object `name`. Those of
+    /// functions are only references they can be called through: unlike
+    /// compiled code, they can't be called by the host.
+    fn eval_global_data_addr(&mut self, name: &str) -> Result<isize, String> {
+        if let Some(function) = self.interp.functions.get(name) {
+            return Ok(function_address(function));
+        }
+        match self.interp.data.get(name) {
+            Some(data) => Ok(data.as_ptr() as isize),
+            None => Err(format!("data object `{}` not defined", name)),
+        }
+    }
+}
+
+/// Check that the function `name` returns as many values as expected.
+fn check_results(name: &str, returns: usize, expected: usize) -> Result<(), String> {
+    if returns != expected {
+        return Err(format!(
+            "function `{}` returns {} values but {} were expected",
+            name, returns, expected
+        ));
+    }
+    Ok(())
+}
+
+/// Check the number of arguments passed to a C function, which takes as many
+/// as its parameters, or at least that many if it's variadic.
+fn check_extern_args(function: &ExternFunction, args: usize) -> Result<(), String> {
+    let params = function.params.len();
+    if function.variadic && args < params {
+        return Err(format!(
+            "function `{}` takes at least {} arguments but {} were supplied",
+            function.name, params, args
+        ));
+    }
+    if !function.variadic && args != params {
+        return Err(format!(
+            "function `{}` takes {} arguments but {} were supplied",
+            function.name, params, args
+        ));
+    }
+    Ok(())
+}
+
+/// Truncate and extend a value passed to or returned by a C function like
+/// its integer type `ty` does in compiled code. Host functions only take
+/// integers, so floating-point values are left as they are.
+fn narrow(ty: FieldType, value: isize) -> isize {
+    match ty {
+        FieldType::I8 => value as i8 as isize,
+        FieldType::I16 => value as i16 as isize,
+        FieldType::I32 => value as i32 as isize,
+        FieldType::U8 => value as u8 as isize,
+        FieldType::U16 => value as u16 as isize,
+        FieldType::U32 => value as u32 as isize,
+        FieldType::I64 | FieldType::U64 => value as i64 as isize,
+        FieldType::F32 | FieldType::F64 | FieldType::Ptr => value,
+    }
+}
+
+/// Return the address of a toy function, which references to it are.
+fn function_address(function: &Function) -> isize {
+    function as *const Function as isize
+}
+
+/// Read an integer of the given width from a raw pointer, like the JIT does.
+/// As there, the pointer may be misaligned, and a bad one crashes.
+unsafe fn load(width: Width, signed: bool, pointer: isize) -> isize {
+    match (width, signed) {
+        (Width::W8, false) => (pointer as *const u8).read_unaligned() as isize,
+        (Width::W8, true) => (pointer as *const i8).read_unaligned() as isize,
+        (Width::W16, false) => (pointer as *const u16).read_unaligned() as isize,
+        (Width::W16, true) => (pointer as *const i16).read_unaligned() as isize,
+        (Width::W32, false) => (pointer as *const u32).read_unaligned() as isize,
+        (Width::W32, true) => (pointer as *const i32).read_unaligned() as isize,
+        (Width::W64, _) => (pointer as *const i64).read_unaligned() as isize,
+    }
+}
+
+unsafe fn store(width: Width, pointer: isize, value: isize) {
+    match width {
+        Width::W8 => (pointer as *mut u8).write_unaligned(value as u8),
+        Width::W16 => (pointer as *mut u16).write_unaligned(value as u16),
+        Width::W32 => (pointer as *mut u32).write_unaligned(value as u32),
+        Width::W64 => (pointer as *mut i64).write_unaligned(value as i64),
+    }
+}
+
+/// Read a field, converting it to a value like the JIT does.
+unsafe fn read_field(ty: FieldType, pointer: isize) -> isize {
+    match ty {
+        FieldType::I8 => load(Width::W8, true, pointer),
+        FieldType::I16 => load(Width::W16, true, pointer),
+        FieldType::I32 => load(Width::W32, true, pointer),
+        FieldType::U8 => load(Width::W8, false, pointer),
+        FieldType::U16 => load(Width::W16, false, pointer),
+        FieldType::U32 => load(Width::W32, false, pointer),
+        FieldType::I64 | FieldType::U64 => load(Width::W64, false, pointer),
+        FieldType::Ptr => (pointer as *const isize).read_unaligned(),
+        FieldType::F32 => (pointer as *const f32).read_unaligned() as isize,
+        FieldType::F64 => (pointer as *const f64).read_unaligned() as isize,
+    }
+}
+
+unsafe fn write_field(ty: FieldType, pointer: isize, value: isize) {
+    match ty {
+        FieldType::I8 | FieldType::U8 => store(Width::W8, pointer, value),
+        FieldType::I16 | FieldType::U16 => store(Width::W16, pointer, value),
+        FieldType::I32 | FieldType::U32 => store(Width::W32, pointer, value),
+        FieldType::I64 | FieldType::U64 => store(Width::W64, pointer, value),
+        FieldType::Ptr => (pointer as *mut isize).write_unaligned(value),
+        FieldType::F32 => (pointer as *mut f32).write_unaligned(value as f32),
+        FieldType::F64 => (pointer as *mut f64).write_unaligned(value as f64),
+    }
+}
+
+/// Recursively descend through the AST, finding all implicit variable
+/// declarations. This declares the same variables as the JIT does.
+fn declare_variables_in_stmt(
+    globals: &HashSet<String>,
+    variables: &mut HashMap<String, isize>,
+    expr: &Expr,
+) {
+    // Assigning a global doesn't declare a variable, unless a parameter or a
+    // return variable shadows it already.
+    let is_local = |variables: &HashMap<String, isize>, name: &str| {
+        variables.contains_key(name) || !globals.contains(name)
+    };
+    match *expr {
+        Expr::Assign(ref name, ref expr) => {
+            if is_local(variables, name) {
+                variables.entry(name.clone()).or_insert(0);
+            }
+            declare_variables_in_stmt(globals, variables, expr);
+        }
+        Expr::Array(ref expr)
+        | Expr::Len(ref expr)
+        | Expr::Float(ref expr)
+        | Expr::Index(_, ref expr)
+        | Expr::Load(_, ref expr)
+        | Expr::SignedLoad(_, ref expr) => {
+            declare_variables_in_stmt(globals, variables, expr);
+        }
+        Expr::AssignIndex(_, ref index, ref expr) | Expr::Store(_, ref index, ref expr) => {
+            declare_variables_in_stmt(globals, variables, index);
+            declare_variables_in_stmt(globals, variables, expr);
+        }
+        Expr::Eq(ref lhs, ref rhs)
+        | Expr::Ne(ref lhs, ref rhs)
+        | Expr::Lt(ref lhs, ref rhs)
+        | Expr::Le(ref lhs, ref rhs)
+        | Expr::Gt(ref lhs, ref rhs)
+        | Expr::Ge(ref lhs, ref rhs)
+        | Expr::Add(ref lhs, ref rhs)
+        | Expr::Sub(ref lhs, ref rhs)
+        | Expr::Mul(ref lhs, ref rhs)
+        | Expr::Div(ref lhs, ref rhs) => {
+            declare_variables_in_stmt(globals, variables, lhs);
+            declare_variables_in_stmt(globals, variables, rhs);
+        }
+        Expr::IfElse(ref condition, ref then_body, ref else_body) => {
+            declare_variables_in_stmt(globals, variables, condition);
+            for stmt in then_body {
+                declare_variables_in_stmt(globals, variables, stmt);
+            }
+            for stmt in else_body {
+                declare_variables_in_stmt(globals, variables, stmt);
+            }
+        }
+        Expr::WhileLoop(ref condition, ref loop_body) => {
+            declare_variables_in_stmt(globals, variables, condition);
+            for stmt in loop_body {
+                declare_variables_in_stmt(globals, variables, stmt);
+            }
+        }
+        Expr::Call(_, ref args) => {
+            for arg in args {
+                declare_variables_in_stmt(globals, variables, arg);
+            }
+        }
+        Expr::Destructure(ref names, _, ref args) => {
+            for name in names {
+                if is_local(variables, name) {
+                    variables.entry(name.clone()).or_insert(0);
+                }
+            }
+            for arg in args {
+                declare_variables_in_stmt(globals, variables, arg);
+            }
+        }
+        Expr::Annotate(ref name, _) => {
+            variables.entry(name.clone()).or_insert(0);
+        }
+        Expr::AssignField(_, _, ref expr) => declare_variables_in_stmt(globals, variables, expr),
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Field(..)
+        | Expr::Closure(..)
+        | Expr::MakeClosure(..) => (),
+    }
+}
@@ -1,10 +1,103 @@
+use crate::clif_interp::ClifInterpreter;
+use crate::closure;
+use crate::coverage::{Coverage, CoverageReport, FunctionCoverage};
+use crate::debug_info::DebugInfo;
 use crate::frontend::*;
+use crate::gc::{self, StackMaps};
+use crate::layout::{self, StructLayout, Structs};
+use crate::perf::Perf;
+use crate::profile::{self, FunctionProfile, ProfileReport, Profiler};
+use crate::runtime::{
+    self, FunctionTable, Heap, HeapStats, ToyGlobal, Trap, TRAP_DIVISION_BY_ZERO,
+    TRAP_INDEX_OUT_OF_BOUNDS,
+};
+use crate::tiered::{call_native, TieredJIT, MAX_NATIVE_ARGS};
+use crate::trace::{self, TraceEvent, Tracer};
+use crate::unwind::Unwind;
+use crate::variadic;
+use cranelift::codegen::ir::{FuncRef, SourceLoc, ValueLabel};
+use cranelift::codegen::isa::CallConv;
+use cranelift::codegen::CompiledCode;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{DataContext, Linkage, Module};
-use std::collections::HashMap;
+use cranelift_module::{DataContext, DataId, FuncId, FuncOrDataId, Linkage, Module};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::iter;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::ptr;
+use std::rc::Rc;
 use std::slice;
 
+/// Resolves the names of the symbols the JIT doesn't define itself. See
+/// `JIT::with_symbol_lookup`.
+type SymbolLookup = Rc<dyn Fn(&str) -> Option<*const u8>>;
+
+/// A function parsed and declared, but not defined yet.
+type ParsedFunction = (FuncId, closure::Function);
+
+/// A thread-local data object. Cranelift's JIT doesn't support TLS, so
+/// rather than with `tls_value`, compiled code finds the calling thread's
+/// copy with `__toy_tls_address`, which makes it from the read-only
+/// `template` when the thread first uses it.
+#[derive(Clone, Copy)]
+struct ThreadLocal {
+    key: usize,
+    template: DataId,
+    size: usize,
+}
+
+/// What a pointer in a data object points to. Its address is written in the
+/// data object when it's finalized, like the addresses in code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DataPointer {
+    /// A toy function compiled before, whose address is a reference it can
+    /// be called through, like `&name`.
+    Function(String),
+
+    /// A data object created before, which isn't thread-local.
+    Data(String),
+}
+
+/// The error enabling garbage collection with thread-local globals gives.
+const THREAD_LOCAL_GC: &str = "thread-local globals aren't supported with garbage collection";
+
+/// A function which was just compiled, as debuggers and profilers get to
+/// know it.
+pub(crate) struct CompiledFunction<'a> {
+    pub(crate) id: FuncId,
+    pub(crate) name: &'a str,
+    pub(crate) file_name: &'a str,
+
+    /// The source line of the function's header.
+    pub(crate) line: u32,
+
+    /// The parameters and variables of the function, as the translation
+    /// declared them.
+    pub(crate) params: &'a [String],
+    pub(crate) variables: &'a HashMap<String, Variable>,
+
+    pub(crate) code: &'a CompiledCode,
+}
+
+impl CompiledFunction<'_> {
+    /// Return the offsets in the code at which a new source line starts,
+    /// with the line.
+    pub(crate) fn lines(&self) -> Vec<(u32, u32)> {
+        // Code without a source location, like the prologue or moves the
+        // register allocator inserted, belongs to the line before it, or the
+        // header if there's none.
+        let mut lines = vec![(0, self.line)];
+        for srcloc in self.code.buffer.get_srclocs_sorted() {
+            if !srcloc.loc.is_default() {
+                lines.push((srcloc.start, srcloc.loc.bits()));
+            }
+        }
+        lines
+    }
+}
+
 /// The basic JIT class.
 pub struct JIT {
     /// The function builder context, which is reused across multiple
@@ -22,10 +115,109 @@ pub struct JIT {
     /// The module, with the jit backend, which manages the JIT'd
     /// functions.
     module: JITModule,
+
+    /// The heap the arrays and strings of the compiled functions are
+    /// allocated on. It's boxed, as the code refers to it by its address.
+    heap: Box<Heap>,
+
+    /// The stack maps of the compiled functions, if garbage collection is
+    /// enabled.
+    stack_maps: Option<StackMaps>,
+
+    /// The compiled functions, which calls through function references are
+    /// checked against, and those defined since the last time they were
+    /// finalized, with their numbers of parameters and return values and
+    /// whether they're the code of closures. It's boxed, as the code refers
+    /// to it by its address.
+    functions: Box<FunctionTable>,
+    pending_functions: Vec<(FuncId, usize, usize, bool)>,
+
+    /// The toy functions defined, by name, with their numbers of parameters
+    /// and return values, and the trampolines the host calls those returning
+    /// several values through.
+    exports: HashMap<String, (FuncId, usize, usize, Option<FuncId>)>,
+
+    /// The structs declared so far.
+    structs: Structs,
+
+    /// The C functions declared so far.
+    externs: HashMap<String, ExternFunction>,
+
+    /// The global variables declared so far, which are data objects of
+    /// their own.
+    globals: HashMap<String, DataId>,
+
+    /// The thread-local data objects, including the thread-local global
+    /// variables, created so far.
+    thread_locals: HashMap<String, ThreadLocal>,
+
+    /// The values of the constants declared so far.
+    consts: HashMap<String, i64>,
+
+    /// The constant tables declared so far, which are read-only data objects
+    /// of their own.
+    tables: HashSet<String>,
+
+    /// The data objects defined so far, which pointers in other data objects
+    /// can point to.
+    defined_data: HashSet<DataId>,
+
+    /// The data objects the string literals compiled so far were interned
+    /// into, by their contents.
+    strings: HashMap<String, DataId>,
+
+    /// The source lines of the closures translated, by the names of the
+    /// functions they're lifted into, until those are translated.
+    closure_lines: HashMap<String, u32>,
+
+    /// The interpreter which the Cranelift IR of the compiled functions is
+    /// also handed to, if enabled.
+    clif_interpreter: Option<ClifInterpreter>,
+
+    /// The lookup the JIT was created with, which the interpreter resolves
+    /// native functions with too.
+    symbol_lookup: SymbolLookup,
+
+    /// The name of the source file the functions compiled from now on are
+    /// described as coming from, in debug info and to profilers.
+    file_name: String,
+
+    /// The unwind info of the compiled functions.
+    unwind: Unwind,
+
+    /// The debug info of the compiled functions, if enabled.
+    debug_info: Option<DebugInfo>,
+
+    /// The compiled functions to tell profilers about, if enabled.
+    perf: Option<Perf>,
+
+    /// The counters of the instrumented functions, if enabled.
+    profiler: Option<Profiler>,
+
+    /// The coverage counters of the compiled functions, if enabled.
+    coverage: Option<Coverage>,
+
+    /// The recorder of what the traced functions do, if enabled.
+    tracer: Option<Tracer>,
+
+    /// The tiered execution engine the functions are handed to instead of
+    /// being compiled up front, if enabled.
+    tiered: Option<TieredJIT>,
 }
 
 impl Default for JIT {
     fn default() -> Self {
+        Self::with_symbol_lookup(|_| None)
+    }
+}
+
+impl JIT {
+    /// Create a JIT which resolves the symbols it doesn't define itself with
+    /// `lookup` first, before falling back to the symbols of the host process.
+    pub fn with_symbol_lookup<F>(lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<*const u8> + 'static,
+    {
         let mut flag_builder = settings::builder();
         flag_builder.set("use_colocated_libcalls", "false").unwrap();
         flag_builder.set("is_pic", "false").unwrap();
@@ -35,7 +227,14 @@ impl Default for JIT {
         let isa = isa_builder
             .finish(settings::Flags::new(flag_builder))
             .unwrap();
-        let builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        let symbol_lookup: SymbolLookup = Rc::new(lookup);
+        let module_lookup = symbol_lookup.clone();
+        builder.symbol_lookup_fn(Box::new(move |name| module_lookup(name)));
+        builder.symbols(runtime::hooks());
+        builder.symbols(profile::hooks());
+        builder.symbols(trace::hooks());
+        builder.symbols(variadic::hooks());
 
         let module = JITModule::new(builder);
         Self {
@@ -43,92 +242,849 @@ impl Default for JIT {
             ctx: module.make_context(),
             data_ctx: DataContext::new(),
             module,
+            heap: Box::default(),
+            stack_maps: None,
+            functions: Box::default(),
+            pending_functions: Vec::new(),
+            exports: HashMap::new(),
+            structs: Structs::default(),
+            externs: HashMap::new(),
+            globals: HashMap::new(),
+            thread_locals: HashMap::new(),
+            consts: HashMap::new(),
+            tables: HashSet::new(),
+            defined_data: HashSet::new(),
+            strings: HashMap::new(),
+            closure_lines: HashMap::new(),
+            clif_interpreter: None,
+            symbol_lookup,
+            file_name: "<toy>".to_owned(),
+            unwind: Unwind::default(),
+            debug_info: None,
+            perf: None,
+            profiler: None,
+            coverage: None,
+            tracer: None,
+            tiered: None,
         }
     }
-}
 
-impl JIT {
+    /// Return the elements of `array`, if it's an array allocated by the
+    /// compiled functions.
+    pub fn read_array(&self, array: isize) -> Option<Vec<isize>> {
+        self.heap.read_array(array)
+    }
+
+    /// Free the arrays and strings the functions allocate once nothing refers
+    /// to them anymore. This has to be enabled before any function is
+    /// compiled, as the functions have to keep their values where the
+    /// collector can find them.
+    ///
+    /// The collector finds the values the functions refer to by walking the
+    /// stack of the thread which allocates, so functions sharing a heap
+    /// mustn't run on several threads at once. It doesn't know about values
+    /// the host keeps, or toy code stores outside the heap: those have to be
+    /// rooted with `add_root` to be kept alive.
+    pub fn enable_gc(&mut self) -> Result<(), String> {
+        if !gc::supported() {
+            return Err("garbage collection isn't supported on this platform".to_owned());
+        }
+        let compiled = self
+            .module
+            .declarations()
+            .get_functions()
+            .any(|(_, decl)| decl.linkage == Linkage::Export);
+        if compiled {
+            return Err(
+                "garbage collection has to be enabled before any function is compiled".to_owned(),
+            );
+        }
+        if self
+            .globals
+            .keys()
+            .any(|name| self.thread_locals.contains_key(name))
+        {
+            return Err(THREAD_LOCAL_GC.to_owned());
+        }
+        self.heap.enable_gc();
+        self.stack_maps.get_or_insert_with(StackMaps::default);
+        Ok(())
+    }
+
+    /// Keep `value`, and what it refers to, alive, if it's an array or a
+    /// string, until it's unrooted as many times as it's rooted.
+    pub fn add_root(&self, value: isize) {
+        self.heap
+            .with_collector(|collector| collector.add_root(value));
+    }
+
+    pub fn remove_root(&self, value: isize) {
+        self.heap
+            .with_collector(|collector| collector.remove_root(value));
+    }
+
+    /// Collect the garbage now, rather than when enough was allocated, if
+    /// garbage collection is enabled.
+    pub fn collect_garbage(&self) {
+        self.heap.collect_garbage();
+    }
+
+    /// Return how much the heap of the compiled functions holds.
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Return a handle to call `value` from Rust, which compiled code
+    /// returned or passed to the host as a closure or a function reference.
+    /// The handle keeps it rooted.
+    pub fn closure(&self, value: isize) -> ToyClosure<'_> {
+        self.add_root(value);
+        ToyClosure { jit: self, value }
+    }
+
+    /// Return a handle to call the toy function `name` from Rust, if it's
+    /// compiled. Unlike through the pointer `compile` returns, this also
+    /// works for functions returning several values.
+    pub fn function(&self, name: &str) -> Option<ToyFunction<'_>> {
+        let &(id, params, returns, trampoline) = self.exports.get(name)?;
+        Some(ToyFunction {
+            address: self.module.get_finalized_function(id) as usize,
+            params,
+            returns,
+            trampoline: trampoline.map(|id| self.module.get_finalized_function(id) as usize),
+            jit: PhantomData,
+        })
+    }
+
+    /// Return a handle to read and assign the global variable `name` from
+    /// Rust, if it's declared.
+    pub fn global(&self, name: &str) -> Option<ToyGlobal<'_>> {
+        let &id = self.globals.get(name)?;
+        // Those which are thread-local are the calling thread's.
+        let address = match self.thread_locals.get(name) {
+            Some(tls) => {
+                let (template, _) = self.module.get_finalized_data(tls.template);
+                runtime::tls_address(tls.key, template, tls.size)
+            }
+            None => self.module.get_finalized_data(id).0 as *mut u8,
+        };
+        Some(unsafe { ToyGlobal::new(address as *mut isize) })
+    }
+
+    /// Parse declarations in the toy language, and make what they declare
+    /// available to the functions compiled from now on.
+    pub fn declare(&mut self, input: &str) -> Result<(), String> {
+        if let Some(tiered) = &mut self.tiered {
+            return tiered.declare(input);
+        }
+        let declarations = parser::declarations(input).map_err(|e| e.to_string())?;
+        for declaration in declarations {
+            match declaration {
+                Declaration::Struct(name, fields) => {
+                    self.structs
+                        .declare(&name, &fields, self.module.target_config())?;
+                }
+                Declaration::Extern(function) => {
+                    if self.externs.contains_key(&function.name) {
+                        return Err(format!(
+                            "duplicate declaration of function `{}`",
+                            function.name
+                        ));
+                    }
+                    self.externs.insert(function.name.clone(), function);
+                }
+                Declaration::Global(name, value, true) => {
+                    if self.globals.contains_key(&name) {
+                        return Err(format!("duplicate declaration of global `{}`", name));
+                    }
+                    // The collector only scans the stack of the thread it
+                    // runs on, and can't find the other threads' copies.
+                    if self.stack_maps.is_some() {
+                        return Err(THREAD_LOCAL_GC.to_owned());
+                    }
+                    let contents = (value as isize).to_ne_bytes().to_vec();
+                    self.create_tls_data(&name, contents)?;
+                    let id = self.thread_locals[&name].template;
+                    self.globals.insert(name, id);
+                }
+                Declaration::Global(name, value, false) => {
+                    if self.globals.contains_key(&name) {
+                        return Err(format!("duplicate declaration of global `{}`", name));
+                    }
+                    let contents = (value as isize).to_ne_bytes().to_vec();
+                    let id = self.define_data(&name, contents, true, &[])?;
+                    let (address, _) = self.module.get_finalized_data(id);
+                    self.heap.add_global(address as *const isize);
+                    self.globals.insert(name, id);
+                }
+                Declaration::Const(name, value) => {
+                    if self.consts.contains_key(&name) || self.tables.contains(&name) {
+                        return Err(format!("duplicate declaration of constant `{}`", name));
+                    }
+                    let value = value.eval(&self.consts)?;
+                    self.consts.insert(name, value);
+                }
+                Declaration::ConstTable(name, values) => {
+                    if self.consts.contains_key(&name) || self.tables.contains(&name) {
+                        return Err(format!("duplicate declaration of constant `{}`", name));
+                    }
+                    // Laid out like an array: the length, then the elements.
+                    // Addresses are written as 0 until they're resolved.
+                    let size = self.module.target_config().pointer_bytes() as usize;
+                    let mut contents = (values.len() as isize).to_ne_bytes().to_vec();
+                    let mut pointers = Vec::new();
+                    for (i, value) in values.into_iter().enumerate() {
+                        let value = match value {
+                            TableEntry::Value(value) => value.eval(&self.consts)? as isize,
+                            TableEntry::Address(name) => {
+                                let pointer = match self.module.get_name(&name) {
+                                    Some(FuncOrDataId::Func(_)) => DataPointer::Function(name),
+                                    _ => DataPointer::Data(name),
+                                };
+                                pointers.push(((i + 1) * size, self.pointee(&pointer)?));
+                                0
+                            }
+                            TableEntry::Str(string) => {
+                                let id = intern_string(&mut self.module, &mut self.strings, string);
+                                pointers.push(((i + 1) * size, FuncOrDataId::Data(id)));
+                                0
+                            }
+                        };
+                        contents.extend_from_slice(&value.to_ne_bytes());
+                    }
+                    let id = self.define_data(&name, contents, false, &pointers)?;
+                    let (address, _) = self.module.get_finalized_data(id);
+                    self.heap.add_table(address as *const isize);
+                    self.tables.insert(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the layout of the struct `name`, as laid out for the target,
+    /// if it's declared.
+    pub fn struct_layout(&self, name: &str) -> Option<&StructLayout> {
+        self.structs.get(name)
+    }
+
+    /// Also hand the Cranelift IR of the functions and the data objects
+    /// defined from now on to an interpreter, so that they can be run without
+    /// going through Cranelift's optimizations and backend, for comparison.
+    pub fn enable_clif_interpreter(&mut self) -> &mut ClifInterpreter {
+        let lookup = self.symbol_lookup.clone();
+        self.clif_interpreter.get_or_insert_with(|| {
+            let mut clif_interpreter = ClifInterpreter::default();
+            clif_interpreter.set_symbol_lookup(move |name| lookup_symbol(&*lookup, name));
+            clif_interpreter
+        })
+    }
+
+    /// Return the Cranelift IR interpreter, if enabled.
+    pub fn clif_interpreter(&self) -> Option<&ClifInterpreter> {
+        self.clif_interpreter.as_ref()
+    }
+
+    /// Set the name of the source file the functions compiled from now on
+    /// come from, for debuggers and profilers to find the source lines in.
+    pub fn set_file_name(&mut self, file_name: &str) {
+        self.file_name = file_name.to_owned();
+    }
+
+    /// Emit debug info for the functions compiled from now on, and register
+    /// it with debuggers through the GDB JIT interface.
+    pub fn enable_debug_info(&mut self) {
+        self.debug_info.get_or_insert_with(DebugInfo::default);
+    }
+
+    /// Write an entry to `/tmp/perf-<pid>.map` for each function compiled from
+    /// now on, for `perf report` to name their frames.
+    pub fn enable_perf_map(&mut self) -> Result<(), String> {
+        self.perf
+            .get_or_insert_with(Perf::default)
+            .enable_perf_map()
+    }
+
+    /// Write the functions compiled from now on, with their code and source
+    /// lines, to a `jit-<pid>.dump` file in `directory`, for `perf inject
+    /// --jit` to merge into a profile recorded with `perf record -k mono`.
+    pub fn enable_jitdump(&mut self, directory: &Path) -> Result<(), String> {
+        self.perf
+            .get_or_insert_with(Perf::default)
+            .enable_jitdump(directory, self.module.isa())
+    }
+
+    /// Instrument the functions compiled from now on with calls to the
+    /// profiler's hooks, which count calls and loop iterations and time the
+    /// calls.
+    pub fn enable_profiler(&mut self) {
+        self.profiler.get_or_insert_with(Profiler::default);
+    }
+
+    /// Return the counters of the instrumented functions so far. This is
+    /// empty unless the profiler is enabled.
+    pub fn profile_report(&self) -> ProfileReport {
+        match &self.profiler {
+            Some(profiler) => profiler.report(),
+            None => ProfileReport {
+                functions: Vec::new(),
+            },
+        }
+    }
+
+    /// Count how often each basic block of the functions compiled from now
+    /// on runs, for `coverage_report` to tell which lines and branches ran.
+    pub fn enable_coverage(&mut self) {
+        self.coverage.get_or_insert_with(Coverage::default);
+    }
+
+    /// Return the coverage counters of the functions compiled so far. This is
+    /// empty unless coverage is enabled.
+    pub fn coverage_report(&self) -> CoverageReport {
+        match &self.coverage {
+            Some(coverage) => coverage.report(&self.module),
+            None => CoverageReport {
+                functions: Vec::new(),
+            },
+        }
+    }
+
+    /// Record each assignment, with the variable's new value, and each call,
+    /// with its arguments and result, in the functions compiled from now on.
+    pub fn enable_trace(&mut self) {
+        self.tracer.get_or_insert_with(Tracer::default);
+    }
+
+    /// Return the events the traced functions recorded since the last time.
+    /// `trace::to_json_lines` writes them out in a structured format. This
+    /// is empty unless tracing is enabled.
+    pub fn take_trace(&self) -> Vec<TraceEvent> {
+        match &self.tracer {
+            Some(tracer) => tracer.take_events(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Enable tiered execution for the functions compiled from now on, and
+    /// the declarations made: rather than compiling them up front, `compile`
+    /// hands them to a `TieredJIT`, which interprets them until the sum of
+    /// their calls and loop iterations reaches `threshold`, and only then
+    /// compiles them in the background. There's no code to return for them,
+    /// so `compile` returns null pointers, and they're called through the
+    /// `TieredJIT` this returns.
+    pub fn enable_tiering(&mut self, threshold: u64) -> &mut TieredJIT {
+        self.tiered.get_or_insert_with(|| TieredJIT::new(threshold))
+    }
+
+    /// Return the tiered execution engine, if enabled.
+    pub fn tiered(&self) -> Option<&TieredJIT> {
+        self.tiered.as_ref()
+    }
+
     /// Compile a string in the toy language into machine code.
     pub fn compile(&mut self, input: &str) -> Result<*const u8, String> {
-        // First, parse the string, producing AST nodes.
-        let (name, params, the_return, stmts) =
-            parser::function(input).map_err(|e| e.to_string())?;
+        if let Some(tiered) = &mut self.tiered {
+            tiered.define(input)?;
+            return Ok(ptr::null());
+        }
+        let mut functions = self.parse_function(input)?;
+        let batch = iter::once(functions[0].0.clone()).collect();
+        self.check_functions(&mut functions, &batch)?;
+        let functions = self.declare_function(functions)?;
+        let id = self.define(input, functions)?;
 
-        // Then, translate the AST nodes into Cranelift IR.
-        self.translate(params, the_return, stmts)?;
+        // Finalize the functions which we just defined, which resolves any
+        // outstanding relocations (patching in addresses, now that they're
+        // available).
+        self.finalize()?;
 
-        // Next, declare the function to jit. Functions must be declared
-        // before they can be called, or defined.
-        //
-        // TODO: This may be an area where the API should be streamlined; should
-        // we have a version of `declare_function` that automatically declares
-        // the function?
-        let id = self
+        // We can now retrieve a pointer to the machine code.
+        let code = self.module.get_finalized_function(id);
+
+        Ok(code)
+    }
+
+    /// Compile several strings in the toy language into machine code at
+    /// once. Unlike with `compile`, the functions may call each other in any
+    /// order, including mutually recursively.
+    pub fn compile_all(&mut self, inputs: &[&str]) -> Result<Vec<*const u8>, String> {
+        if self.tiered.is_some() {
+            return inputs.iter().map(|input| self.compile(input)).collect();
+        }
+        // Declare all the functions before translating any, so that they can
+        // refer to each other with `&name`, which has to know whether `name`
+        // is a function.
+        let mut parsed = Vec::new();
+        for input in inputs {
+            parsed.push(self.parse_function(input)?);
+        }
+        let batch = parsed
+            .iter()
+            .map(|functions| functions[0].0.clone())
+            .collect();
+        for functions in &mut parsed {
+            self.check_functions(functions, &batch)?;
+        }
+        let mut functions = Vec::new();
+        for parsed in parsed {
+            functions.push(self.declare_function(parsed)?);
+        }
+        let mut ids = Vec::new();
+        for (input, functions) in inputs.iter().zip(functions) {
+            ids.push(self.define(input, functions)?);
+        }
+
+        // Only finalize once all the functions are defined, so that calls
+        // between them can be resolved.
+        self.finalize()?;
+
+        Ok(ids
+            .into_iter()
+            .map(|id| self.module.get_finalized_function(id))
+            .collect())
+    }
+
+    // Finalize the definitions, and register the functions defined since the
+    // last time with the unwinder, and with debuggers and profilers if
+    // enabled.
+    fn finalize(&mut self) -> Result<(), String> {
+        self.module
+            .finalize_definitions()
+            .map_err(|e| e.to_string())?;
+        self.unwind.register(&self.module)?;
+        for (id, params, returns, closure) in self.pending_functions.drain(..) {
+            let address = self.module.get_finalized_function(id);
+            self.functions
+                .add(address as usize, params, returns, closure);
+            if let Some(clif_interpreter) = &mut self.clif_interpreter {
+                let name = &self.module.declarations().get_function_decl(id).name;
+                clif_interpreter.define_function_address(name, address);
+            }
+        }
+        if let Some(clif_interpreter) = &mut self.clif_interpreter {
+            // The string literals are interned into data objects of their
+            // own, which are all defined.
+            for id in clif_interpreter.undefined_data() {
+                if self.defined_data.contains(&id) || self.strings.values().any(|&s| s == id) {
+                    clif_interpreter.define_data(id, self.module.get_finalized_data(id).0);
+                }
+            }
+        }
+        if let Some(stack_maps) = &mut self.stack_maps {
+            let module = &self.module;
+            self.heap
+                .with_collector(|collector| stack_maps.register(module, collector));
+        }
+        if let Some(debug_info) = &mut self.debug_info {
+            debug_info.register(&self.module)?;
+        }
+        if let Some(perf) = &mut self.perf {
+            perf.register(&self.module)?;
+        }
+        Ok(())
+    }
+
+    // Parse a string in the toy language into the function it defines,
+    // followed by those its closures are lifted into.
+    fn parse_function(&self, input: &str) -> Result<Vec<closure::Function>, String> {
+        let function = parser::function(input).map_err(|e| e.to_string())?;
+        let globals = &self.globals;
+        Ok(closure::lift(function, &|name| globals.contains_key(name)))
+    }
+
+    // Check that `functions` only read the variables, constants and globals
+    // which are defined, and only call the functions which are, or which are
+    // compiled along with them, whose names are in `batch`. This has to be
+    // done before any of them is declared: calls to functions which don't
+    // exist couldn't be resolved when the module is finalized, and one a
+    // closure is lifted into is only defined after the function it's lifted
+    // out of, which the module can't take back.
+    fn check_functions(
+        &self,
+        functions: &mut [closure::Function],
+        batch: &HashSet<String>,
+    ) -> Result<(), String> {
+        let (globals, consts, tables) = (&self.globals, &self.consts, &self.tables);
+        let is_global = |name: &str| globals.contains_key(name);
+        let module = &self.module;
+        let is_function = |name: &str| {
+            batch.contains(name)
+                || self.exports.contains_key(name)
+                || self.externs.contains_key(name)
+                || RUNTIME_FUNCTIONS.iter().any(|(runtime, _)| *runtime == name)
+                || lookup_symbol(&*self.symbol_lookup, name).is_some()
+                // `check_call` tells calls to data objects apart.
+                || matches!(module.get_name(name), Some(FuncOrDataId::Data(_)))
+        };
+        for function in functions {
+            let variables = closure::variables(function, &is_global);
+            let is_variable = |name: &str| variables.iter().any(|variable| variable == name);
+            let is_defined = |name: &str| {
+                is_variable(name)
+                    || is_global(name)
+                    || consts.contains_key(name)
+                    || tables.contains(name)
+            };
+            let is_callable = |name: &str| is_variable(name) || is_function(name);
+            function
+                .3
+                .iter()
+                .try_for_each(|expr| check_names(&is_defined, &is_callable, expr))?;
+        }
+        Ok(())
+    }
+
+    // Declare the functions `parse_function` returned in the module.
+    fn declare_function(
+        &mut self,
+        lifted: Vec<closure::Function>,
+    ) -> Result<Vec<ParsedFunction>, String> {
+        let mut functions = Vec::new();
+        for (i, function) in lifted.into_iter().enumerate() {
+            let linkage = if i == 0 {
+                Linkage::Export
+            } else {
+                Linkage::Local
+            };
+            let signature = self.signature(function.1.len(), function.2.len());
+            let id = self
+                .module
+                .declare_function(&function.0, linkage, &signature)
+                .map_err(|e| e.to_string())?;
+            functions.push((id, function));
+        }
+        Ok(functions)
+    }
+
+    // The signature of toy functions, which take and return values.
+    fn signature(&self, params: usize, returns: usize) -> Signature {
+        let int = self.module.target_config().pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params = vec![AbiParam::new(int); params];
+        signature.returns = vec![AbiParam::new(int); returns];
+        signature
+    }
+
+    // Translate the functions declared with `declare_function`, and define
+    // them in the module without finalizing them yet. Returns the id of the
+    // function the input defines.
+    fn define(&mut self, input: &str, functions: Vec<ParsedFunction>) -> Result<FuncId, String> {
+        let mut functions = functions.into_iter();
+        let (id, function) = functions.next().unwrap();
+        let (name, params, returns) = (function.0.clone(), function.1.len(), function.2.len());
+        self.define_function(code_lines(input), id, function, false)?;
+        // The code of a closure is all on the line the closure is on, which
+        // was found when the function it's lifted out of was translated.
+        for (id, function) in functions {
+            let line = self.closure_lines.remove(&function.0).unwrap_or(0);
+            let lines = vec![line; function.3.len() + 1];
+            self.define_function(lines, id, function, true)?;
+        }
+
+        let trampoline = if returns > 1 {
+            Some(self.define_results_trampoline(id, &name, params)?)
+        } else {
+            None
+        };
+        self.exports.insert(name, (id, params, returns, trampoline));
+        Ok(id)
+    }
+
+    // Define a function which calls the function `id`, which returns
+    // several values, stores them through the pointer it takes first, and
+    // returns the first one, for the host to call it through: Rust has no
+    // way to receive them all from it directly.
+    fn define_results_trampoline(
+        &mut self,
+        id: FuncId,
+        name: &str,
+        params: usize,
+    ) -> Result<FuncId, String> {
+        let int = self.module.target_config().pointer_type();
+        let name = format!("{}::{{results}}", name);
+        let signature = self.signature(params + 1, 1);
+        let trampoline = self
             .module
-            .declare_function(&name, Linkage::Export, &self.ctx.func.signature)
+            .declare_function(&name, Linkage::Local, &signature)
             .map_err(|e| e.to_string())?;
+        self.ctx.func.signature = signature;
+
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+        let args = builder.block_params(block).to_vec();
+        let callee = self.module.declare_func_in_func(id, builder.func);
+        let call = builder.ins().call(callee, &args[1..]);
+        let results = builder.inst_results(call).to_vec();
+        for (i, &result) in results.iter().enumerate() {
+            let offset = int.bytes() as i32 * i as i32;
+            builder
+                .ins()
+                .store(MemFlags::trusted(), result, args[0], offset);
+        }
+        builder.ins().return_(&results[..1]);
+        builder.finalize();
+
+        self.module
+            .define_function(trampoline, &mut self.ctx)
+            .map_err(|e| e.to_string())?;
+        // Traps unwind through it.
+        let function = CompiledFunction {
+            id: trampoline,
+            name: &name,
+            file_name: &self.file_name,
+            line: 0,
+            params: &[],
+            variables: &HashMap::new(),
+            code: self.ctx.compiled_code().unwrap(),
+        };
+        self.unwind.add_function(&function, self.module.isa());
+        self.module.clear_context(&mut self.ctx);
+        Ok(trampoline)
+    }
+
+    fn define_function(
+        &mut self,
+        lines: Vec<u32>,
+        id: FuncId,
+        function: closure::Function,
+        closure: bool,
+    ) -> Result<(), String> {
+        let (name, params, returns, stmts) = function;
+        let returns_len = returns.len();
+
+        // Then, translate the AST nodes into Cranelift IR.
+        if self.debug_info.is_some() {
+            self.ctx.func.collect_debug_info();
+        }
+        let (variables, coverage) =
+            self.translate(&name, params.clone(), returns, stmts, &lines)?;
+
+        // Keep the IR as the translation produced it, before `define_function`
+        // gets to optimize it.
+        if let Some(clif_interpreter) = &mut self.clif_interpreter {
+            clif_interpreter.define(&name, self.ctx.func.clone(), self.module.declarations());
+        }
 
         // Define the function to jit. This finishes compilation, although
         // there may be outstanding relocations to perform. Currently, jit
         // cannot finish relocations until all functions to be called are
         // defined. For this toy demo for now, we'll just finalize the
-        // function below.
+        // function in the caller.
         self.module
             .define_function(id, &mut self.ctx)
             .map_err(|e| e.to_string())?;
 
-        // Now that compilation is finished, we can clear out the context state.
-        self.module.clear_context(&mut self.ctx);
+        // The coverage counters live in a data object of their own, which
+        // only now has its final size.
+        if let (Some(coverage), Some(function)) = (&mut self.coverage, coverage) {
+            self.data_ctx.define_zeroinit(function.size());
+            self.data_ctx.set_align(8);
+            self.module
+                .define_data(function.data, &self.data_ctx)
+                .map_err(|e| e.to_string())?;
+            self.data_ctx.clear();
+            self.defined_data.insert(function.data);
+            coverage.add_function(function);
+        }
 
-        // Finalize the functions which we just defined, which resolves any
-        // outstanding relocations (patching in addresses, now that they're
-        // available).
-        self.module.finalize_definitions().unwrap();
+        let function = CompiledFunction {
+            id,
+            name: &name,
+            file_name: &self.file_name,
+            line: lines.first().copied().unwrap_or(0),
+            params: &params,
+            variables: &variables,
+            code: self.ctx.compiled_code().unwrap(),
+        };
+        self.unwind.add_function(&function, self.module.isa());
+        if let Some(stack_maps) = &mut self.stack_maps {
+            stack_maps.add_function(&function);
+        }
+        self.pending_functions
+            .push((id, params.len(), returns_len, closure));
+        if let Some(debug_info) = &mut self.debug_info {
+            debug_info.add_function(&function, self.module.isa());
+        }
+        if let Some(perf) = &mut self.perf {
+            perf.add_function(&function);
+        }
 
-        // We can now retrieve a pointer to the machine code.
-        let code = self.module.get_finalized_function(id);
+        // Now that compilation is finished, we can clear out the context state.
+        self.module.clear_context(&mut self.ctx);
 
-        Ok(code)
+        Ok(())
     }
 
     /// Create a zero-initialized data section.
     pub fn create_data(&mut self, name: &str, contents: Vec<u8>) -> Result<&[u8], String> {
+        self.create_data_with_pointers(name, contents, &[])
+    }
+
+    /// Create a data section like `create_data`, with the addresses of
+    /// functions and other data objects at the offsets in `pointers`, such
+    /// as a table of functions to dispatch calls through, or of strings.
+    /// They're written over `contents` when the data object is finalized.
+    pub fn create_data_with_pointers(
+        &mut self,
+        name: &str,
+        contents: Vec<u8>,
+        pointers: &[(usize, DataPointer)],
+    ) -> Result<&[u8], String> {
+        let size = self.module.target_config().pointer_bytes() as usize;
+        let mut relocs = Vec::new();
+        for (offset, pointer) in pointers {
+            if offset
+                .checked_add(size)
+                .is_none_or(|end| end > contents.len())
+            {
+                return Err(format!(
+                    "pointer at offset {} is out of the bounds of data object `{}`",
+                    offset, name
+                ));
+            }
+            relocs.push((*offset, self.pointee(pointer)?));
+        }
+        let id = self.define_data(name, contents, true, &relocs)?;
+        let buffer = self.module.get_finalized_data(id);
+        // TODO: Can we move the unsafe into cranelift?
+        Ok(unsafe { slice::from_raw_parts(buffer.0, buffer.1) })
+    }
+
+    /// Return the function or data object `pointer` points to, if a pointer
+    /// to it can be resolved.
+    fn pointee(&self, pointer: &DataPointer) -> Result<FuncOrDataId, String> {
+        match pointer {
+            DataPointer::Function(name) => match self.exports.get(name) {
+                Some(&(id, ..)) => Ok(FuncOrDataId::Func(id)),
+                None => Err(format!("function `{}` not compiled", name)),
+            },
+            DataPointer::Data(name) if self.thread_locals.contains_key(name) => Err(format!(
+                "data object `{}` is thread-local, and has no single address",
+                name
+            )),
+            DataPointer::Data(name) => match self.module.get_name(name) {
+                Some(FuncOrDataId::Data(id)) if self.defined_data.contains(&id) => {
+                    Ok(FuncOrDataId::Data(id))
+                }
+                _ => Err(format!("data object `{}` not defined", name)),
+            },
+        }
+    }
+
+    /// Create a thread-local data object, of which each thread has a copy of
+    /// its own, initialized with `contents` when the thread first uses it.
+    /// In toy code, `&name` evaluates to the address of the calling
+    /// thread's copy, so the same compiled code can run on several threads
+    /// at once, each with its own state.
+    ///
+    /// Cranelift's JIT can't lower `tls_value`, so finding the copy isn't
+    /// the few instructions it would be in native code, but a call into the
+    /// runtime, which looks it up in a per-thread table. Each access to a
+    /// thread-local global pays for that call, where one to another global
+    /// is a single load or store.
+    pub fn create_tls_data(&mut self, name: &str, contents: Vec<u8>) -> Result<(), String> {
+        let size = contents.len();
+        let template = self.define_data(name, contents, false, &[])?;
+        let tls = ThreadLocal {
+            key: runtime::new_tls_key(),
+            template,
+            size,
+        };
+        self.thread_locals.insert(name.to_owned(), tls);
+        Ok(())
+    }
+
+    /// Define the data object `name`, which is read-only unless `writable`,
+    /// with the addresses of the functions and data objects in `pointers` at
+    /// their offsets, and finalize it.
+    fn define_data(
+        &mut self,
+        name: &str,
+        contents: Vec<u8>,
+        writable: bool,
+        pointers: &[(usize, FuncOrDataId)],
+    ) -> Result<DataId, String> {
         // The steps here are analogous to `compile`, except that data is much
         // simpler than functions.
-        self.data_ctx.define(contents.into_boxed_slice());
         let id = self
             .module
-            .declare_data(name, Linkage::Export, true, false)
+            .declare_data(name, Linkage::Export, writable, false)
             .map_err(|e| e.to_string())?;
 
-        self.module
-            .define_data(id, &self.data_ctx)
-            .map_err(|e| e.to_string())?;
+        self.data_ctx.define(contents.into_boxed_slice());
+        // Like calls in code, the pointers are relocations, which the module
+        // resolves when it's finalized.
+        for &(offset, pointee) in pointers {
+            let offset = offset as u32;
+            match pointee {
+                FuncOrDataId::Func(id) => {
+                    let func = self.module.declare_func_in_data(id, &mut self.data_ctx);
+                    self.data_ctx.write_function_addr(offset, func);
+                }
+                FuncOrDataId::Data(id) => {
+                    let data = self.module.declare_data_in_data(id, &mut self.data_ctx);
+                    self.data_ctx.write_data_addr(offset, data, 0);
+                }
+            }
+        }
+        let result = self.module.define_data(id, &self.data_ctx);
         self.data_ctx.clear();
-        self.module.finalize_definitions().unwrap();
-        let buffer = self.module.get_finalized_data(id);
-        // TODO: Can we move the unsafe into cranelift?
-        Ok(unsafe { slice::from_raw_parts(buffer.0, buffer.1) })
+        result.map_err(|e| e.to_string())?;
+        self.defined_data.insert(id);
+        self.finalize()?;
+        Ok(id)
     }
 
-    // Translate from toy-language AST nodes into Cranelift IR.
+    // Translate from toy-language AST nodes into Cranelift IR. `lines` are
+    // the source lines of the function's code, as returned by `code_lines`.
+    // Returns the variables of the function, and its coverage counters if
+    // enabled.
     fn translate(
         &mut self,
+        name: &str,
         params: Vec<String>,
-        the_return: String,
+        returns: Vec<String>,
         stmts: Vec<Expr>,
-    ) -> Result<(), String> {
+        lines: &[u32],
+    ) -> Result<(HashMap<String, Variable>, Option<FunctionCoverage>), String> {
         // Our toy language currently only supports I64 values, though Cranelift
         // supports other types.
         let int = self.module.target_config().pointer_type();
 
-        for _p in &params {
-            self.ctx.func.signature.params.push(AbiParam::new(int));
+        // With garbage collection, values are kept as references, for
+        // Cranelift to tell where they are at calls.
+        let ty = match self.stack_maps {
+            Some(_) if int == types::I64 => types::R64,
+            Some(_) => types::R32,
+            None => int,
+        };
+
+        // Check the struct fields the function accesses up front, so that
+        // the translation can't fail halfway through.
+        let types = self.structs.variable_types(&stmts)?;
+
+        // Likewise, constants can't be assigned, unless a parameter or a
+        // return variable shadows them.
+        let mut assigned = Vec::new();
+        for expr in &stmts {
+            assigned_names(&mut assigned, expr);
+        }
+        for name in assigned {
+            let shadowed = params
+                .iter()
+                .chain(&returns)
+                .any(|variable| variable == name);
+            if !shadowed && (self.consts.contains_key(name) || self.tables.contains(name)) {
+                return Err(format!("cannot assign to constant `{}`", name));
+            }
         }
 
-        // Our toy language currently only supports one return value, though
-        // Cranelift is designed to support more.
-        self.ctx.func.signature.returns.push(AbiParam::new(int));
+        // Functions may return several values, which Cranelift returns in
+        // registers as far as there are enough of them.
+        self.ctx.func.signature = self.signature(params.len(), returns.len());
 
         // Create the builder to build a function.
         let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
@@ -152,76 +1108,358 @@ impl JIT {
 
         // The toy language allows variables to be declared implicitly.
         // Walk the AST and declare all implicitly-declared variables.
-        let variables =
-            declare_variables(int, &mut builder, &params, &the_return, &stmts, entry_block);
+        let variables = declare_variables(
+            int,
+            ty,
+            &mut builder,
+            &self.globals,
+            &params,
+            &returns,
+            &stmts,
+        );
+
+        // Check the calls against the signatures of the functions declared
+        // so far, which they have to match.
+        let (module, externs) = (&self.module, &self.externs);
+        let checked = stmts
+            .iter()
+            .try_for_each(|expr| check_calls(module, externs, &variables, expr));
+        if let Err(error) = checked {
+            // Start over with the next function, which the builder would
+            // otherwise take to be this one's continuation.
+            self.builder_context = FunctionBuilderContext::new();
+            self.module.clear_context(&mut self.ctx);
+            return Err(error);
+        }
+
+        let coverage = match self.coverage {
+            Some(_) => {
+                let data = self
+                    .module
+                    .declare_anonymous_data(true, false)
+                    .map_err(|e| e.to_string())?;
+                let global_value = self.module.declare_data_in_func(data, builder.func);
+                let line = lines.first().copied().unwrap_or(0);
+                Some(FunctionCoverage::new(
+                    name,
+                    &self.file_name,
+                    line,
+                    data,
+                    global_value,
+                ))
+            }
+            None => None,
+        };
 
         // Now translate the statements of the function body.
         let mut trans = FunctionTranslator {
             int,
+            ty,
             builder,
             variables,
             module: &mut self.module,
+            heap: &*self.heap as *const Heap as usize,
+            functions: &*self.functions as *const FunctionTable as usize,
+            types,
+            externs: &self.externs,
+            globals: &self.globals,
+            thread_locals: &self.thread_locals,
+            consts: &self.consts,
+            tables: &self.tables,
+            strings: &mut self.strings,
+            closure_lines: &mut self.closure_lines,
+            lines,
+            line: 1,
+            profile: self
+                .profiler
+                .as_mut()
+                .map(|profiler| profiler.add_function(name)),
+            coverage,
+            counter: 0,
+            name,
+            tracer: self.tracer.as_mut(),
         };
+        if let Some(profile) = &trans.profile {
+            let address = profile.address();
+            trans.call_hook("__toy_profile_enter", address, &[]);
+        }
+        trans.increment_counter(0);
         for expr in stmts {
-            trans.translate_expr(expr);
+            trans.translate_stmt(expr);
+        }
+
+        // Set up the return variables of the function. Above, we declared
+        // variables to hold the return values. Here, we just do a use of
+        // those variables.
+        let return_values: Vec<Value> = returns
+            .iter()
+            .map(|name| trans.builder.use_var(trans.variables[name]))
+            .collect();
+
+        // Emit the return instruction, at the closing brace.
+        trans.set_srcloc();
+        if let Some(profile) = &trans.profile {
+            let address = profile.address();
+            trans.call_hook("__toy_profile_exit", address, &[]);
         }
+        let return_values: Vec<Value> = return_values
+            .into_iter()
+            .map(|value| trans.int_value(value))
+            .collect();
+        trans.builder.ins().return_(&return_values);
+
+        // Tell the builder we're done with this function.
+        trans.builder.finalize();
+        Ok((trans.variables, trans.coverage))
+    }
+}
+
+/// A closure or a function reference made by compiled code, which the host
+/// can call like toy code does.
+pub struct ToyClosure<'a> {
+    jit: &'a JIT,
+    value: isize,
+}
+
+impl ToyClosure<'_> {
+    pub fn value(&self) -> isize {
+        self.value
+    }
+
+    /// Call the closure with `args`. A closure's code takes the closure as
+    /// its first argument, so a closure can be passed one argument less than
+    /// `MAX_NATIVE_ARGS`, and a function reference that many. Returns the
+    /// trap which stopped it, if any, including those for calling something
+    /// which isn't a closure or a function, or with the wrong number of
+    /// arguments, or too many.
+    pub fn call(&self, args: &[isize]) -> Result<isize, Trap> {
+        let jit = self.jit;
+        let code = jit
+            .functions
+            .resolve(&jit.heap, self.value, args.len(), 1)?;
+        let (address, native_args) = match code {
+            Some(code) => {
+                let mut closure_args = vec![self.value];
+                closure_args.extend_from_slice(args);
+                (code, closure_args)
+            }
+            None => (self.value as usize, args.to_vec()),
+        };
+        if native_args.len() > MAX_NATIVE_ARGS {
+            return Err(Trap::TooManyArguments {
+                max: MAX_NATIVE_ARGS - (native_args.len() - args.len()),
+                args: args.len(),
+            });
+        }
+        let args = native_args;
+        runtime::catch_trap(|| unsafe { call_native(address, &args) })
+    }
+}
+
+impl Drop for ToyClosure<'_> {
+    fn drop(&mut self) {
+        self.jit.remove_root(self.value);
+    }
+}
+
+/// A compiled toy function, which the host can call and get all the return
+/// values of. With garbage collection, those which are arrays or strings
+/// have to be rooted to be kept across calls into toy code.
+pub struct ToyFunction<'a> {
+    address: usize,
+    params: usize,
+    returns: usize,
+
+    /// The trampoline storing the return values to memory, if there are
+    /// several.
+    trampoline: Option<usize>,
+
+    jit: PhantomData<&'a JIT>,
+}
+
+impl ToyFunction<'_> {
+    pub fn params(&self) -> usize {
+        self.params
+    }
+
+    pub fn returns(&self) -> usize {
+        self.returns
+    }
+
+    /// Call the function with `args`, of which there may be at most
+    /// `MAX_NATIVE_ARGS`, one less for a function returning several values,
+    /// and return its return values. Returns the trap which stopped it, if
+    /// any, including those for the wrong number of arguments, and for too
+    /// many.
+    pub fn call(&self, args: &[isize]) -> Result<Vec<isize>, Trap> {
+        let mut results = vec![0; self.returns];
+        self.call_into(args, &mut results)?;
+        Ok(results)
+    }
+
+    /// Call the function like `call`, storing its return values into
+    /// `results`, which has to have room for exactly as many.
+    pub fn call_into(&self, args: &[isize], results: &mut [isize]) -> Result<(), Trap> {
+        if args.len() != self.params {
+            return Err(Trap::SignatureMismatch {
+                params: self.params,
+                args: args.len(),
+            });
+        }
+        if results.len() != self.returns {
+            return Err(Trap::ResultsMismatch {
+                returns: self.returns,
+                results: results.len(),
+            });
+        }
+        let (address, native_args) = match self.trampoline {
+            Some(trampoline) => {
+                let mut trampoline_args = vec![results.as_mut_ptr() as isize];
+                trampoline_args.extend_from_slice(args);
+                (trampoline, trampoline_args)
+            }
+            None => (self.address, args.to_vec()),
+        };
+        if native_args.len() > MAX_NATIVE_ARGS {
+            return Err(Trap::TooManyArguments {
+                max: MAX_NATIVE_ARGS - (native_args.len() - args.len()),
+                args: args.len(),
+            });
+        }
+        let args = native_args;
+        let result = runtime::catch_trap(|| unsafe { call_native(address, &args) })?;
+        if self.trampoline.is_none() {
+            results[0] = result;
+        }
+        Ok(())
+    }
+}
+
+/// A collection of state used for translating from toy-language AST nodes
+/// into Cranelift IR.
+struct FunctionTranslator<'a> {
+    int: types::Type,
+
+    /// The type values are kept as: `int`, or a reference type with garbage
+    /// collection. Only moves take references, so the values are converted
+    /// to `int` for other instructions, but only once the values they're
+    /// computed from are: an `int` live across a call isn't in its stack
+    /// map, and it may be the only reference to an object.
+    ty: types::Type,
+
+    builder: FunctionBuilder<'a>,
+    variables: HashMap<String, Variable>,
+    module: &'a mut JITModule,
+
+    /// The address of the heap arrays are allocated on.
+    heap: usize,
+
+    /// The address of the table of compiled functions, which calls through
+    /// function references are checked against.
+    functions: usize,
+
+    /// The structs the variables declared as struct pointers point to.
+    types: HashMap<String, &'a StructLayout>,
+
+    /// The C functions declared, which are called as declared.
+    externs: &'a HashMap<String, ExternFunction>,
+
+    /// The global variables declared, which are accessed by the names which
+    /// aren't those of variables.
+    globals: &'a HashMap<String, DataId>,
+
+    /// The thread-local data objects, whose addresses depend on the thread.
+    thread_locals: &'a HashMap<String, ThreadLocal>,
+
+    /// The values of the constants declared, which are inlined.
+    consts: &'a HashMap<String, i64>,
 
-        // Set up the return variable of the function. Above, we declared a
-        // variable to hold the return value. Here, we just do a use of that
-        // variable.
-        let return_variable = trans.variables.get(&the_return).unwrap();
-        let return_value = trans.builder.use_var(*return_variable);
+    /// The constant tables declared, which are read-only data objects.
+    tables: &'a HashSet<String>,
 
-        // Emit the return instruction.
-        trans.builder.ins().return_(&[return_value]);
+    /// The interned string literals.
+    strings: &'a mut HashMap<String, DataId>,
 
-        // Tell the builder we're done with this function.
-        trans.builder.finalize();
-        Ok(())
-    }
-}
+    /// The source lines of the closures translated, by the names of the
+    /// functions they're lifted into.
+    closure_lines: &'a mut HashMap<String, u32>,
 
-/// A collection of state used for translating from toy-language AST nodes
-/// into Cranelift IR.
-struct FunctionTranslator<'a> {
-    int: types::Type,
-    builder: FunctionBuilder<'a>,
-    variables: HashMap<String, Variable>,
-    module: &'a mut JITModule,
+    /// The source lines of the function's code, and the index of the one
+    /// being translated.
+    lines: &'a [u32],
+    line: usize,
+
+    /// The profiler's counters for the function, if it's instrumented.
+    profile: Option<&'a mut FunctionProfile>,
+
+    /// The coverage counters of the function, if enabled, and the counter of
+    /// the block being translated.
+    coverage: Option<FunctionCoverage>,
+    counter: u32,
+
+    /// The name of the function, and the recorder of what it does if it's
+    /// traced.
+    name: &'a str,
+    tracer: Option<&'a mut Tracer>,
 }
 
 impl<'a> FunctionTranslator<'a> {
+    /// Translate a statement, which takes up the current line of code and
+    /// possibly more, and move on to the next one.
+    fn translate_stmt(&mut self, expr: Expr) -> Value {
+        self.set_srcloc();
+        if let (Some(coverage), Some(&line)) = (&mut self.coverage, self.lines.get(self.line)) {
+            coverage.add_line(line, self.counter);
+        }
+        let value = self.translate_expr(expr);
+        self.line += 1;
+        value
+    }
+
+    /// Attribute the instructions emitted from now on to the current line.
+    fn set_srcloc(&mut self) {
+        let srcloc = match self.lines.get(self.line) {
+            Some(&line) => SourceLoc::new(line),
+            None => SourceLoc::default(),
+        };
+        self.builder.set_srcloc(srcloc);
+    }
+
     /// When you write out instructions in Cranelift, you get back `Value`s. You
     /// can then use these references in other instructions.
     fn translate_expr(&mut self, expr: Expr) -> Value {
         match expr {
             Expr::Literal(literal) => {
                 let imm: i32 = literal.parse().unwrap();
-                self.builder.ins().iconst(self.int, i64::from(imm))
+                let value = self.builder.ins().iconst(self.int, i64::from(imm));
+                self.toy_value(value)
             }
 
             Expr::Add(lhs, rhs) => {
-                let lhs = self.translate_expr(*lhs);
-                let rhs = self.translate_expr(*rhs);
-                self.builder.ins().iadd(lhs, rhs)
+                let (lhs, rhs) = self.translate_operands(*lhs, *rhs);
+                let value = self.builder.ins().iadd(lhs, rhs);
+                self.toy_value(value)
             }
 
             Expr::Sub(lhs, rhs) => {
-                let lhs = self.translate_expr(*lhs);
-                let rhs = self.translate_expr(*rhs);
-                self.builder.ins().isub(lhs, rhs)
+                let (lhs, rhs) = self.translate_operands(*lhs, *rhs);
+                let value = self.builder.ins().isub(lhs, rhs);
+                self.toy_value(value)
             }
 
             Expr::Mul(lhs, rhs) => {
-                let lhs = self.translate_expr(*lhs);
-                let rhs = self.translate_expr(*rhs);
-                self.builder.ins().imul(lhs, rhs)
+                let (lhs, rhs) = self.translate_operands(*lhs, *rhs);
+                let value = self.builder.ins().imul(lhs, rhs);
+                self.toy_value(value)
             }
 
             Expr::Div(lhs, rhs) => {
-                let lhs = self.translate_expr(*lhs);
-                let rhs = self.translate_expr(*rhs);
-                self.builder.ins().udiv(lhs, rhs)
+                let (lhs, rhs) = self.translate_operands(*lhs, *rhs);
+                // `udiv` by zero would take the whole process down.
+                let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                self.trap_if(is_zero, TRAP_DIVISION_BY_ZERO, &[]);
+                let value = self.builder.ins().udiv(lhs, rhs);
+                self.toy_value(value)
             }
 
             Expr::Eq(lhs, rhs) => self.translate_icmp(IntCC::Equal, *lhs, *rhs),
@@ -230,37 +1468,400 @@ impl<'a> FunctionTranslator<'a> {
             Expr::Le(lhs, rhs) => self.translate_icmp(IntCC::SignedLessThanOrEqual, *lhs, *rhs),
             Expr::Gt(lhs, rhs) => self.translate_icmp(IntCC::SignedGreaterThan, *lhs, *rhs),
             Expr::Ge(lhs, rhs) => self.translate_icmp(IntCC::SignedGreaterThanOrEqual, *lhs, *rhs),
-            Expr::Call(name, args) => self.translate_call(name, args),
-            Expr::GlobalDataAddr(name) => self.translate_global_data_addr(name),
-            Expr::Identifier(name) => {
-                // `use_var` is used to read the value of a variable.
-                let variable = self.variables.get(&name).expect("variable not defined");
-                self.builder.use_var(*variable)
+            Expr::Call(name, args) => self.translate_call(name, args, 1)[0],
+            Expr::GlobalDataAddr(name) => {
+                let value = self.translate_global_data_addr(name);
+                self.toy_value(value)
+            }
+            Expr::MakeClosure(name, captures) => self.translate_make_closure(name, captures),
+            Expr::Closure(..) => unreachable!("closures are lifted out before translation"),
+            Expr::Float(_) => unreachable!("`f64(..)` is only allowed in variadic calls"),
+            Expr::Str(string) => {
+                let value = self.translate_string(string);
+                self.toy_value(value)
             }
+            Expr::Identifier(name) => self.translate_variable(&name),
             Expr::Assign(name, expr) => self.translate_assign(name, *expr),
+            Expr::Destructure(names, name, args) => self.translate_destructure(names, name, args),
             Expr::IfElse(condition, then_body, else_body) => {
                 self.translate_if_else(*condition, then_body, else_body)
             }
             Expr::WhileLoop(condition, loop_body) => {
                 self.translate_while_loop(*condition, loop_body)
             }
+            Expr::Array(len) => {
+                let len = self.translate_expr(*len);
+                let len = self.int_value(len);
+                let heap = self.builder.ins().iconst(self.int, self.heap as i64);
+                let array = self
+                    .call_runtime("__toy_array_new", &[heap, len], true)
+                    .unwrap();
+                self.toy_value(array)
+            }
+            Expr::Len(array) => {
+                let array = self.translate_expr(*array);
+                let array = self.int_value(array);
+                let len = self.translate_array_len(array);
+                self.toy_value(len)
+            }
+            Expr::Index(name, index) => {
+                let index = self.translate_expr(*index);
+                let element = self.translate_element_addr(&name, index);
+                let value = self
+                    .builder
+                    .ins()
+                    .load(self.int, MemFlags::trusted(), element, 0);
+                self.toy_value(value)
+            }
+            Expr::AssignIndex(name, index, expr) => {
+                let index = self.translate_expr(*index);
+                let new_value = self.translate_expr(*expr);
+                let element = self.translate_element_addr(&name, index);
+                let stored = self.int_value(new_value);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), stored, element, 0);
+                new_value
+            }
+            Expr::Load(width, pointer) => {
+                let pointer = self.translate_expr(*pointer);
+                let pointer = self.int_value(pointer);
+                let value = self.translate_load(width, false, pointer);
+                self.toy_value(value)
+            }
+            Expr::SignedLoad(width, pointer) => {
+                let pointer = self.translate_expr(*pointer);
+                let pointer = self.int_value(pointer);
+                let value = self.translate_load(width, true, pointer);
+                self.toy_value(value)
+            }
+            Expr::Store(width, pointer, expr) => {
+                let (pointer, value) = self.translate_operands(*pointer, *expr);
+                let new_value = self.toy_value(value);
+                let ty = Type::int(width.bits() as u16).unwrap();
+                let stored = if ty.bits() < self.int.bits() {
+                    self.builder.ins().ireduce(ty, value)
+                } else if ty.bits() > self.int.bits() {
+                    self.builder.ins().sextend(ty, value)
+                } else {
+                    value
+                };
+                self.builder
+                    .ins()
+                    .store(raw_mem_flags(), stored, pointer, 0);
+                new_value
+            }
+            Expr::Annotate(..) => {
+                let value = self.builder.ins().iconst(self.int, 0);
+                self.toy_value(value)
+            }
+            Expr::Field(name, field) => {
+                let value = self.translate_field(&name, &field, None);
+                self.toy_value(value)
+            }
+            Expr::AssignField(name, field, expr) => {
+                let new_value = self.translate_expr(*expr);
+                let stored = self.int_value(new_value);
+                self.translate_field(&name, &field, Some(stored));
+                new_value
+            }
+        }
+    }
+
+    /// Read the field `field` of the struct the variable `name` points to,
+    /// or write `new_value` to it, converting between the field's type and
+    /// values.
+    fn translate_field(&mut self, name: &str, field: &str, new_value: Option<Value>) -> Value {
+        let field = layout::field_layout(&self.types, name, field).expect("field not checked");
+        let variable = self.variables.get(name).expect("variable not defined");
+        let pointer = self.builder.use_var(*variable);
+        let pointer = self.int_value(pointer);
+        let ty = layout::field_type(field.ty, self.int);
+        // The struct is wherever the host put it, so these get the same
+        // flags as the other accesses through raw pointers, except that the
+        // fields are aligned.
+        let mut flags = raw_mem_flags();
+        flags.set_aligned();
+        let offset = field.offset as i32;
+
+        let new_value = match new_value {
+            Some(new_value) => new_value,
+            None => {
+                let loaded = self.builder.ins().load(ty, flags, pointer, offset);
+                return self.value_from_c(field.ty, loaded);
+            }
+        };
+        let stored = self.c_value(field.ty, new_value);
+        self.builder.ins().store(flags, stored, pointer, offset);
+        new_value
+    }
+
+    /// Convert a value of the type `ty`, read from a field or returned by a
+    /// C function, to a value.
+    fn value_from_c(&mut self, ty: FieldType, value: Value) -> Value {
+        let signed = ty.is_signed();
+        let ty = layout::field_type(ty, self.int);
+        if ty.is_float() {
+            // Rounding toward zero, and saturating, like `as` in Rust.
+            self.builder.ins().fcvt_to_sint_sat(self.int, value)
+        } else if ty.bits() < self.int.bits() && signed {
+            self.builder.ins().sextend(self.int, value)
+        } else if ty.bits() < self.int.bits() {
+            self.builder.ins().uextend(self.int, value)
+        } else if ty.bits() > self.int.bits() {
+            self.builder.ins().ireduce(self.int, value)
+        } else {
+            value
+        }
+    }
+
+    /// Convert a value to the type `ty`, to pass it to a C function as an
+    /// argument of that type.
+    fn c_arg(&mut self, ty: FieldType, value: Value) -> Value {
+        let value = self.c_value(ty, value);
+        let arg_ty = c_arg_type(ty, self.int);
+        if arg_ty.bits() > self.builder.func.dfg.value_type(value).bits() {
+            if ty.is_signed() {
+                self.builder.ins().sextend(arg_ty, value)
+            } else {
+                self.builder.ins().uextend(arg_ty, value)
+            }
+        } else {
+            value
+        }
+    }
+
+    /// Convert the value a C function returned as the type `ty` to a value,
+    /// ignoring the bits of the register past those of a narrow integer.
+    fn value_from_c_result(&mut self, ty: FieldType, value: Value) -> Value {
+        let result_ty = layout::field_type(ty, self.int);
+        let value = if !result_ty.is_float()
+            && result_ty.bits() < self.builder.func.dfg.value_type(value).bits()
+        {
+            self.builder.ins().ireduce(result_ty, value)
+        } else {
+            value
+        };
+        self.value_from_c(ty, value)
+    }
+
+    /// Convert a value to the type `ty`, to write it to a field or pass it
+    /// to a C function.
+    fn c_value(&mut self, ty: FieldType, value: Value) -> Value {
+        let ty = layout::field_type(ty, self.int);
+        if ty.is_float() {
+            self.builder.ins().fcvt_from_sint(ty, value)
+        } else if ty.bits() < self.int.bits() {
+            self.builder.ins().ireduce(ty, value)
+        } else if ty.bits() > self.int.bits() {
+            self.builder.ins().sextend(ty, value)
+        } else {
+            value
+        }
+    }
+
+    /// Load an integer of the given width from a raw pointer, and extend or
+    /// truncate it to a value.
+    fn translate_load(&mut self, width: Width, signed: bool, pointer: Value) -> Value {
+        let ty = Type::int(width.bits() as u16).unwrap();
+        let loaded = self.builder.ins().load(ty, raw_mem_flags(), pointer, 0);
+        if ty.bits() < self.int.bits() {
+            if signed {
+                self.builder.ins().sextend(self.int, loaded)
+            } else {
+                self.builder.ins().uextend(self.int, loaded)
+            }
+        } else if ty.bits() > self.int.bits() {
+            self.builder.ins().ireduce(self.int, loaded)
+        } else {
+            loaded
+        }
+    }
+
+    /// Find the length of an array, which is stored right before its
+    /// elements, trapping if the value is null or isn't an array. Any value
+    /// could be used as one, so the runtime looks it up on the heap.
+    fn translate_array_len(&mut self, array: Value) -> Value {
+        let heap = self.builder.ins().iconst(self.int, self.heap as i64);
+        self.call_runtime("__toy_array_len", &[heap, array], true)
+            .unwrap()
+    }
+
+    /// Compute the address of an element of the array in the variable
+    /// `name`, trapping if `index` is out of bounds.
+    fn translate_element_addr(&mut self, name: &str, index: Value) -> Value {
+        let array = self.translate_variable(name);
+        let (array, index) = (self.int_value(array), self.int_value(index));
+        let len = self.translate_array_len(array);
+
+        // Negative indices are huge when compared unsigned, so this catches
+        // them too.
+        let out_of_bounds = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, index, len);
+        self.trap_if(out_of_bounds, TRAP_INDEX_OUT_OF_BOUNDS, &[index, len]);
+
+        let size = i64::from(self.int.bytes());
+        let offset = self.builder.ins().imul_imm(index, size);
+        let element = self.builder.ins().iadd(array, offset);
+        self.builder.ins().iadd_imm(element, size)
+    }
+
+    /// Branch to a trap if `condition` holds. Rather than with a `trap`
+    /// instruction, which would take the whole process down, the trap is
+    /// raised by calling into the runtime, which unwinds back to the host.
+    fn trap_if(&mut self, condition: Value, code: isize, args: &[Value]) {
+        let trap_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder.set_cold_block(trap_block);
+        self.builder
+            .ins()
+            .brif(condition, trap_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(trap_block);
+        self.builder.seal_block(trap_block);
+        let mut trap_args = vec![self.builder.ins().iconst(self.int, code as i64)];
+        trap_args.extend_from_slice(args);
+        while trap_args.len() < 3 {
+            trap_args.push(self.builder.ins().iconst(self.int, 0));
         }
+        self.call_runtime("__toy_trap", &trap_args, true);
+        self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
     }
 
     fn translate_assign(&mut self, name: String, expr: Expr) -> Value {
-        // `def_var` is used to write the value of a variable. Note that
-        // variables can have multiple definitions. Cranelift will
-        // convert them into SSA form for itself automatically.
         let new_value = self.translate_expr(expr);
-        let variable = self.variables.get(&name).unwrap();
-        self.builder.def_var(*variable, new_value);
+        self.assign(&name, new_value);
         new_value
     }
 
+    /// Assign the return values of a call to the variables `names`, one
+    /// each. The value of the assignment is the first one.
+    fn translate_destructure(
+        &mut self,
+        names: Vec<String>,
+        name: String,
+        args: Vec<Expr>,
+    ) -> Value {
+        let results = self.translate_call(name, args, names.len());
+        for (name, &value) in names.iter().zip(&results) {
+            self.assign(name, value);
+        }
+        results[0]
+    }
+
+    fn assign(&mut self, name: &str, new_value: Value) {
+        match self.variables.get(name) {
+            Some(variable) => {
+                // `def_var` is used to write the value of a variable. Note
+                // that variables can have multiple definitions. Cranelift
+                // will convert them into SSA form for itself automatically.
+                self.builder.def_var(*variable, new_value);
+
+                // Tell the debug info which variable the value is, if
+                // enabled.
+                self.builder
+                    .set_val_label(new_value, ValueLabel::new(variable.index()));
+            }
+            None => {
+                let stored = self.int_value(new_value);
+                self.translate_global(name, Some(stored));
+            }
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            let line = self.lines.get(self.line).copied().unwrap_or(0);
+            let address = tracer.add_assign(self.name, line, name);
+            let traced = self.int_value(new_value);
+            self.call_hook("__toy_trace_assign", address, &[traced]);
+        }
+    }
+
+    /// Read the value of the variable `name`, or of the constant or the
+    /// global variable if there's no such variable.
+    fn translate_variable(&mut self, name: &str) -> Value {
+        // `use_var` is used to read the value of a variable.
+        if let Some(variable) = self.variables.get(name) {
+            return self.builder.use_var(*variable);
+        }
+        let value = if let Some(&value) = self.consts.get(name) {
+            self.builder.ins().iconst(self.int, value)
+        } else if self.tables.contains(name) {
+            self.translate_global_data_addr(name.to_owned())
+        } else {
+            self.translate_global(name, None)
+        };
+        self.toy_value(value)
+    }
+
+    /// Read the global variable `name`, or write `new_value` to it, at the
+    /// address of its data object, which `global_value` computes.
+    fn translate_global(&mut self, name: &str, new_value: Option<Value>) -> Value {
+        let id = *self.globals.get(name).expect("variable not defined");
+        let address = match self.thread_locals.get(name) {
+            Some(&tls) => self.translate_tls_addr(tls),
+            None => {
+                let global = self.module.declare_data_in_func(id, self.builder.func);
+                self.builder.ins().global_value(self.int, global)
+            }
+        };
+        match new_value {
+            Some(new_value) => {
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), new_value, address, 0);
+                new_value
+            }
+            None => self
+                .builder
+                .ins()
+                .load(self.int, MemFlags::trusted(), address, 0),
+        }
+    }
+
+    /// Compute the address of the calling thread's copy of a thread-local
+    /// data object.
+    fn translate_tls_addr(&mut self, tls: ThreadLocal) -> Value {
+        let key = self.builder.ins().iconst(self.int, tls.key as i64);
+        let template = self
+            .module
+            .declare_data_in_func(tls.template, self.builder.func);
+        let template = self.builder.ins().global_value(self.int, template);
+        let size = self.builder.ins().iconst(self.int, tls.size as i64);
+        self.call_runtime("__toy_tls_address", &[key, template, size], true)
+            .unwrap()
+    }
+
     fn translate_icmp(&mut self, cmp: IntCC, lhs: Expr, rhs: Expr) -> Value {
+        let (lhs, rhs) = self.translate_operands(lhs, rhs);
+        let c = self.builder.ins().icmp(cmp, lhs, rhs);
+
+        // `icmp` produces an 8-bit 0 or 1, but all values in the toy language
+        // are of the same type, so widen it.
+        let value = self.builder.ins().uextend(self.int, c);
+        self.toy_value(value)
+    }
+
+    /// Translate the operands of a binary operator, and only then convert
+    /// them to `int`, as translating the second may involve calls.
+    fn translate_operands(&mut self, lhs: Expr, rhs: Expr) -> (Value, Value) {
         let lhs = self.translate_expr(lhs);
         let rhs = self.translate_expr(rhs);
-        self.builder.ins().icmp(cmp, lhs, rhs)
+        (self.int_value(lhs), self.int_value(rhs))
+    }
+
+    /// Convert a value to `int`, or back to the type values are kept as.
+    /// These are no-ops without garbage collection.
+    fn int_value(&mut self, value: Value) -> Value {
+        cast(&mut self.builder, self.int, value)
+    }
+
+    fn toy_value(&mut self, value: Value) -> Value {
+        cast(&mut self.builder, self.ty, value)
     }
 
     fn translate_if_else(
@@ -269,7 +1870,9 @@ impl<'a> FunctionTranslator<'a> {
         then_body: Vec<Expr>,
         else_body: Vec<Expr>,
     ) -> Value {
+        let line = self.lines.get(self.line).copied();
         let condition_value = self.translate_expr(condition);
+        let condition_value = self.int_value(condition_value);
 
         let then_block = self.builder.create_block();
         let else_block = self.builder.create_block();
@@ -280,7 +1883,7 @@ impl<'a> FunctionTranslator<'a> {
         // the then and else bodies. Cranelift uses block parameters,
         // so set up a parameter in the merge block, and we'll pass
         // the return values to it from the branches.
-        self.builder.append_block_param(merge_block, self.int);
+        self.builder.append_block_param(merge_block, self.ty);
 
         // Test the if condition and conditionally branch.
         self.builder
@@ -289,20 +1892,28 @@ impl<'a> FunctionTranslator<'a> {
 
         self.builder.switch_to_block(then_block);
         self.builder.seal_block(then_block);
-        let mut then_return = self.builder.ins().iconst(self.int, 0);
+        let then_counter = self.count_block();
+        self.line += 1;
+        let zero = self.builder.ins().iconst(self.int, 0);
+        let mut then_return = self.toy_value(zero);
         for expr in then_body {
-            then_return = self.translate_expr(expr);
+            then_return = self.translate_stmt(expr);
         }
+        self.set_srcloc();
 
         // Jump to the merge block, passing it the block return value.
         self.builder.ins().jump(merge_block, &[then_return]);
 
         self.builder.switch_to_block(else_block);
         self.builder.seal_block(else_block);
-        let mut else_return = self.builder.ins().iconst(self.int, 0);
+        let else_counter = self.count_block();
+        self.line += 1;
+        let zero = self.builder.ins().iconst(self.int, 0);
+        let mut else_return = self.toy_value(zero);
         for expr in else_body {
-            else_return = self.translate_expr(expr);
+            else_return = self.translate_stmt(expr);
         }
+        self.set_srcloc();
 
         // Jump to the merge block, passing it the block return value.
         self.builder.ins().jump(merge_block, &[else_return]);
@@ -312,6 +1923,10 @@ impl<'a> FunctionTranslator<'a> {
 
         // We've now seen all the predecessors of the merge block.
         self.builder.seal_block(merge_block);
+        self.count_block();
+        if let (Some(coverage), Some(line)) = (&mut self.coverage, line) {
+            coverage.add_branch(line, then_counter, else_counter);
+        }
 
         // Read the value of the if-else by reading the merge block
         // parameter.
@@ -321,6 +1936,7 @@ impl<'a> FunctionTranslator<'a> {
     }
 
     fn translate_while_loop(&mut self, condition: Expr, loop_body: Vec<Expr>) -> Value {
+        let line = self.lines.get(self.line).copied();
         let header_block = self.builder.create_block();
         let body_block = self.builder.create_block();
         let exit_block = self.builder.create_block();
@@ -329,6 +1945,7 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.switch_to_block(header_block);
 
         let condition_value = self.translate_expr(condition);
+        let condition_value = self.int_value(condition_value);
         self.builder
             .ins()
             .brif(condition_value, body_block, &[], exit_block, &[]);
@@ -336,12 +1953,25 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.switch_to_block(body_block);
         self.builder.seal_block(body_block);
 
+        if let Some(profile) = &mut self.profile {
+            let line = self.lines.get(self.line).copied().unwrap_or(0);
+            let address = profile.add_loop(line);
+            self.call_hook("__toy_profile_loop", address, &[]);
+        }
+        let body_counter = self.count_block();
+
+        self.line += 1;
         for expr in loop_body {
-            self.translate_expr(expr);
+            self.translate_stmt(expr);
         }
+        self.set_srcloc();
         self.builder.ins().jump(header_block, &[]);
 
         self.builder.switch_to_block(exit_block);
+        let exit_counter = self.count_block();
+        if let (Some(coverage), Some(line)) = (&mut self.coverage, line) {
+            coverage.add_branch(line, body_counter, exit_counter);
+        }
 
         // We've reached the bottom of the loop, so there will be no
         // more backedges to the header to exits to the bottom.
@@ -349,40 +1979,407 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.seal_block(exit_block);
 
         // Just return 0 for now.
-        self.builder.ins().iconst(self.int, 0)
+        let zero = self.builder.ins().iconst(self.int, 0);
+        self.toy_value(zero)
     }
 
-    fn translate_call(&mut self, name: String, args: Vec<Expr>) -> Value {
+    /// Call the function `name` for `results` return values, which the
+    /// call has been checked to get, or which are checked at run time for
+    /// calls through function references.
+    fn translate_call(&mut self, name: String, args: Vec<Expr>, results: usize) -> Vec<Value> {
         let mut sig = self.module.make_signature();
 
-        // Add a parameter for each argument.
-        for _arg in &args {
+        // Calling a variable calls the function it holds a reference to.
+        let indirect = self.variables.get(&name).copied();
+
+        // The functions of the runtime are imported under names of their
+        // own, and get the heap as an extra first argument.
+        let runtime = indirect.is_none() && RUNTIME_FUNCTIONS.iter().any(|(n, _)| *n == name);
+        let symbol = if runtime {
             sig.params.push(AbiParam::new(self.int));
-        }
+            runtime::symbol_name(&name)
+        } else {
+            name.clone()
+        };
 
-        // For simplicity for now, just make all calls return a single I64.
-        sig.returns.push(AbiParam::new(self.int));
+        // C functions are called with the signature they're declared with.
+        // Otherwise, add a parameter for each argument.
+        let externs = self.externs;
+        let function = match indirect {
+            None if !runtime => externs.get(&name),
+            _ => None,
+        };
+        match function {
+            Some(function) => sig = extern_signature(self.module, function),
+            None => {
+                for _arg in &args {
+                    sig.params.push(AbiParam::new(self.int));
+                }
+                sig.returns = vec![AbiParam::new(self.int); results];
+            }
+        }
 
         // TODO: Streamline the API here?
+        let local_callee = match indirect {
+            Some(_) => None,
+            None => {
+                let callee = self
+                    .module
+                    .declare_function(&symbol, Linkage::Import, &sig)
+                    .expect("problem declaring function");
+                Some(self.module.declare_func_in_func(callee, self.builder.func))
+            }
+        };
+
+        // The arguments in `f64(..)` are those passed as doubles.
+        let mut arg_values = Vec::new();
+        let mut floats = Vec::new();
+        for arg in args {
+            match arg {
+                Expr::Float(arg) => {
+                    arg_values.push(self.translate_expr(*arg));
+                    floats.push(true);
+                }
+                arg => {
+                    arg_values.push(self.translate_expr(arg));
+                    floats.push(false);
+                }
+            }
+        }
+
+        // Pass the arguments and the results to the trace hooks through stack
+        // slots, as their signatures can't depend on the callee's.
+        let site = match &mut self.tracer {
+            Some(tracer) => {
+                let line = self.lines.get(self.line).copied().unwrap_or(0);
+                let address = tracer.add_call(self.name, line, &name, arg_values.len());
+                let args = self.stack_values(&arg_values);
+                self.call_hook("__toy_trace_call", address, &[args]);
+                Some(address)
+            }
+            None => None,
+        };
+
+        let mut arg_values: Vec<Value> = arg_values
+            .into_iter()
+            .map(|arg| self.int_value(arg))
+            .collect();
+        if runtime {
+            let heap = self.builder.ins().iconst(self.int, self.heap as i64);
+            arg_values.insert(0, heap);
+        }
+        let results = match (indirect, local_callee) {
+            (Some(variable), _) => self.translate_indirect_call(variable, sig, arg_values),
+            (None, Some(local_callee)) => match function {
+                Some(function) if function.variadic => {
+                    vec![self.translate_variadic_call(local_callee, function, &arg_values, &floats)]
+                }
+                Some(function) => {
+                    let mut arg_values = self.call_args(&arg_values);
+                    for (arg, (_, ty)) in arg_values.iter_mut().zip(&function.params) {
+                        if let Some(ty) = *ty {
+                            *arg = self.c_arg(ty, *arg);
+                        }
+                    }
+                    let call = self.builder.ins().call(local_callee, &arg_values);
+                    let result = self.builder.inst_results(call)[0];
+                    match function.returns {
+                        Some(ty) => vec![self.value_from_c_result(ty, result)],
+                        None => vec![result],
+                    }
+                }
+                None => {
+                    let arg_values = self.call_args(&arg_values);
+                    let call = self.builder.ins().call(local_callee, &arg_values);
+                    self.builder.inst_results(call).to_vec()
+                }
+            },
+            (None, None) => unreachable!(),
+        };
+        let results: Vec<Value> = results
+            .into_iter()
+            .map(|result| self.toy_value(result))
+            .collect();
+        if let Some(address) = site {
+            let traced = self.stack_values(&results);
+            let len = self.builder.ins().iconst(self.int, results.len() as i64);
+            self.call_hook("__toy_trace_return", address, &[traced, len]);
+        }
+        results
+    }
+
+    /// Store `values` as integers to a new stack slot, and return its
+    /// address, to pass any number of them to a hook.
+    fn stack_values(&mut self, values: &[Value]) -> Value {
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            self.int.bytes() * values.len() as u32,
+        ));
+        for (i, &value) in values.iter().enumerate() {
+            let offset = self.int.bytes() as i32 * i as i32;
+            let value = self.int_value(value);
+            self.builder.ins().stack_store(value, slot, offset);
+        }
+        self.builder.ins().stack_addr(self.int, slot, 0)
+    }
+
+    /// Call a variadic C function, through the shim which sets up the call
+    /// like C does, converting the fixed arguments to their declared types,
+    /// and passing the variadic ones `floats` tells as doubles.
+    fn translate_variadic_call(
+        &mut self,
+        callee: FuncRef,
+        function: &ExternFunction,
+        args: &[Value],
+        floats: &[bool],
+    ) -> Value {
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            variadic::ARGS_SIZE as u32,
+        ));
+        let (mut ints, mut doubles) = (0, 0);
+        for (i, (&arg, &float)) in args.iter().zip(floats).enumerate() {
+            let ty = match function.params.get(i) {
+                Some(&(_, ty)) => ty,
+                None if float => Some(FieldType::F64),
+                None => None,
+            };
+            match ty {
+                Some(ty @ (FieldType::F32 | FieldType::F64)) => {
+                    let float = self.c_value(ty, arg);
+                    let offset = 8 * (variadic::MAX_INT_ARGS + doubles) as i32;
+                    self.builder.ins().stack_store(float, slot, offset);
+                    doubles += 1;
+                }
+                _ => {
+                    // Narrow integers are passed extended in full registers.
+                    let arg = match ty {
+                        Some(ty) => {
+                            let arg = self.c_value(ty, arg);
+                            self.value_from_c(ty, arg)
+                        }
+                        None => arg,
+                    };
+                    self.builder.ins().stack_store(arg, slot, 8 * ints);
+                    ints += 1;
+                }
+            }
+        }
+        let address = self.builder.ins().func_addr(self.int, callee);
+        let args = self.builder.ins().stack_addr(self.int, slot, 0);
+        let doubles = self.builder.ins().iconst(self.int, doubles as i64);
+        let result = self
+            .call_runtime("__toy_call_variadic", &[address, args, doubles], true)
+            .unwrap();
+        match function.returns {
+            Some(ty) => self.value_from_c_result(ty, result),
+            None => result,
+        }
+    }
+
+    /// Call the function or the closure the variable `variable` holds a
+    /// reference to.
+    fn translate_indirect_call(
+        &mut self,
+        variable: Variable,
+        mut sig: Signature,
+        args: Vec<Value>,
+    ) -> Vec<Value> {
+        // Check that the callee is a toy function or a closure with as many
+        // parameters as there are arguments, and as many return values as
+        // are expected, as calling anything else would be undefined
+        // behavior. This returns the code of a closure, or 0.
+        let callee = self.builder.use_var(variable);
+        let callee = self.int_value(callee);
+        let functions = self.builder.ins().iconst(self.int, self.functions as i64);
+        let heap = self.builder.ins().iconst(self.int, self.heap as i64);
+        let len = self.builder.ins().iconst(self.int, args.len() as i64);
+        let results = self
+            .builder
+            .ins()
+            .iconst(self.int, sig.returns.len() as i64);
+        let code = self
+            .call_runtime(
+                "__toy_check_call",
+                &[functions, heap, callee, len, results],
+                true,
+            )
+            .unwrap();
+
+        let function_block = self.builder.create_block();
+        let closure_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
+        for _ in &sig.returns {
+            self.builder.append_block_param(merge_block, self.int);
+        }
+        self.builder
+            .ins()
+            .brif(code, closure_block, &[], function_block, &[]);
+
+        self.builder.switch_to_block(function_block);
+        self.builder.seal_block(function_block);
+        let sig_ref = self.builder.import_signature(sig.clone());
+        let mut operands = vec![callee];
+        operands.extend(&args);
+        let operands = self.call_args(&operands);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(sig_ref, operands[0], &operands[1..]);
+        let results = self.builder.inst_results(call).to_vec();
+        self.builder.ins().jump(merge_block, &results);
+
+        // The code of a closure takes the closure first.
+        self.builder.switch_to_block(closure_block);
+        self.builder.seal_block(closure_block);
+        sig.params.push(AbiParam::new(self.int));
+        let sig_ref = self.builder.import_signature(sig);
+        let mut operands = vec![code, callee];
+        operands.extend(&args);
+        let operands = self.call_args(&operands);
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(sig_ref, operands[0], &operands[1..]);
+        let results = self.builder.inst_results(call).to_vec();
+        self.builder.ins().jump(merge_block, &results);
+
+        self.builder.switch_to_block(merge_block);
+        self.builder.seal_block(merge_block);
+        self.builder.block_params(merge_block).to_vec()
+    }
+
+    /// Create a closure, an array holding the address of the function it was
+    /// lifted into, followed by the values of the variables it captures.
+    fn translate_make_closure(&mut self, name: String, captures: Vec<String>) -> Value {
+        if let Some(&line) = self.lines.get(self.line) {
+            self.closure_lines.insert(name.clone(), line);
+        }
+        let heap = self.builder.ins().iconst(self.int, self.heap as i64);
+        let len = self
+            .builder
+            .ins()
+            .iconst(self.int, captures.len() as i64 + 1);
+        let closure = self
+            .call_runtime("__toy_array_new", &[heap, len], true)
+            .unwrap();
+
+        let code = self.translate_global_data_addr(name);
+        let mut values = vec![code];
+        for capture in &captures {
+            let value = self.builder.use_var(self.variables[capture]);
+            values.push(self.int_value(value));
+        }
+        for (i, value) in values.into_iter().enumerate() {
+            let offset = self.int.bytes() as i32 * (i as i32 + 1);
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), value, closure, offset);
+        }
+        self.toy_value(closure)
+    }
+
+    /// Give the block being translated a coverage counter of its own, if
+    /// coverage is enabled, and return its index.
+    fn count_block(&mut self) -> u32 {
+        let counter = match &mut self.coverage {
+            Some(coverage) => coverage.add_counter(),
+            None => return 0,
+        };
+        self.increment_counter(counter);
+        self.counter = counter;
+        counter
+    }
+
+    /// Increment a coverage counter, if coverage is enabled.
+    fn increment_counter(&mut self, counter: u32) {
+        let global_value = match &self.coverage {
+            Some(coverage) => coverage.global_value,
+            None => return,
+        };
+        let counters = self.builder.ins().symbol_value(self.int, global_value);
+        let offset = counter as i32 * 8;
+        let count = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), counters, offset);
+        let count = self.builder.ins().iadd_imm(count, 1);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), count, counters, offset);
+    }
+
+    /// Call one of the profiler's or the tracer's hooks, which take the
+    /// address of their state first, and then `args`.
+    fn call_hook(&mut self, name: &str, address: usize, args: &[Value]) {
+        let mut hook_args = vec![self.builder.ins().iconst(self.int, address as i64)];
+        hook_args.extend_from_slice(args);
+        self.call_runtime(name, &hook_args, false);
+    }
+
+    /// Call a function of the runtime or a hook, all of whose parameters
+    /// are pointer-sized integers, and which returns one if `returns` is set.
+    fn call_runtime(&mut self, name: &str, args: &[Value], returns: bool) -> Option<Value> {
+        let mut sig = self.module.make_signature();
+        for _ in args {
+            sig.params.push(AbiParam::new(self.int));
+        }
+        if returns {
+            sig.returns.push(AbiParam::new(self.int));
+        }
+
         let callee = self
             .module
-            .declare_function(&name, Linkage::Import, &sig)
+            .declare_function(name, Linkage::Import, &sig)
             .expect("problem declaring function");
         let local_callee = self.module.declare_func_in_func(callee, self.builder.func);
 
-        let mut arg_values = Vec::new();
-        for arg in args {
-            arg_values.push(self.translate_expr(arg))
+        let args = self.call_args(args);
+        let call = self.builder.ins().call(local_callee, &args);
+        self.builder.inst_results(call).first().copied()
+    }
+
+    /// Return the arguments to pass to a call. With garbage collection, they
+    /// are copied: converting a reference to `int` is a no-op, so the
+    /// argument would be the reference itself, and the register allocator
+    /// can't handle a reference which is both an argument of a call and in
+    /// its stack map.
+    fn call_args(&mut self, args: &[Value]) -> Vec<Value> {
+        if self.ty == self.int {
+            return args.to_vec();
         }
-        let call = self.builder.ins().call(local_callee, &arg_values);
-        self.builder.inst_results(call)[0]
+        args.iter()
+            .map(|&arg| self.builder.ins().iadd_imm(arg, 0))
+            .collect()
+    }
+
+    /// Intern a string literal into a read-only data object, unless an
+    /// identical one was already, and return its address.
+    fn translate_string(&mut self, string: String) -> Value {
+        let id = intern_string(self.module, self.strings, string);
+        let local_id = self.module.declare_data_in_func(id, self.builder.func);
+        self.builder.ins().symbol_value(self.int, local_id)
     }
 
+    /// Return the address of the function or data object `name`. Those of
+    /// functions are references they can be called through.
     fn translate_global_data_addr(&mut self, name: String) -> Value {
-        let sym = self
-            .module
-            .declare_data(&name, Linkage::Export, true, false)
-            .expect("problem declaring data object");
+        if let Some(&tls) = self.thread_locals.get(&name) {
+            return self.translate_tls_addr(tls);
+        }
+        // Data objects declared already, such as the read-only ones, keep
+        // their declarations.
+        let sym = match self.module.get_name(&name) {
+            Some(FuncOrDataId::Func(id)) => {
+                let local_id = self.module.declare_func_in_func(id, self.builder.func);
+                return self.builder.ins().func_addr(self.int, local_id);
+            }
+            Some(FuncOrDataId::Data(id)) => id,
+            None => self
+                .module
+                .declare_data(&name, Linkage::Export, true, false)
+                .expect("problem declaring data object"),
+        };
         let local_id = self.module.declare_data_in_func(sym, self.builder.func);
 
         let pointer = self.module.target_config().pointer_type();
@@ -390,77 +2387,493 @@ impl<'a> FunctionTranslator<'a> {
     }
 }
 
+/// Resolve the symbol `name` the way the module does: the hooks come first,
+/// then `lookup`, then the symbols of the host process.
+fn lookup_symbol(lookup: &dyn Fn(&str) -> Option<*const u8>, name: &str) -> Option<*const u8> {
+    let (runtime_hooks, profile_hooks, trace_hooks, variadic_hooks) = (
+        runtime::hooks(),
+        profile::hooks(),
+        trace::hooks(),
+        variadic::hooks(),
+    );
+    let hooks = runtime_hooks
+        .iter()
+        .chain(&profile_hooks)
+        .chain(&trace_hooks)
+        .chain(&variadic_hooks);
+    for &(hook, address) in hooks {
+        if hook == name {
+            return Some(address);
+        }
+    }
+    if let Some(address) = lookup(name) {
+        return Some(address);
+    }
+    let name = CString::new(name).ok()?;
+    let address = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    if address.is_null() {
+        None
+    } else {
+        Some(address as *const u8)
+    }
+}
+
+/// Return the read-only data object holding a NUL-terminated copy of
+/// `string`, defining it if it's the first with these contents.
+fn intern_string(
+    module: &mut JITModule,
+    strings: &mut HashMap<String, DataId>,
+    string: String,
+) -> DataId {
+    if let Some(&id) = strings.get(&string) {
+        return id;
+    }
+    let id = module
+        .declare_anonymous_data(false, false)
+        .expect("problem declaring data object");
+    let mut data_ctx = DataContext::new();
+    let mut contents = string.clone().into_bytes();
+    contents.push(0);
+    data_ctx.define(contents.into_boxed_slice());
+    module
+        .define_data(id, &data_ctx)
+        .expect("problem defining data object");
+    strings.insert(string, id);
+    id
+}
+
+/// Declare the parameters, the return variables, and the variables declared
+/// implicitly, which are those assigned which aren't in `globals`.
 fn declare_variables(
     int: types::Type,
+    ty: types::Type,
     builder: &mut FunctionBuilder,
+    globals: &HashMap<String, DataId>,
     params: &[String],
-    the_return: &str,
+    returns: &[String],
     stmts: &[Expr],
-    entry_block: Block,
 ) -> HashMap<String, Variable> {
     let mut variables = HashMap::new();
     let mut index = 0;
+    // The parameters are those of the entry block, which is the current one.
+    let entry_block = builder.current_block().unwrap();
 
     for (i, name) in params.iter().enumerate() {
         // TODO: cranelift_frontend should really have an API to make it easy to set
         // up param variables.
         let val = builder.block_params(entry_block)[i];
-        let var = declare_variable(int, builder, &mut variables, &mut index, name);
+        let val = cast(builder, ty, val);
+        let var = declare_variable(ty, builder, &mut variables, &mut index, name);
         builder.def_var(var, val);
+        builder.set_val_label(val, ValueLabel::new(var.index()));
     }
     let zero = builder.ins().iconst(int, 0);
-    let return_variable = declare_variable(int, builder, &mut variables, &mut index, the_return);
-    builder.def_var(return_variable, zero);
+    let zero = cast(builder, ty, zero);
+    for name in returns {
+        let return_variable = declare_variable(ty, builder, &mut variables, &mut index, name);
+        builder.def_var(return_variable, zero);
+        builder.set_val_label(zero, ValueLabel::new(return_variable.index()));
+    }
     for expr in stmts {
-        declare_variables_in_stmt(int, builder, &mut variables, &mut index, expr);
+        declare_variables_in_stmt(ty, builder, globals, &mut variables, &mut index, expr);
     }
 
     variables
 }
 
+/// Bitcast `value` to `ty`, unless it's of that type already, to convert
+/// between integers and references.
+fn cast(builder: &mut FunctionBuilder, ty: types::Type, value: Value) -> Value {
+    if builder.func.dfg.value_type(value) == ty {
+        value
+    } else {
+        builder.ins().bitcast(ty, MemFlags::new(), value)
+    }
+}
+
+/// The flags of the loads and stores through raw pointers. Nothing is known
+/// about those pointers: they may be misaligned, as in packed C structs, and
+/// may be invalid, in which case the access has to fault where it is rather
+/// than be moved around or assumed to succeed. So unlike the accesses to the
+/// heap, these get none of the `trusted` flags, and use the byte order of the
+/// host, like the C code they interoperate with.
+fn raw_mem_flags() -> MemFlags {
+    MemFlags::new()
+}
+
 /// Recursively descend through the AST, translating all implicit
-/// variable declarations.
+/// variable declarations. Assignments can appear anywhere an expression
+/// can, so this looks inside expressions too, not just statements.
 fn declare_variables_in_stmt(
-    int: types::Type,
+    ty: types::Type,
     builder: &mut FunctionBuilder,
+    globals: &HashMap<String, DataId>,
     variables: &mut HashMap<String, Variable>,
     index: &mut usize,
     expr: &Expr,
 ) {
+    // Assigning a global doesn't declare a variable, unless a parameter or a
+    // return variable shadows it already.
+    let is_local = |variables: &HashMap<String, Variable>, name: &str| {
+        variables.contains_key(name) || !globals.contains_key(name)
+    };
     match *expr {
-        Expr::Assign(ref name, _) => {
-            declare_variable(int, builder, variables, index, name);
+        Expr::Assign(ref name, ref expr) => {
+            if is_local(variables, name) {
+                declare_variable(ty, builder, variables, index, name);
+            }
+            declare_variables_in_stmt(ty, builder, globals, variables, index, expr);
+        }
+        Expr::Array(ref expr)
+        | Expr::Len(ref expr)
+        | Expr::Float(ref expr)
+        | Expr::Index(_, ref expr)
+        | Expr::Load(_, ref expr)
+        | Expr::SignedLoad(_, ref expr) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, expr);
+        }
+        Expr::AssignIndex(_, ref array_index, ref expr)
+        | Expr::Store(_, ref array_index, ref expr) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, array_index);
+            declare_variables_in_stmt(ty, builder, globals, variables, index, expr);
+        }
+        Expr::Eq(ref lhs, ref rhs)
+        | Expr::Ne(ref lhs, ref rhs)
+        | Expr::Lt(ref lhs, ref rhs)
+        | Expr::Le(ref lhs, ref rhs)
+        | Expr::Gt(ref lhs, ref rhs)
+        | Expr::Ge(ref lhs, ref rhs)
+        | Expr::Add(ref lhs, ref rhs)
+        | Expr::Sub(ref lhs, ref rhs)
+        | Expr::Mul(ref lhs, ref rhs)
+        | Expr::Div(ref lhs, ref rhs) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, lhs);
+            declare_variables_in_stmt(ty, builder, globals, variables, index, rhs);
         }
-        Expr::IfElse(ref _condition, ref then_body, ref else_body) => {
+        Expr::IfElse(ref condition, ref then_body, ref else_body) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, condition);
             for stmt in then_body {
-                declare_variables_in_stmt(int, builder, variables, index, stmt);
+                declare_variables_in_stmt(ty, builder, globals, variables, index, stmt);
             }
             for stmt in else_body {
-                declare_variables_in_stmt(int, builder, variables, index, stmt);
+                declare_variables_in_stmt(ty, builder, globals, variables, index, stmt);
             }
         }
-        Expr::WhileLoop(ref _condition, ref loop_body) => {
+        Expr::WhileLoop(ref condition, ref loop_body) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, condition);
             for stmt in loop_body {
-                declare_variables_in_stmt(int, builder, variables, index, stmt);
+                declare_variables_in_stmt(ty, builder, globals, variables, index, stmt);
+            }
+        }
+        Expr::Call(_, ref args) => {
+            for arg in args {
+                declare_variables_in_stmt(ty, builder, globals, variables, index, arg);
+            }
+        }
+        Expr::Destructure(ref names, _, ref args) => {
+            for name in names {
+                if is_local(variables, name) {
+                    declare_variable(ty, builder, variables, index, name);
+                }
+            }
+            for arg in args {
+                declare_variables_in_stmt(ty, builder, globals, variables, index, arg);
+            }
+        }
+        Expr::Annotate(ref name, _) => {
+            declare_variable(ty, builder, variables, index, name);
+        }
+        Expr::AssignField(_, _, ref expr) => {
+            declare_variables_in_stmt(ty, builder, globals, variables, index, expr);
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Field(..)
+        | Expr::Closure(..)
+        | Expr::MakeClosure(..) => (),
+    }
+}
+
+/// Recursively descend through the AST, checking the calls to the C
+/// functions declared in `externs` against their declarations, and those to
+/// functions declared in `module` against their signatures. Calls through
+/// the variables in `variables` are checked when they're made.
+fn check_calls(
+    module: &JITModule,
+    externs: &HashMap<String, ExternFunction>,
+    variables: &HashMap<String, Variable>,
+    expr: &Expr,
+) -> Result<(), String> {
+    let check = |expr| check_calls(module, externs, variables, expr);
+    match expr {
+        Expr::Call(name, args) | Expr::Destructure(_, name, args) => {
+            let results = match expr {
+                Expr::Destructure(names, ..) => names.len(),
+                _ => 1,
+            };
+            let mut fixed = args.len();
+            if !variables.contains_key(name) {
+                check_call(module, externs, name, args, results)?;
+                match externs.get(name) {
+                    Some(function) if function.variadic => fixed = function.params.len(),
+                    _ => (),
+                }
+            }
+            for (i, arg) in args.iter().enumerate() {
+                match arg {
+                    Expr::Float(arg) if i >= fixed => check(arg)?,
+                    arg => check(arg)?,
+                }
+            }
+            Ok(())
+        }
+        Expr::Float(_) => Err("`f64(..)` can only be passed as a variadic argument".to_owned()),
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::Field(..)
+        | Expr::Closure(..)
+        | Expr::MakeClosure(..) => Ok(()),
+        Expr::Assign(_, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Index(_, expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::AssignField(_, _, expr) => check(expr),
+        Expr::AssignIndex(_, lhs, rhs)
+        | Expr::Store(_, lhs, rhs)
+        | Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            check(lhs)?;
+            check(rhs)
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            check(condition)?;
+            then_body.iter().chain(else_body).try_for_each(check)
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            check(condition)?;
+            loop_body.iter().try_for_each(check)
+        }
+    }
+}
+
+/// Recursively descend through the AST, checking that the names read, and
+/// the arrays indexed, are defined, as `is_defined` tells, and that the
+/// functions called are, as `is_callable` does.
+fn check_names(
+    is_defined: &dyn Fn(&str) -> bool,
+    is_callable: &dyn Fn(&str) -> bool,
+    expr: &Expr,
+) -> Result<(), String> {
+    let check = |expr| check_names(is_defined, is_callable, expr);
+    let check_name = |name: &str| {
+        if is_defined(name) {
+            Ok(())
+        } else {
+            Err(format!("variable `{}` not defined", name))
+        }
+    };
+    match expr {
+        Expr::Identifier(name) => check_name(name),
+        Expr::Index(name, expr) => {
+            check_name(name)?;
+            check(expr)
+        }
+        Expr::AssignIndex(name, lhs, rhs) => {
+            check_name(name)?;
+            check(lhs)?;
+            check(rhs)
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::Field(..)
+        | Expr::Closure(..) => Ok(()),
+        Expr::MakeClosure(_, captures) => captures.iter().try_for_each(|name| check_name(name)),
+        Expr::Assign(_, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::Float(expr)
+        | Expr::AssignField(_, _, expr) => check(expr),
+        Expr::Store(_, lhs, rhs)
+        | Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            check(lhs)?;
+            check(rhs)
+        }
+        Expr::Call(name, args) | Expr::Destructure(_, name, args) => {
+            if !is_callable(name) {
+                return Err(format!("function `{}` not defined", name));
+            }
+            args.iter().try_for_each(check)
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            check(condition)?;
+            then_body.iter().chain(else_body).try_for_each(check)
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            check(condition)?;
+            loop_body.iter().try_for_each(check)
+        }
+    }
+}
+
+/// Return the signature a C function is called with. Variadic functions are
+/// called through a shim, which only takes their address, so only their
+/// fixed parameters are declared, and they return a value as is.
+fn extern_signature(module: &JITModule, function: &ExternFunction) -> Signature {
+    let int = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    // `"system"` is the convention of the platform's system libraries,
+    // which on the targets Cranelift supports is the same as `"C"`'s.
+    if function.abi.as_deref() == Some("system") {
+        sig.call_conv = CallConv::triple_default(module.isa().triple());
+    }
+    // C expects integers narrower than registers to be extended by whoever
+    // passes them, which Cranelift does on the targets requiring it to the
+    // full register.
+    let param = |ty: Option<FieldType>| match ty {
+        Some(ty @ (FieldType::I8 | FieldType::I16 | FieldType::I32)) => {
+            AbiParam::new(c_arg_type(ty, int)).sext()
+        }
+        Some(ty @ (FieldType::U8 | FieldType::U16 | FieldType::U32)) => {
+            AbiParam::new(c_arg_type(ty, int)).uext()
+        }
+        Some(ty) => AbiParam::new(c_arg_type(ty, int)),
+        None => AbiParam::new(int),
+    };
+    sig.params = function.params.iter().map(|&(_, ty)| param(ty)).collect();
+    sig.returns = match function.variadic {
+        true => vec![AbiParam::new(int)],
+        false => vec![param(function.returns)],
+    };
+    sig
+}
+
+/// Return the type an argument or a return value of the type `ty` has in the
+/// signature of a C function. Integers narrower than 32 bits are extended to
+/// 32 bits by the code passing them, as C compilers expect on x86-64 and
+/// AArch64, but Cranelift doesn't do there.
+fn c_arg_type(ty: FieldType, int: Type) -> Type {
+    let ty = layout::field_type(ty, int);
+    if ty.is_int() && ty.bits() < 32 {
+        types::I32
+    } else {
+        ty
+    }
+}
+
+/// Check a call to the function `name`, which isn't a variable. Anything but
+/// a toy function returns one value, like the functions of the runtime and
+/// of the host.
+fn check_call(
+    module: &JITModule,
+    externs: &HashMap<String, ExternFunction>,
+    name: &str,
+    args: &[Expr],
+    results: usize,
+) -> Result<(), String> {
+    let (params, returns) = match (externs.get(name), module.get_name(name)) {
+        (Some(function), _) if function.variadic => {
+            let params = function.params.len();
+            if args.len() < params {
+                return Err(format!(
+                    "function `{}` takes at least {} arguments but {} were supplied",
+                    name,
+                    params,
+                    args.len()
+                ));
+            }
+            if !variadic::supported() {
+                return Err("variadic calls aren't supported on this platform".to_owned());
             }
+            let fixed_floats = function
+                .params
+                .iter()
+                .filter(|(_, ty)| matches!(ty, Some(FieldType::F32 | FieldType::F64)))
+                .count();
+            let floats = fixed_floats
+                + args[params..]
+                    .iter()
+                    .filter(|arg| matches!(arg, Expr::Float(_)))
+                    .count();
+            if args.len() - floats > variadic::MAX_INT_ARGS || floats > variadic::MAX_FLOAT_ARGS {
+                return Err(format!(
+                    "variadic calls can pass at most {} integer and {} floating-point arguments",
+                    variadic::MAX_INT_ARGS,
+                    variadic::MAX_FLOAT_ARGS
+                ));
+            }
+            (None, 1)
+        }
+        (Some(function), _) => (Some(function.params.len()), 1),
+        (None, Some(FuncOrDataId::Func(id))) => {
+            let signature = &module.declarations().get_function_decl(id).signature;
+            (Some(signature.params.len()), signature.returns.len())
+        }
+        (None, Some(FuncOrDataId::Data(_))) => {
+            return Err(format!("`{}` is not a function", name));
+        }
+        _ => (None, 1),
+    };
+    if let Some(params) = params {
+        if params != args.len() {
+            return Err(format!(
+                "function `{}` takes {} arguments but {} were supplied",
+                name,
+                params,
+                args.len()
+            ));
         }
-        _ => (),
     }
+    if returns != results {
+        return Err(format!(
+            "function `{}` returns {} values but {} were expected",
+            name, returns, results
+        ));
+    }
+    Ok(())
 }
 
 /// Declare a single variable declaration.
 fn declare_variable(
-    int: types::Type,
+    ty: types::Type,
     builder: &mut FunctionBuilder,
     variables: &mut HashMap<String, Variable>,
     index: &mut usize,
     name: &str,
 ) -> Variable {
-    let var = Variable::new(*index);
-    if !variables.contains_key(name) {
-        variables.insert(name.into(), var);
-        builder.declare_var(var, int);
-        *index += 1;
+    if let Some(var) = variables.get(name) {
+        return *var;
     }
+    let var = Variable::new(*index);
+    variables.insert(name.into(), var);
+    builder.declare_var(var, ty);
+    *index += 1;
     var
 }
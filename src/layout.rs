@@ -0,0 +1,252 @@
+use crate::frontend::*;
+use cranelift::codegen::ir::Type;
+use cranelift::codegen::isa::{CallConv, TargetFrontendConfig};
+use cranelift::prelude::*;
+use std::collections::HashMap;
+
+/// The layout of a toy struct, which matches that of the C struct with the
+/// same fields on the target, so that pointers to `#[repr(C)]` structs can
+/// be passed between the host and toy code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StructLayout {
+    pub name: String,
+    pub size: u32,
+    pub align: u32,
+    pub fields: Vec<FieldLayout>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: String,
+    pub ty: FieldType,
+    pub offset: u32,
+}
+
+impl StructLayout {
+    /// Lay out the fields in order, each at the next offset which is a
+    /// multiple of its alignment, and pad the struct to a multiple of the
+    /// largest alignment, like C compilers do.
+    pub fn new(
+        name: &str,
+        fields: &[(String, FieldType)],
+        config: TargetFrontendConfig,
+    ) -> Result<Self, String> {
+        let mut layout = Self {
+            name: name.to_owned(),
+            size: 0,
+            align: 1,
+            fields: Vec::new(),
+        };
+        for (field, ty) in fields {
+            if layout.field(field).is_some() {
+                return Err(format!(
+                    "field `{}` is declared more than once in struct `{}`",
+                    field, name
+                ));
+            }
+            let (size, align) = size_and_align(*ty, config);
+            let offset = align_to(layout.size, align);
+            layout.fields.push(FieldLayout {
+                name: field.clone(),
+                ty: *ty,
+                offset,
+            });
+            layout.size = offset + size;
+            layout.align = layout.align.max(align);
+        }
+        layout.size = align_to(layout.size, layout.align);
+        Ok(layout)
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldLayout> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+fn size_and_align(ty: FieldType, config: TargetFrontendConfig) -> (u32, u32) {
+    let size = match ty {
+        FieldType::I8 | FieldType::U8 => 1,
+        FieldType::I16 | FieldType::U16 => 2,
+        FieldType::I32 | FieldType::U32 | FieldType::F32 => 4,
+        FieldType::I64 | FieldType::U64 | FieldType::F64 => 8,
+        FieldType::Ptr => u32::from(config.pointer_bytes()),
+    };
+    // The i386 System V ABI only aligns 8-byte integers and doubles in
+    // structs to 4 bytes. Everywhere else, all of these are aligned to
+    // their size.
+    let align = if config.pointer_bits() == 32 && config.default_call_conv == CallConv::SystemV {
+        size.min(4)
+    } else {
+        size
+    };
+    (size, align)
+}
+
+fn align_to(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
+
+/// The Cranelift type a field is stored as in memory.
+pub(crate) fn field_type(ty: FieldType, int: Type) -> Type {
+    match ty {
+        FieldType::I8 | FieldType::U8 => types::I8,
+        FieldType::I16 | FieldType::U16 => types::I16,
+        FieldType::I32 | FieldType::U32 => types::I32,
+        FieldType::I64 | FieldType::U64 => types::I64,
+        FieldType::F32 => types::F32,
+        FieldType::F64 => types::F64,
+        FieldType::Ptr => int,
+    }
+}
+
+/// The struct declarations a `JIT` or an `Interpreter` knows about.
+#[derive(Default)]
+pub(crate) struct Structs {
+    layouts: HashMap<String, StructLayout>,
+}
+
+impl Structs {
+    pub(crate) fn declare(
+        &mut self,
+        name: &str,
+        fields: &[(String, FieldType)],
+        config: TargetFrontendConfig,
+    ) -> Result<(), String> {
+        if self.layouts.contains_key(name) {
+            return Err(format!("struct `{}` is declared more than once", name));
+        }
+        let layout = StructLayout::new(name, fields, config)?;
+        self.layouts.insert(name.to_owned(), layout);
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&StructLayout> {
+        self.layouts.get(name)
+    }
+
+    /// Find the struct types of the variables of a function, from the
+    /// annotations in its body, and check that each field it accesses
+    /// exists.
+    pub(crate) fn variable_types(
+        &self,
+        stmts: &[Expr],
+    ) -> Result<HashMap<String, &StructLayout>, String> {
+        let mut types = HashMap::new();
+        for expr in stmts {
+            self.annotations_in_stmt(&mut types, expr)?;
+        }
+        for expr in stmts {
+            check_fields_in_stmt(&types, expr)?;
+        }
+        Ok(types)
+    }
+
+    fn annotations_in_stmt<'a>(
+        &'a self,
+        types: &mut HashMap<String, &'a StructLayout>,
+        expr: &Expr,
+    ) -> Result<(), String> {
+        match expr {
+            Expr::Annotate(name, struct_name) => {
+                let layout = self
+                    .get(struct_name)
+                    .ok_or_else(|| format!("struct `{}` not declared", struct_name))?;
+                if let Some(previous) = types.insert(name.clone(), layout) {
+                    if previous.name != layout.name {
+                        return Err(format!(
+                            "variable `{}` is declared as both `{}` and `{}`",
+                            name, previous.name, layout.name
+                        ));
+                    }
+                }
+            }
+            Expr::IfElse(_, then_body, else_body) => {
+                for expr in then_body.iter().chain(else_body) {
+                    self.annotations_in_stmt(types, expr)?;
+                }
+            }
+            Expr::WhileLoop(_, loop_body) => {
+                for expr in loop_body {
+                    self.annotations_in_stmt(types, expr)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Check the field accesses in an expression, recursively.
+fn check_fields_in_stmt(types: &HashMap<String, &StructLayout>, expr: &Expr) -> Result<(), String> {
+    let check = |expr| check_fields_in_stmt(types, expr);
+    match expr {
+        Expr::Field(name, field) => field_layout(types, name, field).map(|_| ()),
+        Expr::AssignField(name, field, expr) => {
+            field_layout(types, name, field)?;
+            check(expr)
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::MakeClosure(..) => Ok(()),
+        Expr::Assign(_, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Float(expr)
+        | Expr::Index(_, expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::Closure(_, expr) => check(expr),
+        Expr::AssignIndex(_, lhs, rhs)
+        | Expr::Store(_, lhs, rhs)
+        | Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            check(lhs)?;
+            check(rhs)
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            check(condition)?;
+            then_body.iter().chain(else_body).try_for_each(check)
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            check(condition)?;
+            loop_body.iter().try_for_each(check)
+        }
+        Expr::Call(_, args) | Expr::Destructure(_, _, args) => args.iter().try_for_each(check),
+    }
+}
+
+/// Return the layout of the field `field` of the struct the variable `name`
+/// points to.
+pub(crate) fn field_layout<'a>(
+    types: &HashMap<String, &'a StructLayout>,
+    name: &str,
+    field: &str,
+) -> Result<&'a FieldLayout, String> {
+    let layout = types
+        .get(name)
+        .ok_or_else(|| format!("variable `{}` isn't declared as a struct pointer", name))?;
+    layout
+        .field(field)
+        .ok_or_else(|| format!("struct `{}` has no field `{}`", layout.name, field))
+}
+
+/// The target configuration of the host, for laying out structs without
+/// an ISA, as the interpreter does.
+pub(crate) fn host_config() -> TargetFrontendConfig {
+    let isa = cranelift_native::builder()
+        .unwrap_or_else(|msg| panic!("host machine is not supported: {}", msg))
+        .finish(settings::Flags::new(settings::builder()))
+        .unwrap();
+    isa.frontend_config()
+}
@@ -1,2 +1,17 @@
+pub mod clif_interp;
+mod closure;
+pub mod coverage;
+pub mod debug_info;
+pub mod format;
 pub mod frontend;
+mod gc;
+pub mod interp;
 pub mod jit;
+pub mod layout;
+pub mod perf;
+pub mod profile;
+pub mod runtime;
+pub mod tiered;
+pub mod trace;
+mod unwind;
+mod variadic;
@@ -0,0 +1,250 @@
+use crate::debug_info::elf_machine;
+use crate::jit::CompiledFunction;
+use cranelift::codegen::isa::TargetIsa;
+use cranelift_jit::JITModule;
+use cranelift_module::FuncId;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::slice;
+use std::sync::Mutex;
+
+/// Tells `perf` about JIT'd toy functions, so that it can name their frames
+/// instead of showing anonymous addresses.
+///
+/// There are two ways to do that: the perf map, a text file with the name
+/// and address range of each function, which `perf report` reads on its own,
+/// and the jitdump format, which also has the code and the source lines, but
+/// has to be merged into a profile with `perf inject --jit` first. Both files
+/// are per process, and shared by all the `JIT`s in it.
+#[derive(Default)]
+pub(crate) struct Perf {
+    perf_map: bool,
+    jitdump: bool,
+
+    /// The functions compiled since the last time they were written out,
+    /// which can't be written out before they're finalized.
+    pending: Vec<FunctionInfo>,
+}
+
+struct FunctionInfo {
+    id: FuncId,
+    name: String,
+    file_name: String,
+    code_size: u32,
+    lines: Vec<(u32, u32)>,
+}
+
+/// The perf map of this process, once opened.
+static PERF_MAP: Mutex<Option<File>> = Mutex::new(None);
+
+/// The jitdump file of this process, once created.
+static JITDUMP: Mutex<Option<JitDump>> = Mutex::new(None);
+
+impl Perf {
+    pub(crate) fn enable_perf_map(&mut self) -> Result<(), String> {
+        let mut perf_map = PERF_MAP.lock().unwrap();
+        if perf_map.is_none() {
+            let path = format!("/tmp/perf-{}.map", process::id());
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+            *perf_map = Some(file);
+        }
+        self.perf_map = true;
+        Ok(())
+    }
+
+    /// Write the functions to a jitdump file in `directory`, unless another
+    /// `JIT` of this process created one already, which is used instead.
+    pub(crate) fn enable_jitdump(
+        &mut self,
+        directory: &Path,
+        isa: &dyn TargetIsa,
+    ) -> Result<(), String> {
+        let mut jitdump = JITDUMP.lock().unwrap();
+        if jitdump.is_none() {
+            *jitdump = Some(JitDump::create(directory, isa)?);
+        }
+        self.jitdump = true;
+        Ok(())
+    }
+
+    /// Record a function which was just compiled.
+    pub(crate) fn add_function(&mut self, function: &CompiledFunction) {
+        self.pending.push(FunctionInfo {
+            id: function.id,
+            name: function.name.to_owned(),
+            file_name: function.file_name.to_owned(),
+            code_size: function.code.buffer.data().len() as u32,
+            lines: function.lines(),
+        });
+    }
+
+    /// Write out the functions compiled since the last time, now that they
+    /// are finalized.
+    pub(crate) fn register(&mut self, module: &JITModule) -> Result<(), String> {
+        for function in self.pending.drain(..) {
+            let address = module.get_finalized_function(function.id);
+            if self.perf_map {
+                let entry = format!(
+                    "{:x} {:x} {}\n",
+                    address as usize, function.code_size, function.name
+                );
+                if let Some(file) = &mut *PERF_MAP.lock().unwrap() {
+                    file.write_all(entry.as_bytes())
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+            if self.jitdump {
+                // TODO: Can we move the unsafe into cranelift?
+                let code = unsafe { slice::from_raw_parts(address, function.code_size as usize) };
+                if let Some(jitdump) = &mut *JITDUMP.lock().unwrap() {
+                    jitdump
+                        .write_function(&function, code)
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The layout of jitdump files is described in `tools/perf/Documentation/
+// jitdump-specification.txt` in the Linux sources. All the fields are in the
+// byte order of the host.
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+
+struct JitDump {
+    file: File,
+
+    /// The number of functions written so far, which identifies the next.
+    code_index: u64,
+}
+
+impl JitDump {
+    #[cfg(target_os = "linux")]
+    fn create(directory: &Path, isa: &dyn TargetIsa) -> Result<Self, String> {
+        let path = directory.join(format!("jit-{}.dump", process::id()));
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(error)?;
+
+        // `perf record` finds the file through an executable mapping of it,
+        // which is left in place for the lifetime of the process.
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+            let marker = libc::mmap(
+                std::ptr::null_mut(),
+                libc::sysconf(libc::_SC_PAGESIZE) as usize,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            );
+            if marker == libc::MAP_FAILED {
+                return Err(error(std::io::Error::last_os_error()));
+            }
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&JITDUMP_MAGIC.to_ne_bytes());
+        header.extend_from_slice(&JITDUMP_VERSION.to_ne_bytes());
+        header.extend_from_slice(&40u32.to_ne_bytes());
+        header.extend_from_slice(&u32::from(elf_machine(isa)?).to_ne_bytes());
+        header.extend_from_slice(&0u32.to_ne_bytes());
+        header.extend_from_slice(&process::id().to_ne_bytes());
+        header.extend_from_slice(&timestamp().to_ne_bytes());
+        header.extend_from_slice(&0u64.to_ne_bytes());
+        file.write_all(&header).map_err(error)?;
+
+        Ok(Self {
+            file,
+            code_index: 0,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn create(_directory: &Path, _isa: &dyn TargetIsa) -> Result<Self, String> {
+        Err("jitdump files are only supported on Linux".to_owned())
+    }
+
+    /// Write the source lines of a function, followed by the function itself.
+    fn write_function(&mut self, function: &FunctionInfo, code: &[u8]) -> std::io::Result<()> {
+        let address = code.as_ptr() as u64;
+
+        let mut debug_info = Vec::new();
+        debug_info.extend_from_slice(&address.to_ne_bytes());
+        debug_info.extend_from_slice(&(function.lines.len() as u64).to_ne_bytes());
+        for &(offset, line) in &function.lines {
+            debug_info.extend_from_slice(&(address + u64::from(offset)).to_ne_bytes());
+            debug_info.extend_from_slice(&line.to_ne_bytes());
+            debug_info.extend_from_slice(&0u32.to_ne_bytes());
+            debug_info.extend_from_slice(function.file_name.as_bytes());
+            debug_info.push(0);
+        }
+        self.write_record(JIT_CODE_DEBUG_INFO, &debug_info)?;
+
+        let mut load = Vec::new();
+        load.extend_from_slice(&process::id().to_ne_bytes());
+        load.extend_from_slice(&thread_id().to_ne_bytes());
+        load.extend_from_slice(&address.to_ne_bytes());
+        load.extend_from_slice(&address.to_ne_bytes());
+        load.extend_from_slice(&(code.len() as u64).to_ne_bytes());
+        load.extend_from_slice(&self.code_index.to_ne_bytes());
+        load.extend_from_slice(function.name.as_bytes());
+        load.push(0);
+        load.extend_from_slice(code);
+        self.write_record(JIT_CODE_LOAD, &load)?;
+
+        self.code_index += 1;
+        Ok(())
+    }
+
+    fn write_record(&mut self, id: u32, body: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(16 + body.len());
+        record.extend_from_slice(&id.to_ne_bytes());
+        record.extend_from_slice(&(16 + body.len() as u32).to_ne_bytes());
+        record.extend_from_slice(&timestamp().to_ne_bytes());
+        record.extend_from_slice(body);
+        self.file.write_all(&record)
+    }
+}
+
+/// The time in nanoseconds on the clock `perf record -k mono` uses.
+#[cfg(target_os = "linux")]
+fn timestamp() -> u64 {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+// There are no jitdump files elsewhere, so these are never called.
+#[cfg(not(target_os = "linux"))]
+fn timestamp() -> u64 {
+    0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u32 {
+    0
+}
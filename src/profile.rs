@@ -0,0 +1,258 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Return the host functions the instrumented code calls, by the names it
+/// imports them under.
+pub(crate) fn hooks() -> [(&'static str, *const u8); 3] {
+    [
+        ("__toy_profile_enter", enter as *const u8),
+        ("__toy_profile_exit", exit as *const u8),
+        ("__toy_profile_loop", loop_iteration as *const u8),
+    ]
+}
+
+/// An instrumentation profiler for toy functions.
+///
+/// Once enabled with `JIT::enable_profiler`, the JIT inserts calls to hooks
+/// into the functions it compiles: on entry, before returning, and at the
+/// start of each loop iteration. The hooks count calls and iterations and
+/// time each call with the CPU's cycle counter, without needing an external
+/// profiler like `perf`.
+#[derive(Default)]
+pub(crate) struct Profiler {
+    /// The counters of the instrumented functions. Each is boxed, as the
+    /// code refers to it by its address.
+    #[allow(clippy::vec_box)]
+    functions: Vec<Box<FunctionProfile>>,
+}
+
+pub(crate) struct FunctionProfile {
+    name: String,
+    calls: AtomicU64,
+    inclusive_cycles: AtomicU64,
+    exclusive_cycles: AtomicU64,
+    #[allow(clippy::vec_box)] // As for `Profiler::functions`.
+    loops: Vec<Box<LoopProfile>>,
+}
+
+struct LoopProfile {
+    line: u32,
+    iterations: AtomicU64,
+}
+
+impl Profiler {
+    /// Add the counters for a function which is about to be instrumented.
+    pub(crate) fn add_function(&mut self, name: &str) -> &mut FunctionProfile {
+        self.functions.push(Box::new(FunctionProfile {
+            name: name.to_owned(),
+            calls: AtomicU64::new(0),
+            inclusive_cycles: AtomicU64::new(0),
+            exclusive_cycles: AtomicU64::new(0),
+            loops: Vec::new(),
+        }));
+        self.functions.last_mut().unwrap()
+    }
+
+    pub(crate) fn report(&self) -> ProfileReport {
+        let mut functions: Vec<FunctionReport> = self
+            .functions
+            .iter()
+            .map(|function| FunctionReport {
+                name: function.name.clone(),
+                calls: function.calls.load(Ordering::Relaxed),
+                inclusive_cycles: function.inclusive_cycles.load(Ordering::Relaxed),
+                exclusive_cycles: function.exclusive_cycles.load(Ordering::Relaxed),
+                loops: function
+                    .loops
+                    .iter()
+                    .map(|l| LoopReport {
+                        line: l.line,
+                        iterations: l.iterations.load(Ordering::Relaxed),
+                    })
+                    .collect(),
+            })
+            .collect();
+        functions.sort_by_key(|function| Reverse(function.inclusive_cycles));
+        ProfileReport { functions }
+    }
+}
+
+impl FunctionProfile {
+    /// Return the address the entry and exit hooks are called with.
+    pub(crate) fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Add the counter for a loop on the source line `line`, and return the
+    /// address the iteration hook is called with.
+    pub(crate) fn add_loop(&mut self, line: u32) -> usize {
+        let counter = Box::new(LoopProfile {
+            line,
+            iterations: AtomicU64::new(0),
+        });
+        let address = &*counter as *const LoopProfile as usize;
+        self.loops.push(counter);
+        address
+    }
+}
+
+/// The counters of the instrumented functions, as returned by
+/// `JIT::profile_report`. Displaying it prints a table, with the functions
+/// which took the most time first.
+#[derive(Clone, Debug)]
+pub struct ProfileReport {
+    pub functions: Vec<FunctionReport>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FunctionReport {
+    pub name: String,
+    pub calls: u64,
+
+    /// The cycles spent in calls of the function, including the functions it
+    /// calls. The cycles of recursive calls are only counted once.
+    pub inclusive_cycles: u64,
+
+    /// The cycles spent in the function's own code.
+    pub exclusive_cycles: u64,
+
+    /// The loops of the function, in source order.
+    pub loops: Vec<LoopReport>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LoopReport {
+    /// The source line of the `while`.
+    pub line: u32,
+    pub iterations: u64,
+}
+
+impl FunctionReport {
+    /// Return the number of iterations of all the function's loops.
+    pub fn loop_iterations(&self) -> u64 {
+        self.loops.iter().map(|l| l.iterations).sum()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>10} {:>16} {:>16} {:>12}",
+            "function", "calls", "inclusive", "exclusive", "iterations"
+        )?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:<24} {:>10} {:>16} {:>16} {:>12}",
+                function.name,
+                function.calls,
+                function.inclusive_cycles,
+                function.exclusive_cycles,
+                function.loop_iterations()
+            )?;
+            for l in &function.loops {
+                writeln!(
+                    f,
+                    "  {:<22} {:>10} {:>16} {:>16} {:>12}",
+                    format!("loop at line {}", l.line),
+                    "",
+                    "",
+                    "",
+                    l.iterations
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A call of an instrumented function which hasn't returned yet.
+struct Frame {
+    function: *const FunctionProfile,
+    start: u64,
+
+    /// The cycles spent in the instrumented functions it called so far.
+    children: u64,
+}
+
+thread_local! {
+    static STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Return how many calls of instrumented functions the current thread is
+/// in.
+pub(crate) fn stack_depth() -> usize {
+    STACK.with(|stack| stack.borrow().len())
+}
+
+/// Drop the frames of the calls a trap unwound out of, which never got to
+/// call the exit hook, down to the `depth` the stack had before.
+pub(crate) fn unwind_stack(depth: usize) {
+    STACK.with(|stack| stack.borrow_mut().truncate(depth));
+}
+
+extern "C" fn enter(function: *const FunctionProfile) {
+    let profile = unsafe { &*function };
+    profile.calls.fetch_add(1, Ordering::Relaxed);
+    STACK.with(|stack| {
+        stack.borrow_mut().push(Frame {
+            function,
+            start: cycles(),
+            children: 0,
+        })
+    });
+}
+
+extern "C" fn exit(function: *const FunctionProfile) {
+    let end = cycles();
+    let profile = unsafe { &*function };
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        // Calls a trap unwound out of never got to call this, so drop their
+        // frames first.
+        let frame = loop {
+            let frame = stack.pop().expect("profile exit hook without entry");
+            if frame.function == function {
+                break frame;
+            }
+        };
+        let elapsed = end.wrapping_sub(frame.start);
+        profile
+            .exclusive_cycles
+            .fetch_add(elapsed.saturating_sub(frame.children), Ordering::Relaxed);
+        // The outermost call of a recursive function already covers the
+        // inner ones.
+        if !stack.iter().any(|outer| outer.function == function) {
+            profile
+                .inclusive_cycles
+                .fetch_add(elapsed, Ordering::Relaxed);
+        }
+        if let Some(caller) = stack.last_mut() {
+            caller.children += elapsed;
+        }
+    });
+}
+
+extern "C" fn loop_iteration(counter: *const LoopProfile) {
+    let counter = unsafe { &*counter };
+    counter.iterations.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Read the CPU's cycle counter, or a clock in nanoseconds on targets
+/// without one we can read.
+#[cfg(target_arch = "x86_64")]
+fn cycles() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cycles() -> u64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
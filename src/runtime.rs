@@ -0,0 +1,568 @@
+use crate::gc::Collector;
+use crate::profile;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Mutex;
+
+/// Return the host functions of the runtime, by the names compiled code
+/// imports them under.
+pub(crate) fn hooks() -> [(&'static str, *const u8); 9] {
+    [
+        ("__toy_array_new", array_new as *const u8),
+        ("__toy_array_len", array_len as *const u8),
+        ("__toy_trap", trap as *const u8),
+        ("__toy_check_call", check_call as *const u8),
+        ("__toy_str_len", str_len as *const u8),
+        ("__toy_str_concat", str_concat as *const u8),
+        ("__toy_str_cmp", str_cmp as *const u8),
+        ("__toy_str_from_int", str_from_int as *const u8),
+        ("__toy_tls_address", tls_address as *const u8),
+    ]
+}
+
+/// Return the name compiled code imports the runtime function `name`
+/// under. They all take the heap as an extra first argument.
+pub(crate) fn symbol_name(name: &str) -> String {
+    format!("__toy_{}", name)
+}
+
+/// The codes compiled code passes to `__toy_trap`, followed by two values
+/// describing the trap.
+pub(crate) const TRAP_INDEX_OUT_OF_BOUNDS: isize = 0;
+pub(crate) const TRAP_DIVISION_BY_ZERO: isize = 1;
+
+/// The longest array which can be allocated. Longer ones are almost
+/// certainly bugs, which are better reported as traps than as allocation
+/// failures.
+pub const MAX_ARRAY_LEN: isize = 1 << 28;
+
+/// A runtime error in toy code, which stops it and returns to the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// An array was indexed out of its bounds.
+    IndexOutOfBounds { index: isize, len: isize },
+
+    /// A value which isn't an array, the 0 variables start out as, was used
+    /// as one.
+    NullArray,
+
+    /// A value which isn't 0 nor an array, such as an integer or a string,
+    /// was used as one.
+    NotAnArray(isize),
+
+    /// An array was allocated with a negative length, or one longer than
+    /// `MAX_ARRAY_LEN`.
+    InvalidArrayLength(isize),
+
+    /// A string function was passed 0 instead of a string.
+    NullString,
+
+    /// A value which isn't a reference to a toy function or a closure was
+    /// called.
+    NotAFunction(isize),
+
+    /// A value was divided by zero.
+    DivisionByZero,
+
+    /// A function or a closure was called through a reference with the wrong
+    /// number of arguments.
+    SignatureMismatch { params: usize, args: usize },
+
+    /// A function was called through a reference for a different number of
+    /// results than it returns.
+    ResultsMismatch { returns: usize, results: usize },
+
+    /// The host called a function or a closure with more arguments than a
+    /// native call can pass, which is `max` for it.
+    TooManyArguments { max: usize, args: usize },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::IndexOutOfBounds { index, len } => write!(
+                f,
+                "index out of bounds: the len is {} but the index is {}",
+                len, index
+            ),
+            Trap::NullArray => write!(f, "array is null"),
+            Trap::NotAnArray(value) => write!(f, "{:#x} isn't an array", value),
+            Trap::InvalidArrayLength(len) => write!(f, "invalid array length {}", len),
+            Trap::NullString => write!(f, "string is null"),
+            Trap::DivisionByZero => write!(f, "division by zero"),
+            Trap::NotAFunction(value) => {
+                write!(f, "indirect call to {:#x}, which isn't a function", value)
+            }
+            Trap::SignatureMismatch { params, args } => write!(
+                f,
+                "function takes {} arguments but {} were supplied",
+                params, args
+            ),
+            Trap::ResultsMismatch { returns, results } => write!(
+                f,
+                "function returns {} values but {} were expected",
+                returns, results
+            ),
+            Trap::TooManyArguments { max, args } => write!(
+                f,
+                "native calls can pass at most {} arguments but {} were supplied",
+                max, args
+            ),
+        }
+    }
+}
+
+/// Run `f`, which calls into toy code, and return the trap which stopped the
+/// toy code, if any.
+///
+/// Traps unwind the stack up to here, so the JIT'd code has to be called
+/// through function pointers which allow unwinding, that is with the Rust or
+/// the `"C-unwind"` ABI. With the `"C"` ABI a trap aborts the process.
+pub fn catch_trap<F, R>(f: F) -> Result<R, Trap>
+where
+    F: FnOnce() -> R,
+{
+    let depth = profile::stack_depth();
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Ok(result),
+        Err(payload) => match payload.downcast::<Trap>() {
+            Ok(trap) => {
+                // The profiler would take the calls the trap unwound out of
+                // to be still running.
+                profile::unwind_stack(depth);
+                Err(*trap)
+            }
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+/// Stop the toy code which called into the host, unwinding up to the nearest
+/// `catch_trap`.
+pub fn raise(trap: Trap) -> ! {
+    // Unlike `panic!`, this doesn't run the panic hook, so nothing gets
+    // printed for traps which are caught.
+    panic::resume_unwind(Box::new(trap))
+}
+
+/// The toy functions a `JIT` compiled, by address, with their numbers of
+/// parameters and return values and whether they're the code of closures,
+/// which calls through function references are checked against.
+#[derive(Default)]
+pub(crate) struct FunctionTable {
+    functions: Mutex<HashMap<usize, (usize, usize, bool)>>,
+}
+
+impl FunctionTable {
+    pub(crate) fn add(&self, address: usize, params: usize, returns: usize, closure: bool) {
+        self.functions
+            .lock()
+            .unwrap()
+            .insert(address, (params, returns, closure));
+    }
+
+    /// Check that `callee` is a reference to a toy function or a closure
+    /// taking `args` arguments and returning `results` values. Returns the
+    /// address of the code of the closure, which takes the closure itself as
+    /// an extra first argument, or `None` for a function.
+    pub(crate) fn resolve(
+        &self,
+        heap: &Heap,
+        callee: isize,
+        args: usize,
+        results: usize,
+    ) -> Result<Option<usize>, Trap> {
+        let functions = self.functions.lock().unwrap();
+        let (code, params, returns) = match functions.get(&(callee as usize)) {
+            Some(&(params, returns, false)) => (None, params, returns),
+            _ => {
+                let code = heap.first_element(callee).unwrap_or(0) as usize;
+                match functions.get(&code) {
+                    Some(&(params, returns, true)) => (Some(code), params - 1, returns),
+                    _ => return Err(Trap::NotAFunction(callee)),
+                }
+            }
+        };
+        if params != args {
+            return Err(Trap::SignatureMismatch { params, args });
+        }
+        if returns != results {
+            return Err(Trap::ResultsMismatch { returns, results });
+        }
+        Ok(code)
+    }
+}
+
+/// The heap toy arrays and strings are allocated on.
+///
+/// An array is a pointer to its length, which is followed by its elements,
+/// all pointer-sized integers. A string is a pointer to its bytes, followed
+/// by a NUL, so that it can be passed to C functions like `puts`. Neither
+/// are freed individually: unless garbage collection is enabled, they live
+/// as long as the heap.
+#[derive(Default)]
+pub(crate) struct Heap {
+    /// The arrays, by address.
+    arrays: Mutex<HashMap<usize, Box<[isize]>>>,
+
+    /// The strings, by address.
+    strings: Mutex<HashMap<usize, Box<[u8]>>>,
+
+    /// The addresses of the constant tables, which are laid out like arrays
+    /// but live in read-only data, and are never freed.
+    tables: Mutex<HashSet<usize>>,
+
+    /// The collector, if garbage collection is enabled.
+    collector: Mutex<Option<Collector>>,
+
+    /// The addresses of the global variables, whose values are roots too.
+    globals: Mutex<Vec<usize>>,
+}
+
+/// How much a heap holds, and how often it was collected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The arrays and strings allocated and not freed yet.
+    pub objects: usize,
+
+    /// The bytes those take.
+    pub bytes: usize,
+
+    pub collections: usize,
+}
+
+impl Heap {
+    /// Allocate an array of `len` zeros.
+    pub(crate) fn allocate(&self, len: isize) -> Result<isize, Trap> {
+        if !(0..=MAX_ARRAY_LEN).contains(&len) {
+            return Err(Trap::InvalidArrayLength(len));
+        }
+        self.account(mem::size_of::<isize>() * (len as usize + 1));
+        let mut array = vec![0; len as usize + 1].into_boxed_slice();
+        array[0] = len;
+        let address = array.as_ptr() as usize;
+        self.arrays.lock().unwrap().insert(address, array);
+        Ok(address as isize)
+    }
+
+    /// Allocate a copy of `bytes`, followed by a NUL.
+    pub(crate) fn allocate_string(&self, bytes: &[u8]) -> isize {
+        self.account(bytes.len() + 1);
+        let mut string = Vec::with_capacity(bytes.len() + 1);
+        string.extend_from_slice(bytes);
+        string.push(0);
+        let string = string.into_boxed_slice();
+        let address = string.as_ptr() as usize;
+        self.strings.lock().unwrap().insert(address, string);
+        address as isize
+    }
+
+    /// Return the elements of `array`, if it was allocated on this heap.
+    pub(crate) fn read_array(&self, array: isize) -> Option<Vec<isize>> {
+        let arrays = self.arrays.lock().unwrap();
+        let array = arrays.get(&(array as usize))?;
+        Some(array[1..].to_vec())
+    }
+
+    /// Return the length of `array`, trapping unless it was allocated on
+    /// this heap. The toy language doesn't tell arrays apart from other
+    /// values, so they're looked up by address.
+    pub(crate) fn array_len(&self, array: isize) -> Result<isize, Trap> {
+        if array == 0 {
+            return Err(Trap::NullArray);
+        }
+        if let Some(array) = self.arrays.lock().unwrap().get(&(array as usize)) {
+            return Ok(array[0]);
+        }
+        // The interpreter's tables are only byte-aligned.
+        if self.tables.lock().unwrap().contains(&(array as usize)) {
+            return Ok(unsafe { (array as *const isize).read_unaligned() });
+        }
+        Err(Trap::NotAnArray(array))
+    }
+
+    /// Return the address of the element `index` of `array`, checking its
+    /// bounds the way compiled code does.
+    pub(crate) fn array_element(&self, array: isize, index: isize) -> Result<*mut isize, Trap> {
+        let len = self.array_len(array)?;
+        // Like the compiled code, compare unsigned, so negative indices are out
+        // of bounds too.
+        if index as usize >= len as usize {
+            return Err(Trap::IndexOutOfBounds { index, len });
+        }
+        Ok(unsafe { (array as *mut isize).offset(index + 1) })
+    }
+
+    /// Return the first element of `array`, if it's an array with one.
+    pub(crate) fn first_element(&self, array: isize) -> Option<isize> {
+        let arrays = self.arrays.lock().unwrap();
+        arrays.get(&(array as usize))?.get(1).copied()
+    }
+
+    /// Let the constant table at `address` be indexed like an array.
+    pub(crate) fn add_table(&self, address: *const isize) {
+        self.tables.lock().unwrap().insert(address as usize);
+    }
+
+    /// Free the arrays and strings once nothing refers to them anymore.
+    /// Keep what the global variable at `address` holds alive, whenever
+    /// it's collected.
+    pub(crate) fn add_global(&self, address: *const isize) {
+        self.globals.lock().unwrap().push(address as usize);
+    }
+
+    pub(crate) fn enable_gc(&self) {
+        self.collector
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Collector::default);
+    }
+
+    /// Run `f` on the collector, if garbage collection is enabled.
+    pub(crate) fn with_collector<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Collector),
+    {
+        if let Some(collector) = &mut *self.collector.lock().unwrap() {
+            f(collector);
+        }
+    }
+
+    /// Count `bytes` as allocated, collecting the garbage first if enough
+    /// was since the last collection. The object being allocated isn't on
+    /// the heap yet, so it can't be freed. The runtime functions read the
+    /// objects they're passed before allocating, so those don't have to
+    /// survive either.
+    fn account(&self, bytes: usize) {
+        if let Some(collector) = &mut *self.collector.lock().unwrap() {
+            if collector.allocate(bytes) {
+                self.collect(collector);
+            }
+        }
+    }
+
+    /// Collect the garbage now, if garbage collection is enabled.
+    pub(crate) fn collect_garbage(&self) {
+        if let Some(collector) = &mut *self.collector.lock().unwrap() {
+            self.collect(collector);
+        }
+    }
+
+    /// Free the arrays and strings which aren't reachable from the roots.
+    ///
+    /// Any value may be a reference, as toy values are untyped, so a value
+    /// is taken to be one exactly if it's the address of an object. That
+    /// keeps the garbage some integers happen to look like alive, but never
+    /// frees a live object.
+    fn collect(&self, collector: &mut Collector) {
+        let mut arrays = self.arrays.lock().unwrap();
+        let mut strings = self.strings.lock().unwrap();
+        let mut marked = HashSet::new();
+        let mut worklist = collector.roots();
+        for &global in &*self.globals.lock().unwrap() {
+            worklist.push(unsafe { (global as *const isize).read_unaligned() });
+        }
+        while let Some(value) = worklist.pop() {
+            let address = value as usize;
+            if marked.contains(&address) {
+                continue;
+            }
+            if let Some(array) = arrays.get(&address) {
+                marked.insert(address);
+                worklist.extend_from_slice(&array[1..]);
+            } else if strings.contains_key(&address) {
+                marked.insert(address);
+            }
+        }
+        arrays.retain(|address, _| marked.contains(address));
+        strings.retain(|address, _| marked.contains(address));
+
+        // Collect again once as much as survived was allocated.
+        collector.allocated = 0;
+        collector.threshold = heap_bytes(&arrays, &strings);
+        collector.collections += 1;
+    }
+
+    pub(crate) fn stats(&self) -> HeapStats {
+        let arrays = self.arrays.lock().unwrap();
+        let strings = self.strings.lock().unwrap();
+        let collections = match &*self.collector.lock().unwrap() {
+            Some(collector) => collector.collections,
+            None => 0,
+        };
+        HeapStats {
+            objects: arrays.len() + strings.len(),
+            bytes: heap_bytes(&arrays, &strings),
+            collections,
+        }
+    }
+}
+
+fn heap_bytes(arrays: &HashMap<usize, Box<[isize]>>, strings: &HashMap<usize, Box<[u8]>>) -> usize {
+    let arrays: usize = arrays
+        .values()
+        .map(|array| mem::size_of_val(&**array))
+        .sum();
+    let strings: usize = strings.values().map(|string| string.len()).sum();
+    arrays + strings
+}
+
+/// A global variable declared by toy code, which the host can read and
+/// assign.
+pub struct ToyGlobal<'a> {
+    address: *mut isize,
+    owner: PhantomData<&'a ()>,
+}
+
+impl ToyGlobal<'_> {
+    /// # Safety
+    ///
+    /// `address` must be that of a global variable, which lives at least as
+    /// long as the handle.
+    pub(crate) unsafe fn new(address: *mut isize) -> Self {
+        Self {
+            address,
+            owner: PhantomData,
+        }
+    }
+
+    pub fn address(&self) -> *mut isize {
+        self.address
+    }
+
+    pub fn get(&self) -> isize {
+        unsafe { self.address.read_unaligned() }
+    }
+
+    /// Assign the global variable. With garbage collection, an array or a
+    /// string it's assigned stays alive for as long as it holds it.
+    pub fn set(&self, value: isize) {
+        unsafe { self.address.write_unaligned(value) }
+    }
+}
+
+/// The key of the next thread-local data object created.
+static NEXT_TLS_KEY: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// This thread's copies of the thread-local data objects it has used so
+    /// far, indexed by their keys, which are handed out in order. They're
+    /// made of `u64`s, to be aligned for any value.
+    static TLS_COPIES: RefCell<Vec<Option<Box<[u64]>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Return a key for a new thread-local data object, which no other one in
+/// the process has, even once the module of the first is dropped.
+pub(crate) fn new_tls_key() -> usize {
+    NEXT_TLS_KEY.fetch_add(1, atomic::Ordering::Relaxed)
+}
+
+/// Return the address of the calling thread's copy of the thread-local data
+/// object `key`, copying its `size` bytes of initial contents from
+/// `template` when the thread first uses it. Compiled code calls this as
+/// `__toy_tls_address`.
+pub(crate) extern "C" fn tls_address(key: usize, template: *const u8, size: usize) -> *mut u8 {
+    TLS_COPIES.with(|copies| {
+        let mut copies = copies.borrow_mut();
+        if copies.len() <= key {
+            copies.resize_with(key + 1, || None);
+        }
+        let copy = copies[key].get_or_insert_with(|| {
+            let mut copy = vec![0u64; size.div_ceil(8)].into_boxed_slice();
+            unsafe { std::ptr::copy_nonoverlapping(template, copy.as_mut_ptr() as *mut u8, size) };
+            copy
+        });
+        copy.as_mut_ptr() as *mut u8
+    })
+}
+
+/// Call the runtime function `name`, as listed in `RUNTIME_FUNCTIONS`, the
+/// way compiled code does.
+///
+/// # Safety
+///
+/// The arguments which are strings must be 0 or point to NUL-terminated
+/// strings.
+pub(crate) unsafe fn call(heap: &Heap, name: &str, args: &[isize]) -> Result<isize, Trap> {
+    match (name, args) {
+        ("str_len", &[s]) => Ok(string(s)?.to_bytes().len() as isize),
+        ("str_concat", &[a, b]) => {
+            let mut bytes = string(a)?.to_bytes().to_vec();
+            bytes.extend_from_slice(string(b)?.to_bytes());
+            Ok(heap.allocate_string(&bytes))
+        }
+        ("str_cmp", &[a, b]) => Ok(match string(a)?.cmp(string(b)?) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }),
+        ("str_from_int", &[n]) => Ok(heap.allocate_string(n.to_string().as_bytes())),
+        _ => unreachable!("bad call of runtime function `{}`", name),
+    }
+}
+
+unsafe fn string<'a>(s: isize) -> Result<&'a CStr, Trap> {
+    if s == 0 {
+        return Err(Trap::NullString);
+    }
+    Ok(CStr::from_ptr(s as *const c_char))
+}
+
+extern "C-unwind" fn str_len(heap: *const Heap, s: isize) -> isize {
+    unsafe { call(&*heap, "str_len", &[s]) }.unwrap_or_else(|trap| raise(trap))
+}
+
+extern "C-unwind" fn str_concat(heap: *const Heap, a: isize, b: isize) -> isize {
+    unsafe { call(&*heap, "str_concat", &[a, b]) }.unwrap_or_else(|trap| raise(trap))
+}
+
+extern "C-unwind" fn str_cmp(heap: *const Heap, a: isize, b: isize) -> isize {
+    unsafe { call(&*heap, "str_cmp", &[a, b]) }.unwrap_or_else(|trap| raise(trap))
+}
+
+extern "C-unwind" fn str_from_int(heap: *const Heap, n: isize) -> isize {
+    unsafe { call(&*heap, "str_from_int", &[n]) }.unwrap_or_else(|trap| raise(trap))
+}
+
+extern "C-unwind" fn array_new(heap: *const Heap, len: isize) -> isize {
+    let heap = unsafe { &*heap };
+    match heap.allocate(len) {
+        Ok(array) => array,
+        Err(trap) => raise(trap),
+    }
+}
+
+extern "C-unwind" fn array_len(heap: *const Heap, array: isize) -> isize {
+    let heap = unsafe { &*heap };
+    heap.array_len(array).unwrap_or_else(|trap| raise(trap))
+}
+
+extern "C-unwind" fn check_call(
+    functions: *const FunctionTable,
+    heap: *const Heap,
+    callee: isize,
+    args: isize,
+    results: isize,
+) -> isize {
+    let (functions, heap) = unsafe { (&*functions, &*heap) };
+    match functions.resolve(heap, callee, args as usize, results as usize) {
+        Ok(code) => code.unwrap_or(0) as isize,
+        Err(trap) => raise(trap),
+    }
+}
+
+extern "C-unwind" fn trap(code: isize, a: isize, b: isize) -> isize {
+    raise(match code {
+        TRAP_INDEX_OUT_OF_BOUNDS => Trap::IndexOutOfBounds { index: a, len: b },
+        TRAP_DIVISION_BY_ZERO => Trap::DivisionByZero,
+        _ => unreachable!("unknown trap code {}", code),
+    })
+}
@@ -0,0 +1,595 @@
+use crate::closure;
+use crate::frontend::*;
+use crate::interp::{Counts, Interpreter};
+use crate::jit::{DataPointer, JIT};
+use crate::runtime::catch_trap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The maximum number of arguments of functions which can be called from
+/// the interpreter once they're compiled.
+pub const MAX_NATIVE_ARGS: usize = 6;
+
+/// How many times compiling a function may fail before it's left to the
+/// interpreter for good.
+const MAX_COMPILE_ATTEMPTS: usize = 3;
+
+/// A request to the background compiler.
+enum CompileRequest {
+    /// Declarations for the functions compiled from now on.
+    Declare(String),
+
+    /// The names and sources of functions to compile together.
+    Compile(Vec<(String, String)>),
+}
+
+/// The background compiler's reply: the names and addresses of the
+/// compiled functions, or the names of those it failed to compile.
+type CompileResult = Result<Vec<(String, usize)>, Vec<String>>;
+
+/// A tiered execution engine.
+///
+/// Functions start out interpreted, which avoids spending time on compiling
+/// code which only runs once. The interpreter counts how often each function
+/// is called and how many loop iterations it executes, and functions which
+/// cross a threshold are compiled with Cranelift on a background thread.
+/// Once the compiled code is ready, it's swapped in for subsequent calls.
+/// `JIT::enable_tiering` hands the functions it compiles to one.
+///
+/// Some functions stay interpreted however hot they get, as compiled code
+/// couldn't run them like the interpreter does: those which use global
+/// variables, make closures, take references to toy functions or return
+/// several values, and those which call them. So do functions with more
+/// than `MAX_NATIVE_ARGS` parameters. `is_compiled` tells which were
+/// compiled.
+pub struct TieredJIT {
+    interp: Interpreter,
+    tiering: Rc<Tiering>,
+}
+
+/// The state shared between the `TieredJIT` and the interpreter's profile
+/// hook, which decides when to compile functions.
+struct Tiering {
+    /// The number of calls plus loop iterations after which a function is
+    /// compiled.
+    threshold: u64,
+
+    /// The toy functions defined so far.
+    functions: RefCell<HashMap<String, ToyFunction>>,
+
+    /// The addresses of the host functions and data objects, by name. This is
+    /// shared with the background compiler, which resolves symbols with it.
+    symbols: Arc<Mutex<HashMap<String, usize>>>,
+
+    /// Functions which have been sent to the background compiler.
+    tiered_up: RefCell<HashSet<String>>,
+
+    /// Functions which can't be compiled, because of what they do, or
+    /// because compiling them kept failing.
+    rejected: RefCell<HashSet<String>>,
+
+    /// How many times compiling each function has failed. It's sent to the
+    /// background compiler again the next time it's hot, up to
+    /// `MAX_COMPILE_ATTEMPTS` times.
+    failures: RefCell<HashMap<String, usize>>,
+
+    /// Functions which have been compiled and swapped in.
+    compiled: RefCell<HashSet<String>>,
+
+    requests: Sender<CompileRequest>,
+    results: Receiver<CompileResult>,
+}
+
+/// What the tiering policy needs to know about a toy function.
+struct ToyFunction {
+    source: String,
+    arity: usize,
+    returns: usize,
+    callees: HashSet<String>,
+    references: HashSet<String>,
+    closures: bool,
+
+    /// Whether it reads or assigns global variables, or takes their
+    /// addresses.
+    globals: bool,
+}
+
+impl Default for TieredJIT {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl TieredJIT {
+    /// Create a tiered execution engine which compiles functions once the
+    /// sum of their calls and loop iterations reaches `threshold`.
+    pub fn new(threshold: u64) -> Self {
+        let symbols = Arc::new(Mutex::new(HashMap::new()));
+        let (requests, results) = spawn_compiler(symbols.clone());
+        let tiering = Rc::new(Tiering {
+            threshold,
+            functions: RefCell::new(HashMap::new()),
+            symbols,
+            tiered_up: RefCell::new(HashSet::new()),
+            rejected: RefCell::new(HashSet::new()),
+            failures: RefCell::new(HashMap::new()),
+            compiled: RefCell::new(HashSet::new()),
+            requests,
+            results,
+        });
+
+        let mut interp = Interpreter::default();
+        let hook_tiering = tiering.clone();
+        interp.set_profile_hook(move |interp, name, counts| {
+            hook_tiering.on_counts(interp, name, counts)
+        });
+
+        Self { interp, tiering }
+    }
+
+    /// Parse a string in the toy language and make the function available
+    /// to be called. Returns the name of the function.
+    pub fn define(&mut self, input: &str) -> Result<String, String> {
+        // The interpreter keeps its own copy of the AST, but the tiering
+        // policy needs to know about calls to decide what to compile.
+        let function = parser::function(input).map_err(|e| e.to_string())?;
+        self.interp.define(input)?;
+        let interp = &self.interp;
+        let is_global = |name: &str| interp.is_global(name);
+        let mut functions = closure::lift(function, &is_global).into_iter();
+        let (name, params, returns, stmts) = functions.next().unwrap();
+        let closures = functions.next().is_some();
+
+        let mut callees = HashSet::new();
+        let mut references = HashSet::new();
+        let mut names = HashSet::new();
+        for expr in &stmts {
+            find_callees(&mut callees, &mut references, expr);
+            find_names(&mut names, expr);
+        }
+        // The parameters and return variables shadow the globals.
+        let globals = references.iter().any(|name| is_global(name))
+            || names
+                .iter()
+                .any(|name| is_global(name) && !params.contains(name) && !returns.contains(name));
+        self.tiering.functions.borrow_mut().insert(
+            name.clone(),
+            ToyFunction {
+                source: input.to_owned(),
+                arity: params.len(),
+                returns: returns.len(),
+                callees,
+                references,
+                closures,
+                globals,
+            },
+        );
+        Ok(name)
+    }
+
+    /// Parse declarations, like `JIT::declare`.
+    pub fn declare(&mut self, input: &str) -> Result<(), String> {
+        self.interp.declare(input)?;
+        // They were checked by the interpreter already.
+        let _ = self
+            .tiering
+            .requests
+            .send(CompileRequest::Declare(input.to_owned()));
+        Ok(())
+    }
+
+    /// Make the native function at `code` available to toy code under
+    /// `name`, both interpreted and compiled.
+    ///
+    /// # Safety
+    ///
+    /// `code` must point to a function with the C calling convention which
+    /// takes `arity` pointer-sized integer arguments and returns a
+    /// pointer-sized integer.
+    pub unsafe fn register_host_function(
+        &mut self,
+        name: &str,
+        code: *const u8,
+        arity: usize,
+    ) -> Result<(), String> {
+        if arity > MAX_NATIVE_ARGS {
+            return Err(format!(
+                "host functions can take at most {} arguments",
+                MAX_NATIVE_ARGS
+            ));
+        }
+        let address = code as usize;
+        self.interp
+            .register_host_function(name, move |args| call_native(address, args));
+        self.tiering
+            .symbols
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), address);
+        Ok(())
+    }
+
+    /// Create a data object, like `JIT::create_data`.
+    pub fn create_data(&mut self, name: &str, contents: Vec<u8>) -> Result<&[u8], String> {
+        let data = self.interp.create_data(name, contents)?;
+        self.tiering
+            .symbols
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), data.as_ptr() as usize);
+        Ok(data)
+    }
+
+    /// Create a data object with pointers to functions and other data
+    /// objects, like `JIT::create_data_with_pointers`. Compiled code sees the
+    /// interpreter's, whose pointers to toy functions are references the
+    /// interpreter looks up.
+    pub fn create_data_with_pointers(
+        &mut self,
+        name: &str,
+        contents: Vec<u8>,
+        pointers: &[(usize, DataPointer)],
+    ) -> Result<&[u8], String> {
+        let data = self
+            .interp
+            .create_data_with_pointers(name, contents, pointers)?;
+        self.tiering
+            .symbols
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), data.as_ptr() as usize);
+        Ok(data)
+    }
+
+    /// Call the function named `name` with the given arguments, running it
+    /// either interpreted or compiled, depending on how hot it is.
+    pub fn call(&self, name: &str, args: &[isize]) -> Result<isize, String> {
+        self.tiering.swap_in_compiled(&self.interp);
+        // The interpreter reports traps as errors, but they unwind out of
+        // compiled code.
+        catch_trap(|| self.interp.call(name, args)).unwrap_or_else(|trap| Err(trap.to_string()))
+    }
+
+    /// Return whether the toy function `name` has been compiled and swapped
+    /// in.
+    pub fn is_compiled(&self, name: &str) -> bool {
+        self.tiering.swap_in_compiled(&self.interp);
+        self.tiering.compiled.borrow().contains(name)
+    }
+
+    /// Return how often the toy function `name` has been interpreted.
+    pub fn counts(&self, name: &str) -> Option<Counts> {
+        self.interp.counts(name)
+    }
+}
+
+impl Tiering {
+    fn on_counts(&self, interp: &Interpreter, name: &str, counts: Counts) {
+        self.swap_in_compiled(interp);
+        if counts.calls + counts.back_edges >= self.threshold {
+            self.tier_up(name);
+        }
+    }
+
+    /// Send `name` to the background compiler, together with all the toy
+    /// functions it calls which aren't compiled yet.
+    fn tier_up(&self, hot: &str) {
+        let mut tiered_up = self.tiered_up.borrow_mut();
+        let mut rejected = self.rejected.borrow_mut();
+        if tiered_up.contains(hot) || rejected.contains(hot) {
+            return;
+        }
+
+        let functions = self.functions.borrow();
+        let symbols = self.symbols.lock().unwrap();
+
+        let mut batch = HashSet::new();
+        let mut worklist = vec![hot.to_owned()];
+        while let Some(name) = worklist.pop() {
+            if tiered_up.contains(&name) || !batch.insert(name.clone()) {
+                continue;
+            }
+            // References to toy functions, and closures, are handles the
+            // interpreter looks up, which compiled code couldn't call, nor
+            // hand out.
+            let function = &functions[&name];
+            if function.closures
+                || function
+                    .references
+                    .iter()
+                    .any(|reference| functions.contains_key(reference))
+            {
+                rejected.insert(hot.to_owned());
+                return;
+            }
+            // The compiler has globals of its own, which the interpreter's
+            // aren't kept in sync with.
+            if function.globals {
+                rejected.insert(hot.to_owned());
+                return;
+            }
+            // Native calls from the interpreter only return one value.
+            if function.returns > 1 {
+                rejected.insert(hot.to_owned());
+                return;
+            }
+            for callee in &functions[&name].callees {
+                if functions.contains_key(callee) {
+                    // Compiled code couldn't call a function which stays
+                    // interpreted, so keep interpreting.
+                    if rejected.contains(callee) {
+                        rejected.insert(hot.to_owned());
+                        return;
+                    }
+                    worklist.push(callee.clone());
+                } else if !symbols.contains_key(callee)
+                    && !RUNTIME_FUNCTIONS.iter().any(|(name, _)| name == callee)
+                {
+                    // Nor a function which isn't defined or registered yet,
+                    // which it may be later, so try again the next time.
+                    return;
+                }
+            }
+        }
+
+        let request: Vec<(String, String)> = batch
+            .into_iter()
+            .map(|name| {
+                let source = functions[&name].source.clone();
+                tiered_up.insert(name.clone());
+                (name, source)
+            })
+            .collect();
+
+        // If the background compiler has gone away, everything simply stays
+        // interpreted.
+        let _ = self.requests.send(CompileRequest::Compile(request));
+    }
+
+    /// Swap in any functions the background compiler has finished compiling.
+    fn swap_in_compiled(&self, interp: &Interpreter) {
+        while let Ok(result) = self.results.try_recv() {
+            // If compilation failed, the functions stay interpreted until
+            // they're hot again, unless they failed too often already.
+            let compiled = match result {
+                Ok(compiled) => compiled,
+                Err(names) => {
+                    let mut failures = self.failures.borrow_mut();
+                    for name in names {
+                        self.tiered_up.borrow_mut().remove(&name);
+                        let attempts = failures.entry(name.clone()).or_insert(0);
+                        *attempts += 1;
+                        if *attempts >= MAX_COMPILE_ATTEMPTS {
+                            self.rejected.borrow_mut().insert(name);
+                        }
+                    }
+                    continue;
+                }
+            };
+            let functions = self.functions.borrow();
+            for (name, address) in compiled {
+                if functions[&name].arity > MAX_NATIVE_ARGS {
+                    continue;
+                }
+                interp.replace_function(&name, move |args| unsafe { call_native(address, args) });
+                self.compiled.borrow_mut().insert(name);
+            }
+        }
+    }
+}
+
+/// Start the background compiler thread, returning the channels to send it
+/// requests and receive the results on. The thread exits once the request
+/// channel is closed.
+fn spawn_compiler(
+    symbols: Arc<Mutex<HashMap<String, usize>>>,
+) -> (Sender<CompileRequest>, Receiver<CompileResult>) {
+    let (request_sender, request_receiver) = mpsc::channel::<CompileRequest>();
+    let (result_sender, result_receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        // The JIT isn't `Send`, so it's created on the background thread and
+        // never leaves it. The memory it allocates for code stays valid for
+        // as long as the process runs.
+        let mut jit = JIT::with_symbol_lookup(move |name| {
+            let symbols = symbols.lock().unwrap();
+            symbols.get(name).map(|&address| address as *const u8)
+        });
+
+        for request in request_receiver {
+            let request = match request {
+                CompileRequest::Declare(input) => {
+                    // If this fails, so does compiling the functions using
+                    // the declarations, which then stay interpreted.
+                    let _ = jit.declare(&input);
+                    continue;
+                }
+                CompileRequest::Compile(request) => request,
+            };
+            let sources: Vec<&str> = request.iter().map(|(_, source)| &source[..]).collect();
+            let names = request.iter().map(|(name, _)| name.clone());
+            let result = match jit.compile_all(&sources) {
+                Ok(code) => Ok(names
+                    .zip(code.into_iter().map(|code| code as usize))
+                    .collect()),
+                Err(_) => Err(names.collect()),
+            };
+            if result_sender.send(result).is_err() {
+                break;
+            }
+        }
+    });
+
+    (request_sender, result_receiver)
+}
+
+/// Call the native function at `address` with the given arguments.
+///
+/// # Safety
+///
+/// `address` must point to a function with the C calling convention which
+/// takes `args.len()` pointer-sized integer arguments and returns a
+/// pointer-sized integer, and `args.len()` must be at most
+/// `MAX_NATIVE_ARGS`.
+pub(crate) unsafe fn call_native(address: usize, args: &[isize]) -> isize {
+    type I = isize;
+    let code = address as *const u8;
+    match *args {
+        [] => mem::transmute::<*const u8, extern "C-unwind" fn() -> I>(code)(),
+        [a] => mem::transmute::<*const u8, extern "C-unwind" fn(I) -> I>(code)(a),
+        [a, b] => mem::transmute::<*const u8, extern "C-unwind" fn(I, I) -> I>(code)(a, b),
+        [a, b, c] => mem::transmute::<*const u8, extern "C-unwind" fn(I, I, I) -> I>(code)(a, b, c),
+        [a, b, c, d] => {
+            mem::transmute::<*const u8, extern "C-unwind" fn(I, I, I, I) -> I>(code)(a, b, c, d)
+        }
+        [a, b, c, d, e] => mem::transmute::<*const u8, extern "C-unwind" fn(I, I, I, I, I) -> I>(
+            code,
+        )(a, b, c, d, e),
+        [a, b, c, d, e, f] => mem::transmute::<
+            *const u8,
+            extern "C-unwind" fn(I, I, I, I, I, I) -> I,
+        >(code)(a, b, c, d, e, f),
+        _ => panic!("too many arguments for a native call"),
+    }
+}
+
+/// Recursively descend through the AST, finding the names of all the
+/// functions called, and of everything `&name` refers to.
+fn find_callees(callees: &mut HashSet<String>, references: &mut HashSet<String>, expr: &Expr) {
+    match expr {
+        Expr::GlobalDataAddr(name) => {
+            references.insert(name.clone());
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::Identifier(_)
+        | Expr::Annotate(..)
+        | Expr::MakeClosure(..)
+        | Expr::Field(..) => {}
+        Expr::AssignField(_, _, expr) => find_callees(callees, references, expr),
+        Expr::Assign(_, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Float(expr)
+        | Expr::Index(_, expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::Closure(_, expr) => find_callees(callees, references, expr),
+        Expr::AssignIndex(_, index, expr) | Expr::Store(_, index, expr) => {
+            find_callees(callees, references, index);
+            find_callees(callees, references, expr);
+        }
+        Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            find_callees(callees, references, lhs);
+            find_callees(callees, references, rhs);
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            find_callees(callees, references, condition);
+            for expr in then_body.iter().chain(else_body) {
+                find_callees(callees, references, expr);
+            }
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            find_callees(callees, references, condition);
+            for expr in loop_body {
+                find_callees(callees, references, expr);
+            }
+        }
+        Expr::Call(name, args) | Expr::Destructure(_, name, args) => {
+            callees.insert(name.clone());
+            for arg in args {
+                find_callees(callees, references, arg);
+            }
+        }
+    }
+}
+
+/// Recursively descend through the AST, finding the names of the variables
+/// it reads and assigns, which may be those of global variables.
+fn find_names(names: &mut HashSet<String>, expr: &Expr) {
+    match expr {
+        Expr::Identifier(name) => {
+            names.insert(name.clone());
+        }
+        Expr::Literal(_)
+        | Expr::Str(_)
+        | Expr::GlobalDataAddr(_)
+        | Expr::Annotate(..)
+        | Expr::MakeClosure(..)
+        | Expr::Field(..) => {}
+        Expr::Assign(name, expr) => {
+            names.insert(name.clone());
+            find_names(names, expr);
+        }
+        Expr::Index(name, expr) => {
+            names.insert(name.clone());
+            find_names(names, expr);
+        }
+        Expr::AssignIndex(name, index, expr) => {
+            names.insert(name.clone());
+            find_names(names, index);
+            find_names(names, expr);
+        }
+        Expr::AssignField(_, _, expr)
+        | Expr::Array(expr)
+        | Expr::Len(expr)
+        | Expr::Float(expr)
+        | Expr::Load(_, expr)
+        | Expr::SignedLoad(_, expr)
+        | Expr::Closure(_, expr) => find_names(names, expr),
+        Expr::Store(_, index, expr) => {
+            find_names(names, index);
+            find_names(names, expr);
+        }
+        Expr::Eq(lhs, rhs)
+        | Expr::Ne(lhs, rhs)
+        | Expr::Lt(lhs, rhs)
+        | Expr::Le(lhs, rhs)
+        | Expr::Gt(lhs, rhs)
+        | Expr::Ge(lhs, rhs)
+        | Expr::Add(lhs, rhs)
+        | Expr::Sub(lhs, rhs)
+        | Expr::Mul(lhs, rhs)
+        | Expr::Div(lhs, rhs) => {
+            find_names(names, lhs);
+            find_names(names, rhs);
+        }
+        Expr::IfElse(condition, then_body, else_body) => {
+            find_names(names, condition);
+            for expr in then_body.iter().chain(else_body) {
+                find_names(names, expr);
+            }
+        }
+        Expr::WhileLoop(condition, loop_body) => {
+            find_names(names, condition);
+            for expr in loop_body {
+                find_names(names, expr);
+            }
+        }
+        Expr::Call(_, args) => {
+            for arg in args {
+                find_names(names, arg);
+            }
+        }
+        Expr::Destructure(targets, _, args) => {
+            names.extend(targets.iter().cloned());
+            for arg in args {
+                find_names(names, arg);
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use serde_json::{json, Value};
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+/// Return the host functions the traced code calls, by the names it imports
+/// them under.
+pub(crate) fn hooks() -> [(&'static str, *const u8); 3] {
+    [
+        ("__toy_trace_assign", assign as *const u8),
+        ("__toy_trace_call", call as *const u8),
+        ("__toy_trace_return", return_ as *const u8),
+    ]
+}
+
+/// A recorder of what toy functions do, step by step.
+///
+/// Once enabled with `JIT::enable_trace`, the JIT inserts calls to hooks into
+/// the functions it compiles: after each assignment, with the variable's new
+/// value, and around each call, with the arguments and the results. Each hook
+/// is called with the address of a `Site` describing where it was called
+/// from, so the code doesn't need to pass names around.
+#[derive(Default)]
+pub(crate) struct Tracer {
+    /// The places the hooks are called from. Each is boxed, as the code
+    /// refers to it by its address.
+    #[allow(clippy::vec_box)]
+    sites: Vec<Box<Site>>,
+
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+}
+
+/// A place in a toy function which calls one of the hooks.
+pub(crate) struct Site {
+    events: Arc<Mutex<Vec<TraceEvent>>>,
+    function: String,
+    line: u32,
+    kind: SiteKind,
+}
+
+enum SiteKind {
+    Assign { variable: String },
+    Call { callee: String, args: usize },
+}
+
+impl Tracer {
+    /// Add the site of an assignment to `variable`, and return the address
+    /// the assignment hook is called with.
+    pub(crate) fn add_assign(&mut self, function: &str, line: u32, variable: &str) -> usize {
+        self.add_site(
+            function,
+            line,
+            SiteKind::Assign {
+                variable: variable.to_owned(),
+            },
+        )
+    }
+
+    /// Add the site of a call to `callee` with `args` arguments, and return
+    /// the address the call and return hooks are called with.
+    pub(crate) fn add_call(
+        &mut self,
+        function: &str,
+        line: u32,
+        callee: &str,
+        args: usize,
+    ) -> usize {
+        self.add_site(
+            function,
+            line,
+            SiteKind::Call {
+                callee: callee.to_owned(),
+                args,
+            },
+        )
+    }
+
+    fn add_site(&mut self, function: &str, line: u32, kind: SiteKind) -> usize {
+        let site = Box::new(Site {
+            events: self.events.clone(),
+            function: function.to_owned(),
+            line,
+            kind,
+        });
+        let address = &*site as *const Site as usize;
+        self.sites.push(site);
+        address
+    }
+
+    /// Return the events recorded so far, and start over.
+    pub(crate) fn take_events(&self) -> Vec<TraceEvent> {
+        std::mem::take(&mut self.events.lock().unwrap())
+    }
+}
+
+/// Something a traced toy function did. `function` and `line` are where it
+/// happened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    Assign {
+        function: String,
+        line: u32,
+        variable: String,
+        value: isize,
+    },
+    Call {
+        function: String,
+        line: u32,
+        callee: String,
+        args: Vec<isize>,
+    },
+    Return {
+        function: String,
+        line: u32,
+        callee: String,
+        results: Vec<isize>,
+    },
+}
+
+impl TraceEvent {
+    /// Return the event as a JSON object, on a single line.
+    pub fn to_json(&self) -> String {
+        let value = match self {
+            TraceEvent::Assign {
+                function,
+                line,
+                variable,
+                value,
+            } => json!({
+                "event": "assign",
+                "function": function,
+                "line": line,
+                "variable": variable,
+                "value": value,
+            }),
+            TraceEvent::Call {
+                function,
+                line,
+                callee,
+                args,
+            } => json!({
+                "event": "call",
+                "function": function,
+                "line": line,
+                "callee": callee,
+                "args": args,
+            }),
+            TraceEvent::Return {
+                function,
+                line,
+                callee,
+                results,
+            } => json!({
+                "event": "return",
+                "function": function,
+                "line": line,
+                "callee": callee,
+                "results": results,
+            }),
+        };
+        value.to_string()
+    }
+
+    /// Parse an event written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let string = |key: &str| -> Result<String, String> {
+            value[key]
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| format!("missing string `{}`", key))
+        };
+        let int = |value: &Value| -> Result<isize, String> {
+            value
+                .as_i64()
+                .map(|int| int as isize)
+                .ok_or_else(|| format!("expected an integer, found `{}`", value))
+        };
+        let ints = |key: &str| -> Result<Vec<isize>, String> {
+            value[key]
+                .as_array()
+                .ok_or_else(|| format!("missing array `{}`", key))?
+                .iter()
+                .map(int)
+                .collect()
+        };
+        let function = string("function")?;
+        let line = value["line"].as_u64().ok_or("missing integer `line`")? as u32;
+        match &string("event")?[..] {
+            "assign" => Ok(TraceEvent::Assign {
+                function,
+                line,
+                variable: string("variable")?,
+                value: int(&value["value"])?,
+            }),
+            "call" => Ok(TraceEvent::Call {
+                function,
+                line,
+                callee: string("callee")?,
+                args: ints("args")?,
+            }),
+            "return" => Ok(TraceEvent::Return {
+                function,
+                line,
+                callee: string("callee")?,
+                results: ints("results")?,
+            }),
+            event => Err(format!("unknown event `{}`", event)),
+        }
+    }
+}
+
+/// Write events out as JSON lines, one object per line.
+pub fn to_json_lines(events: &[TraceEvent]) -> String {
+    let mut json = String::new();
+    for event in events {
+        json.push_str(&event.to_json());
+        json.push('\n');
+    }
+    json
+}
+
+/// Read events written by `to_json_lines`, for example to compare a run
+/// against a recorded one.
+pub fn from_json_lines(json: &str) -> Result<Vec<TraceEvent>, String> {
+    json.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            TraceEvent::from_json(line).map_err(|e| format!("line {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+extern "C" fn assign(site: *const Site, value: isize) {
+    let site = unsafe { &*site };
+    let variable = match &site.kind {
+        SiteKind::Assign { variable } => variable.clone(),
+        SiteKind::Call { .. } => unreachable!(),
+    };
+    site.events.lock().unwrap().push(TraceEvent::Assign {
+        function: site.function.clone(),
+        line: site.line,
+        variable,
+        value,
+    });
+}
+
+extern "C" fn call(site: *const Site, args: *const isize) {
+    let site = unsafe { &*site };
+    let (callee, args) = match &site.kind {
+        SiteKind::Call { callee, args: 0 } => (callee.clone(), Vec::new()),
+        SiteKind::Call { callee, args: len } => (
+            callee.clone(),
+            unsafe { slice::from_raw_parts(args, *len) }.to_vec(),
+        ),
+        SiteKind::Assign { .. } => unreachable!(),
+    };
+    site.events.lock().unwrap().push(TraceEvent::Call {
+        function: site.function.clone(),
+        line: site.line,
+        callee,
+        args,
+    });
+}
+
+extern "C" fn return_(site: *const Site, results: *const isize, len: usize) {
+    let site = unsafe { &*site };
+    let callee = match &site.kind {
+        SiteKind::Call { callee, .. } => callee.clone(),
+        SiteKind::Assign { .. } => unreachable!(),
+    };
+    let results = unsafe { slice::from_raw_parts(results, len) }.to_vec();
+    site.events.lock().unwrap().push(TraceEvent::Return {
+        function: site.function.clone(),
+        line: site.line,
+        callee,
+        results,
+    });
+}
@@ -0,0 +1,115 @@
+use crate::debug_info::endianness;
+use crate::jit::CompiledFunction;
+use cranelift::codegen::gimli::write::{Address, EhFrame, EndianVec, FrameTable};
+use cranelift::codegen::isa::unwind::{systemv, UnwindInfo};
+use cranelift::codegen::isa::TargetIsa;
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, Module};
+use std::convert::TryInto;
+
+/// The unwind info of JIT'd toy functions, registered with the unwinder of
+/// the host process.
+///
+/// `cranelift_jit` doesn't register unwind info by itself, so without this
+/// the unwinder gives up at the first JIT'd frame, and a panic raised in a
+/// host function called from toy code, like a trap of the runtime, aborts the
+/// process instead of unwinding to the host code which called into the JIT.
+#[derive(Default)]
+pub(crate) struct Unwind {
+    /// The functions compiled since the last time unwind info was registered.
+    pending: Vec<(FuncId, systemv::UnwindInfo)>,
+
+    registrations: Vec<Registration>,
+}
+
+impl Unwind {
+    pub(crate) fn add_function(&mut self, function: &CompiledFunction, isa: &dyn TargetIsa) {
+        if let Ok(Some(UnwindInfo::SystemV(unwind_info))) = function.code.create_unwind_info(isa) {
+            self.pending.push((function.id, unwind_info));
+        }
+    }
+
+    /// Register the unwind info of the functions compiled since the last
+    /// time, now that they're finalized.
+    pub(crate) fn register(&mut self, module: &JITModule) -> Result<(), String> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let isa = module.isa();
+        let cie = match isa.create_systemv_cie() {
+            Some(cie) => cie,
+            None => return Ok(()),
+        };
+        let mut frame_table = FrameTable::default();
+        let cie = frame_table.add_cie(cie);
+        for (id, unwind_info) in self.pending.drain(..) {
+            let address = module.get_finalized_function(id) as u64;
+            frame_table.add_fde(cie, unwind_info.to_fde(Address::Constant(address)));
+        }
+        let mut eh_frame = EhFrame::from(EndianVec::new(endianness(isa)));
+        frame_table
+            .write_eh_frame(&mut eh_frame)
+            .map_err(|e| e.to_string())?;
+
+        // The section ends with an entry of length zero.
+        let mut eh_frame = eh_frame.0.into_vec();
+        eh_frame.extend_from_slice(&[0; 4]);
+        self.registrations.push(Registration::new(eh_frame));
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn __register_frame(fde: *const u8);
+    fn __deregister_frame(fde: *const u8);
+}
+
+// Elsewhere, unwinding doesn't use DWARF unwind info.
+#[cfg(not(unix))]
+unsafe fn __register_frame(_fde: *const u8) {}
+#[cfg(not(unix))]
+unsafe fn __deregister_frame(_fde: *const u8) {}
+
+/// An `.eh_frame` section registered with the unwinder, until dropped.
+struct Registration {
+    eh_frame: Vec<u8>,
+}
+
+impl Registration {
+    fn new(eh_frame: Vec<u8>) -> Self {
+        let registration = Self { eh_frame };
+        for entry in registration.entries() {
+            unsafe { __register_frame(entry) };
+        }
+        registration
+    }
+
+    /// Return what `__register_frame` has to be called with: the whole
+    /// section for libgcc's unwinder, and each FDE for the LLVM one of macOS.
+    fn entries(&self) -> Vec<*const u8> {
+        if !cfg!(target_os = "macos") {
+            return vec![self.eh_frame.as_ptr()];
+        }
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 4 < self.eh_frame.len() {
+            let length = &self.eh_frame[offset..offset + 4];
+            let length = u32::from_ne_bytes(length.try_into().unwrap()) as usize;
+            // The first entry is the CIE, which the FDEs refer to.
+            if offset > 0 {
+                entries.push(self.eh_frame[offset..].as_ptr());
+            }
+            offset += 4 + length;
+        }
+        entries
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        for entry in self.entries() {
+            unsafe { __deregister_frame(entry) };
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::mem;
+
+/// The most integer and floating-point arguments a variadic call can pass:
+/// those passed in registers. Arguments passed on the stack aren't
+/// supported.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const MAX_INT_ARGS: usize = 6;
+#[cfg(not(target_arch = "x86_64"))]
+pub(crate) const MAX_INT_ARGS: usize = 8;
+pub(crate) const MAX_FLOAT_ARGS: usize = 8;
+
+/// The size of the block of arguments `__toy_call_variadic` takes: the
+/// integer arguments, followed by the floating-point ones, each in a slot of
+/// 8 bytes.
+pub(crate) const ARGS_SIZE: usize = (MAX_INT_ARGS + MAX_FLOAT_ARGS) * mem::size_of::<u64>();
+
+/// Return the host function compiled code calls variadic C functions
+/// through, by the name it imports it under.
+pub(crate) fn hooks() -> [(&'static str, *const u8); 1] {
+    [("__toy_call_variadic", call_variadic as *const u8)]
+}
+
+/// Whether variadic C functions can be called on this platform.
+///
+/// Cranelift has no notion of variadic functions. On the platforms here,
+/// variadic arguments are passed like any other, in the registers of their
+/// class in order, so a call passing them all the same way works. Except
+/// that on x86-64, the caller of a variadic function has to set `%al` to
+/// (an upper bound on) the number of vector registers it passes arguments
+/// in, which Cranelift can't do. So the calls go through a shim written in
+/// assembly, which loads the argument registers from a block in memory,
+/// sets `%al`, and calls the function.
+pub(crate) fn supported() -> bool {
+    cfg!(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+extern "C" {
+    /// Call the C function `function` with the arguments in `args`, laid
+    /// out as described for `ARGS_SIZE`, of which `floats` are
+    /// floating-point ones. The slots past those used are loaded into
+    /// registers too, whatever they hold.
+    #[link_name = "cranelift_jit_demo_call_variadic"]
+    fn call_variadic(function: usize, args: *const u64, floats: usize) -> isize;
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+std::arch::global_asm!(
+    ".text",
+    ".p2align 4",
+    ".globl cranelift_jit_demo_call_variadic",
+    ".hidden cranelift_jit_demo_call_variadic",
+    ".type cranelift_jit_demo_call_variadic, @function",
+    "cranelift_jit_demo_call_variadic:",
+    ".cfi_startproc",
+    "push rbp",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset rbp, -16",
+    "mov rbp, rsp",
+    ".cfi_def_cfa_register rbp",
+    "mov r11, rdi",
+    "mov r10, rsi",
+    "movsd xmm0, qword ptr [r10 + 48]",
+    "movsd xmm1, qword ptr [r10 + 56]",
+    "movsd xmm2, qword ptr [r10 + 64]",
+    "movsd xmm3, qword ptr [r10 + 72]",
+    "movsd xmm4, qword ptr [r10 + 80]",
+    "movsd xmm5, qword ptr [r10 + 88]",
+    "movsd xmm6, qword ptr [r10 + 96]",
+    "movsd xmm7, qword ptr [r10 + 104]",
+    "mov rax, rdx",
+    "mov rdi, qword ptr [r10]",
+    "mov rsi, qword ptr [r10 + 8]",
+    "mov rdx, qword ptr [r10 + 16]",
+    "mov rcx, qword ptr [r10 + 24]",
+    "mov r8, qword ptr [r10 + 32]",
+    "mov r9, qword ptr [r10 + 40]",
+    "call r11",
+    "pop rbp",
+    ".cfi_def_cfa rsp, 8",
+    "ret",
+    ".cfi_endproc",
+    ".size cranelift_jit_demo_call_variadic, . - cranelift_jit_demo_call_variadic",
+);
+
+// On AArch64 Linux, unlike on macOS, variadic arguments are passed exactly
+// like the others, and there's no register to set.
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+std::arch::global_asm!(
+    ".text",
+    ".p2align 2",
+    ".globl cranelift_jit_demo_call_variadic",
+    ".hidden cranelift_jit_demo_call_variadic",
+    ".type cranelift_jit_demo_call_variadic, %function",
+    "cranelift_jit_demo_call_variadic:",
+    ".cfi_startproc",
+    "stp x29, x30, [sp, #-16]!",
+    ".cfi_def_cfa_offset 16",
+    ".cfi_offset x30, -8",
+    ".cfi_offset x29, -16",
+    "mov x29, sp",
+    "mov x16, x0",
+    "mov x17, x1",
+    "ldp d0, d1, [x17, #64]",
+    "ldp d2, d3, [x17, #80]",
+    "ldp d4, d5, [x17, #96]",
+    "ldp d6, d7, [x17, #112]",
+    "ldp x0, x1, [x17]",
+    "ldp x2, x3, [x17, #16]",
+    "ldp x4, x5, [x17, #32]",
+    "ldp x6, x7, [x17, #48]",
+    "blr x16",
+    "ldp x29, x30, [sp], #16",
+    ".cfi_def_cfa_offset 0",
+    ".cfi_restore x30",
+    ".cfi_restore x29",
+    "ret",
+    ".cfi_endproc",
+    ".size cranelift_jit_demo_call_variadic, . - cranelift_jit_demo_call_variadic",
+);
+
+// Elsewhere, variadic calls aren't compiled.
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+unsafe extern "C" fn call_variadic(_function: usize, _args: *const u64, _floats: usize) -> isize {
+    unreachable!("variadic calls aren't supported on this platform")
+}
@@ -0,0 +1,58 @@
+use cranelift_jit_demo::interp::Interpreter;
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::runtime::Trap;
+
+#[test]
+fn traps() {
+    let mut jit = JIT::default();
+    jit.compile("fn get(n, i) -> (r) {\n    a = array(n)\n    r = a[i]\n}\n")
+        .unwrap();
+
+    let get = jit.function("get").unwrap();
+    assert_eq!(get.call(&[3, 2]), Ok(vec![0]));
+    assert_eq!(
+        get.call(&[3, 3]),
+        Err(Trap::IndexOutOfBounds { index: 3, len: 3 })
+    );
+    assert_eq!(
+        get.call(&[3, -1]),
+        Err(Trap::IndexOutOfBounds { index: -1, len: 3 })
+    );
+    assert_eq!(get.call(&[-1, 0]), Err(Trap::InvalidArrayLength(-1)));
+
+    // Values which aren't arrays trap rather than being read from, both in
+    // compiled and in interpreted code.
+    let not_array = "fn not_array(x) -> (r) {\n    a = x\n    r = a[0]\n}\n";
+    let not_array_len = "fn not_array_len() -> (r) {\n    r = len(\"toy\")\n}\n";
+    jit.compile_all(&[not_array, not_array_len]).unwrap();
+    let mut interp = Interpreter::default();
+    interp.define(not_array).unwrap();
+    interp.define(not_array_len).unwrap();
+
+    let not_array = jit.function("not_array").unwrap();
+    assert_eq!(not_array.call(&[8]), Err(Trap::NotAnArray(8)));
+    assert_eq!(not_array.call(&[0]), Err(Trap::NullArray));
+    assert_eq!(
+        interp.call("not_array", &[8]),
+        Err(Trap::NotAnArray(8).to_string())
+    );
+    let not_array_len = jit.function("not_array_len").unwrap();
+    assert!(matches!(not_array_len.call(&[]), Err(Trap::NotAnArray(_))));
+    assert!(interp.call("not_array_len", &[]).is_err());
+
+    // Constant tables aren't on the heap, but are arrays all the same.
+    let squares = "const SQUARES = [0, 1, 4]\n";
+    let square = "fn square(i) -> (r) {\n    r = SQUARES[i] + len(SQUARES) * 100\n}\n";
+    jit.declare(squares).unwrap();
+    jit.compile(square).unwrap();
+    interp.declare(squares).unwrap();
+    interp.define(square).unwrap();
+
+    let square = jit.function("square").unwrap();
+    assert_eq!(square.call(&[2]), Ok(vec![304]));
+    assert_eq!(
+        square.call(&[3]),
+        Err(Trap::IndexOutOfBounds { index: 3, len: 3 })
+    );
+    assert_eq!(interp.call("square", &[2]), Ok(304));
+}
@@ -0,0 +1,200 @@
+use cranelift_jit_demo::jit::JIT;
+use std::ffi::CStr;
+
+const DIVMOD: &str = r#"
+fn divmod(a, b) -> (q, r) {
+    q = a / b
+    r = a - q * b
+}
+"#;
+
+const LAST_DIGIT: &str = r#"
+fn last_digit(n) -> (r) {
+    q, m = divmod(n, 10)
+    r = m
+}
+"#;
+
+const SUM_SQUARES: &str = r#"
+fn sum_squares(n) -> (r) {
+    a = array(n)
+    i = 0
+    while i != n {
+        a[i] = i * i
+        i = i + 1
+    }
+    i = 0
+    while i != n {
+        r = r + a[i]
+        i = i + 1
+    }
+}
+"#;
+
+const OUT_OF_BOUNDS: &str = r#"
+fn out_of_bounds(i) -> (r) {
+    a = array(3)
+    r = a[i]
+}
+"#;
+
+const BYTES: &str = r#"
+fn bytes(x) -> (r) {
+    store16(&buf + 1, x)
+    r = sload8(&buf + 1) + load8(&buf + 2)
+}
+"#;
+
+const FLOATS: &str = r#"
+fn floats(x) -> (r) {
+    p: Point
+    p = &buf
+    p.y = x * 3
+    p.z = x
+    r = p.y + p.z
+}
+"#;
+
+const REFERENCES: &str = r#"
+fn inc(x) -> (r) {
+    r = x + 1
+}
+
+fn twice(f, x) -> (r) {
+    r = f(f(x))
+}
+
+fn use_twice(x) -> (r) {
+    r = twice(&inc, x)
+}
+
+fn mismatch(x) -> (r) {
+    f = &inc
+    r = f(x, x)
+}
+"#;
+
+const CLOSURES: &str = r#"
+fn closures(n) -> (r) {
+    k = 3
+    f = |x| x * k
+    r = f(n) + f(1)
+}
+"#;
+
+const PRINT: &str = r#"
+fn print(x) -> (r) {
+    r = snprintf(&buf, 16, "%d:%.2f", x, f64(x * 3))
+}
+"#;
+
+/// Check that the Cranelift IR interpreter agrees with the compiled code
+/// of the function `name`, traps included.
+fn check(jit: &JIT, name: &str, args: &[isize]) -> Result<Vec<isize>, String> {
+    let native = jit
+        .function(name)
+        .unwrap()
+        .call(args)
+        .map_err(|trap| format!("trap: {}", trap));
+    let interpreted = jit
+        .clif_interpreter()
+        .unwrap()
+        .call(name, args)
+        .map(|values| values.into_iter().map(|value| value as isize).collect());
+    assert_eq!(native, interpreted, "{}({:?})", name, args);
+    native
+}
+
+/// Split `source` into the functions in it.
+fn functions(source: &str) -> Vec<String> {
+    source
+        .split("\nfn ")
+        .filter(|function| !function.trim().is_empty())
+        .map(|function| format!("fn {}", function.trim_start_matches("fn ")))
+        .collect()
+}
+
+/// Compile all the functions above, and return the address of `buf`, the
+/// buffer they write to.
+fn jit() -> (JIT, usize) {
+    let mut jit = JIT::default();
+    jit.enable_clif_interpreter();
+    let buf = jit.create_data("buf", vec![0; 16]).unwrap().as_ptr() as usize;
+    jit.declare("struct Point {\n    x: i8,\n    y: f64,\n    z: f32,\n}\n")
+        .unwrap();
+    jit.declare("extern fn snprintf(s, n, format, ...);\n")
+        .unwrap();
+    for source in [
+        DIVMOD,
+        LAST_DIGIT,
+        SUM_SQUARES,
+        OUT_OF_BOUNDS,
+        BYTES,
+        FLOATS,
+        CLOSURES,
+        PRINT,
+    ] {
+        jit.compile(source).unwrap();
+    }
+    let references = functions(REFERENCES);
+    let references: Vec<&str> = references.iter().map(|s| &s[..]).collect();
+    jit.compile_all(&references).unwrap();
+    (jit, buf)
+}
+
+#[test]
+fn multiple_return_values() {
+    let (jit, _) = jit();
+    assert_eq!(check(&jit, "divmod", &[17, 5]), Ok(vec![3, 2]));
+    assert_eq!(check(&jit, "last_digit", &[1234]), Ok(vec![4]));
+}
+
+#[test]
+fn arrays() {
+    let (jit, _) = jit();
+    assert_eq!(check(&jit, "sum_squares", &[10]), Ok(vec![285]));
+    assert_eq!(check(&jit, "out_of_bounds", &[1]), Ok(vec![0]));
+    assert!(check(&jit, "out_of_bounds", &[3]).is_err());
+    assert!(check(&jit, "out_of_bounds", &[-1]).is_err());
+}
+
+#[test]
+fn memory() {
+    let (jit, _) = jit();
+    assert_eq!(check(&jit, "bytes", &[0x1ff]), Ok(vec![0]));
+    assert_eq!(check(&jit, "bytes", &[0x280]), Ok(vec![-126]));
+    assert_eq!(check(&jit, "floats", &[7]), Ok(vec![28]));
+    assert_eq!(check(&jit, "floats", &[-5]), Ok(vec![-20]));
+}
+
+#[test]
+fn references_and_closures() {
+    let (jit, _) = jit();
+    assert_eq!(check(&jit, "use_twice", &[40]), Ok(vec![42]));
+    assert!(check(&jit, "mismatch", &[1]).is_err());
+    assert_eq!(check(&jit, "closures", &[5]), Ok(vec![18]));
+}
+
+#[test]
+fn variadic_calls() {
+    let (jit, buf) = jit();
+    let buf = || {
+        unsafe { CStr::from_ptr(buf as *const _) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    };
+    assert_eq!(check(&jit, "print", &[3]), Ok(vec![6]));
+    assert_eq!(buf(), "3:9.00");
+}
+
+#[test]
+fn garbage_collection() {
+    let mut jit = JIT::default();
+    jit.enable_gc().unwrap();
+    jit.enable_clif_interpreter();
+    jit.compile(SUM_SQUARES).unwrap();
+    jit.compile(CLOSURES).unwrap();
+    assert_eq!(check(&jit, "sum_squares", &[10]), Ok(vec![285]));
+    assert_eq!(check(&jit, "closures", &[5]), Ok(vec![18]));
+}
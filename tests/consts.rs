@@ -0,0 +1,93 @@
+use cranelift_jit_demo::interp::Interpreter;
+use cranelift_jit_demo::jit::JIT;
+
+const CONSTS: &str = r#"const LIMIT = 1 << 20
+const HALF = LIMIT / 2
+const MASK = (HALF - 1) + (3 * 2 >> 1)
+const BIG = LIMIT * LIMIT * LIMIT
+"#;
+
+#[test]
+fn derived() {
+    let mut jit = JIT::default();
+    let mut interp = Interpreter::default();
+    jit.declare(CONSTS).unwrap();
+    interp.declare(CONSTS).unwrap();
+    let function = "fn consts() -> (limit, half, mask, big) {\n    \
+                    limit = LIMIT\n    half = HALF\n    mask = MASK\n    big = BIG\n}\n";
+    jit.compile(function).unwrap();
+    interp.define(function).unwrap();
+
+    // `BIG` doesn't fit in the immediates of literals.
+    let expected = vec![1 << 20, 1 << 19, (1 << 19) + 2, 1 << 60];
+    let consts = jit.function("consts").unwrap();
+    assert_eq!(consts.call(&[]), Ok(expected.clone()));
+    assert_eq!(interp.call_results("consts", &[]), Ok(expected));
+}
+
+#[test]
+fn errors() {
+    let declared = |input: &str| {
+        let result = JIT::default().declare(input);
+        assert_eq!(result, Interpreter::default().declare(input), "{}", input);
+        result
+    };
+    let overflow = Err("overflow in constant expression".to_owned());
+    assert_eq!(declared("const A = 1 << 62\nconst B = A * 2\n"), overflow);
+    assert_eq!(declared("const A = 9223372036854775807 + 1\n"), overflow);
+    assert_eq!(declared("const A = -9223372036854775807 - 2\n"), overflow);
+    assert_eq!(
+        declared("const A = 1 << 64\n"),
+        Err("shift by 64 in constant expression".to_owned())
+    );
+    assert_eq!(
+        declared("const A = 1 / (1 - 1)\n"),
+        Err("division by zero in constant expression".to_owned())
+    );
+    assert_eq!(
+        declared("const A = B + 1\nconst B = 1\n"),
+        Err("constant `B` not defined".to_owned())
+    );
+    assert_eq!(
+        declared("const A = 1\nconst A = 2\n"),
+        Err("duplicate declaration of constant `A`".to_owned())
+    );
+
+    let mut jit = JIT::default();
+    jit.declare("const A = 1\n").unwrap();
+    assert!(jit.compile("fn assign() -> (r) {\n    A = 2\n}\n").is_err());
+}
+
+/// Tables are placed in memory which the module made read-only once it was
+/// finalized.
+#[test]
+#[cfg(target_os = "linux")]
+fn read_only_tables() {
+    let mut jit = JIT::default();
+    jit.declare("const SQUARES = [0, 1, 4, 9, 16]\n").unwrap();
+    jit.compile("fn squares() -> (r) {\n    r = SQUARES\n}\n")
+        .unwrap();
+    let table = jit.function("squares").unwrap().call(&[]).unwrap()[0] as usize;
+    assert_eq!(
+        unsafe { std::slice::from_raw_parts(table as *const isize, 6) },
+        [5, 0, 1, 4, 9, 16]
+    );
+
+    // Each line is `START-END PERMISSIONS ...`, in hex.
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let permissions = maps
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(' ');
+            let (start, end) = fields.next()?.split_once('-')?;
+            let range =
+                usize::from_str_radix(start, 16).ok()?..usize::from_str_radix(end, 16).ok()?;
+            if range.contains(&table) {
+                fields.next()
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| panic!("{:#x} isn't mapped", table));
+    assert!(permissions.starts_with("r-"), "{}", permissions);
+}
@@ -0,0 +1,69 @@
+use cranelift_jit_demo::jit::JIT;
+
+const SIGN: &str = r#"fn sign(x) -> (r) {
+    if x == 0 {
+        r = 0
+    } else {
+        r = 1
+    }
+}
+"#;
+
+#[test]
+fn branches() {
+    let mut jit = JIT::default();
+    jit.set_file_name("sign.toy");
+    jit.enable_coverage();
+    jit.compile(SIGN).unwrap();
+
+    let sign = jit.function("sign").unwrap();
+    for x in &[3, 0, 5, 7] {
+        sign.call(&[*x]).unwrap();
+    }
+
+    let report = jit.coverage_report();
+    let sign = &report.functions[0];
+    assert_eq!(sign.name, "sign");
+    assert_eq!(sign.calls, 4);
+    assert_eq!(sign.branches.len(), 1);
+    assert_eq!(sign.branches[0].line, 2);
+    assert_eq!(sign.branches[0].taken, 1);
+    assert_eq!(sign.branches[0].not_taken, 3);
+
+    assert_eq!(
+        report.to_lcov(),
+        "TN:\n\
+         SF:sign.toy\n\
+         FN:1,sign\n\
+         FNDA:4,sign\n\
+         FNF:1\n\
+         FNH:1\n\
+         BRDA:2,0,0,1\n\
+         BRDA:2,0,1,3\n\
+         BRF:2\n\
+         BRH:2\n\
+         DA:1,4\n\
+         DA:2,4\n\
+         DA:3,1\n\
+         DA:5,3\n\
+         LF:4\n\
+         LH:4\n\
+         end_of_record\n"
+    );
+}
+
+#[test]
+fn untaken() {
+    let mut jit = JIT::default();
+    jit.enable_coverage();
+    jit.compile(SIGN).unwrap();
+    jit.function("sign").unwrap().call(&[0]).unwrap();
+
+    let report = jit.coverage_report();
+    let branch = &report.functions[0].branches[0];
+    assert_eq!((branch.taken, branch.not_taken), (1, 0));
+    let lcov = report.to_lcov();
+    assert!(lcov.contains("SF:<toy>\n"), "{}", lcov);
+    assert!(lcov.contains("BRDA:2,0,1,0\nBRF:2\nBRH:1\n"), "{}", lcov);
+    assert!(lcov.contains("DA:5,0\n"), "{}", lcov);
+}
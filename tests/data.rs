@@ -0,0 +1,131 @@
+use cranelift_jit_demo::interp::Interpreter;
+use cranelift_jit_demo::jit::{DataPointer, JIT};
+
+const ADD: &str = "fn add(a, b) -> (r) {\n    r = a + b\n}\n";
+const SUB: &str = "fn sub(a, b) -> (r) {\n    r = a - b\n}\n";
+
+#[test]
+fn function_table() {
+    let mut jit = JIT::default();
+    jit.compile_all(&[ADD, SUB]).unwrap();
+    let function = |name: &str| DataPointer::Function(name.to_owned());
+    jit.create_data_with_pointers(
+        "ops",
+        vec![0; 16],
+        &[(0, function("add")), (8, function("sub"))],
+    )
+    .unwrap();
+    jit.compile("fn apply(op, a, b) -> (r) {\n    f = load64(&ops + op * 8)\n    r = f(a, b)\n}\n")
+        .unwrap();
+
+    let apply = jit.function("apply").unwrap();
+    assert_eq!(apply.call(&[0, 5, 3]), Ok(vec![8]));
+    assert_eq!(apply.call(&[1, 5, 3]), Ok(vec![2]));
+}
+
+#[test]
+fn data_pointers() {
+    let mut jit = JIT::default();
+    let answer = jit
+        .create_data("answer", 42isize.to_ne_bytes().to_vec())
+        .unwrap();
+    let answer = answer.as_ptr() as isize;
+    let mut contents = vec![0xff; 4];
+    contents.extend_from_slice(&[0; 8]);
+    let linked = jit
+        .create_data_with_pointers(
+            "linked",
+            contents,
+            &[(4, DataPointer::Data("answer".to_owned()))],
+        )
+        .unwrap();
+    // The bytes around the pointer are left as they are.
+    assert_eq!(linked[..4], [0xff; 4]);
+    assert_eq!(linked[4..], answer.to_ne_bytes());
+}
+
+#[test]
+fn errors() {
+    let mut jit = JIT::default();
+    jit.compile(ADD).unwrap();
+    jit.create_tls_data("counter", vec![0; 8]).unwrap();
+    let mut create = |name: &str, offset: usize, pointer: DataPointer| {
+        jit.create_data_with_pointers(name, vec![0; 16], &[(offset, pointer)])
+            .map(|_| ())
+    };
+    let function = |name: &str| DataPointer::Function(name.to_owned());
+    let data = |name: &str| DataPointer::Data(name.to_owned());
+
+    assert_eq!(
+        create("uncompiled", 0, function("sub")),
+        Err("function `sub` not compiled".to_owned())
+    );
+    assert_eq!(
+        create("undefined", 0, data("nowhere")),
+        Err("data object `nowhere` not defined".to_owned())
+    );
+    assert_eq!(
+        create("thread_local", 0, data("counter")),
+        Err("data object `counter` is thread-local, and has no single address".to_owned())
+    );
+    assert_eq!(
+        create("past_end", 9, function("add")),
+        Err("pointer at offset 9 is out of the bounds of data object `past_end`".to_owned())
+    );
+    assert_eq!(
+        create("overflow", usize::MAX - 3, function("add")),
+        Err(format!(
+            "pointer at offset {} is out of the bounds of data object `overflow`",
+            usize::MAX - 3
+        ))
+    );
+    let mut interp = Interpreter::default();
+    interp.define(ADD).unwrap();
+    assert!(interp
+        .create_data_with_pointers(
+            "overflow",
+            vec![0; 16],
+            &[(usize::MAX - 3, function("add"))]
+        )
+        .is_err());
+}
+
+const TABLES: &str = r#"const OPS = [&add, &sub]
+const NAMES = ["add", "subtract"]
+"#;
+
+const APPLY: &str = r#"fn apply(op, a, b) -> (r, name_len) {
+    f = OPS[op]
+    r = f(a, b)
+    name = NAMES[op]
+    name_len = str_len(name)
+}
+"#;
+
+/// The toy-level tables, `&name`s and string literals in brackets, which
+/// both engines resolve the same way.
+#[test]
+fn tables() {
+    let mut jit = JIT::default();
+    jit.compile_all(&[ADD, SUB]).unwrap();
+    jit.declare(TABLES).unwrap();
+    jit.compile(APPLY).unwrap();
+    let mut interp = Interpreter::default();
+    interp.define(ADD).unwrap();
+    interp.define(SUB).unwrap();
+    interp.declare(TABLES).unwrap();
+    interp.define(APPLY).unwrap();
+
+    let apply = jit.function("apply").unwrap();
+    for (op, expected) in [(0, vec![8, 3]), (1, vec![2, 8])] {
+        assert_eq!(apply.call(&[op, 5, 3]), Ok(expected.clone()));
+        assert_eq!(interp.call_results("apply", &[op, 5, 3]), Ok(expected));
+    }
+
+    // The functions have to be compiled before the table is declared.
+    let mut jit = JIT::default();
+    assert_eq!(
+        jit.declare("const LATER = [&later]\n"),
+        Err("data object `later` not defined".to_owned())
+    );
+}
@@ -0,0 +1,105 @@
+// The debug info is in ELF images, which this reads as little-endian.
+#![cfg(all(target_os = "linux", target_endian = "little"))]
+
+use cranelift_jit_demo::jit::JIT;
+use gimli::{EndianSlice, LittleEndian};
+use object::{Object, ObjectSection};
+use std::borrow::Cow;
+use std::slice;
+
+// The GDB JIT interface, which the JIT registers its images with.
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+extern "C" {
+    static __jit_debug_descriptor: JitDescriptor;
+}
+
+const SOURCE: &str = "\
+fn triple(x) -> (r) {
+    y = x * 2
+    r = y + x
+}
+";
+
+#[test]
+fn line_table() {
+    let mut jit = JIT::default();
+    jit.set_file_name("triple.toy");
+    jit.enable_debug_info();
+    let code = jit.compile(SOURCE).unwrap() as u64;
+
+    // The image registered last is the first entry.
+    let image = unsafe {
+        let entry = &*__jit_debug_descriptor.first_entry;
+        slice::from_raw_parts(entry.symfile_addr, entry.symfile_size as usize)
+    };
+    let file = object::File::parse(image).unwrap();
+    let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let data = match file.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data().unwrap(),
+            None => Cow::Borrowed(&[][..]),
+        };
+        Ok(data)
+    })
+    .unwrap();
+    let dwarf = dwarf.borrow(|section| EndianSlice::new(section, LittleEndian));
+
+    let mut units = dwarf.units();
+    let header = units.next().unwrap().unwrap();
+    let unit = dwarf.unit(header).unwrap();
+
+    // The function is described by its name and address.
+    let mut entries = unit.entries();
+    let mut subprogram = None;
+    while let Some((_, entry)) = entries.next_dfs().unwrap() {
+        if entry.tag() == gimli::DW_TAG_subprogram {
+            let name = entry.attr_value(gimli::DW_AT_name).unwrap().unwrap();
+            let name = dwarf.attr_string(&unit, name).unwrap();
+            let low_pc = entry.attr_value(gimli::DW_AT_low_pc).unwrap();
+            subprogram = Some((name.to_string().unwrap().to_owned(), low_pc));
+        }
+    }
+    assert_eq!(
+        subprogram,
+        Some(("triple".to_owned(), Some(gimli::AttributeValue::Addr(code))))
+    );
+
+    // The line table maps the code back to the lines of the function.
+    let program = unit.line_program.clone().unwrap();
+    let mut rows = program.rows();
+    let mut lines = Vec::new();
+    while let Some((header, row)) = rows.next_row().unwrap() {
+        if row.end_sequence() {
+            continue;
+        }
+        assert!(row.address() >= code);
+        let file = row.file(header).unwrap();
+        let path = dwarf.attr_string(&unit, file.path_name()).unwrap();
+        assert_eq!(path.to_string().unwrap(), "triple.toy");
+        let line = row.line().unwrap().get();
+        if lines.last() != Some(&line) {
+            lines.push(line);
+        }
+    }
+    assert!(
+        lines.iter().all(|line| (1..=4).contains(line)),
+        "{:?}",
+        lines
+    );
+    assert!(lines.contains(&2) && lines.contains(&3), "{:?}", lines);
+}
@@ -0,0 +1,126 @@
+use cranelift_jit_demo::jit::JIT;
+
+#[test]
+fn printf_with_doubles() {
+    let mut jit = JIT::default();
+    jit.declare("extern fn printf(format, ...);\n").unwrap();
+    jit.compile(
+        "fn show(x) -> (r) {\n    r = printf(\"%d squared is %.2f\\n\", x, f64(x * x))\n}\n",
+    )
+    .unwrap();
+    // printf returns the number of characters it printed.
+    let show = jit.function("show").unwrap();
+    assert_eq!(
+        show.call(&[7]),
+        Ok(vec!["7 squared is 49.00\n".len() as isize])
+    );
+}
+
+// Host functions standing in for C ones. Those taking narrow integers are
+// defined with 32-bit parameters, to see what the callers extended them to,
+// and `wide` returns bits past those of the narrow types it's declared with.
+extern "C" fn scale(x: i32, factor: *const f64) -> f64 {
+    f64::from(x) * unsafe { *factor }
+}
+
+extern "C" fn narrow(a: i32, b: u32) -> i64 {
+    i64::from(a) * 1000 + i64::from(b)
+}
+
+extern "C" fn wide() -> i64 {
+    0x1234_5678_ffff
+}
+
+fn jit() -> JIT {
+    JIT::with_symbol_lookup(|name| match name {
+        "scale" => Some(scale as *const u8),
+        "narrow" => Some(narrow as *const u8),
+        "wide" => Some(wide as *const u8),
+        _ => None,
+    })
+}
+
+#[test]
+fn typed_signatures() {
+    let mut jit = jit();
+    jit.declare(
+        "extern fn scale(x: i32, factor: ptr) -> f64;\n\
+         extern fn narrow(a: i8, b: u8) -> i64;\n\
+         extern fn wide() -> i8;\n\
+         extern fn ldexp(x: f64, exp: i32) -> f64;\n\
+         extern \"system\" fn abs(x: i32) -> i32;\n",
+    )
+    .unwrap();
+    jit.compile_all(&[
+        "fn call_scale(x, factor) -> (r) {\n    r = scale(x, factor)\n}\n",
+        "fn call_narrow(a, b) -> (r) {\n    r = narrow(a, b)\n}\n",
+        "fn call_wide() -> (r) {\n    r = wide()\n}\n",
+        "fn call_ldexp(x, exp) -> (r) {\n    r = ldexp(x, exp)\n}\n",
+        "fn call_abs(x) -> (r) {\n    r = abs(x)\n}\n",
+    ])
+    .unwrap();
+    let call = |name: &str, args: &[isize]| jit.function(name).unwrap().call(args);
+
+    // Floats are rounded toward zero on the way back.
+    let factor = 2.5f64;
+    let factor = &factor as *const f64 as isize;
+    assert_eq!(call("call_scale", &[3, factor]), Ok(vec![7]));
+    assert_eq!(call("call_scale", &[-3, factor]), Ok(vec![-7]));
+    assert_eq!(call("call_ldexp", &[3, 2]), Ok(vec![12]));
+
+    // Arguments are truncated to their types, then sign or zero extended
+    // to 32 bits, and narrow results are extended back from their own bits.
+    assert_eq!(call("call_narrow", &[-1, 255]), Ok(vec![-1000 + 255]));
+    assert_eq!(
+        call("call_narrow", &[0x17f, 0x1ff]),
+        Ok(vec![127_000 + 255])
+    );
+    assert_eq!(call("call_narrow", &[0x80, -1]), Ok(vec![-128_000 + 255]));
+    assert_eq!(call("call_wide", &[]), Ok(vec![-1]));
+
+    assert_eq!(call("call_abs", &[-5]), Ok(vec![5]));
+    assert_eq!(call("call_abs", &[1 << 32 | 5]), Ok(vec![5]));
+}
+
+#[test]
+fn unsigned_results() {
+    let mut jit = jit();
+    jit.declare("extern fn wide() -> u16;\n").unwrap();
+    jit.compile("fn call_wide() -> (r) {\n    r = wide()\n}\n")
+        .unwrap();
+    let call_wide = jit.function("call_wide").unwrap();
+    assert_eq!(call_wide.call(&[]), Ok(vec![0xffff]));
+}
+
+#[test]
+fn mismatched_calls() {
+    let mut jit = jit();
+    jit.declare("extern fn narrow(a: i8, b: u8) -> i64;\nextern fn printf(format, ...);\n")
+        .unwrap();
+    assert_eq!(
+        jit.compile("fn too_few() -> (r) {\n    r = narrow(1)\n}\n"),
+        Err("function `narrow` takes 2 arguments but 1 were supplied".to_owned())
+    );
+    assert_eq!(
+        jit.compile("fn too_many_results() -> (q, r) {\n    q, r = narrow(1, 2)\n}\n"),
+        Err("function `narrow` returns 1 values but 2 were expected".to_owned())
+    );
+    assert_eq!(
+        jit.compile("fn no_format() -> (r) {\n    r = printf()\n}\n"),
+        Err("function `printf` takes at least 1 arguments but 0 were supplied".to_owned())
+    );
+
+    let declared = |input: &str| JIT::default().declare(input);
+    assert_eq!(
+        declared("extern \"fastcall\" fn f(x);\n"),
+        Err("error at 1:9: expected one of \"C\", \"system\"".to_owned())
+    );
+    assert_eq!(
+        declared("extern fn f(x: i128);\n"),
+        Err("error at 1:20: expected field type".to_owned())
+    );
+    assert_eq!(
+        declared("extern fn f(format, ...) -> f64;\n"),
+        Err("error at 2:1: expected an integer return type for a variadic function".to_owned())
+    );
+}
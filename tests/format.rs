@@ -0,0 +1,55 @@
+use cranelift_jit_demo::format::format_source;
+
+const SOURCE: &str = r#"
+// Declarations of every kind.
+struct Point {
+      x: i32,
+  y: f64
+}
+global   counter = 1
+thread_local global  depth = 0
+const LIMIT = (1 << 4)+2
+extern fn printf(format , ...);
+
+
+fn divmod(a,b)->(q,r) { // Unsigned.
+    q = a/b
+  r = a-q*b
+}
+fn digits(n) -> (r) {
+    while n!=0 {
+        n , d = divmod(n, 10)
+
+        // Count it.
+        r=r+1
+    }
+}
+
+fn closures(n) -> (r) {
+    k = (n+1)*2
+    f = |x| x*k
+    r = if n < LIMIT {
+            f(n)
+    } else {
+        printf("%d %f\n", n,f64(n))
+    }
+}
+"#;
+
+#[test]
+fn idempotence() {
+    let formatted = format_source(SOURCE).unwrap();
+    assert_ne!(formatted, SOURCE);
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+}
+
+#[test]
+fn comments_and_blank_lines() {
+    let formatted = format_source(SOURCE).unwrap();
+    assert!(formatted.starts_with("// Declarations of every kind.\nstruct Point {\n"));
+    assert!(formatted.contains("fn divmod(a, b) -> (q, r) { // Unsigned.\n"));
+    assert!(formatted.contains("\n\n        // Count it.\n        r = r + 1\n"));
+    // Functions are separated by a blank line, and runs of them collapse.
+    assert!(formatted.contains("}\n\nfn digits(n) -> (r) {\n"));
+    assert!(!formatted.contains("\n\n\n"));
+}
@@ -0,0 +1,43 @@
+use cranelift_jit_demo::jit::JIT;
+
+#[test]
+fn garbage_collection_under_churn() {
+    let mut jit = JIT::default();
+    if jit.enable_gc().is_err() {
+        // Not supported on this platform.
+        return;
+    }
+    jit.compile(
+        r#"
+fn churn(n) -> (r) {
+    keep = array(n)
+    i = 0
+    while i != n {
+        a = array(100)
+        a[99] = i
+        b = array(1)
+        b[0] = a[99]
+        keep[i] = b
+        i = i + 1
+    }
+    i = 0
+    while i != n {
+        b = keep[i]
+        r = r + b[0]
+        i = i + 1
+    }
+}
+"#,
+    )
+    .unwrap();
+
+    let churn = jit.function("churn").unwrap();
+    assert_eq!(churn.call(&[10_000]), Ok(vec![49_995_000]));
+    let stats = jit.heap_stats();
+    assert!(stats.collections > 0);
+    // The temporary arrays were freed, but not those still referred to.
+    assert!(stats.objects < 20_000, "{} objects", stats.objects);
+
+    jit.collect_garbage();
+    assert_eq!(jit.heap_stats().objects, 0);
+}
@@ -0,0 +1,141 @@
+use cranelift_jit_demo::interp::Interpreter;
+use cranelift_jit_demo::jit::JIT;
+
+const FUNCTIONS: &[&str] = &[
+    r#"
+fn collatz(n) -> (steps) {
+    while n != 1 {
+        half = n / 2
+        n = if half * 2 == n {
+            half
+        } else {
+            n * 3 + 1
+        }
+        steps = steps + 1
+    }
+}
+"#,
+    r#"
+fn divmod(a, b) -> (q, r) {
+    q = a / b
+    r = a - q * b
+}
+"#,
+    r#"
+fn digit_sum(n) -> (r) {
+    while n != 0 {
+        n, digit = divmod(n, 10)
+        r = r + digit
+    }
+}
+"#,
+    r#"
+fn divide(a, b) -> (r) {
+    r = a / b
+}
+"#,
+    r#"
+fn compare(a, b) -> (r) {
+    r = (a < b) + (a <= b) * 2 + (a > b) * 4 + (a >= b) * 8 + (a == b) * 16 + (a != b) * 32
+}
+"#,
+    r#"
+fn loop_value(n) -> (r) {
+    r = while n != 0 {
+        n = n - 1
+    }
+}
+"#,
+    r#"
+fn sum_array(n) -> (r) {
+    a = array(n)
+    i = 0
+    while i != len(a) {
+        a[i] = i - n
+        i = i + 1
+    }
+    i = 0
+    while i != n {
+        r = r + a[i]
+        i = i + 1
+    }
+}
+"#,
+    r#"
+fn adders(n) -> (r) {
+    k = n * 2
+    add = |x| x + k
+    twice = |f, x| f(f(x))
+    r = twice(add, n)
+}
+"#,
+];
+
+/// Define the functions above in both the interpreter and the JIT.
+fn engines() -> (Interpreter, JIT) {
+    let mut interp = Interpreter::default();
+    let mut jit = JIT::default();
+    for function in FUNCTIONS {
+        interp.define(function).unwrap();
+    }
+    jit.compile_all(FUNCTIONS).unwrap();
+    (interp, jit)
+}
+
+/// Check that the interpreter and the compiled code agree on the results of
+/// `name`, or on the trap it raises, and return them.
+fn check(
+    interp: &Interpreter,
+    jit: &JIT,
+    name: &str,
+    args: &[isize],
+) -> Result<Vec<isize>, String> {
+    let interpreted = interp.call_results(name, args);
+    let compiled = jit
+        .function(name)
+        .unwrap()
+        .call(args)
+        .map_err(|trap| trap.to_string());
+    assert_eq!(interpreted, compiled, "{}({:?})", name, args);
+    compiled
+}
+
+#[test]
+fn agreement() {
+    let (interp, jit) = engines();
+    for n in 1..50 {
+        check(&interp, &jit, "collatz", &[n]).unwrap();
+        check(&interp, &jit, "digit_sum", &[n * 7919]).unwrap();
+        check(&interp, &jit, "loop_value", &[n]).unwrap();
+        check(&interp, &jit, "sum_array", &[n]).unwrap();
+    }
+    for a in -3..3 {
+        for b in -3..3 {
+            check(&interp, &jit, "compare", &[a, b]).unwrap();
+        }
+    }
+    assert_eq!(check(&interp, &jit, "collatz", &[27]), Ok(vec![111]));
+    assert_eq!(check(&interp, &jit, "loop_value", &[5]), Ok(vec![0]));
+    assert!(check(&interp, &jit, "sum_array", &[-1]).is_err());
+    assert_eq!(check(&interp, &jit, "divide", &[7, 2]), Ok(vec![3]));
+    assert_eq!(
+        check(&interp, &jit, "divide", &[7, 0]),
+        Err("division by zero".to_owned())
+    );
+}
+
+#[test]
+fn multiple_return_values() {
+    let (interp, jit) = engines();
+    assert_eq!(check(&interp, &jit, "divmod", &[47, 10]), Ok(vec![4, 7]));
+    assert_eq!(check(&interp, &jit, "divmod", &[10, 47]), Ok(vec![0, 10]));
+    assert_eq!(check(&interp, &jit, "digit_sum", &[1234]), Ok(vec![10]));
+}
+
+#[test]
+fn closures() {
+    let (interp, jit) = engines();
+    for n in -5..5 {
+        assert_eq!(check(&interp, &jit, "adders", &[n]), Ok(vec![5 * n]));
+    }
+}
@@ -0,0 +1,92 @@
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::runtime::Trap;
+
+#[test]
+fn too_many_arguments() {
+    let mut jit = JIT::default();
+    jit.compile("fn seven(a, b, c, d, e, f, g) -> (r) {\n    r = a + g\n}\n")
+        .unwrap();
+    jit.compile("fn pair(a, b, c, d, e, f) -> (r, s) {\n    r = a + f\n}\n")
+        .unwrap();
+    jit.compile("fn closure(x) -> (r) {\n    r = |a, b, c, d, e, f| a + x\n}\n")
+        .unwrap();
+    jit.compile("fn reference() -> (r) {\n    r = &seven\n}\n")
+        .unwrap();
+
+    let too_many = |max, args| Trap::TooManyArguments { max, args };
+    assert_eq!(
+        jit.function("seven").unwrap().call(&[1; 7]),
+        Err(too_many(6, 7))
+    );
+    assert_eq!(
+        jit.function("pair").unwrap().call(&[1; 6]),
+        Err(too_many(5, 6))
+    );
+    let closure = jit.function("closure").unwrap().call(&[1]).unwrap()[0];
+    assert_eq!(jit.closure(closure).call(&[1; 6]), Err(too_many(5, 6)));
+    let reference = jit.function("reference").unwrap().call(&[]).unwrap()[0];
+    assert_eq!(jit.closure(reference).call(&[1; 7]), Err(too_many(6, 7)));
+}
+
+#[test]
+fn closure_signature_mismatch() {
+    let mut jit = JIT::default();
+    jit.compile_all(&[
+        "fn call(f) -> (r) {\n    r = f(1, 2)\n}\n",
+        "fn mismatch() -> (r) {\n    r = call(|x| x)\n}\n",
+    ])
+    .unwrap();
+    assert_eq!(
+        jit.function("mismatch").unwrap().call(&[]),
+        Err(Trap::SignatureMismatch { params: 1, args: 2 })
+    );
+}
+
+#[test]
+fn undefined_variables() {
+    let mut jit = JIT::default();
+    for input in &[
+        "fn read() -> (r) {\n    r = nope + 1\n}\n",
+        "fn index() -> (r) {\n    r = nope[0]\n}\n",
+        "fn assign_index() -> (r) {\n    nope[0] = 1\n}\n",
+        "fn capture() -> (r) {\n    f = |x| x + nope\n    r = f(1)\n}\n",
+    ] {
+        assert_eq!(
+            jit.compile(input).map(|_| ()),
+            Err("variable `nope` not defined".to_string())
+        );
+    }
+
+    // The module is left as it was, to compile the next functions.
+    jit.compile("fn inc(x) -> (r) {\n    r = x + 1\n}\n")
+        .unwrap();
+    jit.compile("fn two() -> (r) {\n    r = inc(1)\n}\n")
+        .unwrap();
+    assert_eq!(jit.function("two").unwrap().call(&[]), Ok(vec![2]));
+}
+
+#[test]
+fn undefined_functions() {
+    let mut jit = JIT::default();
+    for (input, name) in &[
+        ("fn call() -> (r) {\n    r = nope(1)\n}\n", "nope"),
+        ("fn destructure() -> (r) {\n    q, r = nope(1)\n}\n", "nope"),
+        (
+            "fn capture() -> (r) {\n    f = |x| nope(x)\n    r = f(1)\n}\n",
+            "nope",
+        ),
+        // The callee is only compiled afterwards.
+        ("fn caller() -> (r) {\n    r = callee(1)\n}\n", "callee"),
+    ] {
+        assert_eq!(
+            jit.compile(input).map(|_| ()),
+            Err(format!("function `{}` not defined", name))
+        );
+    }
+
+    jit.compile("fn callee(x) -> (r) {\n    r = x + 1\n}\n")
+        .unwrap();
+    jit.compile("fn caller() -> (r) {\n    r = callee(1)\n}\n")
+        .unwrap();
+    assert_eq!(jit.function("caller").unwrap().call(&[]), Ok(vec![2]));
+}
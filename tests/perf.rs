@@ -0,0 +1,38 @@
+use cranelift_jit_demo::jit::JIT;
+use std::{fs, process};
+
+#[test]
+fn perf_map() {
+    let mut jit = JIT::default();
+    jit.enable_perf_map().unwrap();
+    let code = jit
+        .compile("fn perf_mapped(x) -> (r) {\n    r = x + 1\n}\n")
+        .unwrap() as usize;
+
+    // Each line is `START SIZE name`, in hex.
+    let map = fs::read_to_string(format!("/tmp/perf-{}.map", process::id())).unwrap();
+    let entry = map
+        .lines()
+        .map(|line| line.splitn(3, ' ').collect::<Vec<_>>())
+        .find(|fields| fields.get(2) == Some(&"perf_mapped"))
+        .unwrap_or_else(|| panic!("no entry in {:?}", map));
+    assert_eq!(usize::from_str_radix(entry[0], 16), Ok(code));
+    assert!(usize::from_str_radix(entry[1], 16).unwrap() > 0);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn jitdump() {
+    let directory = std::env::temp_dir();
+    let mut jit = JIT::default();
+    jit.enable_jitdump(&directory).unwrap();
+    jit.compile("fn dumped(x) -> (r) {\n    r = x + 1\n}\n")
+        .unwrap();
+
+    let path = directory.join(format!("jit-{}.dump", process::id()));
+    let dump = fs::read(&path).unwrap();
+    assert_eq!(dump[..4], 0x4A69_5444u32.to_ne_bytes());
+    // The function's name follows its code load record's header.
+    assert!(dump.windows(7).any(|name| name == b"dumped\0"));
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,67 @@
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::profile::{FunctionReport, ProfileReport};
+use cranelift_jit_demo::runtime::Trap;
+
+const COUNT: &str = r#"
+fn count(n) -> (r) {
+    i = 0
+    while i != n {
+        r = r + 2
+        i = i + 1
+    }
+}
+"#;
+
+const TWICE: &str = r#"
+fn twice(n) -> (r) {
+    r = count(n) + count(n)
+}
+"#;
+
+fn function<'a>(report: &'a ProfileReport, name: &str) -> &'a FunctionReport {
+    report
+        .functions
+        .iter()
+        .find(|function| function.name == name)
+        .unwrap_or_else(|| panic!("no `{}` in {:?}", name, report))
+}
+
+#[test]
+fn counts() {
+    let mut jit = JIT::default();
+    jit.enable_profiler();
+    jit.compile_all(&[COUNT, TWICE]).unwrap();
+
+    let twice = jit.function("twice").unwrap();
+    assert_eq!(twice.call(&[5]), Ok(vec![20]));
+    assert_eq!(twice.call(&[3]), Ok(vec![12]));
+
+    let report = jit.profile_report();
+    let count = function(&report, "count");
+    assert_eq!(count.calls, 4);
+    assert_eq!(count.loops.len(), 1);
+    assert_eq!(count.loops[0].line, 4);
+    assert_eq!(count.loop_iterations(), 16);
+    let twice = function(&report, "twice");
+    assert_eq!(twice.calls, 2);
+    assert_eq!(twice.loop_iterations(), 0);
+    assert!(twice.inclusive_cycles >= count.inclusive_cycles);
+}
+
+#[test]
+fn trap() {
+    let mut jit = JIT::default();
+    jit.enable_profiler();
+    jit.compile("fn divide(d) -> (r) {\n    r = 10 / d\n}\n")
+        .unwrap();
+
+    let divide = jit.function("divide").unwrap();
+    assert_eq!(divide.call(&[0]), Err(Trap::DivisionByZero));
+    assert_eq!(divide.call(&[2]), Ok(vec![5]));
+
+    // The call which trapped mustn't make the next one look recursive.
+    let report = jit.profile_report();
+    let divide = function(&report, "divide");
+    assert_eq!(divide.calls, 2);
+    assert!(divide.inclusive_cycles > 0);
+}
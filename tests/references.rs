@@ -0,0 +1,23 @@
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::runtime::Trap;
+
+#[test]
+fn signature_mismatch() {
+    let mut jit = JIT::default();
+    jit.compile_all(&[
+        "fn unary(x) -> (r) {\n    r = x\n}\n",
+        "fn call(f) -> (r) {\n    r = f(1, 2)\n}\n",
+        "fn binary(x, y) -> (r) {\n    r = x + y\n}\n",
+        "fn matching() -> (r) {\n    r = call(&binary)\n}\n",
+        "fn mismatch() -> (r) {\n    r = call(&unary)\n}\n",
+    ])
+    .unwrap();
+
+    let call = |name: &str, args: &[isize]| jit.function(name).unwrap().call(args);
+    assert_eq!(call("matching", &[]), Ok(vec![3]));
+    assert_eq!(
+        call("mismatch", &[]),
+        Err(Trap::SignatureMismatch { params: 1, args: 2 })
+    );
+    assert_eq!(call("call", &[7]), Err(Trap::NotAFunction(7)));
+}
@@ -0,0 +1,33 @@
+use cranelift_jit_demo::jit::JIT;
+use std::mem;
+use std::thread;
+
+#[test]
+fn thread_local_globals() {
+    let mut jit = JIT::default();
+    jit.declare("thread_local global counter = 100\n").unwrap();
+    let code = jit
+        .compile("fn bump(n) -> (r) {\n    counter = counter + n\n    r = counter\n}\n")
+        .unwrap() as usize;
+
+    // Each thread starts from the initial value, and only sees its own
+    // updates.
+    let bump = move |n: isize| unsafe { mem::transmute::<usize, fn(isize) -> isize>(code)(n) };
+    thread::scope(|scope| {
+        let threads: Vec<_> = (1..=4)
+            .map(|n| {
+                scope.spawn(move || {
+                    for i in 1..=10 {
+                        assert_eq!(bump(n), 100 + i * n);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    });
+    assert_eq!(jit.global("counter").unwrap().get(), 100);
+    assert_eq!(bump(1), 101);
+    assert_eq!(jit.global("counter").unwrap().get(), 101);
+}
@@ -0,0 +1,66 @@
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::tiered::TieredJIT;
+use std::thread;
+use std::time::Duration;
+
+// Call `name` until it's compiled, giving the background compiler a few
+// seconds at most.
+fn call_until_compiled(jit: &TieredJIT, name: &str, args: &[isize]) -> bool {
+    for _ in 0..500 {
+        jit.call(name, args).unwrap();
+        if jit.is_compiled(name) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn callee_defined_later() {
+    let mut jit = TieredJIT::new(10);
+    jit.define(
+        "fn hot(x) -> (r) {\n    r = if x {\n        later(x)\n    } else {\n        0\n    }\n}\n",
+    )
+    .unwrap();
+    for _ in 0..20 {
+        assert_eq!(jit.call("hot", &[0]), Ok(0));
+    }
+    assert!(!jit.is_compiled("hot"));
+
+    // Once `later` is defined, `hot` is hot enough to compile.
+    jit.define("fn later(x) -> (r) {\n    r = x * 2\n}\n")
+        .unwrap();
+    assert!(call_until_compiled(&jit, "hot", &[0]));
+    assert_eq!(jit.call("hot", &[21]), Ok(42));
+}
+
+#[test]
+fn traps_once_compiled() {
+    let mut jit = JIT::default();
+    jit.enable_tiering(10);
+    let code = jit
+        .compile_all(&[
+            "fn divide(a, b) -> (r) {\n    r = a / b\n}\n",
+            "fn get(n, i) -> (r) {\n    a = array(n)\n    r = a[i]\n}\n",
+        ])
+        .unwrap();
+    assert!(code.iter().all(|code| code.is_null()));
+
+    let tiered = jit.tiered().unwrap();
+    assert_eq!(
+        tiered.call("divide", &[7, 0]),
+        Err("division by zero".to_owned())
+    );
+    assert!(call_until_compiled(tiered, "divide", &[7, 2]));
+    assert_eq!(tiered.call("divide", &[7, 2]), Ok(3));
+    assert_eq!(
+        tiered.call("divide", &[7, 0]),
+        Err("division by zero".to_owned())
+    );
+
+    let out_of_bounds = Err("index out of bounds: the len is 2 but the index is 2".to_owned());
+    assert_eq!(tiered.call("get", &[2, 2]), out_of_bounds);
+    assert!(call_until_compiled(tiered, "get", &[2, 1]));
+    assert_eq!(tiered.call("get", &[2, 2]), out_of_bounds);
+}
@@ -0,0 +1,73 @@
+use cranelift_jit_demo::jit::JIT;
+use cranelift_jit_demo::trace::{self, TraceEvent};
+
+const DIVMOD: &str = r#"fn divmod(a, b) -> (q, r) {
+    q = a / b
+    r = a - q * b
+}
+"#;
+
+const LAST_DIGIT: &str = r#"fn last_digit(n) -> (r) {
+    q, m = divmod(n, 10)
+    r = m
+}
+"#;
+
+#[test]
+fn round_trip() {
+    let mut jit = JIT::default();
+    jit.enable_trace();
+    jit.compile_all(&[DIVMOD, LAST_DIGIT]).unwrap();
+    let last_digit = jit.function("last_digit").unwrap();
+    assert_eq!(last_digit.call(&[47]), Ok(vec![7]));
+
+    let assign = |function: &str, line, variable: &str, value| TraceEvent::Assign {
+        function: function.to_owned(),
+        line,
+        variable: variable.to_owned(),
+        value,
+    };
+    let events = jit.take_trace();
+    assert_eq!(
+        events,
+        [
+            TraceEvent::Call {
+                function: "last_digit".to_owned(),
+                line: 2,
+                callee: "divmod".to_owned(),
+                args: vec![47, 10],
+            },
+            assign("divmod", 2, "q", 4),
+            assign("divmod", 3, "r", 7),
+            TraceEvent::Return {
+                function: "last_digit".to_owned(),
+                line: 2,
+                callee: "divmod".to_owned(),
+                results: vec![4, 7],
+            },
+            assign("last_digit", 2, "q", 4),
+            assign("last_digit", 2, "m", 7),
+            assign("last_digit", 3, "r", 7),
+        ]
+    );
+    assert!(jit.take_trace().is_empty());
+
+    let json = trace::to_json_lines(&events);
+    assert_eq!(json.lines().count(), events.len());
+    assert!(json.contains(r#""results":[4,7]"#), "{}", json);
+    assert_eq!(trace::from_json_lines(&json), Ok(events));
+}
+
+#[test]
+fn malformed() {
+    assert_eq!(
+        trace::from_json_lines("\n{\"event\":\"jump\",\"function\":\"f\",\"line\":1}\n"),
+        Err("line 2: unknown event `jump`".to_owned())
+    );
+    assert_eq!(
+        trace::from_json_lines(
+            r#"{"event":"return","function":"f","line":1,"callee":"g","results":7}"#
+        ),
+        Err("line 1: missing array `results`".to_owned())
+    );
+}